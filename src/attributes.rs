@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::config;
+use crate::dircache::DirCache;
+use crate::error as err;
+use crate::objects as obj;
+use crate::pattern::Pattern;
+
+/// The state an attribute has for a path, attributes no line mentions are
+/// unspecified and left out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttrState {
+    Set,
+    Unset,
+    Value(String),
+}
+
+/// One line of an attributes file, a pattern followed by attributes
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AttrLine {
+    pattern: Pattern,
+    // None resets the attribute to unspecified
+    attrs: Vec<(String, Option<AttrState>)>,
+}
+
+type Macros = BTreeMap<String, Vec<(String, Option<AttrState>)>>;
+
+/// The attribute lines of one file along with the macros it defines
+#[derive(Debug, Default)]
+struct AttrFile {
+    lines: Vec<AttrLine>,
+    macros: Macros,
+}
+
+// the binary macro is built in and expands to these attributes
+const BINARY_MACRO: [&str; 3] = ["-diff", "-merge", "-text"];
+
+// attribute names are made of letters, digits, dashes, dots and
+// underscores and can't start with a dash
+fn valid_attr_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'))
+}
+
+fn parse_attr(attr: &str) -> Option<(String, Option<AttrState>)> {
+    let (name, state) = if let Some(name) = attr.strip_prefix('-') {
+        (name, Some(AttrState::Unset))
+    } else if let Some(name) = attr.strip_prefix('!') {
+        (name, None)
+    } else if let Some((name, value)) = attr.split_once('=') {
+        (name, Some(AttrState::Value(value.to_owned())))
+    } else {
+        (attr, Some(AttrState::Set))
+    };
+    valid_attr_name(name).then(|| (name.to_owned(), state))
+}
+
+// negative patterns aren't allowed in attribute files so those lines are
+// skipped, as are macro definitions in files where they aren't allowed
+fn parse_file(contents: &str, allow_macros: bool) -> AttrFile {
+    let mut file = AttrFile::default();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let Some(first) = parts.next() else {
+            continue;
+        };
+        let attrs = parts.filter_map(parse_attr).collect();
+        if let Some(name) = first.strip_prefix("[attr]") {
+            if allow_macros && valid_attr_name(name) {
+                file.macros.insert(name.to_owned(), attrs);
+            }
+            continue;
+        }
+        match Pattern::parse(first) {
+            Some(pattern) if !pattern.negated => file.lines.push(AttrLine { pattern, attrs }),
+            _ => {}
+        }
+    }
+    file
+}
+
+fn read_attr_file(path: &Path, allow_macros: bool) -> Result<Option<AttrFile>, err::Error> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    Ok(Some(parse_file(&read_to_string(path)?, allow_macros)))
+}
+
+fn builtin_macros() -> Macros {
+    let binary = BINARY_MACRO.iter().filter_map(|a| parse_attr(a)).collect();
+    BTreeMap::from([("binary".to_owned(), binary)])
+}
+
+// fills in the attributes of a line that no higher priority line has
+// filled yet, last one first. A macro expands only when this line is the
+// one that sets it, so a macro unset by a later line expands to nothing
+// and each attribute being filled once stops macros expanding forever.
+fn fill_attrs(
+    filled: &mut BTreeMap<String, Option<AttrState>>,
+    attrs: &[(String, Option<AttrState>)],
+    macros: &Macros,
+) {
+    for (name, state) in attrs.iter().rev() {
+        if filled.contains_key(name) {
+            continue;
+        }
+        filled.insert(name.to_owned(), state.clone());
+        if state == &Some(AttrState::Set) {
+            if let Some(expanded) = macros.get(name) {
+                fill_attrs(filled, expanded, macros);
+            }
+        }
+    }
+}
+
+/// The attribute files of a worktree. core.attributesFile and
+/// info/attributes are read up front and each .gitattributes file once, as
+/// the dir holding it is first checked, so converting many files doesn't
+/// read them again. The rules can be shared by threads.
+#[derive(Debug)]
+pub struct AttrRules {
+    // the dir the .gitattributes files are read from, the worktree unless
+    // a tree is being written elsewhere
+    root: PathBuf,
+    global: Option<AttrFile>,
+    info: Option<AttrFile>,
+    // the .gitattributes file of each dir, None when the dir has none
+    dirs: DirCache<Option<AttrFile>>,
+}
+
+impl AttrRules {
+    pub fn new(repo: &obj::Repo) -> Result<AttrRules, err::Error> {
+        AttrRules::with_root(&repo.worktree, repo)
+    }
+
+    /// Rules for a tree written somewhere other than the worktree, its
+    /// .gitattributes files are read from under root
+    pub fn with_root(root: &Path, repo: &obj::Repo) -> Result<AttrRules, err::Error> {
+        let global = match config::get_path(repo, "core.attributesFile")? {
+            Some(global) => read_attr_file(&global, true)?,
+            None => None,
+        };
+        Ok(AttrRules {
+            root: root.to_path_buf(),
+            global,
+            info: read_attr_file(&repo.git_path("info/attributes"), true)?,
+            dirs: DirCache::default(),
+        })
+    }
+
+    fn dir_file(&self, dir: &str) -> Result<Arc<Option<AttrFile>>, err::Error> {
+        self.dirs.get_or_read(dir, || {
+            let path = self.root.join(dir).join(".gitattributes");
+            read_attr_file(&path, dir.is_empty())
+        })
+    }
+
+    /// Forgets the cached file of the path's dir when the path is a
+    /// .gitattributes file, so one written by a checkout is read again
+    pub fn file_written(&self, path: &str) {
+        let dir = match path.rsplit_once('/') {
+            Some((dir, ".gitattributes")) => dir,
+            None if path == ".gitattributes" => "",
+            _ => return,
+        };
+        self.dirs.forget(dir);
+    }
+
+    /// Returns the attributes of the path. Files are read from the lowest
+    /// priority to the highest: core.attributesFile, the .gitattributes
+    /// files from the top of the worktree down to the path's dir and then
+    /// info/attributes. Later lines override earlier ones, so attributes
+    /// are filled in from the last line read back to the first. Macros can
+    /// only be defined outside of subdirs.
+    pub fn path_attributes(&self, path: &str) -> Result<BTreeMap<String, AttrState>, err::Error> {
+        let mut dirs = vec![""];
+        dirs.extend(path.match_indices('/').map(|(i, _)| &path[..i]));
+        let dir_files = dirs
+            .iter()
+            .map(|dir| self.dir_file(dir))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // each file with the dir its patterns are relative to
+        let mut files = Vec::new();
+        files.extend(self.global.as_ref().map(|f| ("", f)));
+        for (dir, file) in dirs.iter().zip(&dir_files) {
+            files.extend(file.as_ref().as_ref().map(|f| (*dir, f)));
+        }
+        files.extend(self.info.as_ref().map(|f| ("", f)));
+
+        let mut macros = builtin_macros();
+        for (_, file) in &files {
+            macros.extend(file.macros.clone());
+        }
+
+        // attributes reset with '!' are filled as None and left out
+        let mut filled = BTreeMap::new();
+        for (dir, file) in files.iter().rev() {
+            let relative = path[dir.len()..].trim_start_matches('/');
+            for line in file.lines.iter().rev() {
+                // patterns ending in '/' only match dirs so never match a file
+                if line.pattern.matches(relative, false) {
+                    fill_attrs(&mut filled, &line.attrs, &macros);
+                }
+            }
+        }
+        Ok(filled
+            .into_iter()
+            .filter_map(|(name, state)| Some((name, state?)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod attributes_tests {
+    use super::*;
+    use crate::test_utils;
+    use std::fs::{create_dir_all, write};
+
+    #[test]
+    fn deeper_files_and_later_lines_take_priority() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        create_dir_all(repo.worktree.join("docs")).unwrap();
+        write(
+            repo.worktree.join(".gitattributes"),
+            "*.txt text eol=crlf\n*.png binary\ndocs/*.txt -text\n",
+        )
+        .unwrap();
+        write(
+            repo.worktree.join("docs/.gitattributes"),
+            "*.txt text=auto !eol\n",
+        )
+        .unwrap();
+
+        let rules = AttrRules::new(&repo).unwrap();
+        let attrs = rules.path_attributes("a.txt").unwrap();
+        assert_eq!(Some(&AttrState::Set), attrs.get("text"));
+        assert_eq!(Some(&AttrState::Value("crlf".to_owned())), attrs.get("eol"));
+
+        let attrs = rules.path_attributes("docs/b.txt").unwrap();
+        assert_eq!(
+            Some(&AttrState::Value("auto".to_owned())),
+            attrs.get("text")
+        );
+        assert_eq!(None, attrs.get("eol"));
+
+        let attrs = rules.path_attributes("img/logo.png").unwrap();
+        assert_eq!(Some(&AttrState::Set), attrs.get("binary"));
+        assert_eq!(Some(&AttrState::Unset), attrs.get("text"));
+        assert_eq!(Some(&AttrState::Unset), attrs.get("diff"));
+        assert!(rules.path_attributes("other").unwrap().is_empty());
+    }
+
+    #[test]
+    fn files_are_read_once_until_written_again() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        create_dir_all(repo.worktree.join("docs")).unwrap();
+        write(repo.worktree.join("docs/.gitattributes"), "*.txt text\n").unwrap();
+        let rules = AttrRules::new(&repo).unwrap();
+        let text = |rules: &AttrRules| rules.path_attributes("docs/a.txt").unwrap()["text"].clone();
+        assert_eq!(AttrState::Set, text(&rules));
+
+        write(repo.worktree.join("docs/.gitattributes"), "*.txt -text\n").unwrap();
+        assert_eq!(AttrState::Set, text(&rules));
+        rules.file_written("docs/a.txt");
+        assert_eq!(AttrState::Set, text(&rules));
+        rules.file_written("docs/.gitattributes");
+        assert_eq!(AttrState::Unset, text(&rules));
+    }
+}
@@ -21,13 +21,106 @@ pub enum GitCmd {
     LsTree {
         sha: String
     },
-    /// Checkout a given sha in a given directory, the directory must be empty and created beforehand
+    /// Switch to a branch or detach HEAD at a commit in the current worktree, or
+    /// with a dir write a given sha into that dir which must be empty and created beforehand
     Checkout {
         sha: String,
-        dir: String
+        dir: Option<String>,
+        /// Create a new branch starting at the given sha and switch to it
+        #[arg(short = 'b', value_name = "new-branch")]
+        new_branch: Option<String>,
+    },
+    /// Switch branches, updating the index and the files in the worktree
+    Switch {
+        /// Branch to switch to, the start point with -c or the commit with --detach
+        target: Option<String>,
+        /// Create a new branch starting at the target, HEAD by default
+        #[arg(short, long, value_name = "new-branch")]
+        create: Option<String>,
+        /// Switch to a commit without being on a branch
+        #[arg(long, default_value_t = false)]
+        detach: bool,
+    },
+    /// Restore files in the worktree from the index or staged files from HEAD
+    Restore {
+        /// Files, directories or globs to restore
+        pathspecs: Vec<String>,
+        /// Restore the index, from HEAD unless a source is given
+        #[arg(short = 'S', long, default_value_t = false)]
+        staged: bool,
+        /// Restore the worktree, the default unless --staged is given
+        #[arg(short = 'W', long, default_value_t = false)]
+        worktree: bool,
+        /// Restore from this commit or tree instead
+        #[arg(short, long, value_name = "tree-ish")]
+        source: Option<String>,
+    },
+    /// Move the current branch to a commit, resetting the index and optionally the worktree,
+    /// or with paths reset their index entries to the commit
+    Reset {
+        /// Commit to reset to, HEAD by default
+        rev: Option<String>,
+        /// Paths to unstage, given after '--'
+        #[arg(last = true)]
+        paths: Vec<String>,
+        /// Only move the branch, leaving the index and worktree alone
+        #[arg(long, default_value_t = false, conflicts_with_all = ["mixed", "hard"])]
+        soft: bool,
+        /// Reset the index but not the worktree, the default
+        #[arg(long, default_value_t = false, conflicts_with = "hard")]
+        mixed: bool,
+        /// Reset the index and the tracked files in the worktree
+        #[arg(long, default_value_t = false)]
+        hard: bool,
     },
     /// Display refs available in local repo along with associated commit IDs
     ShowRef,
+    /// Output information on each ref matching the patterns
+    ForEachRef {
+        /// Only refs matching a glob or starting with one of these up to a '/' are shown
+        patterns: Vec<String>,
+        /// Format with %(atom) placeholders such as %(refname:short), %(objectname:short),
+        /// %(objecttype), %(committerdate), %(subject) and %(upstream)
+        #[arg(long)]
+        format: Option<String>,
+        /// Sort by a field, prefix with '-' for descending, the last key given is the primary one
+        #[arg(long, value_name = "key")]
+        sort: Vec<String>,
+        /// Stop after showing this many refs
+        #[arg(long)]
+        count: Option<usize>,
+        /// Only show refs pointing at the object
+        #[arg(long, value_name = "object")]
+        points_at: Option<String>,
+        /// Only show refs whose commits are reachable from the commit, HEAD by default
+        #[arg(long, value_name = "commit", num_args = 0..=1, default_missing_value = "HEAD")]
+        merged: Option<String>,
+        /// Only show refs whose commits contain the commit, HEAD by default
+        #[arg(long, value_name = "commit", num_args = 0..=1, default_missing_value = "HEAD")]
+        contains: Option<String>,
+    },
+    /// Check a ref name follows git's rules, exits with an error when it doesn't
+    CheckRefFormat {
+        refname: String,
+        /// Check the name as a branch name and print it
+        #[arg(long, default_value_t = false)]
+        branch: bool,
+        /// Drop a leading '/' and collapse repeated '/'s, then print the name
+        #[arg(long, default_value_t = false)]
+        normalize: bool,
+        /// Allow names without a '/'
+        #[arg(long, default_value_t = false)]
+        allow_onelevel: bool,
+        /// Allow a single '*' as used in refspecs
+        #[arg(long, default_value_t = false)]
+        refspec_pattern: bool,
+    },
+    /// Move loose refs into the packed-refs file
+    PackRefs {
+        /// Pack every ref instead of only tags and refs that are already packed
+        #[arg(long, default_value_t = false)]
+        all: bool,
+    },
     /// Create or list tag objects.
     Tag {
         /// Name of the tag, if omitted command assumed to be 'rusty-git tag' which lists all tags
@@ -42,16 +135,320 @@ pub enum GitCmd {
     /// List the names of the files being tracked in the git index
     LsFiles,
     /// Show the working tree status
-    Status,
+    Status {
+        /// Show ignored files as well
+        #[arg(long, default_value_t = false)]
+        ignored: bool,
+        /// Give the output in the short format
+        #[arg(short, long, default_value_t = false)]
+        short: bool,
+        /// Give the output in a stable format for scripts, v1 or v2
+        #[arg(
+            long,
+            value_name = "version",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "v1",
+            value_parser = ["v1", "v2"]
+        )]
+        porcelain: Option<String>,
+        /// Show the branch and its tracking info in the short formats
+        #[arg(short, long, default_value_t = false)]
+        branch: bool,
+        /// Terminate entries with NUL, implies --porcelain=v1 without another format
+        #[arg(short = 'z', default_value_t = false)]
+        null_terminated: bool,
+    },
     /// Add file contents to the index
     Add {
-        file_name: String
+        /// Files, directories or globs to add, '.' adds the whole worktree
+        pathspecs: Vec<String>,
+        /// Stage deletions as well as new and modified files, defaults to the whole worktree
+        #[arg(short = 'A', long = "all", default_value_t = false)]
+        all: bool,
+        /// Only stage modified and deleted files that are already tracked
+        #[arg(short, long, default_value_t = false)]
+        update: bool,
+        /// Show what would be added without changing the index
+        #[arg(short = 'n', long, default_value_t = false)]
+        dry_run: bool,
+        /// Allow adding otherwise ignored files
+        #[arg(short, long, default_value_t = false)]
+        force: bool,
+        /// Allow updating index entries outside of the sparse checkout
+        #[arg(long, default_value_t = false)]
+        sparse: bool,
+    },
+    /// Register file contents in the worktree or given object ids directly in the index
+    UpdateIndex {
+        /// Paths in the worktree to update in the index
+        paths: Vec<String>,
+        /// Allow paths not yet in the index to be added
+        #[arg(long, default_value_t = false)]
+        add: bool,
+        /// Remove paths that no longer exist in the worktree from the index
+        #[arg(long, default_value_t = false)]
+        remove: bool,
+        /// Refresh the stat info of unchanged entries and report changed ones
+        #[arg(long, default_value_t = false)]
+        refresh: bool,
+        /// Insert an entry given as <mode>,<sha>,<path> without a worktree file
+        #[arg(long, value_name = "MODE,SHA,PATH")]
+        cacheinfo: Vec<String>,
+        /// Set (+x) or clear (-x) the executable bit of the given paths
+        #[arg(long, allow_hyphen_values = true)]
+        chmod: Option<String>,
+        /// Mark the given paths so their worktree files aren't checked for changes
+        #[arg(long, default_value_t = false, conflicts_with = "no_assume_unchanged")]
+        assume_unchanged: bool,
+        /// Clear the assume-unchanged bit of the given paths
+        #[arg(long, default_value_t = false)]
+        no_assume_unchanged: bool,
+        /// Mark the given paths as excluded from the worktree
+        #[arg(long, default_value_t = false, conflicts_with = "no_skip_worktree")]
+        skip_worktree: bool,
+        /// Clear the skip-worktree bit of the given paths
+        #[arg(long, default_value_t = false)]
+        no_skip_worktree: bool,
+        /// Read index entries from stdin in the format of 'ls-tree' output
+        #[arg(long, default_value_t = false)]
+        index_info: bool,
+    },
+    /// Create a tree object from the index and print its sha
+    WriteTree {
+        /// Write the tree for this sub directory of the index
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Read tree information into the index
+    ReadTree {
+        /// Tree-ish objects to read, -m accepts up to three for a merge
+        #[arg(required = true, num_args = 1..=3)]
+        trees: Vec<String>,
+        /// Merge the trees into the index instead of replacing it
+        #[arg(short = 'm', default_value_t = false, conflicts_with = "prefix")]
+        merge: bool,
+        /// Read the tree into this sub directory of the index
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Create a commit object for a tree and print its sha, no refs are updated
+    CommitTree {
+        /// Sha of the tree the commit points to
+        tree: String,
+        /// A parent commit, can be given more than once
+        #[arg(short = 'p', value_name = "parent")]
+        parents: Vec<String>,
+        /// A paragraph of the commit message, read from stdin if omitted
+        #[arg(short = 'm', value_name = "message")]
+        msgs: Vec<String>,
     },
     /// Record changes staged in the index to the repository
     Commit {
         #[arg(short, value_name = "Commit message")]
         msg: String
     },
+    /// List, create, delete or rename branches
+    Branch {
+        /// Branch name followed by an optional start point when creating,
+        /// the branches to delete with -d or [old] new with -m
+        args: Vec<String>,
+        /// List remote tracking branches as well as local branches
+        #[arg(short, long, default_value_t = false)]
+        all: bool,
+        /// Show the sha and subject of the commit at the tip of each branch
+        #[arg(short, long, default_value_t = false)]
+        verbose: bool,
+        /// Delete fully merged branches
+        #[arg(short, long, default_value_t = false)]
+        delete: bool,
+        /// Delete branches even if they aren't merged
+        #[arg(short = 'D', default_value_t = false)]
+        force_delete: bool,
+        /// Rename a branch, the current branch if only the new name is given
+        #[arg(short = 'm', long = "move", default_value_t = false)]
+        rename: bool,
+        /// Print the name of the current branch
+        #[arg(long, default_value_t = false)]
+        show_current: bool,
+    },
+    /// Safely update or delete refs, checking their old values first
+    UpdateRef {
+        /// <ref> <new> [<old>], or <ref> [<old>] with -d
+        args: Vec<String>,
+        /// Delete the ref
+        #[arg(short, default_value_t = false)]
+        delete: bool,
+        /// Read update, create, delete and verify commands from stdin
+        #[arg(long, default_value_t = false)]
+        stdin: bool,
+        /// Reason recorded in the reflog
+        #[arg(short = 'm', value_name = "reason")]
+        reason: Option<String>,
+    },
+    /// Read, change or delete a symbolic ref such as HEAD
+    SymbolicRef {
+        /// The symbolic ref, e.g. HEAD
+        name: String,
+        /// The ref to point the symbolic ref at, it's read when omitted
+        target: Option<String>,
+        /// Delete the symbolic ref
+        #[arg(short, long, default_value_t = false)]
+        delete: bool,
+        /// Shorten the ref when reading, e.g. refs/heads/main to main
+        #[arg(long, default_value_t = false)]
+        short: bool,
+        /// Reason recorded in the reflog when the symbolic ref is changed
+        #[arg(short = 'm', value_name = "reason")]
+        reason: Option<String>,
+    },
+    /// Show or manage the history of where refs have pointed, shows HEAD by default
+    Reflog {
+        #[command(subcommand)]
+        action: Option<ReflogCmd>,
+    },
+    /// Reduce the worktree to a subset of the tracked files
+    SparseCheckout {
+        #[command(subcommand)]
+        action: SparseCheckoutCmd,
+    },
+    /// Manage multiple worktrees sharing the same repository
+    Worktree {
+        #[command(subcommand)]
+        action: WorktreeCmd,
+    },
+    /// Print the paths that are ignored by .gitignore, info/exclude or core.excludesFile
+    CheckIgnore {
+        /// Print the rule matching each path, including rules that negate an ignore
+        #[arg(short, long, default_value_t = false)]
+        verbose: bool,
+        paths: Vec<String>,
+    },
+    /// Print the gitattributes of paths
+    CheckAttr {
+        /// Print every attribute set on the paths
+        #[arg(short, long, default_value_t = false)]
+        all: bool,
+        /// Attributes to print followed by the paths, or only paths with --all
+        args: Vec<String>,
+        /// Paths to print the attributes of, given after '--'
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+    /// Run a daemon watching the worktree so status only checks the paths that changed,
+    /// used when core.fsmonitor is true
+    #[command(name = "fsmonitor--daemon")]
+    FsmonitorDaemon {
+        #[command(subcommand)]
+        action: FsmonitorDaemonCmd,
+    },
+}
+
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+pub enum ReflogCmd {
+    /// List the reflog entries of a ref, newest first
+    Show {
+        #[arg(default_value_t = String::from("HEAD"))]
+        reference: String,
+    },
+    /// Remove reflog entries older than the expire time
+    Expire {
+        /// Refs whose reflogs are expired
+        refs: Vec<String>,
+        /// Expire the reflogs of every ref
+        #[arg(long, default_value_t = false)]
+        all: bool,
+        /// Entries older than this are removed, defaults to 90.days.ago
+        #[arg(long, value_name = "time")]
+        expire: Option<String>,
+    },
+    /// Delete single entries given as <ref>@{<n>}
+    Delete {
+        #[arg(required = true)]
+        entries: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+pub enum SparseCheckoutCmd {
+    /// Enable sparse checkout, keeping only the files at the top of the worktree
+    Init {
+        /// Use cone mode where directories are given instead of patterns
+        #[arg(long, default_value_t = false, conflicts_with = "no_cone")]
+        cone: bool,
+        /// Use gitignore style patterns instead of directories
+        #[arg(long, default_value_t = false)]
+        no_cone: bool,
+    },
+    /// Replace the sparse checkout with the given directories or patterns
+    Set {
+        /// Directories in cone mode, gitignore style patterns otherwise
+        patterns: Vec<String>,
+        /// Use cone mode where directories are given instead of patterns
+        #[arg(long, default_value_t = false, conflicts_with = "no_cone")]
+        cone: bool,
+        /// Use gitignore style patterns instead of directories
+        #[arg(long, default_value_t = false)]
+        no_cone: bool,
+    },
+    /// Add directories or patterns to the sparse checkout
+    Add {
+        #[arg(required = true)]
+        patterns: Vec<String>,
+    },
+    /// List the directories or patterns in the sparse checkout
+    List,
+    /// Update the worktree to match the sparse checkout patterns
+    Reapply,
+    /// Disable sparse checkout and restore every file to the worktree
+    Disable,
+}
+
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+pub enum WorktreeCmd {
+    /// Create a worktree at the path, checking out a new branch named after the path's last
+    /// part unless a commit-ish is given
+    Add {
+        path: String,
+        /// Branch to check out, any other commit-ish detaches HEAD
+        commitish: Option<String>,
+        /// Create a new branch at the commit-ish and check it out
+        #[arg(short = 'b', value_name = "new-branch")]
+        create: Option<String>,
+        /// Check out the commit-ish without being on a branch
+        #[arg(long, default_value_t = false)]
+        detach: bool,
+    },
+    /// List the main worktree followed by the linked worktrees
+    List,
+    /// Keep a worktree from being pruned or removed
+    Lock {
+        path: String,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Allow a locked worktree to be pruned or removed again
+    Unlock { path: String },
+    /// Delete a worktree, refusing when it has local changes unless forced
+    Remove {
+        path: String,
+        #[arg(short, long, default_value_t = false)]
+        force: bool,
+    },
+    /// Remove the information of worktrees whose dirs no longer exist
+    Prune,
+}
+
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+pub enum FsmonitorDaemonCmd {
+    /// Start the daemon in the background
+    Start,
+    /// Run the daemon in the foreground
+    Run,
+    /// Stop the daemon watching the worktree
+    Stop,
+    /// Report whether a daemon is watching the worktree
+    Status,
 }
 
 #[derive(Parser, Debug)]
@@ -1,19 +1,24 @@
-use chrono::{TimeZone, Utc};
-use std::fs::{metadata, File};
-use std::io::Write;
+use chrono::{DateTime, TimeZone, Utc};
+use std::fmt::Write as _;
+use std::fs::{remove_file, rename, symlink_metadata, OpenOptions};
+use std::io::{ErrorKind, Write};
 use std::os::unix::prelude::MetadataExt;
+use std::path::Path;
 
+use crate::attributes as attr;
+use crate::cmds::{sparsecheckout, updateindex};
 use crate::error as err;
+use crate::ignore;
 use crate::index as idx;
 use crate::objects::{self as obj, blob, AsBytes};
+use crate::pathspec;
 use crate::utils;
+use crate::walk;
 
-pub fn file_to_index_entry(
-    file_name: &str,
-    repo: &obj::Repo,
-) -> Result<idx::IndexEntry, err::Error> {
-    let file = repo.worktree.join(file_name);
-    let md = metadata(&file)?;
+/// Builds an index entry holding only the stat info and mode of the
+/// worktree file, the sha is left empty
+pub fn stat_index_entry(file_name: &str, repo: &obj::Repo) -> Result<idx::IndexEntry, err::Error> {
+    let md = symlink_metadata(repo.worktree.join(file_name))?;
 
     let c_time_dt;
     if let Some(ct) = Utc
@@ -27,7 +32,7 @@ pub fn file_to_index_entry(
 
     let m_time_dt;
     if let Some(mt) = Utc
-        .timestamp_opt(md.ctime(), md.ctime_nsec() as u32)
+        .timestamp_opt(md.mtime(), md.mtime_nsec() as u32)
         .single()
     {
         m_time_dt = mt;
@@ -35,49 +40,516 @@ pub fn file_to_index_entry(
         return Err(err::Error::TimestampConversion);
     };
 
-    let blob = blob::blob_from_path(file)?;
-    let sha = obj::write_object(blob, None)?;
+    // git only tracks the executable bit, all other permission bits are dropped
+    let mode = if md.is_dir() {
+        0o160000
+    } else if md.is_symlink() {
+        0o120000
+    } else if md.mode() & 0o111 != 0 {
+        0o100755
+    } else {
+        0o100644
+    };
 
     Ok(idx::IndexEntry {
         c_time: c_time_dt,
         m_time: m_time_dt,
         dev: md.dev() as u32,
         inode: md.ino() as u32,
-        mode: md.mode(),
+        mode,
         uid: md.uid(),
         gid: md.gid(),
         size: md.size() as u32,
-        sha: sha.bytes().to_vec(),
+        sha: Vec::new(),
         name: file_name.to_owned(),
+        flags: 0,
+        extended_flags: 0,
     })
 }
 
-pub fn add_entry_to_index(repo: &obj::Repo, file_name: &str) -> Result<idx::Index, err::Error> {
-    let index_contents = utils::git_read_index(repo)?;
-    let mut index = idx::parse_git_index(&index_contents)?;
+pub fn file_to_index_entry(
+    file_name: &str,
+    attrs: &attr::AttrRules,
+    index_sha: Option<&str>,
+    repo: &obj::Repo,
+    write_blob: bool,
+) -> Result<idx::IndexEntry, err::Error> {
+    let mut entry = stat_index_entry(file_name, repo)?;
+
+    // a nested repo is recorded as a gitlink to the commit it has checked
+    // out, its files belong to it and no blob is written
+    entry.sha = if entry.mode == 0o160000 {
+        let sha = obj::Repo::new(repo.worktree.join(file_name))
+            .and_then(|nested| utils::git_sha_from_head(&nested))
+            .map_err(|_| err::Error::GitSubmoduleNoCommit(file_name.to_owned()))?;
+        hex::decode(sha).map_err(|_| err::Error::GitMalformedObject)?
+    } else {
+        let blob = blob::blob_from_worktree(file_name, attrs, index_sha, repo)?;
+        obj::write_object(blob, write_blob.then_some(repo))?.bytes().to_vec()
+    };
+    Ok(entry)
+}
 
-    let entry = file_to_index_entry(file_name, repo)?;
-    match index.entries.binary_search(&entry) {
-        // already exists, remove existing, replace with new
-        Ok(pos) => {
-            index.entries.remove(pos);
-            index.entries.insert(pos, entry);
+// entries whose files changed in the same tick the index is written in
+// can't be trusted by their stat info once the index mtime moves past
+// them, their size is zeroed so the file is always hashed
+fn index_bytes(
+    mut index: idx::Index,
+    index_mtime: DateTime<Utc>,
+    repo: &obj::Repo,
+) -> Result<Vec<u8>, err::Error> {
+    for entry in index.entries.iter_mut() {
+        if entry.m_time >= index_mtime {
+            entry.size = 0;
         }
-        // doesn't exist, add at pos where entry should be
-        Err(pos) => index.entries.insert(pos, entry),
-    };
-    Ok(index.to_owned())
+    }
+    if index.untracked.is_some() || index.fsmonitor.is_some() {
+        index.invalidate_extensions(&idx::read_index_or_empty(repo)?);
+    }
+    Ok(index.as_bytes())
 }
 
+/// Writes the index to index.lock and renames it into place so readers never
+/// see a partly written index. Fails when another process holds the lock.
 pub fn write_index(index: idx::Index, repo: &obj::Repo) -> Result<(), err::Error> {
-    // the File::create call will truncate the index
-    let mut index_file = File::create(repo.gitdir.join("index"))?;
-    index_file.write_all(&index.as_bytes())?;
-    Ok(())
+    let lock = repo.gitdir.join("index.lock");
+    let mut lock_file = match OpenOptions::new().write(true).create_new(true).open(&lock) {
+        Ok(lock_file) => lock_file,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            return Err(err::Error::GitIndexLocked(lock.display().to_string()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let written = lock_file
+        .metadata()
+        .map_err(err::Error::from)
+        .and_then(|md| {
+            let bytes = index_bytes(index, md.modified()?.into(), repo)?;
+            lock_file.write_all(&bytes)?;
+            Ok(rename(&lock, repo.gitdir.join("index"))?)
+        });
+    if written.is_err() {
+        let _ = remove_file(&lock);
+    }
+    written
+}
+
+// whether the path is a file, link or nested repo in the worktree, the
+// kinds of paths the walk returns
+fn is_worktree_file(name: &str, repo: &obj::Repo) -> bool {
+    let path = repo.worktree.join(name);
+    symlink_metadata(&path).is_ok_and(|md| !md.is_dir() || path.join(".git").exists())
 }
 
-pub fn update_index(repo: &obj::Repo, file_name: &str) -> Result<(), err::Error> {
-    let updated_index = add_entry_to_index(repo, file_name)?;
-    write_index(updated_index, repo)?;
-    Ok(())
+/// Returns the path of every file in the worktree relative to the top of
+/// the worktree, the .git dir is skipped but ignored files are included.
+/// A dir holding a nested repo is returned as a single path.
+pub fn gather_worktree_files(
+    path: Option<&Path>,
+    repo: &obj::Repo,
+) -> Result<Vec<String>, err::Error> {
+    let dir = path.unwrap_or(&repo.worktree);
+    let threads = walk::worker_threads(repo)?;
+    let names = walk::walk_worktree(dir, None, threads, repo)?;
+    Ok(names.files.into_iter().collect())
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AddOptions {
+    /// stage deletions of tracked files as well as new and modified files
+    pub all: bool,
+    /// only stage changes to files that are already tracked
+    pub update: bool,
+    /// report what would be staged without touching the index or object store
+    pub dry_run: bool,
+    /// allow adding files that are otherwise ignored
+    pub force: bool,
+    /// allow updating entries outside of the sparse checkout
+    pub sparse: bool,
+}
+
+/// Stages every file matching the pathspecs, the index is only written once
+/// after all the matching files have been processed. When no pathspecs are
+/// given and either -A or -u is set the whole worktree is considered.
+pub fn add(
+    pathspecs: &[String],
+    opts: &AddOptions,
+    repo: &obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    if pathspecs.is_empty() && !opts.all && !opts.update {
+        return Err(err::Error::GitNothingSpecifiedToAdd);
+    }
+    let pathspec = pathspec::Pathspec::new(pathspecs, repo)?;
+
+    let starting_index = idx::read_index_or_empty(repo)?;
+    let mut index = starting_index.clone();
+    let ignore_rules = ignore::IgnoreRules::new(repo)?;
+    let sparse = sparsecheckout::read_sparse_checkout(repo)?.filter(|_| !opts.sparse);
+
+    // without -f the walk never enters ignored dirs. Tracked files are
+    // staged even when ignored so they're found through the index.
+    let threads = walk::worker_threads(repo)?;
+    let rules = (!opts.force).then_some(&ignore_rules);
+    let walked = walk::walk_worktree(&repo.worktree, rules, threads, repo)?;
+    let mut worktree_files = walked.files;
+    for entry in &index.entries {
+        if !worktree_files.contains(&entry.name) && is_worktree_file(&entry.name, repo) {
+            worktree_files.insert(entry.name.clone());
+        }
+    }
+    let ignored: Vec<&str> = walked
+        .ignored
+        .iter()
+        .map(|p| p.trim_end_matches('/'))
+        .collect();
+
+    // ignored paths named on the command line are reported, including
+    // ones inside ignored dirs the walk didn't look in
+    let mut explicitly_ignored: Vec<String> = ignored
+        .iter()
+        .filter(|p| pathspec.names_exactly(p))
+        .map(|p| p.to_string())
+        .collect();
+    let unmatched = pathspec.unmatched(
+        worktree_files
+            .iter()
+            .chain(index.entries.iter().map(|e| &e.name))
+            .map(|p| p.as_str())
+            .chain(ignored.iter().copied()),
+    );
+    for spec in unmatched {
+        let path = pathspec::normalize_path(&spec, repo)?;
+        match symlink_metadata(repo.worktree.join(&path)) {
+            Ok(md) if ignore_rules.is_ignored(&path, md.is_dir())? => explicitly_ignored.push(path),
+            _ => return Err(err::Error::GitPathspecNoMatch(spec)),
+        }
+    }
+
+    let mut to_stage: Vec<&String> = Vec::new();
+    let mut outside_sparse: Vec<&String> = index
+        .entries
+        .iter()
+        .filter(|e| sparse.is_some() && e.skip_worktree() && pathspec.names_exactly(&e.name))
+        .map(|e| &e.name)
+        .collect();
+    for file in worktree_files.iter().filter(|f| pathspec.matches(f)) {
+        let tracked = index.find_entry(file).is_ok();
+        if opts.update && !tracked {
+            continue;
+        }
+        if sparse.as_ref().is_some_and(|s| !s.includes(file)) {
+            // like ignored files only paths named exactly are reported
+            if pathspec.names_exactly(file) {
+                outside_sparse.push(file);
+            }
+            continue;
+        }
+        to_stage.push(file);
+    }
+
+    if !explicitly_ignored.is_empty() {
+        return Err(err::Error::GitPathsIgnored(explicitly_ignored.join("\n")));
+    }
+    if !outside_sparse.is_empty() {
+        let paths: Vec<&str> = outside_sparse.iter().map(|p| p.as_str()).collect();
+        return Err(err::Error::GitPathsOutsideSparseCheckout(paths.join("\n")));
+    }
+
+    // the files are hashed and written to the object store across threads,
+    // tracked files whose stat info matches the index aren't read at all
+    let attrs = attr::AttrRules::new(repo)?;
+    let index_mtime = utils::git_index_mtime(repo);
+    let entries = walk::parallel_map(&to_stage, threads, |file| {
+        let indexed = index.find_entry(file).ok().map(|i| &index.entries[i]);
+        if let Some(entry) = indexed {
+            let current = stat_index_entry(file, repo)?;
+            if updateindex::stat_clean(entry, &current, index_mtime) {
+                return Ok(entry.clone());
+            }
+        }
+        let index_sha = indexed.map(|e| hex::encode(&e.sha));
+        file_to_index_entry(file, &attrs, index_sha.as_deref(), repo, !opts.dry_run)
+    })?;
+    let mut output = String::new();
+    for (file, entry) in to_stage.iter().zip(entries) {
+        if let Some(replaced) = index.add_entry(entry.clone()) {
+            if replaced.sha == entry.sha && replaced.mode == entry.mode {
+                continue;
+            }
+        }
+        writeln!(output, "add '{file}'")?;
+    }
+
+    // tracked files which are gone from the worktree get removed from the
+    // index, skip-worktree entries are missing from the worktree on purpose
+    let mut removed: Vec<String> = Vec::new();
+    index.entries.retain(|e| {
+        let deleted =
+            pathspec.matches(&e.name) && !e.skip_worktree() && !worktree_files.contains(&e.name);
+        if deleted {
+            removed.push(e.name.clone());
+        }
+        !deleted
+    });
+    for name in removed {
+        writeln!(output, "remove '{name}'")?;
+    }
+
+    if opts.dry_run {
+        return Ok(if output.is_empty() {
+            None
+        } else {
+            Some(output)
+        });
+    }
+
+    if index != starting_index {
+        write_index(index, repo)?;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod add_tests {
+    use super::*;
+    use crate::test_utils;
+    use std::fs::{create_dir_all, remove_file, File};
+
+    fn write_file(repo: &obj::Repo, name: &str, contents: &str) {
+        let path = repo.worktree.join(name);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        let mut file = File::create(path).unwrap();
+        write!(file, "{}", contents).unwrap();
+    }
+
+    fn index_names(repo: &obj::Repo) -> Vec<String> {
+        idx::read_index_or_empty(repo)
+            .unwrap()
+            .entries
+            .into_iter()
+            .map(|e| e.name)
+            .collect()
+    }
+
+    #[test]
+    fn add_directory_stages_nested_files_and_skips_ignored() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+
+        write_file(&repo, ".gitignore", "src/gen.rs\n");
+        write_file(&repo, "src/main.rs", "fn main() {}");
+        write_file(&repo, "src/cmds/add.rs", "// add");
+        write_file(&repo, "src/gen.rs", "// generated");
+        write_file(&repo, "README.md", "readme");
+
+        add(&["src".to_owned()], &AddOptions::default(), &repo).unwrap();
+        assert_eq!(vec!["src/cmds/add.rs", "src/main.rs"], index_names(&repo));
+
+        // naming an ignored file requires -f
+        let ignored = add(&["src/gen.rs".to_owned()], &AddOptions::default(), &repo);
+        assert_eq!(
+            Err(err::Error::GitPathsIgnored("src/gen.rs".to_owned())),
+            ignored
+        );
+        let force = AddOptions {
+            force: true,
+            ..Default::default()
+        };
+        add(&["src/gen.rs".to_owned()], &force, &repo).unwrap();
+        assert_eq!(
+            vec!["src/cmds/add.rs", "src/gen.rs", "src/main.rs"],
+            index_names(&repo)
+        );
+    }
+
+    #[test]
+    fn add_fails_while_another_process_holds_the_index_lock() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+
+        write_file(&repo, "a.txt", "a");
+        add(&["a.txt".to_owned()], &AddOptions::default(), &repo).unwrap();
+        write_file(&repo, "b.txt", "b");
+        let lock = repo.gitdir.join("index.lock");
+        File::create(&lock).unwrap();
+        assert_eq!(
+            Err(err::Error::GitIndexLocked(lock.display().to_string())),
+            add(&["b.txt".to_owned()], &AddOptions::default(), &repo)
+        );
+        // the other process's lock is left alone and the index is unchanged
+        assert!(lock.exists());
+        assert_eq!(vec!["a.txt"], index_names(&repo));
+
+        remove_file(&lock).unwrap();
+        add(&["b.txt".to_owned()], &AddOptions::default(), &repo).unwrap();
+        assert_eq!(vec!["a.txt", "b.txt"], index_names(&repo));
+        assert!(!lock.exists());
+    }
+
+    #[test]
+    fn racily_clean_entries_are_smudged_when_the_index_is_written() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        write_file(&repo, "a", "aaa");
+        add(&["a".to_owned()], &AddOptions::default(), &repo).unwrap();
+
+        // a is edited to the same size in the tick the index is written in,
+        // its entry has the stat info of the edited file
+        write_file(&repo, "a", "bbb");
+        let tick = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(repo.worktree.join("a"))
+            .unwrap()
+            .set_modified(tick)
+            .unwrap();
+        let mut index = idx::read_index_or_empty(&repo).unwrap();
+        let stat = stat_index_entry("a", &repo).unwrap();
+        index.entries[0] = idx::IndexEntry {
+            sha: index.entries[0].sha.clone(),
+            ..stat
+        };
+        write_index(index, &repo).unwrap();
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        assert_eq!(0, index.entries[0].size);
+
+        // once a later write moves the index mtime past the entry its stat
+        // info would be trusted, the zeroed size still gets it hashed
+        File::options()
+            .write(true)
+            .open(repo.gitdir.join("index"))
+            .unwrap()
+            .set_modified(tick + std::time::Duration::from_secs(1))
+            .unwrap();
+        let attrs = attr::AttrRules::new(&repo).unwrap();
+        let index_mtime = utils::git_index_mtime(&repo);
+        assert_eq!(
+            None,
+            updateindex::refreshed_entry(&index.entries[0], index_mtime, &attrs, &repo).unwrap()
+        );
+    }
+
+    #[test]
+    fn add_skips_ignored_dirs_but_updates_tracked_ignored_files() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        write_file(&repo, "target/debug/tracked", "v1");
+        add(&["target".to_owned()], &AddOptions::default(), &repo).unwrap();
+
+        write_file(&repo, ".gitignore", "target/\n");
+        write_file(&repo, "target/debug/tracked", "v2");
+        write_file(&repo, "target/debug/untracked", "new");
+        let all = AddOptions {
+            all: true,
+            ..Default::default()
+        };
+        add(&[], &all, &repo).unwrap();
+        assert_eq!(
+            vec![".gitignore", ".rusty-git-allowed", "target/debug/tracked"],
+            index_names(&repo)
+        );
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        let v2 = obj::write_object(obj::GitObj::Blob(blob::Blob::new(b"v2")), None).unwrap();
+        assert_eq!(v2.bytes().to_vec(), index.entries[2].sha);
+
+        // a file inside an ignored dir the walk didn't enter is still
+        // reported when it's named
+        let untracked = "target/debug/untracked".to_owned();
+        assert_eq!(
+            Err(err::Error::GitPathsIgnored(untracked.clone())),
+            add(&[untracked], &AddOptions::default(), &repo)
+        );
+    }
+
+    #[test]
+    fn add_doesnt_hash_files_whose_stat_info_matches_the_index() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        write_file(&repo, "a", "a");
+        // an mtime well before the index is written so the entry isn't racy
+        let past = std::time::SystemTime::now() - std::time::Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(repo.worktree.join("a"))
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+        add(&["a".to_owned()], &AddOptions::default(), &repo).unwrap();
+
+        // the blob is only written again if the file is hashed
+        let sha = hex::encode(&idx::read_index_or_empty(&repo).unwrap().entries[0].sha);
+        let object = utils::git_obj_path_from_sha(&sha, &repo).unwrap();
+        remove_file(&object).unwrap();
+        add(&["a".to_owned()], &AddOptions::default(), &repo).unwrap();
+        assert!(!object.exists());
+
+        write_file(&repo, "a", "b");
+        add(&["a".to_owned()], &AddOptions::default(), &repo).unwrap();
+        let sha = hex::encode(&idx::read_index_or_empty(&repo).unwrap().entries[0].sha);
+        assert!(utils::git_obj_path_from_sha(&sha, &repo).is_ok());
+    }
+
+    #[test]
+    fn add_all_stages_deletions_and_update_skips_untracked() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+
+        write_file(&repo, "a.txt", "a");
+        write_file(&repo, "b.txt", "b");
+        add(&[".".to_owned()], &AddOptions::default(), &repo).unwrap();
+        assert_eq!(
+            vec![".rusty-git-allowed", "a.txt", "b.txt"],
+            index_names(&repo)
+        );
+
+        remove_file(repo.worktree.join("a.txt")).unwrap();
+        write_file(&repo, "b.txt", "b changed");
+        write_file(&repo, "c.txt", "c");
+
+        let dry_run = AddOptions {
+            update: true,
+            dry_run: true,
+            ..Default::default()
+        };
+        let output = add(&[], &dry_run, &repo).unwrap();
+        assert_eq!(Some("add 'b.txt'\nremove 'a.txt'\n".to_owned()), output);
+        // dry run leaves the index alone
+        assert_eq!(
+            vec![".rusty-git-allowed", "a.txt", "b.txt"],
+            index_names(&repo)
+        );
+
+        let update = AddOptions {
+            update: true,
+            ..Default::default()
+        };
+        add(&[], &update, &repo).unwrap();
+        assert_eq!(vec![".rusty-git-allowed", "b.txt"], index_names(&repo));
+
+        let all = AddOptions {
+            all: true,
+            ..Default::default()
+        };
+        add(&[], &all, &repo).unwrap();
+        assert_eq!(
+            vec![".rusty-git-allowed", "b.txt", "c.txt"],
+            index_names(&repo)
+        );
+    }
+
+    #[test]
+    fn add_errors_on_pathspec_without_matches() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+
+        write_file(&repo, "a.txt", "a");
+        assert_eq!(
+            Err(err::Error::GitPathspecNoMatch("*.rs".to_owned())),
+            add(&["*.rs".to_owned()], &AddOptions::default(), &repo)
+        );
+        assert_eq!(
+            Err(err::Error::GitNothingSpecifiedToAdd),
+            add(&[], &AddOptions::default(), &repo)
+        );
+    }
 }
@@ -0,0 +1,432 @@
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::fs::{create_dir_all, remove_file, rename};
+
+use crate::cmds::{refs, updateref, worktree};
+use crate::config;
+use crate::error as err;
+use crate::objects as obj;
+
+fn branch_ref(name: &str) -> String {
+    format!("refs/heads/{name}")
+}
+
+/// Checks the name is usable as a branch, on top of the ref name rules a
+/// branch can't be called HEAD or start with a '-'
+pub fn check_branch_name(name: &str) -> Result<(), err::Error> {
+    let invalid = || err::Error::GitInvalidBranchName(name.to_owned());
+    if name == "HEAD" || name.starts_with('-') {
+        return Err(invalid());
+    }
+    refs::check_ref_format(&branch_ref(name), refs::RefFormatOptions::default())
+        .map(|_| ())
+        .map_err(|_| invalid())
+}
+
+/// Returns the name of the branch HEAD points at, None when HEAD is detached
+pub fn current_branch(repo: &obj::Repo) -> Result<Option<String>, err::Error> {
+    Ok(refs::head_symref(repo)?.and_then(|r| r.strip_prefix("refs/heads/").map(|b| b.to_owned())))
+}
+
+/// The first line of the commit message
+pub fn commit_subject(sha: &str, repo: &obj::Repo) -> Result<String, err::Error> {
+    match obj::read_object(sha, repo)? {
+        obj::GitObj::Commit(commit) => Ok(commit.msg.lines().next().unwrap_or("").to_owned()),
+        _ => Err(err::Error::GitNotAValidObject(
+            sha.to_owned(),
+            "commit".to_owned(),
+        )),
+    }
+}
+
+/// Lists the local branches marking the current one with a '*', with all
+/// set remote tracking branches are listed too. Verbose adds the sha and
+/// subject of the commit at the tip of each branch.
+pub fn list_branches(all: bool, verbose: bool, repo: &obj::Repo) -> Result<String, err::Error> {
+    let current = current_branch(repo)?;
+    let mut branches: Vec<(String, String, bool)> = refs::list_refs("refs/heads/", repo)?
+        .into_iter()
+        .map(|(name, sha)| {
+            let short = name.trim_start_matches("refs/heads/").to_owned();
+            let is_current = current.as_ref() == Some(&short);
+            (short, sha, is_current)
+        })
+        .collect();
+    if all {
+        for (name, sha) in refs::list_refs("refs/remotes/", repo)? {
+            let short = name.trim_start_matches("refs/").to_owned();
+            branches.push((short, sha, false));
+        }
+    }
+
+    // a detached HEAD is listed first in place of a branch
+    if let obj::GitHead::Detached(sha) = refs::read_head(repo)? {
+        let name = format!("(HEAD detached at {})", &sha[..7]);
+        branches.insert(0, (name, sha, true));
+    }
+
+    let width = branches.iter().map(|(n, _, _)| n.len()).max().unwrap_or(0);
+    let mut output = String::new();
+    for (name, sha, is_current) in branches {
+        let marker = if is_current { '*' } else { ' ' };
+        if verbose {
+            let subject = commit_subject(&sha, repo)?;
+            writeln!(output, "{marker} {name:<width$} {} {subject}", &sha[..7])?;
+        } else {
+            writeln!(output, "{marker} {name}")?;
+        }
+    }
+    Ok(output)
+}
+
+/// Creates a branch pointing at the start point, HEAD when not given
+pub fn create_branch(
+    name: &str,
+    start_point: Option<&str>,
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
+    check_branch_name(name)?;
+    if refs::read_ref(&branch_ref(name), repo)?.is_some() {
+        return Err(err::Error::GitBranchAlreadyExists(name.to_owned()));
+    }
+    let start_point = start_point.unwrap_or("HEAD");
+    let sha = refs::rev_parse(start_point, repo)?;
+    let sha = refs::peel(&sha, "commit", repo)?;
+    let msg = format!("branch: Created from {start_point}");
+    refs::update_ref(&branch_ref(name), &sha, &msg, repo)
+}
+
+/// Walks back through the history of descendant looking for ancestor
+pub fn is_ancestor(ancestor: &str, descendant: &str, repo: &obj::Repo) -> Result<bool, err::Error> {
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut queue = VecDeque::from([descendant.to_owned()]);
+    while let Some(sha) = queue.pop_front() {
+        if sha == ancestor {
+            return Ok(true);
+        }
+        if !seen.insert(sha.clone()) {
+            continue;
+        }
+        if let obj::GitObj::Commit(commit) = obj::read_object(&sha, repo)? {
+            queue.extend(commit.parents);
+        }
+    }
+    Ok(false)
+}
+
+/// Counts the commits reachable from one commit but not the other, giving
+/// how far ahead and behind the first commit is compared to the second.
+/// Both sides are walked together newest first, the walk stops once every
+/// commit left to visit is reachable from both so the shared history below
+/// the merge base isn't read.
+pub fn ahead_behind(
+    sha: &str,
+    other: &str,
+    repo: &obj::Repo,
+) -> Result<(usize, usize), err::Error> {
+    const OURS: u8 = 1;
+    const THEIRS: u8 = 2;
+    const BOTH: u8 = OURS | THEIRS;
+    let read = |sha: &str| -> Result<(i64, Vec<String>), err::Error> {
+        match obj::read_object(sha, repo)? {
+            obj::GitObj::Commit(commit) => Ok((commit.committer.time(), commit.parents)),
+            _ => Ok((0, Vec::new())),
+        }
+    };
+
+    // the sides each commit is reachable from, and the sides already passed
+    // on to its parents
+    let mut marks: HashMap<String, u8> = HashMap::new();
+    let mut visited: HashMap<String, u8> = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    for (start, side) in [(sha, OURS), (other, THEIRS)] {
+        *marks.entry(start.to_owned()).or_default() |= side;
+        let (time, parents) = read(start)?;
+        queue.push((time, start.to_owned(), parents));
+    }
+    while queue.iter().any(|(_, sha, _)| marks[sha] != BOTH) {
+        let Some((_, sha, parents)) = queue.pop() else {
+            break;
+        };
+        let mark = marks[&sha];
+        if visited.insert(sha, mark) == Some(mark) {
+            continue;
+        }
+        for parent in parents {
+            let parent_mark = marks.entry(parent.clone()).or_default();
+            if *parent_mark | mark != *parent_mark {
+                *parent_mark |= mark;
+                let (time, grandparents) = read(&parent)?;
+                queue.push((time, parent, grandparents));
+            }
+        }
+    }
+    let count = |side: u8| marks.values().filter(|m| **m == side).count();
+    Ok((count(OURS), count(THEIRS)))
+}
+
+/// The remote tracking ref configured as the upstream of a local branch
+/// through branch.<name>.remote and branch.<name>.merge
+pub fn upstream_ref(name: &str, repo: &obj::Repo) -> Result<Option<String>, err::Error> {
+    let Some(branch) = name.strip_prefix("refs/heads/") else {
+        return Ok(None);
+    };
+    let remote = config::get(&repo.gitconf, &format!("branch.{branch}.remote"))?;
+    let merge = config::get(&repo.gitconf, &format!("branch.{branch}.merge"))?;
+    Ok(match (remote, merge) {
+        (Some(remote), Some(merge)) if remote == "." => Some(merge),
+        (Some(remote), Some(merge)) => {
+            let merge = merge.strip_prefix("refs/heads/").unwrap_or(&merge);
+            Some(format!("refs/remotes/{remote}/{merge}"))
+        }
+        _ => None,
+    })
+}
+
+/// Deletes the branch, unless forced the branch must be merged into HEAD
+pub fn delete_branch(name: &str, force: bool, repo: &obj::Repo) -> Result<String, err::Error> {
+    let ref_name = branch_ref(name);
+    let Some(sha) = refs::read_ref(&ref_name, repo)? else {
+        return Err(err::Error::GitBranchNotFound(name.to_owned()));
+    };
+    let current = current_branch(repo)?.as_deref() == Some(name);
+    if current || worktree::checked_out_elsewhere(&ref_name, repo)?.is_some() {
+        return Err(err::Error::GitBranchCheckedOut(name.to_owned()));
+    }
+    if !force {
+        let merged = match refs::rev_parse("HEAD", repo) {
+            Ok(head) => is_ancestor(&sha, &head, repo)?,
+            Err(err::Error::GitNoCommitsExistYet) => false,
+            Err(e) => return Err(e),
+        };
+        if !merged {
+            return Err(err::Error::GitBranchNotFullyMerged(name.to_owned()));
+        }
+    }
+    refs::delete_ref(&ref_name, repo)?;
+
+    let reflog = repo.git_path(format!("logs/{ref_name}"));
+    if reflog.exists() {
+        remove_file(reflog)?;
+    }
+    Ok(format!("Deleted branch {name} (was {}).\n", &sha[..7]))
+}
+
+/// Renames a branch, or the current branch when old_name isn't given. The
+/// HEAD of every worktree on the branch follows it and the reflog moves
+/// with it.
+pub fn rename_branch(
+    old_name: Option<&str>,
+    new_name: &str,
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
+    let current = current_branch(repo)?;
+    let old_name = match (old_name, current.as_deref()) {
+        (Some(old), _) => old,
+        (None, Some(current)) => current,
+        (None, None) => return Err(err::Error::GitBranchRenameDetached),
+    };
+    check_branch_name(new_name)?;
+
+    let (old_ref, new_ref) = (branch_ref(old_name), branch_ref(new_name));
+    let old_head = Some(obj::GitHead::Symbolic(old_ref.clone()));
+    let on_branch: Vec<worktree::Worktree> = worktree::list_worktrees(repo)?
+        .into_iter()
+        .filter(|wt| wt.head == old_head)
+        .collect();
+    let sha = refs::read_ref(&old_ref, repo)?;
+    // a checked out branch can be renamed before anything is committed to it
+    if sha.is_none() && on_branch.is_empty() {
+        return Err(err::Error::GitBranchNotFound(old_name.to_owned()));
+    }
+    if old_ref != new_ref && refs::read_ref(&new_ref, repo)?.is_some() {
+        return Err(err::Error::GitBranchAlreadyExists(new_name.to_owned()));
+    }
+
+    // the log is moved first so a rename that can't happen changes nothing
+    let old_log = repo.git_path(format!("logs/{old_ref}"));
+    let new_log = repo.git_path(format!("logs/{new_ref}"));
+    let move_log = old_log.exists();
+    if move_log {
+        if let Some(parent) = new_log.parent() {
+            create_dir_all(parent)?;
+        }
+        rename(&old_log, &new_log)?;
+    }
+    if let Some(sha) = &sha {
+        let mut transaction =
+            updateref::RefTransaction::new(&format!("Branch: renamed {old_ref} to {new_ref}"));
+        transaction
+            .delete(&old_ref, Some(sha))
+            .create(&new_ref, sha);
+        if let Err(e) = transaction.commit(repo) {
+            if move_log {
+                rename(&new_log, &old_log)?;
+            }
+            return Err(e);
+        }
+    }
+
+    for wt in on_branch {
+        let wt_repo = obj::Repo {
+            gitdir: wt.gitdir,
+            ..repo.clone()
+        };
+        refs::write_symref("HEAD", &new_ref, &wt_repo)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod branch_tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn create_list_and_delete_branches() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let first = test_utils::test_commit_files(&[], "first\n", &repo).unwrap();
+
+        create_branch("feature/a", None, &repo).unwrap();
+        let second = test_utils::test_commit_files(&[], "second\n", &repo).unwrap();
+        create_branch("topic", Some(&first), &repo).unwrap();
+        assert_eq!(
+            Err(err::Error::GitBranchAlreadyExists("topic".to_owned())),
+            create_branch("topic", None, &repo)
+        );
+        assert_eq!(
+            Err(err::Error::GitInvalidBranchName("bad..name".to_owned())),
+            create_branch("bad..name", None, &repo)
+        );
+
+        assert_eq!(
+            "  feature/a\n* master\n  topic\n",
+            list_branches(false, false, &repo).unwrap()
+        );
+        assert_eq!(
+            format!(
+                "  feature/a {} first\n* master    {} second\n  topic     {} first\n",
+                &first[..7],
+                &second[..7],
+                &first[..7]
+            ),
+            list_branches(false, true, &repo).unwrap()
+        );
+
+        assert_eq!(
+            Err(err::Error::GitBranchCheckedOut("master".to_owned())),
+            delete_branch("master", true, &repo)
+        );
+        assert_eq!(
+            format!("Deleted branch feature/a (was {}).\n", &first[..7]),
+            delete_branch("feature/a", false, &repo).unwrap()
+        );
+        assert!(!repo.gitdir.join("refs/heads/feature").exists());
+        assert!(repo.gitdir.join("refs/heads").exists());
+    }
+
+    #[test]
+    fn delete_refuses_unmerged_branch_unless_forced() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        test_utils::test_commit_files(&[], "first\n", &repo).unwrap();
+
+        // commit on a side branch that master never sees
+        create_branch("side", None, &repo).unwrap();
+        refs::write_symref("HEAD", "refs/heads/side", &repo).unwrap();
+        test_utils::test_commit_files(&[], "side work\n", &repo).unwrap();
+        refs::write_symref("HEAD", "refs/heads/master", &repo).unwrap();
+
+        assert_eq!(
+            Err(err::Error::GitBranchNotFullyMerged("side".to_owned())),
+            delete_branch("side", false, &repo)
+        );
+        delete_branch("side", true, &repo).unwrap();
+        assert_eq!(None, refs::read_ref("refs/heads/side", &repo).unwrap());
+    }
+
+    #[test]
+    fn rename_current_branch_moves_head_and_reflog() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let sha = test_utils::test_commit_files(&[], "first\n", &repo).unwrap();
+        let log_dir = repo.gitdir.join("logs/refs/heads");
+        create_dir_all(&log_dir).unwrap();
+        std::fs::write(log_dir.join("master"), "log entry\n").unwrap();
+
+        rename_branch(None, "main", &repo).unwrap();
+        assert_eq!(Some("main".to_owned()), current_branch(&repo).unwrap());
+        assert_eq!(Some(sha), refs::read_ref("refs/heads/main", &repo).unwrap());
+        assert_eq!(None, refs::read_ref("refs/heads/master", &repo).unwrap());
+        assert!(log_dir.join("main").exists());
+        assert!(!log_dir.join("master").exists());
+    }
+
+    #[test]
+    fn rename_moves_other_worktrees_heads_and_changes_nothing_when_it_fails() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let sha = test_utils::test_commit_files(&[], "first\n", &repo).unwrap();
+        let other = test_utils::test_tempdir().unwrap();
+        let opts = worktree::WorktreeAddOptions {
+            create: Some("side".to_owned()),
+            ..Default::default()
+        };
+        let path = other.path().join("side");
+        worktree::add_worktree(&path, None, &opts, &repo).unwrap();
+        let linked = obj::Repo::new(path).unwrap();
+
+        rename_branch(Some("side"), "renamed", &repo).unwrap();
+        assert_eq!(Some("renamed".to_owned()), current_branch(&linked).unwrap());
+        assert_eq!(Some("master".to_owned()), current_branch(&repo).unwrap());
+
+        // a branch can't go under its own name, the ref and log stay put
+        let log = repo.git_path("logs/refs/heads/master");
+        std::fs::write(&log, "log entry\n").unwrap();
+        assert!(rename_branch(None, "master/sub", &repo).is_err());
+        assert_eq!(
+            Some(sha),
+            refs::read_ref("refs/heads/master", &repo).unwrap()
+        );
+        assert_eq!("log entry\n", std::fs::read_to_string(&log).unwrap());
+        assert_eq!(Some("master".to_owned()), current_branch(&repo).unwrap());
+    }
+
+    #[test]
+    fn ahead_behind_stops_at_the_merge_base() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let tree =
+            crate::cmds::writetree::write_tree(&crate::index::Index::empty(), None, &repo).unwrap();
+        // commits a second apart so the walk order doesn't depend on shas
+        let commit = |msg: &str, time: i64, parents: &[&String]| {
+            let mut user = obj::commit::create_dummy_user();
+            user.timestamp = format!("{time} +0000");
+            let mut commit = obj::commit::Commit {
+                tree: tree.clone(),
+                parents: parents.iter().map(|p| p.to_string()).collect(),
+                author: user.clone(),
+                committer: user,
+                msg: format!("{msg}\n"),
+                sha: String::new(),
+            };
+            commit.calc_and_update_sha();
+            obj::write_object(obj::GitObj::Commit(Box::new(commit.clone())), Some(&repo)).unwrap();
+            commit.sha
+        };
+        let root = commit("root", 1, &[]);
+        let base = commit("base", 2, &[&root]);
+        let ours = commit("ours", 3, &[&base]);
+        let ours = commit("ours again", 4, &[&ours]);
+        let theirs = commit("theirs", 3, &[&base]);
+        let merge = commit("merge", 5, &[&theirs, &ours]);
+
+        // history below the merge base is never read
+        std::fs::remove_file(repo.git_path(format!("objects/{}/{}", &root[..2], &root[2..])))
+            .unwrap();
+        assert_eq!((2, 1), ahead_behind(&ours, &theirs, &repo).unwrap());
+        assert_eq!((0, 2), ahead_behind(&ours, &merge, &repo).unwrap());
+        assert_eq!((0, 0), ahead_behind(&merge, &merge, &repo).unwrap());
+    }
+}
@@ -0,0 +1,117 @@
+use std::fmt::Write as _;
+
+use crate::attributes::{self as attr, AttrState};
+use crate::error as err;
+use crate::objects as obj;
+
+/// Which attributes to print for each path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckAttrs {
+    /// every attribute that isn't unspecified
+    All,
+    /// only these attributes, including unspecified ones
+    Names(Vec<String>),
+}
+
+fn describe(state: Option<&AttrState>) -> &str {
+    match state {
+        None => "unspecified",
+        Some(AttrState::Set) => "set",
+        Some(AttrState::Unset) => "unset",
+        Some(AttrState::Value(value)) => value,
+    }
+}
+
+/// Prints a "<path>: <attr>: <state>" line for each attribute of each path,
+/// the paths are relative to the top of the worktree
+pub fn check_attr(
+    attrs: &CheckAttrs,
+    paths: &[String],
+    repo: &obj::Repo,
+) -> Result<String, err::Error> {
+    let rules = attr::AttrRules::new(repo)?;
+    let mut output = String::new();
+    for path in paths {
+        let path_attrs = rules.path_attributes(path.trim_end_matches('/'))?;
+        match attrs {
+            CheckAttrs::All => {
+                for (name, state) in &path_attrs {
+                    writeln!(output, "{path}: {name}: {}", describe(Some(state)))?;
+                }
+            }
+            CheckAttrs::Names(names) => {
+                for name in names {
+                    writeln!(output, "{path}: {name}: {}", describe(path_attrs.get(name)))?;
+                }
+            }
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod checkattr_tests {
+    use super::*;
+    use crate::test_utils;
+    use std::fs::{create_dir_all, write};
+
+    #[test]
+    fn check_attr_reports_states_from_every_attribute_file() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        create_dir_all(repo.git_path("info")).unwrap();
+        create_dir_all(repo.worktree.join("src")).unwrap();
+        write(
+            repo.worktree.join(".gitattributes"),
+            "[attr]rust text eol=lf diff=rust\n*.rs rust\n*.png binary\n",
+        )
+        .unwrap();
+        // macros can't be defined in subdirs
+        write(
+            repo.worktree.join("src/.gitattributes"),
+            "[attr]gen -diff\ngen.rs gen !eol\n",
+        )
+        .unwrap();
+        write(repo.git_path("info/attributes"), "*.rs eol=crlf\n").unwrap();
+
+        let names = CheckAttrs::Names(vec!["diff".to_owned(), "eol".to_owned()]);
+        let paths = ["a.rs".to_owned(), "src/gen.rs".to_owned()];
+        assert_eq!(
+            "a.rs: diff: rust\na.rs: eol: crlf\n\
+             src/gen.rs: diff: rust\nsrc/gen.rs: eol: crlf\n",
+            check_attr(&names, &paths, &repo).unwrap()
+        );
+
+        assert_eq!(
+            "logo.png: binary: set\nlogo.png: diff: unset\n\
+             logo.png: merge: unset\nlogo.png: text: unset\n",
+            check_attr(&CheckAttrs::All, &["logo.png".to_owned()], &repo).unwrap()
+        );
+    }
+
+    #[test]
+    fn macros_unset_by_later_lines_leave_their_attributes_unspecified() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        write(
+            repo.worktree.join(".gitattributes"),
+            "*.png binary\n*.png -binary\n*.jpg binary\n*.jpg !binary\n*.gif binary text\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            "logo.png: binary: unset\n",
+            check_attr(&CheckAttrs::All, &["logo.png".to_owned()], &repo).unwrap()
+        );
+        assert_eq!(
+            "",
+            check_attr(&CheckAttrs::All, &["logo.jpg".to_owned()], &repo).unwrap()
+        );
+        // attributes after a macro on the same line override what it sets
+        assert_eq!(
+            "logo.gif: binary: set\nlogo.gif: diff: unset\n\
+             logo.gif: merge: unset\nlogo.gif: text: set\n",
+            check_attr(&CheckAttrs::All, &["logo.gif".to_owned()], &repo).unwrap()
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt::Write as _;
+use std::fs::symlink_metadata;
+
+use crate::error as err;
+use crate::ignore;
+use crate::index as idx;
+use crate::objects as obj;
+use crate::pathspec;
+
+/// Prints the paths that are ignored. With verbose every path that matches
+/// a rule is printed after the rule, including rules that negate an ignore.
+/// Tracked paths are never ignored.
+pub fn check_ignore(
+    paths: &[String],
+    verbose: bool,
+    repo: &obj::Repo,
+) -> Result<String, err::Error> {
+    if paths.is_empty() {
+        return Err(err::Error::GitCheckIgnoreNoPath);
+    }
+    let index = idx::read_index_or_empty(repo)?;
+    let rules = ignore::IgnoreRules::new(repo)?;
+
+    let mut output = String::new();
+    for path in paths {
+        let name = pathspec::normalize_path(path, repo)?;
+        if index.find_entry(&name).is_ok() {
+            continue;
+        }
+        let is_dir = path.ends_with('/')
+            || symlink_metadata(repo.worktree.join(&name)).is_ok_and(|md| md.is_dir());
+        match rules.matching_rule(&name, is_dir)? {
+            Some(rule) if verbose => writeln!(
+                output,
+                "{}:{}:{}\t{path}",
+                rule.source, rule.line, rule.text
+            )?,
+            Some(rule) if rule.ignores() => writeln!(output, "{path}")?,
+            _ => {}
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod checkignore_tests {
+    use super::*;
+    use crate::attributes as attr;
+    use crate::cmds::add;
+    use crate::test_utils;
+    use std::fs::write;
+
+    #[test]
+    fn check_ignore_reports_matching_rules() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        write(repo.worktree.join(".gitignore"), "*.log\n!keep.log\n").unwrap();
+        write(repo.worktree.join("tracked.log"), "t\n").unwrap();
+        let attrs = attr::AttrRules::new(&repo).unwrap();
+        let mut index = idx::Index::empty();
+        let entry = add::file_to_index_entry("tracked.log", &attrs, None, &repo, true).unwrap();
+        index.add_entry(entry);
+        add::write_index(index, &repo).unwrap();
+
+        let paths: Vec<String> = ["a.log", "keep.log", "a.txt", "tracked.log"]
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        assert_eq!("a.log\n", check_ignore(&paths, false, &repo).unwrap());
+        assert_eq!(
+            ".gitignore:1:*.log\ta.log\n.gitignore:2:!keep.log\tkeep.log\n",
+            check_ignore(&paths, true, &repo).unwrap()
+        );
+    }
+}
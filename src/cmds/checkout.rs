@@ -1,9 +1,18 @@
-use std::fs::{create_dir, File};
+use std::fs::{
+    create_dir_all, metadata, read_dir, remove_dir, remove_file, set_permissions, symlink_metadata,
+    File,
+};
 use std::io::Write;
+use std::os::unix::fs::{symlink, PermissionsExt};
 use std::path::Path;
+use std::str::from_utf8;
 
+use crate::attributes as attr;
+use crate::cmds::{add, sparsecheckout};
+use crate::convert;
 use crate::error as err;
-use crate::objects::{self as obj, tree};
+use crate::index as idx;
+use crate::objects::{self as obj, blob, tree};
 use crate::utils;
 
 fn dir_path_to_string(path: &Path) -> Result<String, err::Error> {
@@ -28,23 +37,273 @@ pub fn dir_ok_for_checkout(path: &Path) -> Result<bool, err::Error> {
     }
 }
 
-pub fn checkout_tree(tree: tree::Tree, path: &Path, repo: &obj::Repo) -> Result<(), err::Error> {
+/// Writes a tree entry to the worktree path according to its mode. Mode
+/// 100755 files are made executable, 120000 entries become symlinks to the
+/// blob contents and 160000 gitlinks are created as empty dirs without
+/// reading the object, which lives in another repo. The name is the path
+/// inside the tree, it's what attributes and filters see.
+pub fn write_worktree_entry(
+    path: &Path,
+    name: &str,
+    mode: u32,
+    sha: &str,
+    attrs: &attr::AttrRules,
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    if mode == 0o160000 {
+        create_dir_all(path)?;
+        return Ok(());
+    }
+
+    let obj::GitObj::Blob(blob) = obj::read_object(sha, repo)? else {
+        return Err(err::Error::GitTreeInvalidObject);
+    };
+    // whatever is at the path is replaced, File::create would otherwise
+    // follow a symlink and write through it
+    if symlink_metadata(path).is_ok_and(|md| !md.is_dir()) {
+        remove_file(path)?;
+    }
+    if mode == 0o120000 {
+        let target = from_utf8(&blob.contents)?;
+        symlink(target, path)?;
+        return Ok(());
+    }
+
+    let contents = convert::to_worktree(name, blob.contents, attrs, repo)?;
+    File::create(path)?.write_all(&contents)?;
+    // files written after a .gitattributes file get its attributes
+    attrs.file_written(name);
+    let mut perms = metadata(path)?.permissions();
+    if mode == 0o100755 {
+        // only add execute where the file can be read
+        perms.set_mode(perms.mode() | (perms.mode() & 0o444) >> 2);
+    } else {
+        perms.set_mode(perms.mode() & !0o111);
+    }
+    set_permissions(path, perms)?;
+    Ok(())
+}
+
+// tree_path is the path of the tree inside the commit, it's what the sparse
+// checkout patterns are matched against. Dirs are only created once a file
+// inside them is written so dirs outside the sparse checkout don't appear.
+fn checkout_sparse_tree(
+    tree: tree::Tree,
+    path: &Path,
+    tree_path: &Path,
+    sparse: Option<&sparsecheckout::SparseCheckout>,
+    attrs: &attr::AttrRules,
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
     for leaf in tree.contents {
-        let obj = obj::read_object(&utils::get_sha_from_binary(&leaf.sha), repo)?;
-        match obj {
-            obj::GitObj::Tree(sub_tree) => {
-                let dir_path = path.join(&leaf.path);
-                let dst = repo.worktree.join(&dir_path);
-                create_dir(dst)?;
-                checkout_tree(sub_tree, &dir_path, repo)?;
-            }
-            obj::GitObj::Blob(blob) => {
-                let dst = repo.worktree.join(path).join(&leaf.path);
-                let mut dstfile = File::create(dst)?;
-                dstfile.write_all(&blob.contents)?;
-            }
-            _ => return Err(err::Error::GitTreeInvalidObject),
+        let leaf_tree_path = tree_path.join(&leaf.path);
+        let sha = utils::get_sha_from_binary(&leaf.sha);
+        if leaf.is_tree() {
+            let sub_tree = tree::read_tree(&sha, repo)?;
+            let dir_path = path.join(&leaf.path);
+            checkout_sparse_tree(sub_tree, &dir_path, &leaf_tree_path, sparse, attrs, repo)?;
+            continue;
+        }
+
+        let name = leaf_tree_path
+            .to_str()
+            .ok_or(err::Error::PathToUtf8Conversion)?;
+        if sparse.is_none_or(|s| s.includes(name)) {
+            let dst = repo.worktree.join(path).join(&leaf.path);
+            write_worktree_entry(&dst, name, leaf.mode_as_u32()?, &sha, attrs, repo)?;
         }
     }
     Ok(())
 }
+
+/// Writes the files of the tree into the dir at path, when sparse checkout
+/// is enabled only the files included by the sparse checkout are written.
+/// Attributes come from the .gitattributes files written into the dir.
+pub fn checkout_tree(tree: tree::Tree, path: &Path, repo: &obj::Repo) -> Result<(), err::Error> {
+    let sparse = sparsecheckout::read_sparse_checkout(repo)?;
+    let attrs = attr::AttrRules::with_root(&repo.worktree.join(path), repo)?;
+    checkout_sparse_tree(tree, path, Path::new(""), sparse.as_ref(), &attrs, repo)
+}
+
+/// Checks whether the worktree file for the entry has the contents recorded
+/// in the entry
+pub fn worktree_file_matches_entry(
+    entry: &idx::IndexEntry,
+    attrs: &attr::AttrRules,
+    repo: &obj::Repo,
+) -> Result<bool, err::Error> {
+    // the contents of a submodule belong to the submodule's own repo
+    if entry.mode == 0o160000 {
+        return Ok(true);
+    }
+    let index_sha = hex::encode(&entry.sha);
+    let blob = blob::blob_from_worktree(&entry.name, attrs, Some(&index_sha), repo)?;
+    Ok(obj::write_object(blob, None)?.bytes().to_vec() == entry.sha)
+}
+
+/// Removes the file from the worktree along with any dirs left empty, the
+/// dir of a gitlink is only removed when it's empty
+pub fn remove_worktree_file(name: &str, repo: &obj::Repo) -> Result<(), err::Error> {
+    let path = repo.worktree.join(name);
+    if symlink_metadata(&path)?.is_dir() {
+        if read_dir(&path)?.next().is_some() {
+            return Ok(());
+        }
+        remove_dir(&path)?;
+    } else {
+        remove_file(&path)?;
+    }
+    for dir in path.ancestors().skip(1) {
+        if dir == repo.worktree || read_dir(dir)?.next().is_some() {
+            break;
+        }
+        remove_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Writes the entry's blob to the worktree and returns the entry with the
+/// stat info of the new file, the entry is no longer skip-worktree
+pub fn checkout_entry(
+    entry: &idx::IndexEntry,
+    attrs: &attr::AttrRules,
+    repo: &obj::Repo,
+) -> Result<idx::IndexEntry, err::Error> {
+    let sha = utils::get_sha_from_binary(&entry.sha);
+    let path = repo.worktree.join(&entry.name);
+    write_worktree_entry(&path, &entry.name, entry.mode, &sha, attrs, repo)?;
+    if entry.mode == 0o160000 {
+        let mut restored = entry.clone();
+        restored.set_skip_worktree(false);
+        return Ok(restored);
+    }
+
+    // pick up the stat info of the new file while keeping everything else
+    let index_sha = hex::encode(&entry.sha);
+    let mut restored = add::file_to_index_entry(&entry.name, attrs, Some(&index_sha), repo, false)?;
+    restored.mode = entry.mode;
+    restored.sha = entry.sha.clone();
+    restored.flags = entry.flags;
+    restored.extended_flags = entry.extended_flags;
+    restored.set_skip_worktree(false);
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod checkout_tests {
+    use super::*;
+    use crate::cmds::{committree, init, refs, writetree};
+    use crate::test_utils;
+    use std::fs::{read_link, write};
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn checkout_restores_exec_bit_symlinks_and_gitlinks() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let run = repo.worktree.join("run.sh");
+        write(&run, "echo hi\n").unwrap();
+        let mut perms = metadata(&run).unwrap().permissions();
+        perms.set_mode(0o755);
+        set_permissions(&run, perms).unwrap();
+        symlink("run.sh", repo.worktree.join("link")).unwrap();
+
+        let sub_path = repo.worktree.join("sub");
+        create_dir_all(&sub_path).unwrap();
+        init::create_git_repo(&sub_path).unwrap();
+        let sub = obj::Repo::new(sub_path.clone()).unwrap();
+        let sub_tree = writetree::write_tree(&idx::Index::empty(), None, &sub).unwrap();
+        let sub_commit = committree::commit_tree(&sub_tree, &[], "sub\n", &sub).unwrap();
+        refs::write_ref("refs/heads/master", &sub_commit, &sub).unwrap();
+
+        assert_eq!(
+            vec![".rusty-git-allowed", "link", "run.sh", "sub"],
+            add::gather_worktree_files(None, &repo).unwrap()
+        );
+        let attrs = attr::AttrRules::new(&repo).unwrap();
+        let mut index = idx::Index::empty();
+        for name in ["link", "run.sh", "sub"] {
+            index.add_entry(add::file_to_index_entry(name, &attrs, None, &repo, true).unwrap());
+        }
+        let modes: Vec<u32> = index.entries.iter().map(|e| e.mode).collect();
+        assert_eq!(vec![0o120000, 0o100755, 0o160000], modes);
+        assert_eq!(
+            sub_commit,
+            utils::get_sha_from_binary(&index.entries[2].sha)
+        );
+
+        let tree_sha = writetree::write_tree(&index, None, &repo).unwrap();
+        let tree = tree::read_tree(&tree_sha.to_string(), &repo).unwrap();
+        checkout_tree(tree, Path::new("out"), &repo).unwrap();
+        let out = repo.worktree.join("out");
+        assert_eq!(0o111, metadata(out.join("run.sh")).unwrap().mode() & 0o111);
+        assert_eq!(Path::new("run.sh"), read_link(out.join("link")).unwrap());
+        assert!(out.join("sub").is_dir());
+        assert_eq!(0, read_dir(out.join("sub")).unwrap().count());
+
+        // a file replacing the link gets its mode from the entry
+        remove_file(repo.worktree.join("link")).unwrap();
+        write(repo.worktree.join("link"), "not a link\n").unwrap();
+        assert!(!worktree_file_matches_entry(&index.entries[0], &attrs, &repo).unwrap());
+        checkout_entry(&index.entries[0], &attrs, &repo).unwrap();
+        assert!(symlink_metadata(repo.worktree.join("link"))
+            .unwrap()
+            .is_symlink());
+        assert!(worktree_file_matches_entry(&index.entries[0], &attrs, &repo).unwrap());
+    }
+
+    #[test]
+    fn attributes_checked_out_apply_to_later_files() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        write(repo.worktree.join(".gitattributes"), "*.txt eol=crlf\n").unwrap();
+        write(repo.worktree.join("a.txt"), "a\n").unwrap();
+        let attrs = attr::AttrRules::new(&repo).unwrap();
+        let mut index = idx::Index::empty();
+        for name in [".gitattributes", "a.txt"] {
+            index.add_entry(add::file_to_index_entry(name, &attrs, None, &repo, true).unwrap());
+        }
+        let tree_sha = writetree::write_tree(&index, None, &repo).unwrap();
+        let tree = tree::read_tree(&tree_sha.to_string(), &repo).unwrap();
+
+        // only the .gitattributes written by the checkout applies
+        remove_file(repo.worktree.join(".gitattributes")).unwrap();
+        checkout_tree(tree, Path::new("out"), &repo).unwrap();
+        let out = repo.worktree.join("out");
+        assert_eq!(b"a\r\n".to_vec(), std::fs::read(out.join("a.txt")).unwrap());
+    }
+
+    #[test]
+    fn checkout_into_a_dir_matches_attributes_against_paths_in_the_tree() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        create_dir_all(repo.worktree.join("dir")).unwrap();
+        write(repo.worktree.join(".gitattributes"), "/a.txt eol=crlf\n").unwrap();
+        write(repo.worktree.join("a.txt"), "a\n").unwrap();
+        write(repo.worktree.join("dir/a.txt"), "a\n").unwrap();
+        let attrs = attr::AttrRules::new(&repo).unwrap();
+        let mut index = idx::Index::empty();
+        for name in [".gitattributes", "a.txt", "dir/a.txt"] {
+            index.add_entry(add::file_to_index_entry(name, &attrs, None, &repo, true).unwrap());
+        }
+        let tree_sha = writetree::write_tree(&index, None, &repo)
+            .unwrap()
+            .to_string();
+
+        // a dir relative to the worktree and one outside it
+        let outside = test_utils::test_tempdir().unwrap();
+        for dir in [Path::new("out"), outside.path()] {
+            let tree = tree::read_tree(&tree_sha, &repo).unwrap();
+            checkout_tree(tree, dir, &repo).unwrap();
+            let out = repo.worktree.join(dir);
+            assert_eq!(b"a\r\n".to_vec(), std::fs::read(out.join("a.txt")).unwrap());
+            assert_eq!(
+                b"a\n".to_vec(),
+                std::fs::read(out.join("dir/a.txt")).unwrap()
+            );
+        }
+    }
+}
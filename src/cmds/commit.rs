@@ -1,11 +1,7 @@
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
-
-use crate::cmds::status;
+use crate::cmds::{committree, refs, status, symbolicref, writetree};
 use crate::error as err;
 use crate::index as idx;
-use crate::objects::{self as obj, blob, commit, tree};
+use crate::objects as obj;
 use crate::utils;
 
 pub fn commit(msg: String, repo: obj::Repo) -> Result<Option<String>, err::Error> {
@@ -21,47 +17,41 @@ pub fn commit(msg: String, repo: obj::Repo) -> Result<Option<String>, err::Error
             return Ok(None);
         }
 
-        let tree = tree::index_to_tree(&index);
-        let tree_sha = obj::write_object(obj::GitObj::Tree(tree.clone()), Some(&repo))?;
+        // add writes the blobs for staged files so only trees need writing
+        let tree_sha = writetree::write_tree(&index, None, &repo)?;
 
-        // make sure blobs exist for all files in tree
-        for elm in tree.contents {
-            let elm_path = PathBuf::from(elm.path);
-            let blob = blob::blob_from_path(elm_path)?;
-            obj::write_object(blob, Some(&repo))?;
-        }
+        let parents = match utils::git_sha_from_head(&repo) {
+            Ok(head_sha) => vec![head_sha],
+            Err(_) => Vec::new(),
+        };
 
-        let parent;
-        if let Ok(head_sha) = utils::git_sha_from_head(&repo) {
-            parent = Some(head_sha)
-        } else {
-            parent = None
-        }
+        let msg = committree::format_commit_msg(&[msg]);
+        let commit_sha = committree::commit_tree(&tree_sha, &parents, &msg, &repo)?;
 
-        let mut commit = commit::Commit {
-            tree: tree_sha.to_string(),
-            parent,
-            author: commit::create_dummy_user(),
-            committer: commit::create_dummy_user(),
-            msg,
-            sha: "".to_string(),
+        // move the branch in HEAD to the new commit
+        let subject = msg.lines().next().unwrap_or("");
+        let reflog_msg = if parents.is_empty() {
+            format!("commit (initial): {subject}")
+        } else {
+            format!("commit: {subject}")
         };
-        commit.calc_and_update_sha();
-        obj::write_object(obj::GitObj::Commit(Box::new(commit.clone())), Some(&repo))?;
+        refs::update_ref("HEAD", &commit_sha, &reflog_msg, &repo)?;
 
-        // write commit to ref path in HEAD
-        let ref_path = utils::git_head_ref_path(&repo)?;
-        // create will truncate the sha in the ref file if it previously existed
-        let mut ref_file = File::create(ref_path)?;
-        ref_file.write_all(commit.sha.as_bytes())?;
+        let branch = match refs::head_symref(&repo)? {
+            Some(target) => symbolicref::shorten_ref(&target).to_owned(),
+            None => "detached HEAD".to_owned(),
+        };
+        let root = if parents.is_empty() { " (root-commit)" } else { "" };
+        Ok(Some(format!(
+            "[{branch}{root} {}] {subject}\n",
+            &commit_sha[..7]
+        )))
     } else {
-        return Ok(Some(
+        Ok(Some(
             "Nothing in the stagging area!
              The .git/index file doesn't yet exist try:
              'rusty-git add <file-name>' to trigger index creation"
                 .to_owned(),
-        ));
+        ))
     }
-
-    Ok(None)
 }
@@ -0,0 +1,97 @@
+use crate::error as err;
+use crate::objects::{self as obj, commit};
+
+fn ensure_obj_type(sha: &str, expected: &str, repo: &obj::Repo) -> Result<(), err::Error> {
+    let matches = matches!(
+        (obj::read_object(sha, repo)?, expected),
+        (obj::GitObj::Tree(_), "tree") | (obj::GitObj::Commit(_), "commit")
+    );
+    if matches {
+        Ok(())
+    } else {
+        Err(err::Error::GitNotAValidObject(
+            sha.to_owned(),
+            expected.to_owned(),
+        ))
+    }
+}
+
+/// Joins the -m messages into paragraphs the same way git does and makes
+/// sure the message ends in a newline
+pub fn format_commit_msg(msgs: &[String]) -> String {
+    let mut msg = msgs.join("\n\n");
+    if !msg.ends_with('\n') {
+        msg.push('\n');
+    }
+    msg
+}
+
+/// Creates a commit object pointing at the tree with the given parents and
+/// returns its sha, no refs are updated
+pub fn commit_tree(
+    tree_sha: &str,
+    parents: &[String],
+    msg: &str,
+    repo: &obj::Repo,
+) -> Result<String, err::Error> {
+    ensure_obj_type(tree_sha, "tree", repo)?;
+    for parent in parents {
+        ensure_obj_type(parent, "commit", repo)?;
+    }
+
+    let user = commit::current_user(&repo.gitconf)?;
+    let mut commit = commit::Commit {
+        tree: tree_sha.to_owned(),
+        parents: parents.to_vec(),
+        author: user.clone(),
+        committer: user,
+        msg: msg.to_owned(),
+        sha: "".to_string(),
+    };
+    commit.calc_and_update_sha();
+    obj::write_object(obj::GitObj::Commit(Box::new(commit.clone())), Some(repo))?;
+    Ok(commit.sha)
+}
+
+#[cfg(test)]
+mod commit_tree_tests {
+    use super::*;
+    use crate::cmds::{add, log, writetree};
+    use crate::index as idx;
+    use crate::test_utils;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn commit_tree_links_parents_without_moving_refs() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+
+        writeln!(File::create(repo.worktree.join("foo.txt")).unwrap(), "foo").unwrap();
+        add::add(&["foo.txt".to_owned()], &Default::default(), &repo).unwrap();
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        let tree_sha = writetree::write_tree(&index, None, &repo).unwrap();
+
+        let msg = format_commit_msg(&["first".to_owned()]);
+        let first = commit_tree(&tree_sha, &[], &msg, &repo).unwrap();
+        let msg = format_commit_msg(&["second".to_owned(), "body".to_owned()]);
+        let parents = [first.clone()];
+        let second = commit_tree(&tree_sha, &parents, &msg, &repo).unwrap();
+
+        let commits = log::follow_commits_to_root(&second, &repo).unwrap();
+        assert_eq!(2, commits.len());
+        assert_eq!(vec![first.clone()], commits[0].parents);
+        assert_eq!("second\n\nbody\n", commits[0].msg);
+
+        // commit-tree doesn't touch HEAD
+        assert!(!repo.gitdir.join("refs/heads/master").exists());
+
+        assert_eq!(
+            Err(err::Error::GitNotAValidObject(
+                first.clone(),
+                "tree".to_owned()
+            )),
+            commit_tree(&first, &[], &msg, &repo)
+        );
+    }
+}
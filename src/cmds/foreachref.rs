@@ -0,0 +1,365 @@
+use std::cmp::Ordering;
+
+use crate::cmds::{branch, refs, symbolicref};
+use crate::error as err;
+use crate::objects as obj;
+use crate::wildmatch;
+
+const DEFAULT_FORMAT: &str = "%(objectname) %(objecttype)\t%(refname)";
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ForEachRefOptions {
+    pub format: Option<String>,
+    // sort keys like refname or -committerdate, the last key is the primary
+    pub sort: Vec<String>,
+    pub count: Option<usize>,
+    pub points_at: Option<String>,
+    pub merged: Option<String>,
+    pub contains: Option<String>,
+}
+
+struct RefInfo {
+    name: String,
+    sha: String,
+    object: obj::GitObj,
+    // what an annotated tag points at once every tag is followed
+    peeled: Option<String>,
+}
+
+impl RefInfo {
+    fn commit(&self) -> Option<&obj::commit::Commit> {
+        match &self.object {
+            obj::GitObj::Commit(commit) => Some(commit.as_ref()),
+            _ => None,
+        }
+    }
+
+    fn msg(&self) -> Option<&str> {
+        match &self.object {
+            obj::GitObj::Commit(commit) => Some(&commit.msg),
+            obj::GitObj::Tag(tag) => Some(&tag.msg),
+            _ => None,
+        }
+    }
+}
+
+// a pattern matches refs it globs or, taken literally, refs it is a
+// prefix of up to a '/'
+fn matches_pattern(name: &str, pattern: &str) -> bool {
+    let prefix = pattern.trim_end_matches('/');
+    name == prefix
+        || name.starts_with(&format!("{prefix}/"))
+        || wildmatch::wildmatch(pattern, name, true)
+}
+
+fn object_type(object: &obj::GitObj) -> &'static str {
+    match object {
+        obj::GitObj::Blob(_) => "blob",
+        obj::GitObj::Tree(_) => "tree",
+        obj::GitObj::Commit(_) => "commit",
+        obj::GitObj::Tag(_) => "tag",
+    }
+}
+
+fn atom_value(info: &RefInfo, atom: &str, repo: &obj::Repo) -> Result<String, err::Error> {
+    let (name, modifier) = match atom.split_once(':') {
+        Some((name, modifier)) => (name, Some(modifier)),
+        None => (atom, None),
+    };
+    let value = match (name, modifier) {
+        ("refname", None) => info.name.clone(),
+        ("refname", Some("short")) => symbolicref::shorten_ref(&info.name).to_owned(),
+        ("objectname", None) => info.sha.clone(),
+        ("objectname", Some("short")) => info.sha[..7].to_owned(),
+        ("objecttype", None) => object_type(&info.object).to_owned(),
+        ("committerdate", None) => info
+            .commit()
+            .map(|c| c.committer.date())
+            .unwrap_or_default(),
+        ("authordate", None) => info.commit().map(|c| c.author.date()).unwrap_or_default(),
+        ("authorname", None) => info
+            .commit()
+            .map(|c| c.author.name.clone())
+            .unwrap_or_default(),
+        ("subject", None) => info
+            .msg()
+            .and_then(|msg| msg.lines().next())
+            .unwrap_or_default()
+            .to_owned(),
+        ("upstream", None) => branch::upstream_ref(&info.name, repo)?.unwrap_or_default(),
+        ("upstream", Some("short")) => {
+            let upstream = branch::upstream_ref(&info.name, repo)?.unwrap_or_default();
+            symbolicref::shorten_ref(&upstream).to_owned()
+        }
+        _ => return Err(err::Error::GitForEachRefBadAtom(atom.to_owned())),
+    };
+    Ok(value)
+}
+
+/// Expands the %(atom) placeholders in the format for the ref, '%%' is a
+/// literal '%'
+fn format_ref(format: &str, info: &RefInfo, repo: &obj::Repo) -> Result<String, err::Error> {
+    let mut output = String::new();
+    let mut rest = format;
+    while let Some(start) = rest.find('%') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if let Some(after) = rest.strip_prefix('%') {
+            output.push('%');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('(') {
+            let end = after
+                .find(')')
+                .ok_or_else(|| err::Error::GitForEachRefBadAtom(rest.to_owned()))?;
+            output.push_str(&atom_value(info, &after[..end], repo)?);
+            rest = &after[end + 1..];
+        } else {
+            output.push('%');
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn compare(a: &RefInfo, b: &RefInfo, key: &str, repo: &obj::Repo) -> Result<Ordering, err::Error> {
+    let (key, reverse) = match key.strip_prefix('-') {
+        Some(key) => (key, true),
+        None => (key, false),
+    };
+    let order = match key {
+        // dates sort by time rather than by how they are written
+        "committerdate" | "authordate" => {
+            let time = |info: &RefInfo| {
+                info.commit()
+                    .map(|c| {
+                        if key == "authordate" {
+                            c.author.time()
+                        } else {
+                            c.committer.time()
+                        }
+                    })
+                    .unwrap_or(0)
+            };
+            time(a).cmp(&time(b))
+        }
+        _ => atom_value(a, key, repo)?.cmp(&atom_value(b, key, repo)?),
+    };
+    Ok(if reverse { order.reverse() } else { order })
+}
+
+/// Formats every ref matching the patterns and filters in the options,
+/// sorted by refname unless other sort keys are given
+pub fn for_each_ref(
+    patterns: &[String],
+    opts: &ForEachRefOptions,
+    repo: &obj::Repo,
+) -> Result<String, err::Error> {
+    let points_at = match &opts.points_at {
+        Some(rev) => Some(refs::rev_parse(rev, repo)?),
+        None => None,
+    };
+    let merged = match &opts.merged {
+        Some(rev) => Some(refs::peel(&refs::rev_parse(rev, repo)?, "commit", repo)?),
+        None => None,
+    };
+    let contains = match &opts.contains {
+        Some(rev) => Some(refs::peel(&refs::rev_parse(rev, repo)?, "commit", repo)?),
+        None => None,
+    };
+
+    let packed = refs::read_packed_refs(repo)?;
+    let mut infos = Vec::new();
+    for line in refs::gather_refs(None, repo)? {
+        let Some((sha, name)) = line.trim_end().split_once(' ') else {
+            continue;
+        };
+        if !patterns.is_empty() && !patterns.iter().any(|p| matches_pattern(name, p)) {
+            continue;
+        }
+        let object = obj::read_object(sha, repo)?;
+        // a packed peeled value is only good while the ref still holds the
+        // sha it was recorded for
+        let packed_peeled = packed
+            .iter()
+            .find(|p| p.name == name && p.sha == sha)
+            .and_then(|p| p.peeled.clone());
+        let peeled = match &object {
+            obj::GitObj::Tag(_) if packed_peeled.is_some() => packed_peeled,
+            obj::GitObj::Tag(_) => refs::peel_tag(sha, repo)?,
+            _ => None,
+        };
+        let info = RefInfo {
+            name: name.to_owned(),
+            sha: sha.to_owned(),
+            object,
+            peeled,
+        };
+        // a tag points at an object both directly and once peeled
+        if let Some(points_at) = &points_at {
+            if points_at != sha && info.peeled.as_ref() != Some(points_at) {
+                continue;
+            }
+        }
+        // only commits can be merged into or contain other commits, tags
+        // are filtered by the commit they point at
+        if merged.is_some() || contains.is_some() {
+            let commit = match refs::peel(sha, "commit", repo) {
+                Ok(commit) => commit,
+                Err(err::Error::GitNotAValidObject(..)) => continue,
+                Err(e) => return Err(e),
+            };
+            if let Some(merged) = &merged {
+                if !branch::is_ancestor(&commit, merged, repo)? {
+                    continue;
+                }
+            }
+            if let Some(contains) = &contains {
+                if !branch::is_ancestor(contains, &commit, repo)? {
+                    continue;
+                }
+            }
+        }
+        infos.push(info);
+    }
+
+    // the refs come sorted by refname, each key is applied with a stable
+    // sort so the last key given ends up deciding the order first
+    for key in &opts.sort {
+        let mut failed = None;
+        infos.sort_by(|a, b| {
+            compare(a, b, key, repo).unwrap_or_else(|e| {
+                failed = Some(e);
+                Ordering::Equal
+            })
+        });
+        if let Some(e) = failed {
+            return Err(e);
+        }
+    }
+
+    let format = opts.format.as_deref().unwrap_or(DEFAULT_FORMAT);
+    let mut output = String::new();
+    for info in infos.iter().take(opts.count.unwrap_or(usize::MAX)) {
+        output.push_str(&format_ref(format, info, repo)?);
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod foreachref_tests {
+    use super::*;
+    use crate::cmds::{committree, writetree};
+    use crate::config;
+    use crate::index as idx;
+    use crate::test_utils;
+
+    #[test]
+    fn formats_filters_and_sorts_refs() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let mut repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let tree = writetree::write_tree(&idx::Index::empty(), None, &repo).unwrap();
+        let first = committree::commit_tree(&tree, &[], "first\n", &repo).unwrap();
+        let second =
+            committree::commit_tree(&tree, std::slice::from_ref(&first), "second\n", &repo)
+                .unwrap();
+        refs::write_ref("refs/heads/master", &second, &repo).unwrap();
+        refs::write_ref("refs/heads/old", &first, &repo).unwrap();
+        refs::write_ref("refs/tags/v1", &first, &repo).unwrap();
+        refs::write_ref("refs/remotes/origin/master", &first, &repo).unwrap();
+        config::set(&mut repo, "branch.master.remote", "origin").unwrap();
+        config::set(&mut repo, "branch.master.merge", "refs/heads/master").unwrap();
+
+        let opts = ForEachRefOptions {
+            format: Some(
+                "%(refname:short) %(objectname:short) %(subject) %(upstream:short)".to_owned(),
+            ),
+            ..Default::default()
+        };
+        assert_eq!(
+            format!(
+                "master {} second origin/master\nold {} first \n",
+                &second[..7],
+                &first[..7]
+            ),
+            for_each_ref(&["refs/heads".to_owned()], &opts, &repo).unwrap()
+        );
+
+        let opts = ForEachRefOptions {
+            format: Some("%(refname)".to_owned()),
+            points_at: Some(first.clone()),
+            ..Default::default()
+        };
+        assert_eq!(
+            "refs/remotes/origin/master\n",
+            for_each_ref(&["refs/remotes/*/*".to_owned()], &opts, &repo).unwrap()
+        );
+        assert_eq!(
+            "refs/heads/old\nrefs/remotes/origin/master\nrefs/tags/v1\n",
+            for_each_ref(&[], &opts, &repo).unwrap()
+        );
+
+        let opts = ForEachRefOptions {
+            format: Some("%(refname:short)".to_owned()),
+            contains: Some(second.clone()),
+            ..Default::default()
+        };
+        assert_eq!("master\n", for_each_ref(&[], &opts, &repo).unwrap());
+
+        let opts = ForEachRefOptions {
+            format: Some("%(refname:short) %(objecttype)".to_owned()),
+            sort: vec!["-refname".to_owned()],
+            merged: Some("old".to_owned()),
+            count: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            "v1 commit\norigin/master commit\n",
+            for_each_ref(&[], &opts, &repo).unwrap()
+        );
+
+        let opts = ForEachRefOptions {
+            format: Some("%(nope)".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            Err(err::Error::GitForEachRefBadAtom("nope".to_owned())),
+            for_each_ref(&[], &opts, &repo)
+        );
+    }
+
+    #[test]
+    fn annotated_tags_are_listed_and_peeled() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let tree = writetree::write_tree(&idx::Index::empty(), None, &repo).unwrap();
+        let commit = committree::commit_tree(&tree, &[], "first\n", &repo).unwrap();
+        let tag = obj::GitObj::Tag(Box::new(obj::tag::Tag {
+            object: commit.clone(),
+            obj_type: "commit".to_owned(),
+            tag: "v1".to_owned(),
+            tagger: Some(obj::commit::create_dummy_user()),
+            msg: "release one\n".to_owned(),
+        }));
+        let tag_sha = obj::write_object(tag, Some(&repo)).unwrap().to_string();
+        refs::write_ref("refs/heads/master", &commit, &repo).unwrap();
+        refs::write_ref("refs/tags/v1", &tag_sha, &repo).unwrap();
+        refs::write_ref("refs/tags/v2", &tag_sha, &repo).unwrap();
+        refs::pack_refs(false, &repo).unwrap();
+        refs::write_ref("refs/tags/v1", &tag_sha, &repo).unwrap();
+
+        let opts = ForEachRefOptions {
+            format: Some("%(refname:short) %(objecttype) %(subject)".to_owned()),
+            points_at: Some(commit.clone()),
+            merged: Some("master".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            "master commit first\nv1 tag release one\nv2 tag release one\n",
+            for_each_ref(&[], &opts, &repo).unwrap()
+        );
+        assert_eq!(commit, refs::rev_parse("v2^{commit}", &repo).unwrap());
+        let packed = refs::read_packed_refs(&repo).unwrap();
+        assert_eq!(Some(commit), packed[1].peeled);
+    }
+}
@@ -0,0 +1,16 @@
+use crate::error as err;
+use crate::fsmonitor;
+use crate::objects as obj;
+use crate::utils;
+
+/// Reports whether a daemon is watching the worktree
+pub fn status(repo: &obj::Repo) -> Result<String, err::Error> {
+    let watching = match fsmonitor::is_running(repo)? {
+        true => "watching",
+        false => "not watching",
+    };
+    Ok(format!(
+        "fsmonitor-daemon is {watching} '{}'",
+        utils::absolute_worktree(repo).display()
+    ))
+}
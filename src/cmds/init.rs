@@ -47,7 +47,7 @@ mod init_tests {
         let tempdir = test_utils::test_tempdir().unwrap();
         let tempdir_path = tempdir.path();
 
-        let create_git_repo_result = create_git_repo(&tempdir_path);
+        let create_git_repo_result = create_git_repo(tempdir_path);
         if create_git_repo_result.is_err() {
             panic!("repo setup failed in test!")
         }
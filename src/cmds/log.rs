@@ -32,8 +32,9 @@ pub fn follow_commits_to_root(
     // add the first commit to log
     commit_log.push(commit.clone());
 
-    while let Some(parent) = commit.parent {
-        commit = read_commit(&parent, repo)?;
+    // only the first parent is followed through merge commits
+    while let Some(parent) = commit.parents.first() {
+        commit = read_commit(parent, repo)?;
         commit_log.push(commit.clone()); // add parent commits to log
     }
     Ok(commit_log)
@@ -1,9 +1,26 @@
+pub mod add;
+pub mod branch;
+pub mod checkattr;
+pub mod checkignore;
+pub mod checkout;
+pub mod commit;
+pub mod committree;
+pub mod foreachref;
+pub mod fsmonitordaemon;
 pub mod init;
 pub mod log;
 pub mod lstree;
-pub mod checkout;
+pub mod readtree;
+pub mod reflog;
 pub mod refs;
-pub mod tag;
+pub mod reset;
+pub mod restore;
+pub mod sparsecheckout;
 pub mod status;
-pub mod add;
-pub mod commit;
+pub mod switch;
+pub mod symbolicref;
+pub mod tag;
+pub mod updateindex;
+pub mod updateref;
+pub mod worktree;
+pub mod writetree;
@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::cmds::add;
+use crate::error as err;
+use crate::index as idx;
+use crate::objects::{self as obj, tree};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReadTreeOptions {
+    /// merge the trees into the existing index instead of replacing it
+    pub merge: bool,
+    /// read the tree into this sub directory of the index
+    pub prefix: Option<String>,
+}
+
+pub type TreeEntries = BTreeMap<String, idx::IndexEntry>;
+
+/// Reads every blob and gitlink of the tree into index entries keyed by path
+pub fn tree_entries(
+    tree_sha: &str,
+    prefix: Option<&str>,
+    repo: &obj::Repo,
+) -> Result<TreeEntries, err::Error> {
+    let tree = tree::read_tree(tree_sha, repo)?;
+    let mut entries = BTreeMap::new();
+    for leaf in tree::flatten_tree(tree, prefix, repo)? {
+        let mode = leaf.mode_as_u32()?;
+        let entry = idx::IndexEntry::new(mode, leaf.sha, &leaf.path);
+        entries.insert(leaf.path, entry);
+    }
+    Ok(entries)
+}
+
+pub fn same(a: Option<&idx::IndexEntry>, b: Option<&idx::IndexEntry>) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some(a), Some(b)) => a.sha == b.sha && a.mode == b.mode,
+        _ => false,
+    }
+}
+
+pub fn stage_zero_entries(index: &idx::Index) -> Result<TreeEntries, err::Error> {
+    let mut entries = BTreeMap::new();
+    for entry in &index.entries {
+        if entry.stage() != 0 {
+            return Err(err::Error::GitIndexUnmerged(entry.name.clone()));
+        }
+        entries.insert(entry.name.clone(), entry.clone());
+    }
+    Ok(entries)
+}
+
+// when the merge result matches what is already in the index the existing
+// entry is kept so its stat info stays valid
+fn keep_stat_info(
+    result: Option<&idx::IndexEntry>,
+    current: Option<&idx::IndexEntry>,
+) -> Option<idx::IndexEntry> {
+    if same(result, current) {
+        current.cloned()
+    } else {
+        result.cloned()
+    }
+}
+
+fn one_way_merge(current: &TreeEntries, target: TreeEntries) -> Vec<idx::IndexEntry> {
+    target
+        .iter()
+        .filter_map(|(path, entry)| keep_stat_info(Some(entry), current.get(path)))
+        .collect()
+}
+
+/// Moves the index from the head tree to the merge tree while carrying
+/// forward any changes staged in the index that the merge doesn't touch
+pub fn two_way_merge(
+    current: &TreeEntries,
+    head: TreeEntries,
+    merge: TreeEntries,
+) -> Result<Vec<idx::IndexEntry>, err::Error> {
+    let paths: BTreeSet<&String> = current
+        .keys()
+        .chain(head.keys())
+        .chain(merge.keys())
+        .collect();
+
+    let mut entries = Vec::new();
+    for path in paths {
+        let (i, h, m) = (current.get(path), head.get(path), merge.get(path));
+        let result = if same(i, h) {
+            keep_stat_info(m, i)
+        } else if same(h, m) || same(i, m) {
+            i.cloned()
+        } else {
+            return Err(err::Error::GitReadTreeWouldOverwrite(path.to_owned()));
+        };
+        entries.extend(result);
+    }
+    Ok(entries)
+}
+
+/// Resolves paths where at most one side changed and records the rest as
+/// conflicts using stage 1 for the base, stage 2 for ours and stage 3 for
+/// theirs. Paths deleted on one side and unchanged on the other are
+/// resolved as deleted.
+fn three_way_merge(
+    current: &TreeEntries,
+    base: TreeEntries,
+    ours: TreeEntries,
+    theirs: TreeEntries,
+) -> Result<Vec<idx::IndexEntry>, err::Error> {
+    let paths: BTreeSet<&String> = current
+        .keys()
+        .chain(base.keys())
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect();
+
+    let mut entries = Vec::new();
+    for path in paths {
+        let i = current.get(path);
+        let (o, a, b) = (base.get(path), ours.get(path), theirs.get(path));
+
+        if o.is_none() && a.is_none() && b.is_none() {
+            // only in the index, nothing to merge
+            entries.extend(i.cloned());
+            continue;
+        }
+
+        let resolved = if same(a, b) || same(o, b) {
+            Some(a)
+        } else if same(o, a) {
+            Some(b)
+        } else {
+            None
+        };
+
+        match resolved {
+            Some(result) => {
+                // the index may only differ from ours where the merge keeps it
+                if !same(i, a) && !same(i, result) {
+                    return Err(err::Error::GitReadTreeWouldOverwrite(path.to_owned()));
+                }
+                entries.extend(keep_stat_info(result, i));
+            }
+            None => {
+                if !same(i, a) {
+                    return Err(err::Error::GitReadTreeWouldOverwrite(path.to_owned()));
+                }
+                for (stage, entry) in [(1, o), (2, a), (3, b)] {
+                    if let Some(entry) = entry {
+                        let mut staged = entry.clone();
+                        staged.set_stage(stage);
+                        entries.push(staged);
+                    }
+                }
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn read_tree_into_prefix(
+    tree_sha: &str,
+    prefix: &str,
+    index: &mut idx::Index,
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
+    let prefix = prefix.trim_end_matches('/');
+    let dir = format!("{prefix}/");
+    if index
+        .entries
+        .iter()
+        .any(|e| e.name == prefix || e.name.starts_with(&dir))
+    {
+        return Err(err::Error::GitReadTreePrefixExists(prefix.to_owned()));
+    }
+    for entry in tree_entries(tree_sha, Some(prefix), repo)?.into_values() {
+        index.add_entry(entry);
+    }
+    Ok(())
+}
+
+/// Reads tree information into the index. Without -m the index is replaced
+/// by the tree, or the tree is added under --prefix. With -m one, two or
+/// three trees are merged into the index.
+pub fn read_tree(
+    tree_shas: &[String],
+    opts: &ReadTreeOptions,
+    repo: &obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    let mut index = idx::read_index_or_empty(repo)?;
+
+    if let Some(prefix) = &opts.prefix {
+        if tree_shas.len() != 1 {
+            return Err(err::Error::GitReadTreeWrongNumberOfTrees(tree_shas.len()));
+        }
+        read_tree_into_prefix(&tree_shas[0], prefix, &mut index, repo)?;
+    } else if !opts.merge {
+        if tree_shas.len() != 1 {
+            return Err(err::Error::GitReadTreeWrongNumberOfTrees(tree_shas.len()));
+        }
+        index.entries = tree_entries(&tree_shas[0], None, repo)?
+            .into_values()
+            .collect();
+    } else {
+        let current = stage_zero_entries(&index)?;
+        let mut trees = Vec::new();
+        for sha in tree_shas {
+            trees.push(tree_entries(sha, None, repo)?);
+        }
+
+        let mut entries = match trees.len() {
+            1 => one_way_merge(&current, trees.remove(0)),
+            2 => {
+                let merge = trees.remove(1);
+                two_way_merge(&current, trees.remove(0), merge)?
+            }
+            3 => {
+                let theirs = trees.remove(2);
+                let ours = trees.remove(1);
+                three_way_merge(&current, trees.remove(0), ours, theirs)?
+            }
+            n => return Err(err::Error::GitReadTreeWrongNumberOfTrees(n)),
+        };
+        entries.sort();
+        index.entries = entries;
+    }
+
+    add::write_index(index, repo)?;
+    Ok(None)
+}
+
+#[cfg(test)]
+mod read_tree_tests {
+    use super::*;
+    use crate::cmds::writetree;
+    use crate::utils;
+
+    fn entry(name: &str, contents: &str) -> idx::IndexEntry {
+        let blob = obj::GitObj::Blob(obj::blob::Blob::new(contents.as_bytes()));
+        let sha = obj::write_object(blob, None).unwrap();
+        idx::IndexEntry::new(0o100644, sha.bytes().to_vec(), name)
+    }
+
+    fn entries(list: &[(&str, &str)]) -> TreeEntries {
+        list.iter()
+            .map(|(name, contents)| (name.to_string(), entry(name, contents)))
+            .collect()
+    }
+
+    fn names_and_stages(entries: &[idx::IndexEntry]) -> Vec<(String, u16)> {
+        entries
+            .iter()
+            .map(|e| (e.name.clone(), e.stage()))
+            .collect()
+    }
+
+    #[test]
+    fn two_way_merge_carries_forward_index_changes() {
+        let head = entries(&[("a", "a"), ("b", "b")]);
+        let merge = entries(&[("a", "a2"), ("b", "b")]);
+
+        // the index has a staged change to b which the merge doesn't touch
+        let current = entries(&[("a", "a"), ("b", "b staged")]);
+        let result = two_way_merge(&current, head.clone(), merge.clone()).unwrap();
+        assert_eq!(entry("a", "a2").sha, result[0].sha);
+        assert_eq!(entry("b", "b staged").sha, result[1].sha);
+
+        // a staged change to a would be lost by the merge
+        let current = entries(&[("a", "a staged"), ("b", "b")]);
+        assert_eq!(
+            Err(err::Error::GitReadTreeWouldOverwrite("a".to_owned())),
+            two_way_merge(&current, head, merge)
+        );
+    }
+
+    #[test]
+    fn three_way_merge_resolves_trivial_changes_and_stages_conflicts() {
+        let base = entries(&[("same", "x"), ("ours", "x"), ("theirs", "x"), ("both", "x")]);
+        let ours = entries(&[("same", "x"), ("ours", "o"), ("theirs", "x"), ("both", "o")]);
+        let theirs = entries(&[("same", "x"), ("ours", "x"), ("theirs", "t"), ("both", "t")]);
+
+        let mut result = three_way_merge(&ours, base, ours.clone(), theirs).unwrap();
+        result.sort();
+        assert_eq!(
+            vec![
+                ("both".to_owned(), 1),
+                ("both".to_owned(), 2),
+                ("both".to_owned(), 3),
+                ("ours".to_owned(), 0),
+                ("same".to_owned(), 0),
+                ("theirs".to_owned(), 0),
+            ],
+            names_and_stages(&result)
+        );
+        let theirs_entry = result.iter().find(|e| e.name == "theirs").unwrap();
+        assert_eq!(entry("theirs", "t").sha, theirs_entry.sha);
+    }
+
+    #[test]
+    fn read_tree_with_prefix_adds_entries_under_the_dir() {
+        let gitdir = crate::test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+
+        let mut index = idx::Index::empty();
+        for name in ["a.txt", "dir/b.txt"] {
+            let blob = obj::GitObj::Blob(obj::blob::Blob::new(name.as_bytes()));
+            let sha = obj::write_object(blob, Some(&repo)).unwrap();
+            index.add_entry(idx::IndexEntry::new(0o100644, sha.bytes().to_vec(), name));
+        }
+        let tree_sha = writetree::write_tree(&index, None, &repo).unwrap();
+        let trees = [tree_sha.clone()];
+        add::write_index(index, &repo).unwrap();
+
+        let opts = ReadTreeOptions {
+            prefix: Some("vendor/".to_owned()),
+            ..Default::default()
+        };
+        read_tree(&trees, &opts, &repo).unwrap();
+        let names: Vec<String> = idx::read_index_or_empty(&repo)
+            .unwrap()
+            .entries
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(
+            vec!["a.txt", "dir/b.txt", "vendor/a.txt", "vendor/dir/b.txt"],
+            names
+        );
+
+        assert_eq!(
+            Err(err::Error::GitReadTreePrefixExists("vendor".to_owned())),
+            read_tree(&trees, &opts, &repo)
+        );
+
+        // reading the tree without a prefix replaces the index
+        read_tree(&trees, &ReadTreeOptions::default(), &repo).unwrap();
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        assert_eq!(2, index.entries.len());
+        assert_eq!(
+            tree_sha,
+            writetree::write_tree(&index, None, &repo).unwrap()
+        );
+        assert!(utils::git_index_exists(&repo));
+    }
+}
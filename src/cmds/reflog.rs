@@ -0,0 +1,389 @@
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use std::fmt::Write as _;
+use std::fs::{create_dir_all, read_to_string, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::cmds::refs;
+use crate::config;
+use crate::error as err;
+use crate::objects::{self as obj, commit};
+
+pub const ZERO_SHA: &str = "0000000000000000000000000000000000000000";
+
+// reflog entries older than this are removed by expire when no time is given
+const DEFAULT_EXPIRE: &str = "90.days.ago";
+
+/// One line of a reflog recording a ref moving from old to new
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+    pub old: String,
+    pub new: String,
+    pub committer: commit::User,
+    pub msg: String,
+}
+
+impl ReflogEntry {
+    /// The unix timestamp the entry was recorded at
+    pub fn time(&self) -> i64 {
+        self.committer.time()
+    }
+}
+
+fn reflog_path(name: &str, repo: &obj::Repo) -> PathBuf {
+    repo.git_path(Path::new("logs").join(name))
+}
+
+fn parse_reflog_line(line: &str) -> Result<ReflogEntry, err::Error> {
+    let malformed = || err::Error::GitReflogMalformed(line.to_owned());
+    let (old, rest) = line.split_once(' ').ok_or_else(malformed)?;
+    let (new, rest) = rest.split_once(' ').ok_or_else(malformed)?;
+    let is_sha = |sha: &str| sha.len() == 40 && sha.chars().all(|c| c.is_ascii_hexdigit());
+    if !is_sha(old) || !is_sha(new) {
+        return Err(malformed());
+    }
+    let (user, msg) = rest.split_once('\t').unwrap_or((rest, ""));
+    let (_, committer) =
+        commit::parse_user_bytes(format!("{user}\n").as_bytes()).map_err(|_| malformed())?;
+    Ok(ReflogEntry {
+        old: old.to_owned(),
+        new: new.to_owned(),
+        committer,
+        msg: msg.to_owned(),
+    })
+}
+
+/// Reads the reflog of the ref, oldest entry first. A ref without a
+/// reflog gives an empty list.
+pub fn read_reflog(name: &str, repo: &obj::Repo) -> Result<Vec<ReflogEntry>, err::Error> {
+    let path = reflog_path(name, repo);
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    read_to_string(path)?
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(parse_reflog_line)
+        .collect()
+}
+
+fn format_entry(e: &ReflogEntry) -> String {
+    let user = &e.committer;
+    format!(
+        "{} {} {} {} {}\t{}\n",
+        e.old, e.new, user.name, user.email, user.timestamp, e.msg
+    )
+}
+
+fn write_reflog(name: &str, entries: &[ReflogEntry], repo: &obj::Repo) -> Result<(), err::Error> {
+    let contents: String = entries.iter().map(format_entry).collect();
+    File::create(reflog_path(name, repo))?.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+// like git's core.logAllRefUpdates default only branches, remote tracking
+// branches, notes and HEAD get a reflog created for them, refs that already
+// have a reflog keep being logged
+fn should_log(name: &str, repo: &obj::Repo) -> Result<bool, err::Error> {
+    if reflog_path(name, repo).is_file() {
+        return Ok(true);
+    }
+    let key = "core.logAllRefUpdates";
+    match config::get(&repo.gitconf, key)?.as_deref() {
+        Some("always") => Ok(true),
+        Some(value) if !config::parse_bool(key, value)? => Ok(false),
+        _ => Ok(name == "HEAD"
+            || ["refs/heads/", "refs/remotes/", "refs/notes/"]
+                .iter()
+                .any(|prefix| name.starts_with(prefix))),
+    }
+}
+
+/// Records the ref moving from old to new, a ref being created has no old
+/// value and is logged as moving from the zero sha
+pub fn append_reflog(
+    name: &str,
+    old: Option<&str>,
+    new: &str,
+    msg: &str,
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
+    if !should_log(name, repo)? {
+        return Ok(());
+    }
+    let path = reflog_path(name, repo);
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let entry = ReflogEntry {
+        old: old.unwrap_or(ZERO_SHA).to_owned(),
+        new: new.to_owned(),
+        committer: commit::current_user(&repo.gitconf)?,
+        // reflog messages are a single line
+        msg: msg.lines().next().unwrap_or("").to_owned(),
+    };
+    let mut log = OpenOptions::new().create(true).append(true).open(path)?;
+    log.write_all(format_entry(&entry).as_bytes())?;
+    Ok(())
+}
+
+/// Parses the subset of git's approxidate formats that are most common in
+/// revisions and reflog commands: 'now', 'yesterday', '<n> <unit>s ago'
+/// (with spaces or dots), '@<unix time>', 'YYYY-MM-DD' and
+/// 'YYYY-MM-DD HH:MM:SS' in local time. Returns a unix timestamp.
+pub fn parse_date(date: &str, now: i64) -> Result<i64, err::Error> {
+    let invalid = || err::Error::GitInvalidDate(date.to_owned());
+    let normalized = date.trim().replace('.', " ").to_lowercase();
+    match normalized.as_str() {
+        "now" => return Ok(now),
+        "yesterday" => return Ok(now - 24 * 60 * 60),
+        _ => {}
+    }
+    if let Some(ts) = date.strip_prefix('@') {
+        return ts.parse().map_err(|_| invalid());
+    }
+    if let Ok(dt) = NaiveDateTime::parse_from_str(date.trim(), "%Y-%m-%d %H:%M:%S") {
+        return Local
+            .from_local_datetime(&dt)
+            .earliest()
+            .map(|d| d.timestamp())
+            .ok_or_else(invalid);
+    }
+    if let Ok(d) = NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d") {
+        return Local
+            .from_local_datetime(&d.and_hms_opt(0, 0, 0).ok_or_else(invalid)?)
+            .earliest()
+            .map(|d| d.timestamp())
+            .ok_or_else(invalid);
+    }
+
+    let words: Vec<&str> = normalized.split_whitespace().collect();
+    let (count, unit) = match words.as_slice() {
+        [count, unit] | [count, unit, "ago"] => {
+            (count.parse::<i64>().map_err(|_| invalid())?, *unit)
+        }
+        _ => return Err(invalid()),
+    };
+    let seconds = match unit.trim_end_matches('s') {
+        "second" | "sec" => 1,
+        "minute" | "min" => 60,
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        "week" => 7 * 24 * 60 * 60,
+        "month" => 30 * 24 * 60 * 60,
+        "year" => 365 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    Ok(now - count * seconds)
+}
+
+/// Finds the sha a ref pointed at for a reflog selector, the part inside
+/// '@{...}'. A number counts back through the entries from the newest and
+/// a date finds the value the ref had at that time.
+pub fn resolve_selector(
+    name: &str,
+    selector: &str,
+    repo: &obj::Repo,
+) -> Result<String, err::Error> {
+    let entries = read_reflog(name, repo)?;
+    if entries.is_empty() {
+        return Err(err::Error::GitReflogMissing(name.to_owned()));
+    }
+
+    if let Ok(n) = selector.parse::<usize>() {
+        return entries.iter().rev().nth(n).map(|e| e.new.clone()).ok_or(
+            err::Error::GitReflogTooShort(name.to_owned(), entries.len()),
+        );
+    }
+
+    let time = parse_date(selector, Local::now().timestamp())?;
+    match entries.iter().rev().find(|e| e.time() <= time) {
+        Some(entry) => Ok(entry.new.clone()),
+        // the date is before the log starts so use the oldest known value
+        None => {
+            let oldest = &entries[0];
+            Ok(if oldest.old == ZERO_SHA {
+                oldest.new.clone()
+            } else {
+                oldest.old.clone()
+            })
+        }
+    }
+}
+
+// reflog commands take short names like master, these are expanded to the
+// full ref name the reflog is stored under
+fn full_ref_name(name: &str, repo: &obj::Repo) -> Result<String, err::Error> {
+    refs::expand_ref_name(name, repo)?.ok_or_else(|| err::Error::GitReflogMissing(name.to_owned()))
+}
+
+// splits 'master@{2}' into the ref and the selector
+fn split_selector(entry: &str) -> Option<(&str, &str)> {
+    let (name, rest) = entry.split_once("@{")?;
+    Some((name, rest.strip_suffix('}')?))
+}
+
+/// Lists the reflog of the ref newest entry first in the same format as
+/// git reflog show
+pub fn show(name: &str, repo: &obj::Repo) -> Result<String, err::Error> {
+    let full_name = full_ref_name(name, repo)?;
+    let mut output = String::new();
+    for (i, entry) in read_reflog(&full_name, repo)?.iter().rev().enumerate() {
+        writeln!(output, "{} {name}@{{{i}}}: {}", &entry.new[..7], entry.msg)?;
+    }
+    Ok(output)
+}
+
+/// Removes the reflog entries older than the expire time from the given
+/// refs, or every reflog when all is set
+pub fn expire(
+    names: &[String],
+    all: bool,
+    expire_time: Option<&str>,
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
+    let now = Local::now().timestamp();
+    let cutoff = match expire_time.unwrap_or(DEFAULT_EXPIRE) {
+        "never" | "false" => return Ok(()),
+        "all" | "now" => i64::MAX,
+        date => parse_date(date, now)?,
+    };
+
+    let full_names = if all {
+        let mut logged = vec!["HEAD".to_owned()];
+        logged.extend(refs::list_refs("refs/", repo)?.into_iter().map(|(n, _)| n));
+        logged
+            .into_iter()
+            .filter(|n| reflog_path(n, repo).is_file())
+            .collect()
+    } else {
+        names
+            .iter()
+            .map(|n| full_ref_name(n, repo))
+            .collect::<Result<Vec<String>, err::Error>>()?
+    };
+
+    for name in full_names {
+        let mut entries = read_reflog(&name, repo)?;
+        entries.retain(|e| e.time() > cutoff);
+        write_reflog(&name, &entries, repo)?;
+    }
+    Ok(())
+}
+
+/// Deletes single entries given as ref@{n}, the numbers refer to the
+/// entries before any of them are removed
+pub fn delete(selectors: &[String], repo: &obj::Repo) -> Result<(), err::Error> {
+    let mut by_ref: Vec<(String, Vec<usize>)> = Vec::new();
+    for selector in selectors {
+        let (name, n) = split_selector(selector)
+            .and_then(|(name, n)| Some((name, n.parse::<usize>().ok()?)))
+            .ok_or_else(|| err::Error::GitReflogBadSelector(selector.to_owned()))?;
+        let full_name = full_ref_name(if name.is_empty() { "HEAD" } else { name }, repo)?;
+        match by_ref.iter_mut().find(|(n, _)| *n == full_name) {
+            Some((_, positions)) => positions.push(n),
+            None => by_ref.push((full_name, vec![n])),
+        }
+    }
+
+    for (name, positions) in by_ref {
+        let entries = read_reflog(&name, repo)?;
+        let len = entries.len();
+        if let Some(n) = positions.iter().find(|n| **n >= len) {
+            return Err(err::Error::GitReflogBadSelector(format!("{name}@{{{n}}}")));
+        }
+        let kept: Vec<ReflogEntry> = entries
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !positions.contains(&(len - 1 - i)))
+            .map(|(_, e)| e)
+            .collect();
+        write_reflog(&name, &kept, repo)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod reflog_tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn parse_date_handles_relative_and_absolute_dates() {
+        let now = 1_700_000_000;
+        assert_eq!(Ok(now), parse_date("now", now));
+        assert_eq!(Ok(now - 86400), parse_date("yesterday", now));
+        assert_eq!(Ok(now - 2 * 86400), parse_date("2.days.ago", now));
+        assert_eq!(Ok(now - 3 * 3600), parse_date("3 hours ago", now));
+        assert_eq!(Ok(1234), parse_date("@1234", now));
+        assert!(parse_date("2023-11-14", now).is_ok());
+        assert_eq!(
+            Err(err::Error::GitInvalidDate("soon".to_owned())),
+            parse_date("soon", now)
+        );
+    }
+
+    #[test]
+    fn updates_are_logged_for_the_branch_and_head() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let mut repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        crate::config::set(&mut repo, "user.name", "A U Thor").unwrap();
+        crate::config::set(&mut repo, "user.email", "author@example.com").unwrap();
+
+        let first = test_utils::test_commit_files(&[], "first", &repo).unwrap();
+        let second = test_utils::test_commit_files(&[], "second", &repo).unwrap();
+
+        let head_log = read_reflog("HEAD", &repo).unwrap();
+        assert_eq!(head_log, read_reflog("refs/heads/master", &repo).unwrap());
+        assert_eq!(ZERO_SHA, head_log[0].old);
+        assert_eq!(
+            (first.as_str(), second.as_str()),
+            (head_log[1].old.as_str(), head_log[1].new.as_str())
+        );
+        assert_eq!("A U Thor", head_log[1].committer.name);
+        assert_eq!("<author@example.com>", head_log[1].committer.email);
+
+        assert_eq!(
+            format!(
+                "{} master@{{0}}: commit: second\n{} master@{{1}}: commit: first\n",
+                &second[..7],
+                &first[..7]
+            ),
+            show("master", &repo).unwrap()
+        );
+
+        assert_eq!(first, refs::rev_parse("@{1}", &repo).unwrap());
+        assert_eq!(second, refs::rev_parse("HEAD@{0}", &repo).unwrap());
+        assert_eq!(first, refs::rev_parse("master@{1}~0", &repo).unwrap());
+        assert_eq!(
+            Err(err::Error::GitReflogTooShort(
+                "refs/heads/master".to_owned(),
+                2
+            )),
+            refs::rev_parse("master@{2}", &repo)
+        );
+        // the log starts after yesterday so the oldest value is used
+        assert_eq!(first, refs::rev_parse("master@{yesterday}", &repo).unwrap());
+
+        delete(&["master@{0}".to_owned()], &repo).unwrap();
+        assert_eq!(first, refs::rev_parse("master@{0}", &repo).unwrap());
+
+        expire(&[], true, Some("now"), &repo).unwrap();
+        assert!(read_reflog("HEAD", &repo).unwrap().is_empty());
+        assert!(read_reflog("refs/heads/master", &repo).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_lines_are_malformed() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let line = format!("{ZERO_SHA} abc A U Thor <author@example.com> 1700000000 +0000\tx");
+        create_dir_all(repo.git_path("logs/refs/heads")).unwrap();
+        std::fs::write(repo.git_path("logs/refs/heads/master"), format!("{line}\n")).unwrap();
+
+        assert_eq!(
+            Err(err::Error::GitReflogMalformed(line)),
+            read_reflog("refs/heads/master", &repo)
+        );
+    }
+}
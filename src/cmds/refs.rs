@@ -1,47 +1,579 @@
-use std::fs::{metadata, read_dir, read_to_string};
+use std::collections::BTreeMap;
+use std::fs::{
+    create_dir_all, metadata, read, read_dir, read_to_string, remove_dir, remove_file, rename,
+    write, OpenOptions,
+};
+use std::io::{ErrorKind, Write};
 use std::path::{Path, PathBuf};
 
+use crate::cmds::{reflog, updateref};
 use crate::error as err;
 use crate::objects as obj;
+use crate::utils;
 
-pub fn resolve_ref(ref_path: &Path, repo: &obj::Repo) -> Result<String, err::Error> {
-    let data = read_to_string(repo.gitdir.join(ref_path))?;
-    if "ref: " == &data[..5] {
-        resolve_ref(&PathBuf::from(data[5..].trim()), repo)
-    } else {
-        Ok(data.trim().to_owned())
+const PACKED_REFS_HEADER: &str = "# pack-refs with: peeled fully-peeled sorted \n";
+/// How many symbolic refs are followed before giving up, git's
+/// SYMREF_MAXDEPTH. Anything deeper is most likely a loop.
+pub const SYMREF_MAX_DEPTH: usize = 5;
+
+/// A ref stored in .git/packed-refs, peeled holds the sha of the object an
+/// annotated tag points at when git recorded it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackedRef {
+    pub name: String,
+    pub sha: String,
+    pub peeled: Option<String>,
+}
+
+/// Parses .git/packed-refs, each ref is a '<sha> <name>' line which may be
+/// followed by a '^<sha>' line holding the peeled value of a tag
+pub fn read_packed_refs(repo: &obj::Repo) -> Result<Vec<PackedRef>, err::Error> {
+    let path = repo.git_path("packed-refs");
+    if !path.is_file() {
+        return Ok(Vec::new());
     }
+
+    let mut packed: Vec<PackedRef> = Vec::new();
+    for line in read_to_string(path)?.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(peeled) = line.strip_prefix('^') {
+            match packed.last_mut() {
+                Some(last) if last.peeled.is_none() => last.peeled = Some(peeled.to_owned()),
+                _ => return Err(err::Error::GitPackedRefsMalformed(line.to_owned())),
+            }
+        } else if let Some((sha, name)) = line.split_once(' ') {
+            packed.push(PackedRef {
+                name: name.to_owned(),
+                sha: sha.to_owned(),
+                peeled: None,
+            });
+        } else {
+            return Err(err::Error::GitPackedRefsMalformed(line.to_owned()));
+        }
+    }
+    Ok(packed)
 }
 
-pub fn gather_refs(path: Option<&Path>, repo: &obj::Repo) -> Result<Vec<String>, err::Error> {
-    let refs_dir_path = if path == None {
-        repo.gitdir.join("refs/")
+/// A held packed-refs.lock. The refs are read once the lock is taken so
+/// they can't change underneath, the lock is removed when dropped without
+/// being committed.
+#[derive(Debug)]
+pub struct PackedRefsLock {
+    path: PathBuf,
+    packed: Vec<PackedRef>,
+}
+
+impl PackedRefsLock {
+    /// Takes the lock, failing if another writer holds it
+    pub fn acquire(repo: &obj::Repo) -> Result<PackedRefsLock, err::Error> {
+        let path = repo.git_path("packed-refs.lock");
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                return Err(err::Error::GitRefLockFailed(
+                    "packed-refs".to_owned(),
+                    "the lock file already exists".to_owned(),
+                ))
+            }
+            Err(e) => return Err(e.into()),
+        }
+        // the lock is released if reading fails
+        let mut lock = PackedRefsLock {
+            path,
+            packed: Vec::new(),
+        };
+        lock.packed = read_packed_refs(repo)?;
+        Ok(lock)
+    }
+
+    /// Drops the ref from the packed refs, returns whether it was packed
+    pub fn remove(&mut self, name: &str) -> bool {
+        let count = self.packed.len();
+        self.packed.retain(|p| p.name != name);
+        self.packed.len() != count
+    }
+
+    /// Writes the refs to the lock file and moves it into place so readers
+    /// never see a half written file
+    pub fn commit(self, repo: &obj::Repo) -> Result<(), err::Error> {
+        let mut contents = PACKED_REFS_HEADER.to_owned();
+        for p in &self.packed {
+            contents.push_str(&format!("{} {}\n", p.sha, p.name));
+            if let Some(peeled) = &p.peeled {
+                contents.push_str(&format!("^{peeled}\n"));
+            }
+        }
+        write(&self.path, contents)?;
+        rename(&self.path, repo.git_path("packed-refs"))?;
+        Ok(())
+    }
+}
+
+impl Drop for PackedRefsLock {
+    fn drop(&mut self) {
+        if self.path.exists() {
+            let _ = remove_file(&self.path);
+        }
+    }
+}
+
+fn find_packed_ref(name: &str, repo: &obj::Repo) -> Result<Option<PackedRef>, err::Error> {
+    Ok(read_packed_refs(repo)?.into_iter().find(|p| p.name == name))
+}
+
+/// Relaxations of the ref name rules, matching the check-ref-format flags
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RefFormatOptions {
+    // names don't need a '/' like refs/heads/main does
+    pub allow_onelevel: bool,
+    // a single '*' is allowed as used in refspecs like refs/heads/*
+    pub refspec_pattern: bool,
+    // a leading '/' is dropped and repeated '/'s are collapsed
+    pub normalize: bool,
+}
+
+/// Checks the name against git's ref name rules and returns it, normalized
+/// when asked for
+pub fn check_ref_format(name: &str, opts: RefFormatOptions) -> Result<String, err::Error> {
+    let invalid = || err::Error::GitInvalidRefName(name.to_owned());
+    let name = if opts.normalize {
+        let mut normalized = String::new();
+        for c in name.trim_start_matches('/').chars() {
+            if !(c == '/' && normalized.ends_with('/')) {
+                normalized.push(c);
+            }
+        }
+        normalized
     } else {
-        path.unwrap().to_path_buf()
+        name.to_owned()
+    };
+
+    let bad_char = |c: char| c.is_ascii_control() || " ~^:?[\\".contains(c);
+    let stars = name.matches('*').count();
+    let invalid_name = name.is_empty()
+        || name == "@"
+        || name.starts_with('/')
+        || name.ends_with('/')
+        || name.ends_with('.')
+        || name.contains("//")
+        || name.contains("..")
+        || name.contains("@{")
+        || name.chars().any(bad_char)
+        || stars > usize::from(opts.refspec_pattern)
+        || (!opts.allow_onelevel && !name.contains('/'))
+        || name
+            .split('/')
+            .any(|part| part.starts_with('.') || part.ends_with(".lock"));
+    if invalid_name {
+        Err(invalid())
+    } else {
+        Ok(name)
+    }
+}
+
+/// Checks a name is safe to write as a ref, it must be a valid ref under
+/// refs/ or a one level pseudo ref like HEAD or ORIG_HEAD
+pub fn check_ref_name(name: &str) -> Result<(), err::Error> {
+    let pseudo_ref = !name.is_empty() && name.chars().all(|c| c.is_ascii_uppercase() || c == '_');
+    if pseudo_ref {
+        return Ok(());
+    }
+    if !name.starts_with("refs/") {
+        return Err(err::Error::GitInvalidRefName(name.to_owned()));
+    }
+    check_ref_format(name, RefFormatOptions::default()).map(|_| ())
+}
+
+/// Follows the ref until it reaches a sha, loose refs take priority over
+/// refs in packed-refs
+pub fn resolve_ref(ref_path: &Path, repo: &obj::Repo) -> Result<String, err::Error> {
+    let name = ref_path.to_string_lossy();
+    read_ref(&name, repo)?.ok_or_else(|| err::Error::GitRefNotFound(name.into_owned()))
+}
+
+/// Returns the sha a ref like refs/heads/master points at, or None if the
+/// ref doesn't exist. Symbolic refs are followed, a symbolic ref pointing
+/// at a ref that doesn't exist yet gives None.
+pub fn read_ref(name: &str, repo: &obj::Repo) -> Result<Option<String>, err::Error> {
+    let mut current = name.to_owned();
+    for _ in 0..=SYMREF_MAX_DEPTH {
+        let ref_path = repo.git_path(&current);
+        if !ref_path.is_file() {
+            return Ok(find_packed_ref(&current, repo)?.map(|p| p.sha));
+        }
+        let data = read_to_string(ref_path)?;
+        match data.strip_prefix("ref:") {
+            Some(target) => current = target.trim().to_owned(),
+            None => return Ok(Some(data.trim().to_owned())),
+        }
+    }
+    Err(err::Error::GitSymbolicRefTooDeep(name.to_owned()))
+}
+
+// writes the ref's lock file and moves it into place, creating the ref's
+// dirs when needed. Two writers can't interleave and readers never see a
+// half written ref.
+fn write_loose_ref(name: &str, contents: &str, repo: &obj::Repo) -> Result<(), err::Error> {
+    let ref_path = repo.git_path(name);
+    if let Some(parent) = ref_path.parent() {
+        create_dir_all(parent)?;
+    }
+    let lock = repo.git_path(format!("{name}.lock"));
+    let mut file = match OpenOptions::new().write(true).create_new(true).open(&lock) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            return Err(err::Error::GitRefLockFailed(
+                name.to_owned(),
+                "the lock file already exists".to_owned(),
+            ))
+        }
+        Err(e) => return Err(e.into()),
     };
+    let written = file
+        .write_all(contents.as_bytes())
+        .and_then(|_| rename(&lock, &ref_path));
+    if let Err(e) = written {
+        let _ = remove_file(&lock);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Points the ref at the sha without a reflog entry, commands go through
+/// update_ref or a transaction
+#[cfg(test)]
+pub fn write_ref(name: &str, sha: &str, repo: &obj::Repo) -> Result<(), err::Error> {
+    check_ref_name(name)?;
+    write_loose_ref(name, &format!("{sha}\n"), repo)
+}
+
+/// Points the ref at the sha and records the change in the reflog. When
+/// the ref is HEAD, or the branch HEAD points at, both the branch and HEAD
+/// get a reflog entry.
+pub fn update_ref(name: &str, sha: &str, msg: &str, repo: &obj::Repo) -> Result<(), err::Error> {
+    let mut transaction = updateref::RefTransaction::new(msg);
+    transaction.update(name, sha, None);
+    transaction.commit(repo)
+}
+
+/// Makes a symbolic ref like HEAD point at another ref, through the ref's
+/// lock file
+pub fn write_symref(name: &str, target: &str, repo: &obj::Repo) -> Result<(), err::Error> {
+    check_ref_name(name)?;
+    check_ref_name(target)?;
+    write_loose_ref(name, &format!("ref: {target}\n"), repo)
+}
+
+/// Returns the ref HEAD points at e.g. refs/heads/master, the ref may not
+/// exist yet if nothing has been committed to it
+pub fn head_symref(repo: &obj::Repo) -> Result<Option<String>, err::Error> {
+    match read_head(repo)? {
+        obj::GitHead::Symbolic(target) => Ok(Some(target)),
+        obj::GitHead::Detached(_) => Ok(None),
+    }
+}
+
+/// Reads HEAD which is either symbolic or detached at a commit
+pub fn read_head(repo: &obj::Repo) -> Result<obj::GitHead, err::Error> {
+    obj::parse_git_head(&read(repo.git_path("HEAD"))?)
+}
+
+/// Removes a loose ref file along with any dirs left empty by it, the top
+/// level dirs like refs/heads are always kept
+pub fn remove_loose_ref(ref_path: &Path, repo: &obj::Repo) -> Result<(), err::Error> {
+    remove_file(ref_path)?;
+    let refs_dir = repo.git_path("refs");
+    for dir in ref_path.ancestors().skip(1) {
+        let top_level = dir.parent() == Some(refs_dir.as_path());
+        if !dir.starts_with(&refs_dir) || top_level || read_dir(dir)?.next().is_some() {
+            break;
+        }
+        remove_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Deletes the ref whether it's a loose ref, in packed-refs or both
+pub fn delete_ref(name: &str, repo: &obj::Repo) -> Result<(), err::Error> {
+    let ref_path = repo.git_path(name);
+    let loose = ref_path.is_file();
+
+    let mut lock = PackedRefsLock::acquire(repo)?;
+    let was_packed = lock.remove(name);
+
+    if !loose && !was_packed {
+        return Err(err::Error::GitRefNotFound(name.to_owned()));
+    }
+    if was_packed {
+        lock.commit(repo)?;
+    }
+    if loose {
+        remove_loose_ref(&ref_path, repo)?;
+    }
+    Ok(())
+}
+
+/// Moves loose refs into packed-refs and removes the loose files. Without
+/// all only tags and refs that are already packed are moved, symbolic refs
+/// are never packed.
+pub fn pack_refs(all: bool, repo: &obj::Repo) -> Result<(), err::Error> {
+    let mut lock = PackedRefsLock::acquire(repo)?;
+    let mut packed: BTreeMap<String, PackedRef> = std::mem::take(&mut lock.packed)
+        .into_iter()
+        .map(|p| (p.name.clone(), p))
+        .collect();
 
-    let mut all_refs: Vec<String> = Vec::new();
-    let refs_dir = read_dir(refs_dir_path)?;
+    let mut loose = BTreeMap::new();
+    gather_loose_refs(&repo.git_path("refs"), repo, &mut loose)?;
+
+    let mut moved = Vec::new();
+    for (name, sha) in loose {
+        let ref_path = repo.git_path(&name);
+        if read_to_string(&ref_path)?.starts_with("ref: ") {
+            continue;
+        }
+        if all || name.starts_with("refs/tags/") || packed.contains_key(&name) {
+            // the header promises every annotated tag has a peeled line
+            let peeled = match packed.get(&name).filter(|p| p.sha == sha) {
+                Some(p) if p.peeled.is_some() => p.peeled.clone(),
+                _ => peel_tag(&sha, repo)?,
+            };
+            packed.insert(name.clone(), PackedRef { name, sha, peeled });
+            moved.push(ref_path);
+        }
+    }
 
-    for rf in refs_dir {
+    lock.packed = packed.into_values().collect();
+    lock.commit(repo)?;
+    for ref_path in moved {
+        remove_loose_ref(&ref_path, repo)?;
+    }
+    Ok(())
+}
+
+/// Lists the name and sha of every ref under the prefix, e.g. refs/heads/,
+/// sorted by name
+pub fn list_refs(prefix: &str, repo: &obj::Repo) -> Result<Vec<(String, String)>, err::Error> {
+    let prefix_path = repo.git_path(prefix);
+    let mut refs = Vec::new();
+    for line in gather_refs(Some(&prefix_path), repo)? {
+        if let Some((sha, name)) = line.trim_end().split_once(' ') {
+            refs.push((name.to_owned(), sha.to_owned()));
+        }
+    }
+    refs.sort();
+    Ok(refs)
+}
+
+/// The paths, relative to .git, that a short ref name could refer to in
+/// the order git searches them
+fn ref_candidates(name: &str) -> Vec<String> {
+    let mut candidates = Vec::new();
+    // only special refs like ORIG_HEAD live at the top of .git, this keeps
+    // names like 'config' or 'index' from matching files that aren't refs
+    if name.starts_with("refs/") || name.chars().all(|c| c.is_ascii_uppercase() || c == '_') {
+        candidates.push(name.to_owned());
+    }
+    candidates.extend([
+        format!("refs/{name}"),
+        format!("refs/tags/{name}"),
+        format!("refs/heads/{name}"),
+        format!("refs/remotes/{name}"),
+        format!("refs/remotes/{name}/HEAD"),
+    ]);
+    candidates
+}
+
+/// Expands a short ref name like master into the full name of the ref it
+/// refers to, e.g. refs/heads/master, using the same search order as
+/// revisions. Returns None when nothing matches.
+pub fn expand_ref_name(name: &str, repo: &obj::Repo) -> Result<Option<String>, err::Error> {
+    if name == "HEAD" || name == "@" {
+        return Ok(Some("HEAD".to_owned()));
+    }
+    for candidate in ref_candidates(name) {
+        if read_ref(&candidate, repo)?.is_some() {
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(None)
+}
+
+fn resolve_rev_base(rev: &str, repo: &obj::Repo) -> Result<String, err::Error> {
+    if let Some((name, selector)) = rev.strip_suffix('}').and_then(|r| r.split_once("@{")) {
+        let full_name = if name.is_empty() {
+            // '@{n}' on its own refers to the reflog of the current branch
+            head_symref(repo)?.unwrap_or_else(|| "HEAD".to_owned())
+        } else {
+            expand_ref_name(name, repo)?
+                .ok_or_else(|| err::Error::GitBadRevision(rev.to_owned()))?
+        };
+        return reflog::resolve_selector(&full_name, selector, repo);
+    }
+    if rev == "HEAD" || rev == "@" {
+        return utils::git_sha_from_head(repo);
+    }
+    for candidate in ref_candidates(rev) {
+        if let Some(sha) = read_ref(&candidate, repo)? {
+            return Ok(sha);
+        }
+    }
+    utils::git_expand_abbrev_sha(rev, repo).map_err(|e| match e {
+        err::Error::GitAmbiguousRevision(_) => e,
+        _ => err::Error::GitBadRevision(rev.to_owned()),
+    })
+}
+
+fn nth_parent(sha: &str, n: usize, rev: &str, repo: &obj::Repo) -> Result<String, err::Error> {
+    match obj::read_object(sha, repo)? {
+        obj::GitObj::Commit(commit) => commit
+            .parents
+            .get(n - 1)
+            .cloned()
+            .ok_or_else(|| err::Error::GitBadRevision(rev.to_owned())),
+        _ => Err(err::Error::GitBadRevision(rev.to_owned())),
+    }
+}
+
+/// Peels the object to the given type, tags are followed to the object
+/// they point at and commits can be peeled to their tree. An empty type
+/// follows tags until something that isn't a tag is reached.
+pub fn peel(sha: &str, obj_type: &str, repo: &obj::Repo) -> Result<String, err::Error> {
+    match (obj::read_object(sha, repo)?, obj_type) {
+        (_, "") => Ok(peel_tag(sha, repo)?.unwrap_or_else(|| sha.to_owned())),
+        (obj::GitObj::Commit(_), "commit")
+        | (obj::GitObj::Tree(_), "tree")
+        | (obj::GitObj::Tag(_), "tag") => Ok(sha.to_owned()),
+        (obj::GitObj::Tag(tag), _) => peel(&tag.object, obj_type, repo),
+        (obj::GitObj::Commit(commit), "tree") => Ok(commit.tree),
+        _ => Err(err::Error::GitNotAValidObject(
+            sha.to_owned(),
+            obj_type.to_owned(),
+        )),
+    }
+}
+
+/// For an annotated tag, the sha of the object it points at after every
+/// tag in the chain is followed. None for anything that isn't a tag.
+pub fn peel_tag(sha: &str, repo: &obj::Repo) -> Result<Option<String>, err::Error> {
+    let mut peeled = None;
+    while let obj::GitObj::Tag(tag) = obj::read_object(peeled.as_deref().unwrap_or(sha), repo)? {
+        peeled = Some(tag.object);
+    }
+    Ok(peeled)
+}
+
+/// Resolves a revision to the sha of the object it names. Revisions can be
+/// HEAD, a ref name, a full or abbreviated sha or a reflog entry like
+/// 'master@{2}' or '@{yesterday}', followed by any number of '~<n>' (nth
+/// first parent), '^<n>' (nth parent) or '^{<type>}' suffixes.
+pub fn rev_parse(rev: &str, repo: &obj::Repo) -> Result<String, err::Error> {
+    let split = rev.find(['~', '^']).unwrap_or(rev.len());
+    let (base, mut suffix) = rev.split_at(split);
+    let mut sha = resolve_rev_base(base, repo)?;
+
+    while !suffix.is_empty() {
+        if let Some(rest) = suffix.strip_prefix("^{") {
+            let end = rest
+                .find('}')
+                .ok_or_else(|| err::Error::GitBadRevision(rev.to_owned()))?;
+            sha = peel(&sha, &rest[..end], repo)?;
+            suffix = &rest[end + 1..];
+            continue;
+        }
+
+        let op = match suffix.chars().next() {
+            Some(op @ ('~' | '^')) => op,
+            _ => return Err(err::Error::GitBadRevision(rev.to_owned())),
+        };
+        suffix = &suffix[1..];
+        let digits = suffix.chars().take_while(|c| c.is_ascii_digit()).count();
+        let n = if digits == 0 {
+            1
+        } else {
+            suffix[..digits].parse::<usize>()?
+        };
+        suffix = &suffix[digits..];
+
+        match (op, n) {
+            ('~', _) => {
+                for _ in 0..n {
+                    sha = nth_parent(&sha, 1, rev, repo)?;
+                }
+            }
+            ('^', 0) => sha = peel(&sha, "commit", repo)?,
+            _ => sha = nth_parent(&sha, n, rev, repo)?,
+        }
+    }
+    Ok(sha)
+}
+
+// lock files of refs being written aren't refs, and neither are symbolic
+// refs pointing at refs that don't exist or at a loop
+fn gather_loose_refs(
+    refs_dir_path: &Path,
+    repo: &obj::Repo,
+    all_refs: &mut BTreeMap<String, String>,
+) -> Result<(), err::Error> {
+    for rf in read_dir(refs_dir_path)? {
         let rfs_path = &rf?.path();
         let ref_md = metadata(rfs_path)?;
 
         if ref_md.is_dir() {
-            let mut nested_refs = gather_refs(Some(rfs_path), repo)?;
-            all_refs.append(&mut nested_refs);
+            gather_loose_refs(rfs_path, repo, all_refs)?;
         } else {
             // resolve_ref expects paths relative to .git/
-            let clean_rf_path = rfs_path.strip_prefix(&repo.gitdir)?.to_owned();
-            let resolved_ref = resolve_ref(&clean_rf_path, repo)?;
-            if let Some(clean_path) = clean_rf_path.to_str() {
-                all_refs.push(format!("{resolved_ref} {clean_path}\n"));
-            } else {
+            let clean_rf_path = rfs_path.strip_prefix(&repo.commondir)?;
+            let Some(clean_path) = clean_rf_path.to_str() else {
                 return Err(err::Error::PathToUtf8Conversion);
             };
+            if clean_path.ends_with(".lock") {
+                continue;
+            }
+            match resolve_ref(clean_rf_path, repo) {
+                Ok(sha) => {
+                    all_refs.insert(clean_path.to_owned(), sha);
+                }
+                Err(err::Error::GitRefNotFound(_) | err::Error::GitSymbolicRefTooDeep(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns a '<sha> <name>' line for every ref under the dir, defaulting
+/// to .git/refs, sorted by name. Refs in packed-refs are included unless a
+/// loose ref with the same name exists.
+pub fn gather_refs(path: Option<&Path>, repo: &obj::Repo) -> Result<Vec<String>, err::Error> {
+    let refs_dir_path = if let Some(p) = path {
+        p.to_path_buf()
+    } else {
+        repo.git_path("refs/")
+    };
+
+    let mut all_refs: BTreeMap<String, String> = BTreeMap::new();
+    if refs_dir_path.is_dir() {
+        gather_loose_refs(&refs_dir_path, repo, &mut all_refs)?;
+    }
+
+    let prefix = refs_dir_path.strip_prefix(&repo.commondir)?;
+    let Some(prefix) = prefix.to_str() else {
+        return Err(err::Error::PathToUtf8Conversion);
+    };
+    let prefix = format!("{}/", prefix.trim_end_matches('/'));
+    for packed in read_packed_refs(repo)? {
+        if packed.name.starts_with(&prefix) {
+            all_refs.entry(packed.name).or_insert(packed.sha);
         }
     }
-    Ok(all_refs)
+
+    Ok(all_refs
+        .into_iter()
+        .map(|(name, sha)| format!("{sha} {name}\n"))
+        .collect())
 }
 
 #[cfg(test)]
@@ -51,6 +583,41 @@ mod refs_tests {
     use std::fs::File;
     use std::io::Write;
 
+    #[test]
+    fn check_ref_format_follows_git_rules() {
+        let opts = RefFormatOptions::default();
+        for name in ["refs/heads/main", "heads/feature/a-b", "refs/tags/v1.0"] {
+            assert_eq!(Ok(name.to_owned()), check_ref_format(name, opts));
+        }
+        let bad = [
+            "main", "refs/heads/.hidden", "refs/heads/a.lock", "refs/heads/a..b",
+            "refs/heads/a b", "refs/heads/a~1", "refs/heads/a:b", "refs/heads/a\\b",
+            "refs/heads/a@{1}", "refs/heads/", "/refs/heads/a", "refs//heads", "refs/heads/a.",
+            "refs/heads/*", "@",
+        ];
+        for name in bad {
+            assert_eq!(
+                Err(err::Error::GitInvalidRefName(name.to_owned())),
+                check_ref_format(name, opts),
+                "{name}"
+            );
+        }
+
+        let relaxed = RefFormatOptions {
+            allow_onelevel: true,
+            refspec_pattern: true,
+            normalize: true,
+        };
+        assert_eq!(Ok("main".to_owned()), check_ref_format("main", relaxed));
+        assert_eq!(Ok("refs/heads/*".to_owned()), check_ref_format("refs/heads/*", relaxed));
+        assert_eq!(Ok("refs/heads/a".to_owned()), check_ref_format("//refs//heads/a", relaxed));
+        assert!(check_ref_format("refs/*/*", relaxed).is_err());
+
+        assert_eq!(Ok(()), check_ref_name("ORIG_HEAD"));
+        assert!(check_ref_name("config").is_err());
+        assert!(check_ref_name("refs/../config").is_err());
+    }
+
     #[test]
     fn resolve_ref_follows_indirect_refs_until_direct_ref() {
         let gitdir = test_utils::test_gitdir().unwrap();
@@ -68,4 +635,247 @@ mod refs_tests {
 
         assert_eq!(direct_ref, resolved_ref);
     }
+
+    #[test]
+    fn rev_parse_resolves_names_abbreviations_and_suffixes() {
+        use crate::cmds::committree;
+        use crate::cmds::writetree;
+        use crate::index as idx;
+
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+
+        let tree_sha = writetree::write_tree(&idx::Index::empty(), None, &repo).unwrap();
+        let first = committree::commit_tree(&tree_sha, &[], "first\n", &repo).unwrap();
+        let parents = [first.clone()];
+        let second = committree::commit_tree(&tree_sha, &parents, "second\n", &repo).unwrap();
+        test_utils::test_add_dummy_commit_and_update_ref_heads(&second, &repo).unwrap();
+
+        assert_eq!(second, rev_parse("HEAD", &repo).unwrap());
+        assert_eq!(second, rev_parse("master", &repo).unwrap());
+        assert_eq!(second, rev_parse("refs/heads/master", &repo).unwrap());
+        assert_eq!(second, rev_parse(&second[..7], &repo).unwrap());
+        assert_eq!(first, rev_parse("HEAD~1", &repo).unwrap());
+        assert_eq!(first, rev_parse("master^", &repo).unwrap());
+        assert_eq!(tree_sha, rev_parse("HEAD~^{tree}", &repo).unwrap());
+        assert_eq!(
+            Err(err::Error::GitBadRevision("HEAD~2".to_owned())),
+            rev_parse("HEAD~2", &repo)
+        );
+        assert_eq!(
+            Err(err::Error::GitBadRevision("nope".to_owned())),
+            rev_parse("nope", &repo)
+        );
+    }
+
+    #[test]
+    fn rev_parse_rejects_unknown_suffixes() {
+        use crate::cmds::committree;
+        use crate::cmds::writetree;
+        use crate::index as idx;
+
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let tree_sha = writetree::write_tree(&idx::Index::empty(), None, &repo).unwrap();
+        let commit = committree::commit_tree(&tree_sha, &[], "first\n", &repo).unwrap();
+        test_utils::test_add_dummy_commit_and_update_ref_heads(&commit, &repo).unwrap();
+
+        for rev in ["HEAD^{commit}é", "HEAD~x", "HEAD^{tree}!"] {
+            assert_eq!(
+                Err(err::Error::GitBadRevision(rev.to_owned())),
+                rev_parse(rev, &repo)
+            );
+        }
+    }
+
+    #[test]
+    fn packed_refs_are_merged_with_loose_refs() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let (a, b, c) = ("a".repeat(40), "b".repeat(40), "c".repeat(40));
+
+        std::fs::write(
+            repo.git_path("packed-refs"),
+            format!(
+                "{PACKED_REFS_HEADER}{a} refs/heads/master\n{a} refs/heads/old\n\
+                 {b} refs/tags/v1\n^{a}\n"
+            ),
+        )
+        .unwrap();
+        write_ref("refs/heads/master", &c, &repo).unwrap();
+
+        // the loose master wins over the packed one
+        assert_eq!(Some(c.clone()), read_ref("refs/heads/master", &repo).unwrap());
+        assert_eq!(c, utils::git_sha_from_head(&repo).unwrap());
+        assert_eq!(a, rev_parse("old", &repo).unwrap());
+        assert_eq!(
+            vec![
+                format!("{c} refs/heads/master\n"),
+                format!("{a} refs/heads/old\n"),
+                format!("{b} refs/tags/v1\n"),
+            ],
+            gather_refs(None, &repo).unwrap()
+        );
+
+        // deleting a packed only ref rewrites packed-refs keeping the rest
+        delete_ref("refs/heads/old", &repo).unwrap();
+        assert_eq!(None, read_ref("refs/heads/old", &repo).unwrap());
+        let tag = find_packed_ref("refs/tags/v1", &repo).unwrap().unwrap();
+        assert_eq!(Some(a.clone()), tag.peeled);
+        assert_eq!(
+            Err(err::Error::GitRefNotFound("refs/heads/old".to_owned())),
+            delete_ref("refs/heads/old", &repo)
+        );
+
+        // deleting master removes both the loose and the stale packed copy
+        delete_ref("refs/heads/master", &repo).unwrap();
+        assert_eq!(None, read_ref("refs/heads/master", &repo).unwrap());
+    }
+
+    #[test]
+    fn pack_refs_moves_loose_refs_and_skips_symrefs() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        // packing reads the objects to find annotated tags
+        let blob = |s: &str| obj::GitObj::Blob(obj::blob::Blob::new(s.as_bytes()));
+        let a = obj::write_object(blob("a"), Some(&repo)).unwrap().to_string();
+        let b = obj::write_object(blob("b"), Some(&repo)).unwrap().to_string();
+        write_ref("refs/heads/master", &a, &repo).unwrap();
+        write_ref("refs/tags/v1", &b, &repo).unwrap();
+        write_ref("refs/remotes/origin/main", &a, &repo).unwrap();
+        write_symref("refs/remotes/origin/HEAD", "refs/remotes/origin/main", &repo).unwrap();
+
+        pack_refs(false, &repo).unwrap();
+        assert!(!repo.git_path("refs/tags/v1").exists());
+        assert!(repo.git_path("refs/heads/master").exists());
+
+        pack_refs(true, &repo).unwrap();
+        assert!(!repo.git_path("refs/heads/master").exists());
+        assert!(!repo.git_path("refs/remotes/origin/main").exists());
+        assert!(repo.git_path("refs/remotes/origin/HEAD").exists());
+        assert_eq!(a, rev_parse("origin", &repo).unwrap());
+        let names: Vec<String> = read_packed_refs(&repo)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+        assert_eq!(
+            vec!["refs/heads/master", "refs/remotes/origin/main", "refs/tags/v1"],
+            names
+        );
+
+        // another writer's lock is left alone and packed-refs is unchanged
+        std::fs::write(repo.git_path("packed-refs.lock"), "").unwrap();
+        assert_eq!(
+            Err(err::Error::GitRefLockFailed(
+                "packed-refs".to_owned(),
+                "the lock file already exists".to_owned()
+            )),
+            delete_ref("refs/tags/v1", &repo)
+        );
+        assert!(repo.git_path("packed-refs.lock").exists());
+        assert_eq!(Some(b), read_ref("refs/tags/v1", &repo).unwrap());
+    }
+
+    #[test]
+    fn pack_refs_writes_peeled_lines_for_annotated_tags() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let blob = obj::GitObj::Blob(obj::blob::Blob::new(b"a"));
+        let a = obj::write_object(blob, Some(&repo)).unwrap().to_string();
+        let tag = obj::GitObj::Tag(Box::new(obj::tag::Tag {
+            object: a.clone(),
+            obj_type: "blob".to_owned(),
+            tag: "v1".to_owned(),
+            tagger: Some(obj::commit::create_dummy_user()),
+            msg: "release one\n".to_owned(),
+        }));
+        let tag_sha = obj::write_object(tag, Some(&repo)).unwrap().to_string();
+        write_ref("refs/tags/v1", &tag_sha, &repo).unwrap();
+        write_ref("refs/tags/v2", &a, &repo).unwrap();
+
+        pack_refs(false, &repo).unwrap();
+        let packed = read_packed_refs(&repo).unwrap();
+        assert_eq!(Some(a), packed[0].peeled);
+        assert_eq!(None, packed[1].peeled);
+        let contents = std::fs::read_to_string(repo.git_path("packed-refs")).unwrap();
+        assert!(contents.starts_with(PACKED_REFS_HEADER));
+    }
+
+    #[test]
+    fn empty_peel_follows_tags_to_the_object_they_point_at() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let blob = obj::GitObj::Blob(obj::blob::Blob::new(b"a"));
+        let a = obj::write_object(blob, Some(&repo)).unwrap().to_string();
+        let mut tag_sha = a.clone();
+        // a tag of a tag is followed all the way down
+        for (obj_type, name) in [("blob", "inner"), ("tag", "v1")] {
+            let tag = obj::GitObj::Tag(Box::new(obj::tag::Tag {
+                object: tag_sha,
+                obj_type: obj_type.to_owned(),
+                tag: name.to_owned(),
+                tagger: Some(obj::commit::create_dummy_user()),
+                msg: "release\n".to_owned(),
+            }));
+            tag_sha = obj::write_object(tag, Some(&repo)).unwrap().to_string();
+        }
+        write_ref("refs/tags/v1", &tag_sha, &repo).unwrap();
+
+        assert_eq!(a, rev_parse("v1^{}", &repo).unwrap());
+        assert_eq!(tag_sha, rev_parse("v1^{tag}", &repo).unwrap());
+        assert_eq!(a, rev_parse(&format!("{a}^{{}}"), &repo).unwrap());
+    }
+
+    #[test]
+    fn lock_files_and_dangling_symrefs_are_skipped() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let blob = obj::GitObj::Blob(obj::blob::Blob::new(b"a"));
+        let a = obj::write_object(blob, Some(&repo)).unwrap().to_string();
+        write_ref("refs/heads/master", &a, &repo).unwrap();
+        write(repo.git_path("refs/heads/x.lock"), "").unwrap();
+        write_symref("refs/heads/dangling", "refs/heads/nope", &repo).unwrap();
+        write_symref("refs/heads/loop", "refs/heads/loop", &repo).unwrap();
+
+        assert_eq!(
+            vec![format!("{a} refs/heads/master\n")],
+            gather_refs(None, &repo).unwrap()
+        );
+        pack_refs(true, &repo).unwrap();
+        let names: Vec<String> = read_packed_refs(&repo)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+        assert_eq!(vec!["refs/heads/master"], names);
+        assert!(repo.git_path("refs/heads/x.lock").exists());
+    }
+
+    #[test]
+    fn symrefs_are_written_through_their_lock_file() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let lock = repo.git_path("HEAD.lock");
+        write(&lock, "").unwrap();
+        assert_eq!(
+            Err(err::Error::GitRefLockFailed(
+                "HEAD".to_owned(),
+                "the lock file already exists".to_owned()
+            )),
+            write_symref("HEAD", "refs/heads/side", &repo)
+        );
+        assert_eq!(
+            Some("refs/heads/master".to_owned()),
+            head_symref(&repo).unwrap()
+        );
+
+        remove_file(&lock).unwrap();
+        write_symref("HEAD", "refs/heads/side", &repo).unwrap();
+        assert_eq!(
+            Some("refs/heads/side".to_owned()),
+            head_symref(&repo).unwrap()
+        );
+        assert!(!lock.exists());
+    }
 }
@@ -0,0 +1,215 @@
+use std::fmt::Write as _;
+use std::fs::symlink_metadata;
+
+use crate::attributes as attr;
+use crate::cmds::{add, branch, checkout, readtree, refs, restore, sparsecheckout};
+use crate::error as err;
+use crate::index as idx;
+use crate::objects as obj;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ResetMode {
+    /// only move the branch
+    Soft,
+    /// move the branch and reset the index
+    #[default]
+    Mixed,
+    /// move the branch and reset the index and worktree
+    Hard,
+}
+
+fn on_disk(path: &str, repo: &obj::Repo) -> bool {
+    symlink_metadata(repo.worktree.join(path)).is_ok()
+}
+
+// the tracked files in the worktree which differ from the index
+fn unstaged_changes(index: &idx::Index, repo: &obj::Repo) -> Result<String, err::Error> {
+    let attrs = attr::AttrRules::new(repo)?;
+    let mut output = String::new();
+    for entry in index.entries.iter().filter(|e| !e.skip_worktree()) {
+        if !on_disk(&entry.name, repo) {
+            writeln!(output, "D\t{}", entry.name)?;
+        } else if !checkout::worktree_file_matches_entry(entry, &attrs, repo)? {
+            writeln!(output, "M\t{}", entry.name)?;
+        }
+    }
+    Ok(output)
+}
+
+/// Moves the current branch, or HEAD when detached, to the rev. The mixed
+/// and hard modes also reset the index to the rev's tree and the hard mode
+/// makes the tracked files in the worktree match it too.
+pub fn reset(rev: Option<&str>, mode: ResetMode, repo: &obj::Repo) -> Result<String, err::Error> {
+    let rev = rev.unwrap_or("HEAD");
+    let sha = refs::peel(&refs::rev_parse(rev, repo)?, "commit", repo)?;
+
+    let mut output = String::new();
+    if mode != ResetMode::Soft {
+        let current = idx::read_index_or_empty(repo)?;
+        let target = readtree::tree_entries(&refs::peel(&sha, "tree", repo)?, None, repo)?;
+        let sparse = sparsecheckout::read_sparse_checkout(repo)?;
+        let attrs = attr::AttrRules::new(repo)?;
+
+        if mode == ResetMode::Hard {
+            // tracked files that aren't in the target are removed, untracked
+            // files are left alone. This happens before anything is written
+            // so a file can take the place of a dir that's going away.
+            for entry in &current.entries {
+                if !target.contains_key(&entry.name)
+                    && !entry.skip_worktree()
+                    && on_disk(&entry.name, repo)
+                {
+                    checkout::remove_worktree_file(&entry.name, repo)?;
+                }
+            }
+        }
+
+        let mut index = idx::Index::empty();
+        for (path, entry) in &target {
+            let entry = if sparse.as_ref().is_some_and(|s| !s.includes(path)) {
+                let mut entry = entry.clone();
+                entry.set_skip_worktree(true);
+                entry
+            } else if mode == ResetMode::Hard {
+                checkout::checkout_entry(entry, &attrs, repo)?
+            } else {
+                restore::with_worktree_stat(entry, &attrs, repo)?
+            };
+            index.add_entry(entry);
+        }
+
+        if mode == ResetMode::Hard {
+            let subject = branch::commit_subject(&sha, repo)?;
+            output = format!("HEAD is now at {} {subject}\n", &sha[..7]);
+        } else {
+            let changes = unstaged_changes(&index, repo)?;
+            if !changes.is_empty() {
+                output = format!("Unstaged changes after reset:\n{changes}");
+            }
+        }
+        add::write_index(index, repo)?;
+    }
+
+    refs::update_ref("HEAD", &sha, &format!("reset: moving to {rev}"), repo)?;
+    Ok(output)
+}
+
+/// Resets the index entries matching the paths to the rev's tree without
+/// moving HEAD, which unstages any changes to them
+pub fn reset_paths(
+    rev: Option<&str>,
+    paths: &[String],
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
+    let opts = restore::RestoreOptions {
+        staged: true,
+        source: Some(rev.unwrap_or("HEAD").to_owned()),
+        ..Default::default()
+    };
+    restore::restore(paths, &opts, repo)
+}
+
+#[cfg(test)]
+mod reset_tests {
+    use super::*;
+    use crate::cmds::reflog;
+    use crate::test_utils;
+    use std::fs::{read_to_string, write};
+
+    fn index_names(repo: &obj::Repo) -> Vec<String> {
+        let index = idx::read_index_or_empty(repo).unwrap();
+        index.entries.into_iter().map(|e| e.name).collect()
+    }
+
+    #[test]
+    fn reset_modes_move_the_branch_index_and_worktree() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let base = test_utils::test_commit_files(&[("a", "a\n")], "base\n", &repo).unwrap();
+        let second =
+            test_utils::test_commit_files(&[("a", "second a\n"), ("b", "b\n")], "second\n", &repo)
+                .unwrap();
+
+        assert_eq!("", reset(Some("HEAD~1"), ResetMode::Soft, &repo).unwrap());
+        assert_eq!(
+            Some(base.clone()),
+            refs::read_ref("refs/heads/master", &repo).unwrap()
+        );
+        assert_eq!(vec!["a", "b"], index_names(&repo));
+        assert_eq!(
+            "reset: moving to HEAD~1",
+            reflog::read_reflog("refs/heads/master", &repo)
+                .unwrap()
+                .last()
+                .unwrap()
+                .msg
+        );
+
+        reset(Some(&second), ResetMode::Soft, &repo).unwrap();
+        assert_eq!(
+            "Unstaged changes after reset:\nM\ta\n",
+            reset(Some(&base), ResetMode::Mixed, &repo).unwrap()
+        );
+        assert_eq!(vec!["a"], index_names(&repo));
+        assert_eq!(
+            "second a\n",
+            read_to_string(repo.worktree.join("a")).unwrap()
+        );
+
+        reset(Some(&second), ResetMode::Mixed, &repo).unwrap();
+        write(repo.worktree.join("untracked"), "u\n").unwrap();
+        assert_eq!(
+            format!("HEAD is now at {} base\n", &base[..7]),
+            reset(Some(&base), ResetMode::Hard, &repo).unwrap()
+        );
+        assert_eq!("a\n", read_to_string(repo.worktree.join("a")).unwrap());
+        assert!(!repo.worktree.join("b").exists());
+        assert!(repo.worktree.join("untracked").exists());
+        assert_eq!(
+            "",
+            unstaged_changes(&idx::read_index_or_empty(&repo).unwrap(), &repo).unwrap()
+        );
+    }
+
+    #[test]
+    fn hard_reset_replaces_dirs_with_files_and_back() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let file = test_utils::test_commit_files(&[("a", "file a\n")], "file\n", &repo).unwrap();
+        std::fs::remove_file(repo.worktree.join("a")).unwrap();
+        let mut index = idx::read_index_or_empty(&repo).unwrap();
+        index.remove_entry("a");
+        add::write_index(index, &repo).unwrap();
+        let dir = test_utils::test_commit_files(&[("a/b", "b\n")], "dir\n", &repo).unwrap();
+
+        reset(Some(&file), ResetMode::Hard, &repo).unwrap();
+        assert_eq!("file a\n", read_to_string(repo.worktree.join("a")).unwrap());
+        assert_eq!(vec!["a"], index_names(&repo));
+
+        reset(Some(&dir), ResetMode::Hard, &repo).unwrap();
+        assert_eq!("b\n", read_to_string(repo.worktree.join("a/b")).unwrap());
+        assert_eq!(vec!["a/b"], index_names(&repo));
+        assert_eq!(
+            Some(dir),
+            refs::read_ref("refs/heads/master", &repo).unwrap()
+        );
+    }
+
+    #[test]
+    fn reset_paths_unstages_without_moving_head() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let base = test_utils::test_commit_files(&[("a", "a\n")], "base\n", &repo).unwrap();
+        test_utils::test_commit_files(&[("a", "staged\n"), ("b", "b\n")], "second\n", &repo)
+            .unwrap();
+        refs::write_ref("refs/heads/master", &base, &repo).unwrap();
+
+        reset_paths(None, &["b".to_owned()], &repo).unwrap();
+        assert_eq!(vec!["a"], index_names(&repo));
+        assert!(repo.worktree.join("b").exists());
+        assert_eq!(
+            Some(base),
+            refs::read_ref("refs/heads/master", &repo).unwrap()
+        );
+    }
+}
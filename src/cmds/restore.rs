@@ -0,0 +1,199 @@
+use std::collections::BTreeSet;
+use std::fs::symlink_metadata;
+
+use crate::attributes as attr;
+use crate::cmds::{add, checkout, readtree, refs, sparsecheckout};
+use crate::error as err;
+use crate::index as idx;
+use crate::objects as obj;
+use crate::pathspec;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RestoreOptions {
+    /// restore the index entries, from HEAD unless a source is given
+    pub staged: bool,
+    /// restore the worktree files, the default when --staged isn't given
+    pub worktree: bool,
+    /// tree-ish to restore from instead of the index or HEAD
+    pub source: Option<String>,
+}
+
+// the entries of the tree the rev points at, a branch without commits yet
+// has an empty tree
+fn source_entries(rev: &str, repo: &obj::Repo) -> Result<readtree::TreeEntries, err::Error> {
+    match refs::rev_parse(rev, repo) {
+        Ok(sha) => readtree::tree_entries(&refs::peel(&sha, "tree", repo)?, None, repo),
+        Err(err::Error::GitNoCommitsExistYet) => Ok(readtree::TreeEntries::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Keeps the stat info of the worktree file when it already has the contents
+/// of the entry so status doesn't report the file as modified
+pub fn with_worktree_stat(
+    entry: &idx::IndexEntry,
+    attrs: &attr::AttrRules,
+    repo: &obj::Repo,
+) -> Result<idx::IndexEntry, err::Error> {
+    let on_disk = symlink_metadata(repo.worktree.join(&entry.name)).is_ok_and(|md| !md.is_dir());
+    if !on_disk || !checkout::worktree_file_matches_entry(entry, attrs, repo)? {
+        return Ok(entry.clone());
+    }
+    let index_sha = hex::encode(&entry.sha);
+    let mut refreshed =
+        add::file_to_index_entry(&entry.name, attrs, Some(&index_sha), repo, false)?;
+    if refreshed.mode != entry.mode {
+        return Ok(entry.clone());
+    }
+    refreshed.flags = entry.flags;
+    refreshed.extended_flags = entry.extended_flags;
+    Ok(refreshed)
+}
+
+/// Restores the paths matching the pathspecs in the worktree from the index
+/// and/or in the index from HEAD, or from the source tree when one is given.
+/// Tracked paths missing from the source are removed.
+pub fn restore(
+    pathspecs: &[String],
+    opts: &RestoreOptions,
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
+    if pathspecs.is_empty() {
+        return Err(err::Error::GitRestoreNoPathspec);
+    }
+    let pathspec = pathspec::Pathspec::new(pathspecs, repo)?;
+    let worktree = opts.worktree || !opts.staged;
+    let mut index = idx::read_index_or_empty(repo)?;
+
+    let source = match (&opts.source, opts.staged) {
+        (Some(rev), _) => source_entries(rev, repo)?,
+        (None, true) => source_entries("HEAD", repo)?,
+        (None, false) => {
+            let mut entries = readtree::TreeEntries::new();
+            for entry in index.entries.iter().filter(|e| pathspec.matches(&e.name)) {
+                if entry.stage() != 0 {
+                    return Err(err::Error::GitIndexUnmerged(entry.name.clone()));
+                }
+                entries.insert(entry.name.clone(), entry.clone());
+            }
+            entries
+        }
+    };
+
+    let known = index.entries.iter().map(|e| &e.name).chain(source.keys());
+    let unmatched = pathspec.unmatched(known.clone().map(|p| p.as_str()));
+    if let Some(spec) = unmatched.first() {
+        return Err(err::Error::GitPathspecNoMatch(spec.to_owned()));
+    }
+    let paths: BTreeSet<String> = known.filter(|p| pathspec.matches(p)).cloned().collect();
+
+    let sparse = sparsecheckout::read_sparse_checkout(repo)?;
+    let attrs = attr::AttrRules::new(repo)?;
+    for path in &paths {
+        let entry = source.get(path);
+        if opts.staged {
+            index.remove_entry(path);
+            if let Some(entry) = entry {
+                index.add_entry(with_worktree_stat(entry, &attrs, repo)?);
+            }
+        }
+        if !worktree || sparse.as_ref().is_some_and(|s| !s.includes(path)) {
+            continue;
+        }
+        match entry {
+            Some(entry) => {
+                let restored = checkout::checkout_entry(entry, &attrs, repo)?;
+                // the index only gets the new stat info when it has the
+                // same contents as the file that was written
+                if opts.staged || opts.source.is_none() {
+                    index.add_entry(restored);
+                }
+            }
+            None if symlink_metadata(repo.worktree.join(path)).is_ok() => {
+                checkout::remove_worktree_file(path, repo)?
+            }
+            None => {}
+        }
+    }
+    add::write_index(index, repo)
+}
+
+#[cfg(test)]
+mod restore_tests {
+    use super::*;
+    use crate::cmds::status;
+    use crate::test_utils;
+    use std::fs::{read_to_string, write};
+
+    fn index_sha(name: &str, repo: &obj::Repo) -> Vec<u8> {
+        let index = idx::read_index_or_empty(repo).unwrap();
+        index.entries[index.find_entry(name).unwrap()].sha.clone()
+    }
+
+    #[test]
+    fn restore_worktree_and_staged_files() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        test_utils::test_commit_files(&[("a", "a\n"), ("b", "b\n")], "base\n", &repo).unwrap();
+        let committed = index_sha("a", &repo);
+
+        // discard a local edit
+        write(repo.worktree.join("a"), "edit\n").unwrap();
+        let opts = RestoreOptions::default();
+        restore(&["a".to_owned()], &opts, &repo).unwrap();
+        assert_eq!("a\n", read_to_string(repo.worktree.join("a")).unwrap());
+
+        // unstage a change while keeping it in the worktree
+        write(repo.worktree.join("a"), "staged\n").unwrap();
+        write(repo.worktree.join("new"), "new\n").unwrap();
+        let paths = ["a".to_owned(), "new".to_owned()];
+        add::add(&paths, &Default::default(), &repo).unwrap();
+        let opts = RestoreOptions {
+            staged: true,
+            ..Default::default()
+        };
+        restore(&[".".to_owned()], &opts, &repo).unwrap();
+        assert_eq!(committed, index_sha("a", &repo));
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        assert!(index.find_entry("new").is_err());
+        assert_eq!("staged\n", read_to_string(repo.worktree.join("a")).unwrap());
+        assert!(repo.worktree.join("new").exists());
+
+        let opts = RestoreOptions {
+            staged: true,
+            worktree: true,
+            ..Default::default()
+        };
+        restore(&["a".to_owned()], &opts, &repo).unwrap();
+        assert_eq!("a\n", read_to_string(repo.worktree.join("a")).unwrap());
+        assert!(!status::status(&Default::default(), &repo).unwrap().contains("modified"));
+
+        assert_eq!(
+            Err(err::Error::GitPathspecNoMatch("nope".to_owned())),
+            restore(&["nope".to_owned()], &RestoreOptions::default(), &repo)
+        );
+    }
+
+    #[test]
+    fn restore_from_source_only_touches_the_worktree() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        test_utils::test_commit_files(&[("a", "old\n")], "base\n", &repo).unwrap();
+        test_utils::test_commit_files(&[("a", "new\n"), ("b", "b\n")], "second\n", &repo).unwrap();
+        let current = index_sha("a", &repo);
+
+        let opts = RestoreOptions {
+            source: Some("HEAD~1".to_owned()),
+            ..Default::default()
+        };
+        restore(&[".".to_owned()], &opts, &repo).unwrap();
+        assert_eq!("old\n", read_to_string(repo.worktree.join("a")).unwrap());
+        // b isn't in the source so it's removed from the worktree only
+        assert!(!repo.worktree.join("b").exists());
+        assert_eq!(current, index_sha("a", &repo));
+        assert!(idx::read_index_or_empty(&repo)
+            .unwrap()
+            .find_entry("b")
+            .is_ok());
+    }
+}
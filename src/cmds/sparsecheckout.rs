@@ -0,0 +1,370 @@
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::fs::{create_dir_all, read_to_string, File};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::attributes as attr;
+use crate::cmds::{add, checkout};
+use crate::config;
+use crate::error as err;
+use crate::index as idx;
+use crate::objects as obj;
+use crate::pattern;
+use crate::utils;
+use crate::wildmatch;
+
+// includes the files at the top of the worktree and excludes every dir,
+// this is what git writes when sparse checkout is first enabled
+const DEFAULT_PATTERNS: &str = "/*\n!/*/\n";
+
+fn sparse_checkout_path(repo: &obj::Repo) -> PathBuf {
+    repo.git_path("info/sparse-checkout")
+}
+
+/// The patterns from info/sparse-checkout, both cone mode and full
+/// patterns are evaluated the same way
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SparseCheckout {
+    patterns: Vec<pattern::Pattern>,
+}
+
+impl SparseCheckout {
+    /// A path is included when the last pattern matching it is positive,
+    /// if no pattern matches the path its parent dirs are checked from the
+    /// deepest dir up
+    pub fn includes(&self, path: &str) -> bool {
+        if let Some(included) = pattern::last_match(&self.patterns, path, false) {
+            return included;
+        }
+        let mut dir = path;
+        while let Some((parent, _)) = dir.rsplit_once('/') {
+            if let Some(included) = pattern::last_match(&self.patterns, parent, true) {
+                return included;
+            }
+            dir = parent;
+        }
+        false
+    }
+}
+
+/// Returns the sparse checkout patterns, or None when sparse checkout
+/// isn't enabled for the repo
+pub fn read_sparse_checkout(repo: &obj::Repo) -> Result<Option<SparseCheckout>, err::Error> {
+    let enabled = config::get_bool(&repo.gitconf, "core.sparseCheckout")?.unwrap_or(false);
+    let path = sparse_checkout_path(repo);
+    if !enabled || !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(SparseCheckout {
+        patterns: pattern::parse_patterns(&read_to_string(path)?),
+    }))
+}
+
+fn write_patterns(contents: &str, repo: &obj::Repo) -> Result<(), err::Error> {
+    let path = sparse_checkout_path(repo);
+    if let Some(info_dir) = path.parent() {
+        create_dir_all(info_dir)?;
+    }
+    File::create(path)?.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+// cone mode is used unless it's turned off on the command line or in the
+// config, the flag takes priority over the config
+fn cone_mode(cone: Option<bool>, repo: &obj::Repo) -> Result<bool, err::Error> {
+    Ok(match cone {
+        Some(c) => c,
+        None => config::get_bool(&repo.gitconf, "core.sparseCheckoutCone")?.unwrap_or(true),
+    })
+}
+
+fn normalize_cone_dir(dir: &str) -> Result<String, err::Error> {
+    let normalized = dir.trim_matches('/');
+    if normalized.starts_with('!') || wildmatch::has_glob_chars(normalized) {
+        return Err(err::Error::GitSparseCheckoutNotADirectory(dir.to_owned()));
+    }
+    Ok(normalized.to_owned())
+}
+
+/// Builds the cone mode patterns for the dirs. Every file at the top of the
+/// worktree is included, along with the files directly inside each parent
+/// of the dirs and everything below the dirs themselves.
+pub fn cone_patterns(dirs: &BTreeSet<String>) -> String {
+    // a dir inside another listed dir is already included
+    let recursive: BTreeSet<&String> = dirs
+        .iter()
+        .filter(|d| {
+            !dirs
+                .iter()
+                .any(|other| d.starts_with(other.as_str()) && d[other.len()..].starts_with('/'))
+        })
+        .collect();
+
+    let mut parents: BTreeSet<&str> = BTreeSet::new();
+    for dir in &recursive {
+        let mut current = dir.as_str();
+        while let Some((parent, _)) = current.rsplit_once('/') {
+            parents.insert(parent);
+            current = parent;
+        }
+    }
+
+    let mut output = DEFAULT_PATTERNS.to_owned();
+    for parent in parents {
+        output.push_str(&format!("/{parent}/\n!/{parent}/*/\n"));
+    }
+    for dir in recursive {
+        output.push_str(&format!("/{dir}/\n"));
+    }
+    output
+}
+
+/// Returns the dirs that are included recursively by cone mode patterns
+pub fn cone_dirs(patterns: &[pattern::Pattern]) -> BTreeSet<String> {
+    let parents: BTreeSet<&str> = patterns
+        .iter()
+        .filter(|p| p.negated && p.dir_only)
+        .filter_map(|p| p.pattern.strip_suffix("/*"))
+        .collect();
+    patterns
+        .iter()
+        .filter(|p| !p.negated && p.dir_only && p.pattern != "*")
+        .filter(|p| !parents.contains(p.pattern.as_str()))
+        .map(|p| p.pattern.clone())
+        .collect()
+}
+
+/// Makes the worktree and the skip-worktree bits in the index match the
+/// sparse checkout, passing None checks out every file. Files outside the
+/// sparse checkout that have local changes are left in place and returned.
+pub fn update_worktree(
+    index: &mut idx::Index,
+    sparse: Option<&SparseCheckout>,
+    repo: &obj::Repo,
+) -> Result<Vec<String>, err::Error> {
+    let attrs = attr::AttrRules::new(repo)?;
+    let mut left_behind = Vec::new();
+    for entry in index.entries.iter_mut().filter(|e| e.stage() == 0) {
+        let included = sparse.is_none_or(|s| s.includes(&entry.name));
+        if included && entry.skip_worktree() {
+            *entry = checkout::checkout_entry(entry, &attrs, repo)?;
+        } else if !included && !entry.skip_worktree() {
+            if repo.worktree.join(&entry.name).exists() {
+                if !checkout::worktree_file_matches_entry(entry, &attrs, repo)? {
+                    left_behind.push(entry.name.clone());
+                    continue;
+                }
+                checkout::remove_worktree_file(&entry.name, repo)?;
+            }
+            entry.set_skip_worktree(true);
+        }
+    }
+    Ok(left_behind)
+}
+
+// brings the worktree in line with the current sparse checkout settings
+fn reapply_patterns(repo: &obj::Repo) -> Result<Option<String>, err::Error> {
+    if !utils::git_index_exists(repo) {
+        return Ok(None);
+    }
+    let starting_index = idx::read_index_or_empty(repo)?;
+    let mut index = starting_index.clone();
+    let sparse = read_sparse_checkout(repo)?;
+    let left_behind = update_worktree(&mut index, sparse.as_ref(), repo)?;
+    if index != starting_index {
+        add::write_index(index, repo)?;
+    }
+
+    if left_behind.is_empty() {
+        return Ok(None);
+    }
+    let mut output =
+        "warning: The following paths are not up to date and were left despite sparse patterns:\n"
+            .to_owned();
+    for path in left_behind {
+        writeln!(output, "\t{path}")?;
+    }
+    Ok(Some(output))
+}
+
+fn enable(cone: bool, repo: &mut obj::Repo) -> Result<(), err::Error> {
+    config::set(repo, "core.sparseCheckout", "true")?;
+    config::set(
+        repo,
+        "core.sparseCheckoutCone",
+        if cone { "true" } else { "false" },
+    )
+}
+
+/// Turns on sparse checkout, when no patterns exist yet only the files at
+/// the top of the worktree are kept
+pub fn init(cone: Option<bool>, repo: &mut obj::Repo) -> Result<Option<String>, err::Error> {
+    let cone = cone_mode(cone, repo)?;
+    enable(cone, repo)?;
+    if !sparse_checkout_path(repo).exists() {
+        write_patterns(DEFAULT_PATTERNS, repo)?;
+    }
+    reapply_patterns(repo)
+}
+
+/// Replaces the sparse checkout patterns, in cone mode the arguments are
+/// dirs to include and in non cone mode they are gitignore style patterns
+pub fn set(
+    patterns: &[String],
+    cone: Option<bool>,
+    repo: &mut obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    let cone = cone_mode(cone, repo)?;
+    let contents = if cone {
+        let dirs = patterns
+            .iter()
+            .map(|p| normalize_cone_dir(p))
+            .collect::<Result<BTreeSet<String>, err::Error>>()?;
+        cone_patterns(&dirs)
+    } else {
+        patterns.iter().map(|p| format!("{p}\n")).collect()
+    };
+    enable(cone, repo)?;
+    write_patterns(&contents, repo)?;
+    reapply_patterns(repo)
+}
+
+/// Adds patterns, or dirs in cone mode, to the existing sparse checkout
+pub fn add(patterns: &[String], repo: &obj::Repo) -> Result<Option<String>, err::Error> {
+    let Some(sparse) = read_sparse_checkout(repo)? else {
+        return Err(err::Error::GitSparseCheckoutNotEnabled);
+    };
+    let contents = if cone_mode(None, repo)? {
+        let mut dirs = cone_dirs(&sparse.patterns);
+        for p in patterns {
+            dirs.insert(normalize_cone_dir(p)?);
+        }
+        cone_patterns(&dirs)
+    } else {
+        let mut contents = read_to_string(sparse_checkout_path(repo))?;
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        patterns
+            .iter()
+            .for_each(|p| contents.push_str(&format!("{p}\n")));
+        contents
+    };
+    write_patterns(&contents, repo)?;
+    reapply_patterns(repo)
+}
+
+/// Lists the dirs included in cone mode or the raw patterns otherwise
+pub fn list(repo: &obj::Repo) -> Result<Option<String>, err::Error> {
+    let Some(sparse) = read_sparse_checkout(repo)? else {
+        return Err(err::Error::GitSparseCheckoutNotEnabled);
+    };
+    if cone_mode(None, repo)? {
+        Ok(Some(
+            cone_dirs(&sparse.patterns)
+                .into_iter()
+                .map(|d| format!("{d}\n"))
+                .collect(),
+        ))
+    } else {
+        Ok(Some(read_to_string(sparse_checkout_path(repo))?))
+    }
+}
+
+/// Updates the worktree after info/sparse-checkout was edited by hand
+pub fn reapply(repo: &obj::Repo) -> Result<Option<String>, err::Error> {
+    if read_sparse_checkout(repo)?.is_none() {
+        return Err(err::Error::GitSparseCheckoutNotEnabled);
+    }
+    reapply_patterns(repo)
+}
+
+/// Turns off sparse checkout and restores every file to the worktree, the
+/// patterns are kept so sparse checkout can be turned on again later
+pub fn disable(repo: &mut obj::Repo) -> Result<Option<String>, err::Error> {
+    config::set(repo, "core.sparseCheckout", "false")?;
+    reapply_patterns(repo)
+}
+
+#[cfg(test)]
+mod sparse_checkout_tests {
+    use super::*;
+    use crate::test_utils;
+
+    fn write_file(repo: &obj::Repo, name: &str, contents: &str) {
+        let path = repo.worktree.join(name);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write!(File::create(path).unwrap(), "{}", contents).unwrap();
+    }
+
+    fn dirs(list: &[&str]) -> BTreeSet<String> {
+        list.iter().map(|d| d.to_string()).collect()
+    }
+
+    #[test]
+    fn cone_patterns_include_parents_and_recursive_dirs() {
+        let contents = cone_patterns(&dirs(&["a/b/c", "a/b/c/d", "e"]));
+        assert_eq!(
+            "/*\n!/*/\n/a/\n!/a/*/\n/a/b/\n!/a/b/*/\n/a/b/c/\n/e/\n",
+            contents
+        );
+        let sparse = SparseCheckout {
+            patterns: pattern::parse_patterns(&contents),
+        };
+        assert_eq!(dirs(&["a/b/c", "e"]), cone_dirs(&sparse.patterns));
+
+        assert!(sparse.includes("top.txt"));
+        assert!(sparse.includes("a/file.txt"));
+        assert!(sparse.includes("a/b/c/d/deep.txt"));
+        assert!(sparse.includes("e/f/g.txt"));
+        assert!(!sparse.includes("a/x/file.txt"));
+        assert!(!sparse.includes("a/b/x/file.txt"));
+        assert!(!sparse.includes("f/file.txt"));
+    }
+
+    #[test]
+    fn full_patterns_use_gitignore_rules() {
+        let sparse = SparseCheckout {
+            patterns: pattern::parse_patterns("*.md\n/docs/\n!/docs/internal/\n"),
+        };
+        assert!(sparse.includes("README.md"));
+        assert!(sparse.includes("src/notes.md"));
+        assert!(sparse.includes("docs/guide.txt"));
+        assert!(!sparse.includes("docs/internal/secret.txt"));
+        assert!(!sparse.includes("src/main.rs"));
+    }
+
+    #[test]
+    fn set_and_disable_update_worktree_and_skip_worktree_bits() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let mut repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        for name in ["top.txt", "a/in.txt", "b/out.txt", "b/changed.txt"] {
+            write_file(&repo, name, name);
+        }
+        add::add(&[".".to_owned()], &Default::default(), &repo).unwrap();
+        write_file(&repo, "b/changed.txt", "local change");
+
+        let output = set(&["a".to_owned()], None, &mut repo).unwrap();
+        assert!(output.unwrap().contains("\tb/changed.txt\n"));
+        assert!(repo.worktree.join("a/in.txt").exists());
+        assert!(!repo.worktree.join("b/out.txt").exists());
+        assert!(repo.worktree.join("b/changed.txt").exists());
+
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        let skipped: Vec<&str> = index
+            .entries
+            .iter()
+            .filter(|e| e.skip_worktree())
+            .map(|e| e.name.as_str())
+            .collect();
+        assert_eq!(vec!["b/out.txt"], skipped);
+        assert_eq!(Some("a\n".to_owned()), list(&repo).unwrap());
+
+        disable(&mut repo).unwrap();
+        assert!(repo.worktree.join("b/out.txt").exists());
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        assert!(index.entries.iter().all(|e| !e.skip_worktree()));
+        assert_eq!(Err(err::Error::GitSparseCheckoutNotEnabled), list(&repo));
+    }
+}
@@ -1,208 +1,1165 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fmt::Write as _;
+use std::fs::symlink_metadata;
+
 use chrono::{DateTime, Utc};
-use std::collections::HashSet;
-use std::fs::{metadata, read, read_dir};
-use std::path::{Path, PathBuf};
-use std::str::from_utf8;
 
+use crate::attributes as attr;
+use crate::cmds::{add, branch, readtree, refs, sparsecheckout, symbolicref, updateindex};
 use crate::error as err;
+use crate::fsmonitor;
+use crate::ignore;
 use crate::index as idx;
-use crate::objects::{self as obj, tree, NameSha};
+use crate::objects as obj;
+use crate::untracked;
 use crate::utils;
+use crate::walk;
+
+// the minimum similarity for an added and a deleted path to be a rename,
+// git's default for -M
+const RENAME_THRESHOLD: usize = 50;
+
+/// How a path differs between HEAD and the index or the index and the
+/// worktree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileChange {
+    Added(String),
+    Modified(String),
+    Deleted(String),
+    /// the score is how similar the contents are from 0 to 100
+    Renamed {
+        from: String,
+        to: String,
+        score: usize,
+    },
+    /// the path changed between a file, a symlink and a gitlink
+    TypeChanged(String),
+}
+
+impl FileChange {
+    /// The path the change is sorted by, the new path of a rename
+    pub fn path(&self) -> &str {
+        match self {
+            FileChange::Added(path)
+            | FileChange::Modified(path)
+            | FileChange::Deleted(path)
+            | FileChange::TypeChanged(path) => path,
+            FileChange::Renamed { to, .. } => to,
+        }
+    }
+}
+
+impl fmt::Display for FileChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileChange::Added(path) => write!(f, "new file: {path}"),
+            FileChange::Modified(path) => write!(f, "modified: {path}"),
+            FileChange::Deleted(path) => write!(f, "deleted: {path}"),
+            FileChange::Renamed { from, to, .. } => write!(f, "renamed: {from} -> {to}"),
+            FileChange::TypeChanged(path) => write!(f, "typechange: {path}"),
+        }
+    }
+}
+
+/// A path left with conflicting stages in the index by a merge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unmerged {
+    pub path: String,
+    /// the base, our and their entries, None for a stage that is missing
+    pub stages: [Option<idx::IndexEntry>; 3],
+}
+
+impl Unmerged {
+    // git's two letter code and description for which sides are present
+    fn kind(&self) -> (&'static str, &'static str) {
+        match self.stages.each_ref().map(|e| e.is_some()) {
+            [true, false, false] => ("DD", "both deleted"),
+            [false, true, false] => ("AU", "added by us"),
+            [true, true, false] => ("UD", "deleted by them"),
+            [false, false, true] => ("UA", "added by them"),
+            [true, false, true] => ("DU", "deleted by us"),
+            [false, true, true] => ("AA", "both added"),
+            _ => ("UU", "both modified"),
+        }
+    }
+}
+
+impl fmt::Display for Unmerged {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.kind().1, self.path)
+    }
+}
+
+// files, symlinks and gitlinks are different types, the executable bit
+// doesn't change the type
+fn object_type(mode: u32) -> u32 {
+    mode & 0o170000
+}
+
+fn blob_contents(sha: &[u8], repo: &obj::Repo) -> Result<Vec<u8>, err::Error> {
+    match obj::read_object(&hex::encode(sha), repo)? {
+        obj::GitObj::Blob(blob) => Ok(blob.contents),
+        other => Err(err::Error::GitUnexpectedInternalType(format!(
+            "{:?}",
+            other
+        ))),
+    }
+}
+
+/// Scores how much of the contents two files share from 0 to 100, the bytes
+/// of the lines they have in common over the size of the larger file
+pub fn similarity(old: &[u8], new: &[u8]) -> usize {
+    let larger = old.len().max(new.len());
+    if larger == 0 {
+        return 100;
+    }
+    let mut lines: HashMap<&[u8], usize> = HashMap::new();
+    for line in old.split_inclusive(|b| *b == b'\n') {
+        *lines.entry(line).or_default() += 1;
+    }
+    let mut common = 0;
+    for line in new.split_inclusive(|b| *b == b'\n') {
+        if let Some(count) = lines.get_mut(line).filter(|c| **c > 0) {
+            *count -= 1;
+            common += line.len();
+        }
+    }
+    common * 100 / larger
+}
+
+// pairs deleted paths with added ones whose contents are similar enough,
+// the most similar pairs are matched first and each path is used once
+fn detect_renames(
+    changes: Vec<FileChange>,
+    old: &readtree::TreeEntries,
+    new: &readtree::TreeEntries,
+    repo: &obj::Repo,
+) -> Result<Vec<FileChange>, err::Error> {
+    // only files are renamed, gitlinks and symlinks never are
+    let is_file = |e: &idx::IndexEntry| object_type(e.mode) == 0o100000;
+    let deleted: Vec<&idx::IndexEntry> = changes
+        .iter()
+        .filter_map(|c| match c {
+            FileChange::Deleted(path) => old.get(path).filter(|e| is_file(e)),
+            _ => None,
+        })
+        .collect();
+    let added: Vec<&idx::IndexEntry> = changes
+        .iter()
+        .filter_map(|c| match c {
+            FileChange::Added(path) => new.get(path).filter(|e| is_file(e)),
+            _ => None,
+        })
+        .collect();
+    if deleted.is_empty() || added.is_empty() {
+        return Ok(changes);
+    }
+
+    let mut added_contents = Vec::new();
+    for entry in &added {
+        added_contents.push(blob_contents(&entry.sha, repo)?);
+    }
+    let mut candidates = Vec::new();
+    for (d, from) in deleted.iter().enumerate() {
+        let old_contents = blob_contents(&from.sha, repo)?;
+        for (a, to) in added.iter().enumerate() {
+            let score = if from.sha == to.sha {
+                100
+            } else if old_contents.is_empty() || added_contents[a].is_empty() {
+                // empty files are only renamed when nothing else changed
+                0
+            } else {
+                similarity(&old_contents, &added_contents[a])
+            };
+            if score >= RENAME_THRESHOLD {
+                candidates.push((score, d, a));
+            }
+        }
+    }
+    candidates.sort_by(|x, y| y.0.cmp(&x.0).then(x.1.cmp(&y.1)).then(x.2.cmp(&y.2)));
+
+    let mut renamed: HashMap<&str, (&str, usize)> = HashMap::new();
+    let mut used_added = HashSet::new();
+    for (score, d, a) in candidates {
+        let from = deleted[d].name.as_str();
+        if renamed.contains_key(from) || used_added.contains(&a) {
+            continue;
+        }
+        renamed.insert(from, (added[a].name.as_str(), score));
+        used_added.insert(a);
+    }
+    let targets: HashSet<&str> = renamed.values().map(|(to, _)| *to).collect();
 
-fn index_file_sha_pairs<T: obj::NameSha>(
-    input: &[T],
-    name_prefix: Option<String>,
-) -> HashSet<(String, String)> {
-    return input
+    let mut result: Vec<FileChange> = changes
         .iter()
-        .map(|elm| elm.get_name_and_sha(name_prefix.clone()))
+        .filter(|c| match c {
+            FileChange::Deleted(path) => !renamed.contains_key(path.as_str()),
+            FileChange::Added(path) => !targets.contains(path.as_str()),
+            _ => true,
+        })
+        .cloned()
         .collect();
+    result.extend(
+        renamed
+            .iter()
+            .map(|(from, (to, score))| FileChange::Renamed {
+                from: from.to_string(),
+                to: to.to_string(),
+                score: *score,
+            }),
+    );
+    result.sort_by(|x, y| x.path().cmp(y.path()));
+    Ok(result)
+}
+
+// the entries of the tree HEAD points at, empty before the first commit
+fn head_entries(repo: &obj::Repo) -> Result<readtree::TreeEntries, err::Error> {
+    match utils::git_sha_from_head(repo) {
+        Ok(sha) => readtree::tree_entries(&refs::peel(&sha, "tree", repo)?, None, repo),
+        Err(err::Error::GitNoCommitsExistYet) => Ok(readtree::TreeEntries::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn stage_zero_entries(index: &idx::Index) -> readtree::TreeEntries {
+    index
+        .entries
+        .iter()
+        .filter(|e| e.stage() == 0)
+        .map(|e| (e.name.clone(), e.clone()))
+        .collect()
 }
 
-fn tree_file_sha_pairs(
-    tree: tree::Tree,
-    name_prefix: Option<String>,
+// the entries with stages 1 to 3 grouped by path
+fn unmerged_entries(index: &idx::Index) -> Vec<Unmerged> {
+    let mut unmerged: BTreeMap<&str, Unmerged> = BTreeMap::new();
+    for entry in index.entries.iter().filter(|e| e.stage() != 0) {
+        let path = unmerged.entry(&entry.name).or_insert(Unmerged {
+            path: entry.name.clone(),
+            stages: Default::default(),
+        });
+        path.stages[usize::from(entry.stage()) - 1] = Some(entry.clone());
+    }
+    unmerged.into_values().collect()
+}
+
+fn staged_changes_between(
+    head: &readtree::TreeEntries,
+    staged: &readtree::TreeEntries,
+    unmerged: &[Unmerged],
     repo: &obj::Repo,
-) -> Result<HashSet<(String, String)>, err::Error> {
-    let mut file_sha_pairs: HashSet<(String, String)> = HashSet::new();
-    // extra complexity needed to deal with nested git Tree objects
-    for elm in tree.contents.iter() {
-        if PathBuf::from(&elm.path).is_dir() {
-            let obj = obj::read_object(&utils::get_sha_from_binary(&elm.sha), repo)?;
-            match obj {
-                obj::GitObj::Tree(inner_tree) => {
-                    let nested_name_prefix: Option<String>;
-                    if let Some(ref nnp) = name_prefix {
-                        nested_name_prefix = Some(format!("{}/{}", nnp, elm.path));
-                    } else {
-                        nested_name_prefix = Some(elm.path.clone());
-                    }
-                    let inner_tree_file_sha_pairs =
-                        tree_file_sha_pairs(inner_tree, nested_name_prefix, repo)?;
-                    file_sha_pairs.extend(inner_tree_file_sha_pairs);
-                }
-                _ => return Err(err::Error::GitLsTreeWrongObjType(format!("{:?}", obj))),
+) -> Result<Vec<FileChange>, err::Error> {
+    // unmerged paths are reported on their own, not as deleted from HEAD
+    let unmerged: HashSet<&str> = unmerged.iter().map(|u| u.path.as_str()).collect();
+    let mut changes = Vec::new();
+    for (path, entry) in staged {
+        match head.get(path) {
+            None => changes.push(FileChange::Added(path.clone())),
+            Some(old) if object_type(old.mode) != object_type(entry.mode) => {
+                changes.push(FileChange::TypeChanged(path.clone()))
             }
-        } else {
-            file_sha_pairs.insert(elm.get_name_and_sha(name_prefix.clone()));
+            Some(old) if old.sha != entry.sha || old.mode != entry.mode => {
+                changes.push(FileChange::Modified(path.clone()))
+            }
+            Some(_) => {}
         }
     }
-    Ok(file_sha_pairs)
+    let deleted = |p: &&String| !staged.contains_key(*p) && !unmerged.contains(p.as_str());
+    for path in head.keys().filter(deleted) {
+        changes.push(FileChange::Deleted(path.clone()));
+    }
+    changes.sort_by(|x, y| x.path().cmp(y.path()));
+    detect_renames(changes, head, staged, repo)
+}
+
+/// The changes staged in the index compared to HEAD
+pub fn staged_changes(repo: &obj::Repo, index: &idx::Index) -> Result<Vec<FileChange>, err::Error> {
+    staged_changes_between(
+        &head_entries(repo)?,
+        &stage_zero_entries(index),
+        &unmerged_entries(index),
+        repo,
+    )
 }
 
 pub fn staged_but_not_commited(repo: &obj::Repo, index: &idx::Index) -> Result<String, err::Error> {
-    let commit_tree_files_n_shas: HashSet<(String, String)>;
-    let head_sha = utils::git_sha_from_head(repo);
-
-    if let Ok(hsha) = head_sha {
-        // get a set of (name, sha) pairs for each file in the last commit object
-        if let obj::GitObj::Commit(commit) = obj::read_object(&hsha, repo)? {
-            let commit_tree = utils::git_get_tree_from_commit(*commit, repo)?;
-            commit_tree_files_n_shas = tree_file_sha_pairs(commit_tree, None, repo)?;
-        } else {
-            return Err(err::Error::GitUnexpectedInternalType(format!(
-                "{:?}",
-                "Expected a commit object"
-            )));
-        }
-    } else {
-        // This error happens when no commits exist yet and you try to look
-        // them up from HEAD. This can happen when running status before
-        // a commit is added.
-        assert!(head_sha.err() == Some(err::Error::GitNoCommitsExistYet));
-        commit_tree_files_n_shas = HashSet::new();
+    let mut output = String::new();
+    for change in staged_changes(repo, index)? {
+        writeln!(output, "{change}")?;
+    }
+    Ok(output)
+}
+
+/// How status prints its result
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StatusFormat {
+    #[default]
+    Long,
+    /// the two column XY format
+    Short,
+    PorcelainV1,
+    PorcelainV2,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StatusOptions {
+    /// list the ignored files as well
+    pub ignored: bool,
+    pub format: StatusFormat,
+    /// print the branch and its upstream in the short and porcelain formats
+    pub branch: bool,
+    /// end entries with NUL rather than newline and don't quote paths
+    pub null_terminated: bool,
+}
+
+/// The branch HEAD is on and how it compares to its upstream
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BranchStatus {
+    /// the short branch name, None when HEAD is detached
+    pub head: Option<String>,
+    /// the commit HEAD points at, None before the first commit
+    pub oid: Option<String>,
+    /// the short name of the upstream, e.g. origin/master
+    pub upstream: Option<String>,
+    /// commits ahead and behind the upstream, None when the upstream ref
+    /// doesn't exist
+    pub ahead_behind: Option<(usize, usize)>,
+}
+
+/// Everything status reports, sorted by path
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StatusResult {
+    pub branch: BranchStatus,
+    pub staged: Vec<FileChange>,
+    pub unstaged: Vec<FileChange>,
+    pub unmerged: Vec<Unmerged>,
+    pub untracked: Vec<String>,
+    pub ignored: Vec<String>,
+    /// the percent of tracked files present in a sparse checkout
+    pub sparse: Option<usize>,
+    // the entries the changes were found from, porcelain v2 prints their
+    // modes and object names
+    head_entries: readtree::TreeEntries,
+    index_entries: readtree::TreeEntries,
+}
+
+fn branch_status(repo: &obj::Repo) -> Result<BranchStatus, err::Error> {
+    let oid = match utils::git_sha_from_head(repo) {
+        Ok(sha) => Some(sha),
+        Err(err::Error::GitNoCommitsExistYet) => None,
+        Err(e) => return Err(e),
+    };
+    let obj::GitHead::Symbolic(target) = refs::read_head(repo)? else {
+        return Ok(BranchStatus {
+            oid,
+            ..Default::default()
+        });
     };
+    let upstream = branch::upstream_ref(&target, repo)?;
+    let ahead_behind = match (&oid, &upstream) {
+        (Some(sha), Some(upstream)) => match refs::read_ref(upstream, repo)? {
+            Some(other) => Some(branch::ahead_behind(sha, &other, repo)?),
+            None => None,
+        },
+        _ => None,
+    };
+    Ok(BranchStatus {
+        head: Some(symbolicref::shorten_ref(&target).to_owned()),
+        oid,
+        upstream: upstream.map(|u| symbolicref::shorten_ref(&u).to_owned()),
+        ahead_behind,
+    })
+}
 
-    // get set of (name, sha) pairs for each file in the index
-    let index_files_n_shas: HashSet<(String, String)> = index_file_sha_pairs(&index.entries, None);
+// what checking an index entry against its file in the worktree found
+enum EntryState {
+    Unchanged,
+    /// the contents match but the stat info in the index is stale
+    Refreshed(idx::IndexEntry),
+    Changed(FileChange),
+}
 
-    Ok(index_files_n_shas
-        .difference(&commit_tree_files_n_shas)
-        .into_iter()
-        .map(|(name, _)| format!("modified: {name}\n"))
-        .collect::<String>())
+fn entry_state(
+    entry: &idx::IndexEntry,
+    index_mtime: Option<DateTime<Utc>>,
+    attrs: &attr::AttrRules,
+    repo: &obj::Repo,
+) -> Result<EntryState, err::Error> {
+    // entries outside the sparse checkout aren't expected in the
+    // worktree so they are never missing
+    if entry.skip_worktree() || entry.assume_valid() {
+        return Ok(EntryState::Unchanged);
+    }
+    if symlink_metadata(repo.worktree.join(&entry.name)).is_err() {
+        return Ok(EntryState::Changed(FileChange::Deleted(entry.name.clone())));
+    }
+    if object_type(add::stat_index_entry(&entry.name, repo)?.mode) != object_type(entry.mode) {
+        return Ok(EntryState::Changed(FileChange::TypeChanged(entry.name.clone())));
+    }
+    Ok(match updateindex::refreshed_entry(entry, index_mtime, attrs, repo)? {
+        Some(refreshed) if refreshed == *entry => EntryState::Unchanged,
+        Some(refreshed) => EntryState::Refreshed(refreshed),
+        None => EntryState::Changed(FileChange::Modified(entry.name.clone())),
+    })
 }
 
-fn ignored_files(repo: &obj::Repo) -> Result<HashSet<PathBuf>, err::Error> {
-    let gitignore_path = repo.worktree.join(".gitignore");
-    // if no gitignore return empty hashset
-    if !gitignore_path.exists() {
-        return Ok(HashSet::new());
+// asks the fsmonitor daemon what changed since the index was last checked,
+// None when core.fsmonitor is off or the daemon isn't running
+fn fsmonitor_changes(
+    index: &mut idx::Index,
+    repo: &obj::Repo,
+) -> Result<Option<fsmonitor::FsmonitorChanges>, err::Error> {
+    if !fsmonitor::enabled(repo)? {
+        index.fsmonitor = None;
+        return Ok(None);
+    }
+    let token = index.fsmonitor.as_ref().map_or("", |f| f.token.as_str());
+    let changes = fsmonitor::query(token, repo)?;
+    if changes.is_none() {
+        index.fsmonitor = None;
     }
+    Ok(changes)
+}
 
-    let gitignore = read(gitignore_path)?;
+// which entries fsmonitor knows are unchanged since they were last found
+// clean. Nested repos aren't watched so they're always checked.
+fn fsmonitor_unchanged(
+    index: &idx::Index,
+    changes: Option<&fsmonitor::FsmonitorChanges>,
+) -> Vec<bool> {
+    match (&index.fsmonitor, changes) {
+        (Some(data), Some(changes)) if data.dirty.len() == index.entries.len() => index
+            .entries
+            .iter()
+            .zip(&data.dirty)
+            .map(|(e, dirty)| !dirty && e.mode != 0o160000 && !changes.covers(&e.name))
+            .collect(),
+        _ => vec![false; index.entries.len()],
+    }
+}
 
-    let mut output: HashSet<PathBuf> = HashSet::new();
-    for path in from_utf8(&gitignore)?.split('\n') {
-        if path.is_empty() {
-            continue;
-        } else if let Some(p) = path.strip_prefix('/') {
-            output.insert(PathBuf::from(p.to_owned()));
-        } else {
-            output.insert(PathBuf::from(path.to_owned()));
+// the entries are checked across the threads, skipping those known to be
+// unchanged. The stat info of unchanged entries is refreshed in the index
+// so later runs don't hash them again.
+fn unstaged_changes(
+    repo: &obj::Repo,
+    index: &mut idx::Index,
+    unchanged: &[bool],
+    threads: usize,
+) -> Result<Vec<FileChange>, err::Error> {
+    let index_mtime = utils::git_index_mtime(repo);
+    let attrs = attr::AttrRules::new(repo)?;
+    let entries: Vec<(&idx::IndexEntry, bool)> =
+        index.entries.iter().zip(unchanged.iter().copied()).collect();
+    // unmerged entries are reported on their own
+    let states = walk::parallel_map(&entries, threads, |(entry, unchanged)| {
+        match *unchanged || entry.stage() != 0 {
+            true => Ok(EntryState::Unchanged),
+            false => entry_state(entry, index_mtime, &attrs, repo),
+        }
+    })?;
+    let mut changes = Vec::new();
+    for (entry, state) in index.entries.iter_mut().zip(states) {
+        match state {
+            EntryState::Unchanged => {}
+            EntryState::Refreshed(refreshed) => *entry = refreshed,
+            EntryState::Changed(change) => changes.push(change),
         }
     }
-    Ok(output)
+    Ok(changes)
 }
 
-fn gather_mtime_from_worktree(
-    path: Option<&Path>,
-    repo: &obj::Repo,
-) -> Result<HashSet<(String, DateTime<Utc>)>, err::Error> {
-    let work_path = if path == None {
-        repo.worktree.clone()
-    } else {
-        path.unwrap().to_path_buf()
+/// Compares HEAD, the index and the worktree. The refreshed stat info of
+/// unchanged files is saved to the index when it can be written.
+pub fn collect_status(opts: &StatusOptions, repo: &obj::Repo) -> Result<StatusResult, err::Error> {
+    let mut index = idx::read_index_or_empty(repo)?;
+    let head_entries = head_entries(repo)?;
+    let index_entries = stage_zero_entries(&index);
+    let unmerged = unmerged_entries(&index);
+    let staged = staged_changes_between(&head_entries, &index_entries, &unmerged, repo)?;
+
+    let threads = walk::worker_threads(repo)?;
+    let starting_index = index.clone();
+    // fsmonitor is asked before anything is checked so a change made while
+    // status runs is reported next time
+    let changes = fsmonitor_changes(&mut index, repo)?;
+    let unchanged = fsmonitor_unchanged(&starting_index, changes.as_ref());
+    let unstaged = unstaged_changes(repo, &mut index, &unchanged, threads)?;
+    if let Some(changes) = &changes {
+        let dirty: HashSet<&str> = unstaged.iter().map(|c| c.path()).collect();
+        index.fsmonitor = Some(fsmonitor::FsmonitorData {
+            token: changes.token.clone(),
+            dirty: index
+                .entries
+                .iter()
+                .map(|e| dirty.contains(e.name.as_str()))
+                .collect(),
+        });
+    }
+
+    match untracked::configured(repo)? {
+        Some(true) if index.untracked.is_none() => {
+            index.untracked = Some(untracked::UntrackedCache::new(repo))
+        }
+        Some(false) => index.untracked = None,
+        _ => {}
+    }
+    let rules = ignore::IgnoreRules::new(repo)?;
+    let tracked: HashSet<&str> = index.entries.iter().map(|e| e.name.as_str()).collect();
+    let (untracked, ignored) = match index.untracked.as_mut() {
+        // the cache doesn't hold ignored files
+        Some(cache) if !opts.ignored => {
+            // with every change since the cache was saved reported the dirs
+            // that didn't change aren't checked
+            let trusted = match (&starting_index.fsmonitor, &changes) {
+                (Some(_), Some(fsmonitor::FsmonitorChanges { paths: Some(paths), .. })) => {
+                    for path in paths {
+                        cache.invalidate_path(path);
+                    }
+                    true
+                }
+                _ => false,
+            };
+            let index_mtime = utils::git_index_mtime(repo);
+            let files = cache.untracked_files(&rules, &tracked, index_mtime, trusted, repo)?;
+            let files = files.into_iter().filter(|f| !tracked.contains(f.as_str()));
+            (files.collect(), Vec::new())
+        }
+        _ => {
+            let names = walk::walk_worktree(&repo.worktree, Some(&rules), threads, repo)?;
+            let not_tracked = |n: &String| !tracked.contains(n.as_str());
+            let untracked = names.files.into_iter().filter(not_tracked).collect();
+            let ignored = match opts.ignored {
+                true => names.ignored.into_iter().filter(not_tracked).collect(),
+                false => Vec::new(),
+            };
+            (untracked, ignored)
+        }
     };
 
-    let mut file_mtime_pairs: HashSet<(String, DateTime<Utc>)> = HashSet::new();
-    let worktree_dir = read_dir(work_path)?;
-    let ignored_files = ignored_files(repo)?;
+    let sparse = match sparsecheckout::read_sparse_checkout(repo)? {
+        Some(_) => {
+            let present = index.entries.iter().filter(|e| !e.skip_worktree()).count();
+            Some(
+                (present * 100)
+                    .checked_div(index.entries.len())
+                    .unwrap_or(100),
+            )
+        }
+        None => None,
+    };
 
-    for node in worktree_dir {
-        let node_val = node?;
-        let node_path = &node_val.path();
-        let node_name = &node_val.file_name();
+    // like git status itself never fails because the index can't be
+    // written, the refresh is dropped when another process holds the lock
+    if index != starting_index && utils::git_check_for_rusty_git_allowed(repo).is_ok() {
+        let _ = add::write_index(index, repo);
+    }
+    Ok(StatusResult {
+        branch: branch_status(repo)?,
+        staged,
+        unstaged,
+        unmerged,
+        untracked,
+        ignored,
+        sparse,
+        head_entries,
+        index_entries,
+    })
+}
 
-        if node_name == ".git" || ignored_files.contains(node_path.strip_prefix(&repo.worktree)?) {
-            continue;
+fn plural(count: usize) -> &'static str {
+    if count == 1 {
+        "commit"
+    } else {
+        "commits"
+    }
+}
+
+// how the branch compares to its upstream in the long format
+fn tracking_message(branch: &BranchStatus) -> Option<String> {
+    let upstream = branch.upstream.as_ref()?;
+    let message = match branch.ahead_behind {
+        None => format!("Your branch is based on '{upstream}', but the upstream is gone."),
+        Some((0, 0)) => format!("Your branch is up to date with '{upstream}'."),
+        Some((ahead, 0)) => {
+            format!(
+                "Your branch is ahead of '{upstream}' by {ahead} {}.",
+                plural(ahead)
+            )
         }
+        Some((0, behind)) => format!(
+            "Your branch is behind '{upstream}' by {behind} {}, and can be fast-forwarded.",
+            plural(behind)
+        ),
+        Some((ahead, behind)) => format!(
+            "Your branch and '{upstream}' have diverged,\n\
+             and have {ahead} and {behind} different commits each, respectively."
+        ),
+    };
+    Some(message)
+}
 
-        let node_md = metadata(&node_val.path())?;
-        if node_md.is_dir() {
-            let inner_vals = gather_mtime_from_worktree(Some(node_path), repo)?;
-            file_mtime_pairs.extend(inner_vals);
-        } else {
-            let node_mtime = node_md.modified()?;
-            let node_dt: DateTime<Utc> = node_mtime.into();
-            let clean_node_path = node_path.strip_prefix(&repo.worktree)?;
-            if let Some(node_path) = clean_node_path.to_str() {
-                let file_output = (node_path.to_owned(), node_dt);
-                file_mtime_pairs.insert(file_output);
-            } else {
-                return Err(err::Error::PathToUtf8Conversion);
-            };
+fn format_long(result: &StatusResult, opts: &StatusOptions) -> Result<String, err::Error> {
+    let mut status = String::new();
+    match (&result.branch.head, &result.branch.oid) {
+        (Some(branch), _) => writeln!(status, "On branch {branch}\n")?,
+        (None, Some(sha)) => writeln!(status, "HEAD detached at {}\n", &sha[..7])?,
+        (None, None) => {}
+    }
+    if let Some(message) = tracking_message(&result.branch) {
+        writeln!(status, "{message}\n")?;
+    }
+    if let Some(percent) = result.sparse {
+        writeln!(
+            status,
+            "You are in a sparse checkout with {percent}% of tracked files present.\n"
+        )?;
+    }
+    status.push_str("Changes to be committed:\n\n");
+    for change in &result.staged {
+        writeln!(status, "{change}")?;
+    }
+    if !result.unmerged.is_empty() {
+        status.push_str("\nUnmerged paths:\n\n");
+        for unmerged in &result.unmerged {
+            writeln!(status, "{unmerged}")?;
+        }
+    }
+    status.push_str("\nChanges not staged for commit:\n\n");
+    for change in &result.unstaged {
+        writeln!(status, "{change}")?;
+    }
+    status.push_str("\nUntracked files:\n\n");
+    for path in &result.untracked {
+        writeln!(status, "{path}")?;
+    }
+    if opts.ignored {
+        status.push_str("\nIgnored files:\n\n");
+        for path in &result.ignored {
+            writeln!(status, "{path}")?;
         }
     }
-    Ok(file_mtime_pairs)
+    Ok(status)
+}
+
+// git's C style quoting of paths holding quotes, backslashes, control
+// characters or non ASCII bytes, other paths are printed as they are.
+// The short format quotes paths holding spaces too.
+fn quote_path(path: &str, quote_spaces: bool) -> String {
+    let needs_quotes = path.bytes().any(|b| {
+        b == b'"' || b == b'\\' || !(0x20..0x7f).contains(&b) || (quote_spaces && b == b' ')
+    });
+    if !needs_quotes {
+        return path.to_owned();
+    }
+    let mut quoted = String::from("\"");
+    for b in path.bytes() {
+        match b {
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            b'\x07' => quoted.push_str("\\a"),
+            b'\x08' => quoted.push_str("\\b"),
+            b'\t' => quoted.push_str("\\t"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\x0b' => quoted.push_str("\\v"),
+            b'\x0c' => quoted.push_str("\\f"),
+            b'\r' => quoted.push_str("\\r"),
+            b if !(0x20..0x7f).contains(&b) => quoted.push_str(&format!("\\{b:03o}")),
+            b => quoted.push(b as char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn change_code(change: &FileChange) -> char {
+    match change {
+        FileChange::Added(_) => 'A',
+        FileChange::Modified(_) => 'M',
+        FileChange::Deleted(_) => 'D',
+        FileChange::Renamed { .. } => 'R',
+        FileChange::TypeChanged(_) => 'T',
+    }
+}
+
+/// A tracked path with changes, merging its staged and unstaged changes
+struct ChangedPath<'a> {
+    path: &'a str,
+    orig_path: Option<&'a str>,
+    staged: Option<&'a FileChange>,
+    unstaged: Option<&'a FileChange>,
+    unmerged: Option<&'a Unmerged>,
+}
+
+fn changed_paths(result: &StatusResult) -> Vec<ChangedPath<'_>> {
+    let mut paths: BTreeMap<&str, ChangedPath> = BTreeMap::new();
+    for change in &result.staged {
+        let orig_path = match change {
+            FileChange::Renamed { from, .. } => Some(from.as_str()),
+            _ => None,
+        };
+        paths.insert(
+            change.path(),
+            ChangedPath {
+                path: change.path(),
+                orig_path,
+                staged: Some(change),
+                unstaged: None,
+                unmerged: None,
+            },
+        );
+    }
+    for change in &result.unstaged {
+        paths
+            .entry(change.path())
+            .or_insert(ChangedPath {
+                path: change.path(),
+                orig_path: None,
+                staged: None,
+                unstaged: None,
+                unmerged: None,
+            })
+            .unstaged = Some(change);
+    }
+    for unmerged in &result.unmerged {
+        paths.insert(
+            &unmerged.path,
+            ChangedPath {
+                path: &unmerged.path,
+                orig_path: None,
+                staged: None,
+                unstaged: None,
+                unmerged: Some(unmerged),
+            },
+        );
+    }
+    paths.into_values().collect()
 }
 
-struct LocalChanges {
-    not_staged: String,
-    not_tracked: String,
+fn branch_header(branch: &BranchStatus) -> String {
+    let mut header = match (&branch.head, &branch.oid) {
+        (Some(head), None) => format!("## No commits yet on {head}"),
+        (Some(head), Some(_)) => format!("## {head}"),
+        (None, _) => "## HEAD (no branch)".to_owned(),
+    };
+    if let (Some(upstream), Some(_)) = (&branch.upstream, &branch.oid) {
+        header.push_str(&format!("...{upstream}"));
+        match branch.ahead_behind {
+            None => header.push_str(" [gone]"),
+            Some((0, 0)) => {}
+            Some((ahead, 0)) => header.push_str(&format!(" [ahead {ahead}]")),
+            Some((0, behind)) => header.push_str(&format!(" [behind {behind}]")),
+            Some((ahead, behind)) => header.push_str(&format!(" [ahead {ahead}, behind {behind}]")),
+        }
+    }
+    header
 }
 
-fn local_changes_not_staged_for_commit_or_untracked(
+fn format_short(result: &StatusResult, opts: &StatusOptions) -> String {
+    let (end, quote): (&str, fn(&str) -> String) = if opts.null_terminated {
+        ("\0", str::to_owned)
+    } else {
+        ("\n", |p| quote_path(p, true))
+    };
+    let mut status = String::new();
+    if opts.branch {
+        status.push_str(&branch_header(&result.branch));
+        status.push_str(end);
+    }
+    for changed in changed_paths(result) {
+        let x = changed.staged.map_or(' ', change_code);
+        let y = changed.unstaged.map_or(' ', change_code);
+        let path = quote(changed.path);
+        if let Some(unmerged) = changed.unmerged {
+            status.push_str(&format!("{} {path}{end}", unmerged.kind().0));
+            continue;
+        }
+        match changed.orig_path {
+            // with -z the original path comes after the new one
+            Some(orig) if opts.null_terminated => {
+                status.push_str(&format!("{x}{y} {path}\0{orig}\0"))
+            }
+            Some(orig) => status.push_str(&format!("{x}{y} {} -> {path}\n", quote(orig))),
+            None => status.push_str(&format!("{x}{y} {path}{end}")),
+        }
+    }
+    for path in &result.untracked {
+        status.push_str(&format!("?? {}{end}", quote(path)));
+    }
+    for path in &result.ignored {
+        status.push_str(&format!("!! {}{end}", quote(path)));
+    }
+    status
+}
+
+fn format_porcelain_v2(
+    result: &StatusResult,
+    opts: &StatusOptions,
     repo: &obj::Repo,
-    index: &idx::Index,
-) -> Result<LocalChanges, err::Error> {
-    let names_mtimes = index
-        .entries
-        .iter()
-        .map(|idx::IndexEntry { name, m_time, .. }| (name.to_owned(), m_time.to_owned()));
-
-    let idx_name_mtime_pairs: HashSet<(String, DateTime<Utc>)> = HashSet::from_iter(names_mtimes);
-    let worktree_name_mtime_pairs = gather_mtime_from_worktree(None, repo)?;
-
-    let not_staged = idx_name_mtime_pairs
-        .difference(&worktree_name_mtime_pairs)
-        .into_iter()
-        .map(|(name, _)| format!("modified: {name}\n"))
-        .collect::<String>();
-
-    let not_tracked = worktree_name_mtime_pairs
-        .difference(&idx_name_mtime_pairs)
-        .into_iter()
-        .map(|(name, _)| format!("{name}\n"))
-        .collect::<String>();
-
-    Ok(LocalChanges {
-        not_staged,
-        not_tracked,
-    })
+) -> Result<String, err::Error> {
+    let (end, quote): (&str, fn(&str) -> String) = if opts.null_terminated {
+        ("\0", str::to_owned)
+    } else {
+        ("\n", |p| quote_path(p, false))
+    };
+    let mut status = String::new();
+    if opts.branch {
+        let branch = &result.branch;
+        let oid = branch.oid.as_deref().unwrap_or("(initial)");
+        let head = branch.head.as_deref().unwrap_or("(detached)");
+        status.push_str(&format!("# branch.oid {oid}{end}# branch.head {head}{end}"));
+        if let Some(upstream) = &branch.upstream {
+            status.push_str(&format!("# branch.upstream {upstream}{end}"));
+            if let Some((ahead, behind)) = branch.ahead_behind {
+                status.push_str(&format!("# branch.ab +{ahead} -{behind}{end}"));
+            }
+        }
+    }
+
+    let null_sha = "0".repeat(40);
+    // git lists the unmerged paths after the other changes
+    let mut unmerged_lines = String::new();
+    for changed in changed_paths(result) {
+        let x = changed.staged.map_or('.', change_code);
+        let y = changed.unstaged.map_or('.', change_code);
+        let worktree_mode = match symlink_metadata(repo.worktree.join(changed.path)) {
+            Ok(_) => add::stat_index_entry(changed.path, repo)?.mode,
+            Err(_) => 0,
+        };
+        let mode = |e: Option<&idx::IndexEntry>| e.map_or(0, |e| e.mode);
+        let sha = |e: Option<&idx::IndexEntry>| e.map_or(null_sha.clone(), |e| hex::encode(&e.sha));
+        if let Some(unmerged) = changed.unmerged {
+            let [base, ours, theirs] = unmerged.stages.each_ref().map(Option::as_ref);
+            let submodule = match [base, ours, theirs].iter().any(|e| mode(*e) == 0o160000) {
+                true => "S...",
+                false => "N...",
+            };
+            unmerged_lines.push_str(&format!(
+                "u {} {submodule} {:06o} {:06o} {:06o} {worktree_mode:06o} {} {} {} {}{end}",
+                unmerged.kind().0,
+                mode(base),
+                mode(ours),
+                mode(theirs),
+                sha(base),
+                sha(ours),
+                sha(theirs),
+                quote(changed.path)
+            ));
+            continue;
+        }
+        let head = result
+            .head_entries
+            .get(changed.orig_path.unwrap_or(changed.path));
+        let index = result.index_entries.get(changed.path);
+        let submodule = if mode(index) == 0o160000 || mode(head) == 0o160000 {
+            "S..."
+        } else {
+            "N..."
+        };
+        let fields = format!(
+            "{x}{y} {submodule} {:06o} {:06o} {:06o} {} {}",
+            mode(head),
+            mode(index),
+            worktree_mode,
+            sha(head),
+            sha(index)
+        );
+        let path = quote(changed.path);
+        match (changed.orig_path, changed.staged) {
+            (Some(orig), Some(staged)) => {
+                let score = match staged {
+                    FileChange::Renamed { score, .. } => *score,
+                    _ => 100,
+                };
+                let sep = if opts.null_terminated { "\0" } else { "\t" };
+                status.push_str(&format!(
+                    "2 {fields} R{score} {path}{sep}{}{end}",
+                    quote(orig)
+                ))
+            }
+            _ => status.push_str(&format!("1 {fields} {path}{end}")),
+        }
+    }
+    status.push_str(&unmerged_lines);
+    for path in &result.untracked {
+        status.push_str(&format!("? {}{end}", quote(path)));
+    }
+    for path in &result.ignored {
+        status.push_str(&format!("! {}{end}", quote(path)));
+    }
+    Ok(status)
 }
 
-pub fn status(repo: &obj::Repo) -> Result<String, err::Error> {
-    if !utils::git_index_exists(repo) {
+pub fn status(opts: &StatusOptions, repo: &obj::Repo) -> Result<String, err::Error> {
+    if opts.format == StatusFormat::Long && !utils::git_index_exists(repo) {
         return Ok("Nothing in the stagging area!
              The .git/index file doesn't yet exist try:
              'rusty-git add <file-name>' to trigger index creation"
             .to_owned());
     }
+    let result = collect_status(opts, repo)?;
+    match opts.format {
+        StatusFormat::Long => format_long(&result, opts),
+        StatusFormat::Short | StatusFormat::PorcelainV1 => Ok(format_short(&result, opts)),
+        StatusFormat::PorcelainV2 => format_porcelain_v2(&result, opts, repo),
+    }
+}
 
-    let idx = utils::git_read_index(repo)?;
-    let index = idx::parse_git_index(&idx)?;
+#[cfg(test)]
+mod status_tests {
+    use super::*;
+    use crate::test_utils;
+    use std::fs::{write, File};
+    use std::time::{Duration, SystemTime};
 
-    let staged = staged_but_not_commited(repo, &index)?;
-    let LocalChanges {
-        not_staged,
-        not_tracked,
-    } = local_changes_not_staged_for_commit_or_untracked(repo, &index)?;
-    let status = format!(
-        "Changes to be committed:\n\n{}\n\
-         Changes not staged for commit:\n\n{}\n\
-         Untracked files:\n\n{}",
-        staged, not_staged, not_tracked
-    );
-    Ok(status)
+    fn not_staged(repo: &obj::Repo) -> String {
+        let status = status(&StatusOptions::default(), repo).unwrap();
+        let start = status.find("Changes not staged for commit:").unwrap();
+        let end = status.find("Untracked files:").unwrap();
+        let lines: Vec<&str> = status[start..end].lines().skip(2).collect();
+        lines.join("\n").trim_end().to_owned()
+    }
+
+    #[test]
+    fn status_compares_contents_when_stat_info_cant_be_trusted() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        write(repo.worktree.join("a"), "a\n").unwrap();
+        add::add(&["a".to_owned()], &Default::default(), &repo).unwrap();
+        assert_eq!("", not_staged(&repo));
+
+        // touching the file changes its stat info but not its contents,
+        // the index gets the new stat info
+        let later = SystemTime::now() + Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(repo.worktree.join("a"))
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!("", not_staged(&repo));
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        let later: chrono::DateTime<chrono::Utc> = later.into();
+        assert_eq!(later, index.entries[0].m_time);
+
+        // an edit keeping the same size and stat info is still found when
+        // the index was written before the file was last modified
+        write(repo.worktree.join("a"), "b\n").unwrap();
+        let mut index = idx::read_index_or_empty(&repo).unwrap();
+        index.entries[0] = idx::IndexEntry {
+            sha: index.entries[0].sha.clone(),
+            ..add::stat_index_entry("a", &repo).unwrap()
+        };
+        add::write_index(index, &repo).unwrap();
+        let earlier = SystemTime::now() - Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(repo.git_path("index"))
+            .unwrap()
+            .set_modified(earlier)
+            .unwrap();
+        assert_eq!("modified: a", not_staged(&repo));
+    }
+
+    #[test]
+    fn status_skips_the_refresh_when_the_index_is_locked() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        write(repo.worktree.join("a"), "a\n").unwrap();
+        add::add(&["a".to_owned()], &Default::default(), &repo).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(repo.worktree.join("a"))
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        let before = std::fs::read(repo.git_path("index")).unwrap();
+
+        // another process holds the lock so the stale stat info is kept
+        write(repo.gitdir.join("index.lock"), "").unwrap();
+        assert_eq!("", not_staged(&repo));
+        assert_eq!(before, std::fs::read(repo.git_path("index")).unwrap());
+        assert!(repo.gitdir.join("index.lock").exists());
+
+        std::fs::remove_file(repo.gitdir.join("index.lock")).unwrap();
+        assert_eq!("", not_staged(&repo));
+        assert_ne!(before, std::fs::read(repo.git_path("index")).unwrap());
+        assert!(!repo.gitdir.join("index.lock").exists());
+    }
+
+    #[test]
+    fn staged_and_unstaged_changes_are_categorized() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let long = "line one\nline two\nline three\nline four\n";
+        for (name, contents) in [
+            ("old", long),
+            ("gone", "g\n"),
+            ("link", "l\n"),
+            ("m", "m\n"),
+        ] {
+            write(repo.worktree.join(name), contents).unwrap();
+        }
+        let paths = ["old", "gone", "link", "m"].map(|p| p.to_owned());
+        add::add(&paths, &Default::default(), &repo).unwrap();
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        let tree = crate::cmds::writetree::write_tree(&index, None, &repo).unwrap();
+        let sha = crate::cmds::committree::commit_tree(&tree, &[], "base\n", &repo).unwrap();
+        refs::update_ref("HEAD", &sha, "base", &repo).unwrap();
+
+        // a renamed file with a small edit, a deletion and a file replaced
+        // by a symlink are staged
+        std::fs::remove_file(repo.worktree.join("old")).unwrap();
+        write(repo.worktree.join("new"), format!("{long}line five\n")).unwrap();
+        std::fs::remove_file(repo.worktree.join("link")).unwrap();
+        std::os::unix::fs::symlink("m", repo.worktree.join("link")).unwrap();
+        std::fs::remove_file(repo.worktree.join("gone")).unwrap();
+        let paths = ["old", "new", "link", "gone"].map(|p| p.to_owned());
+        add::add(&paths, &Default::default(), &repo).unwrap();
+
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        let changes: Vec<String> = staged_changes(&repo, &index)
+            .unwrap()
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        assert_eq!(
+            vec!["deleted: gone", "typechange: link", "renamed: old -> new"],
+            changes
+        );
+
+        // the worktree loses m and turns the link back into a file
+        std::fs::remove_file(repo.worktree.join("m")).unwrap();
+        std::fs::remove_file(repo.worktree.join("link")).unwrap();
+        write(repo.worktree.join("link"), "l\n").unwrap();
+        assert_eq!("typechange: link\ndeleted: m", not_staged(&repo));
+    }
+
+    #[test]
+    fn quote_path_escapes_control_characters_like_git() {
+        assert_eq!("plain name", quote_path("plain name", false));
+        assert_eq!("\"plain name\"", quote_path("plain name", true));
+        assert_eq!(
+            r#""\a\b\t\n\v\f\r""#,
+            quote_path("\x07\x08\t\n\x0b\x0c\r", false)
+        );
+        assert_eq!(r#""\001\177\"\\""#, quote_path("\x01\x7f\"\\", false));
+        assert_eq!(r#""caf\303\251""#, quote_path("café", false));
+    }
+
+    #[test]
+    fn short_and_porcelain_formats_report_branch_and_changes() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let mut repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        write(repo.worktree.join("a"), "a\n").unwrap();
+        add::add(&["a".to_owned()], &Default::default(), &repo).unwrap();
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        let tree = crate::cmds::writetree::write_tree(&index, None, &repo).unwrap();
+        let base = crate::cmds::committree::commit_tree(&tree, &[], "base\n", &repo).unwrap();
+        refs::update_ref("HEAD", &base, "base", &repo).unwrap();
+        refs::update_ref("refs/remotes/origin/master", &base, "fetch", &repo).unwrap();
+        crate::config::set(&mut repo, "branch.master.remote", "origin").unwrap();
+        crate::config::set(&mut repo, "branch.master.merge", "refs/heads/master").unwrap();
+        let next = crate::cmds::committree::commit_tree(&tree, &[base], "next\n", &repo).unwrap();
+        refs::update_ref("HEAD", &next, "next", &repo).unwrap();
+
+        write(repo.worktree.join("a"), "changed\n").unwrap();
+        write(repo.worktree.join("new file"), "n\n").unwrap();
+        let short = StatusOptions {
+            format: StatusFormat::Short,
+            branch: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            "## master...origin/master [ahead 1]\n M a\n?? .rusty-git-allowed\n?? \"new file\"\n",
+            status(&short, &repo).unwrap()
+        );
+        let nul = StatusOptions {
+            format: StatusFormat::PorcelainV1,
+            null_terminated: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            " M a\0?? .rusty-git-allowed\0?? new file\0",
+            status(&nul, &repo).unwrap()
+        );
+
+        let v2 = StatusOptions {
+            format: StatusFormat::PorcelainV2,
+            branch: true,
+            ..Default::default()
+        };
+        let sha = hex::encode(&index.entries[0].sha);
+        assert_eq!(
+            format!(
+                "# branch.oid {next}\n# branch.head master\n# branch.upstream origin/master\n\
+                 # branch.ab +1 -0\n1 .M N... 100644 100644 100644 {sha} {sha} a\n\
+                 ? .rusty-git-allowed\n? new file\n"
+            ),
+            status(&v2, &repo).unwrap()
+        );
+        let long = status(&StatusOptions::default(), &repo).unwrap();
+        assert!(long.contains("Your branch is ahead of 'origin/master' by 1 commit.\n"));
+    }
+
+    #[test]
+    fn unmerged_paths_are_reported_on_their_own() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        test_utils::test_commit_files(&[("f", "base\n"), ("g", "g\n")], "base\n", &repo).unwrap();
+
+        // f conflicts between both sides and n was only added on ours
+        let mut index = idx::read_index_or_empty(&repo).unwrap();
+        let mut base = index.entries.remove(0);
+        let attrs = attr::AttrRules::new(&repo).unwrap();
+        let mut stages = Vec::new();
+        for (name, contents) in [("f", "ours\n"), ("f", "theirs\n"), ("n", "n\n")] {
+            write(repo.worktree.join(name), contents).unwrap();
+            stages.push(add::file_to_index_entry(name, &attrs, None, &repo, true).unwrap());
+        }
+        base.set_stage(1);
+        stages[0].set_stage(2);
+        stages[1].set_stage(3);
+        stages[2].set_stage(2);
+        let shas: Vec<String> = [&base, &stages[0], &stages[1], &stages[2]]
+            .iter()
+            .map(|e| hex::encode(&e.sha))
+            .collect();
+        index.add_entry(base);
+        for entry in stages {
+            index.add_entry(entry);
+        }
+        add::write_index(index, &repo).unwrap();
+        write(repo.worktree.join("f"), "<<<<<<< ours\n").unwrap();
+
+        let long = status(&StatusOptions::default(), &repo).unwrap();
+        assert!(long.contains("Changes to be committed:\n\n\nUnmerged paths:\n\n"));
+        assert!(long.contains("both modified: f\nadded by us: n\n"));
+        assert_eq!("", not_staged(&repo));
+
+        let short = StatusOptions {
+            format: StatusFormat::Short,
+            ..Default::default()
+        };
+        assert_eq!(
+            "UU f\nAU n\n?? .rusty-git-allowed\n",
+            status(&short, &repo).unwrap()
+        );
+        let v2 = StatusOptions {
+            format: StatusFormat::PorcelainV2,
+            ..Default::default()
+        };
+        let null_sha = "0".repeat(40);
+        assert_eq!(
+            format!(
+                "u UU N... 100644 100644 100644 100644 {} {} {} f\n\
+                 u AU N... 000000 100644 000000 100644 {null_sha} {} {null_sha} n\n\
+                 ? .rusty-git-allowed\n",
+                shas[0], shas[1], shas[2], shas[3]
+            ),
+            status(&v2, &repo).unwrap()
+        );
+    }
 }
@@ -0,0 +1,376 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs::symlink_metadata;
+
+use crate::attributes as attr;
+use crate::cmds::{
+    add, branch, checkout, readtree, reflog, refs, sparsecheckout, updateref, worktree,
+};
+use crate::error as err;
+use crate::index as idx;
+use crate::objects as obj;
+use crate::walk;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SwitchOptions {
+    /// create a branch with this name at the target and switch to it
+    pub create: Option<String>,
+    /// move HEAD to the target commit without being on a branch
+    pub detach: bool,
+}
+
+fn head_tree_entries(repo: &obj::Repo) -> Result<readtree::TreeEntries, err::Error> {
+    match refs::rev_parse("HEAD", repo) {
+        Ok(sha) => readtree::tree_entries(&refs::peel(&sha, "tree", repo)?, None, repo),
+        Err(err::Error::GitNoCommitsExistYet) => Ok(readtree::TreeEntries::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn path_list(paths: &[String]) -> String {
+    paths.iter().map(|p| format!("\t{p}\n")).collect()
+}
+
+/// Moves the index and worktree from HEAD's tree to the commit's tree.
+/// Changes in the index or worktree are carried over when the two trees
+/// agree on the path, otherwise nothing is touched and an error lists the
+/// paths that would be overwritten.
+pub fn checkout_commit(sha: &str, repo: &obj::Repo) -> Result<(), err::Error> {
+    let index = idx::read_index_or_empty(repo)?;
+    let current = readtree::stage_zero_entries(&index)?;
+    let target = readtree::tree_entries(&refs::peel(sha, "tree", repo)?, None, repo)?;
+    let merged: readtree::TreeEntries =
+        match readtree::two_way_merge(&current, head_tree_entries(repo)?, target) {
+            Ok(entries) => entries.into_iter().map(|e| (e.name.clone(), e)).collect(),
+            Err(err::Error::GitReadTreeWouldOverwrite(path)) => {
+                return Err(err::Error::GitSwitchLocalChanges(path_list(&[path])))
+            }
+            Err(e) => return Err(e),
+        };
+
+    let changed: Vec<&String> = current
+        .keys()
+        .chain(merged.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|p| !readtree::same(current.get(*p), merged.get(*p)))
+        .collect();
+
+    // tracked files the switch removes, they don't count as being in the
+    // way of a path the target adds
+    let leaving: HashSet<&str> = changed
+        .iter()
+        .filter(|p| !merged.contains_key(**p))
+        .map(|p| p.as_str())
+        .collect();
+
+    // check every path before the worktree is touched
+    let attrs = attr::AttrRules::new(repo)?;
+    let mut modified = Vec::new();
+    let mut untracked = Vec::new();
+    for path in &changed {
+        let worktree_path = repo.worktree.join(path);
+        let on_disk = symlink_metadata(&worktree_path);
+        match (current.get(*path), on_disk) {
+            (Some(entry), Ok(_)) if entry.skip_worktree() => {}
+            (Some(entry), Ok(_))
+                if !checkout::worktree_file_matches_entry(entry, &attrs, repo)? =>
+            {
+                modified.push(path.to_string())
+            }
+            (Some(_), _) | (None, Err(_)) => {}
+            // a dir in the way only blocks the target when something in it
+            // isn't a tracked file that's being removed
+            (None, Ok(md)) if md.is_dir() && !worktree_path.join(".git").exists() => {
+                let inside = walk::walk_worktree(&worktree_path, None, 1, repo)?;
+                untracked.extend(
+                    inside
+                        .files
+                        .into_iter()
+                        .filter(|f| !leaving.contains(f.as_str())),
+                );
+            }
+            (None, Ok(_)) => untracked.push(path.to_string()),
+        }
+    }
+    if !modified.is_empty() {
+        return Err(err::Error::GitSwitchLocalChanges(path_list(&modified)));
+    }
+    if !untracked.is_empty() {
+        return Err(err::Error::GitSwitchUntrackedFiles(path_list(&untracked)));
+    }
+
+    let sparse = sparsecheckout::read_sparse_checkout(repo)?;
+    let mut new_index = idx::Index {
+        entries: merged.values().cloned().collect(),
+        ..idx::Index::empty()
+    };
+    let included = |path: &str| sparse.as_ref().is_none_or(|s| s.includes(path));
+    // files are removed before any are written, so a dir that's replaced
+    // by a file, or a file replaced by a dir, is out of the way first
+    for path in &changed {
+        let on_disk = symlink_metadata(repo.worktree.join(path)).is_ok();
+        match new_index.find_entry(path) {
+            Ok(pos) if !included(path) => {
+                if on_disk {
+                    checkout::remove_worktree_file(path, repo)?;
+                }
+                new_index.entries[pos].set_skip_worktree(true);
+            }
+            Ok(_) => {}
+            Err(_) if on_disk => checkout::remove_worktree_file(path, repo)?,
+            Err(_) => {}
+        }
+    }
+    for path in changed {
+        if let Ok(pos) = new_index.find_entry(path) {
+            if included(path) {
+                let entry = &mut new_index.entries[pos];
+                *entry = checkout::checkout_entry(entry, &attrs, repo)?;
+            }
+        }
+    }
+    add::write_index(new_index, repo)
+}
+
+// records the move in HEAD's reflog the way git checkout does
+fn log_head_move(
+    old: Option<&str>,
+    new: &str,
+    from: &str,
+    to: &str,
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
+    let msg = format!("checkout: moving from {from} to {to}");
+    reflog::append_reflog("HEAD", old, new, &msg, repo)
+}
+
+/// Switches to the target branch, creates a branch starting at the target
+/// or detaches HEAD at the target commit, updating the index and worktree
+/// to match
+pub fn switch(
+    target: Option<&str>,
+    opts: &SwitchOptions,
+    repo: &obj::Repo,
+) -> Result<String, err::Error> {
+    let old = refs::rev_parse("HEAD", repo).ok();
+    let from = match branch::current_branch(repo)? {
+        Some(name) => name,
+        None => old.clone().unwrap_or_else(|| "HEAD".to_owned()),
+    };
+
+    if let Some(name) = &opts.create {
+        branch::check_branch_name(name)?;
+        if refs::read_ref(&format!("refs/heads/{name}"), repo)?.is_some() {
+            return Err(err::Error::GitBranchAlreadyExists(name.to_owned()));
+        }
+        let start_point = target.unwrap_or("HEAD");
+        let sha = refs::peel(&refs::rev_parse(start_point, repo)?, "commit", repo)?;
+        checkout_commit(&sha, repo)?;
+        branch::create_branch(name, Some(&sha), repo)?;
+        refs::write_symref("HEAD", &format!("refs/heads/{name}"), repo)?;
+        log_head_move(old.as_deref(), &sha, &from, name, repo)?;
+        return Ok(format!("Switched to a new branch '{name}'\n"));
+    }
+
+    let Some(target) = target else {
+        return Err(err::Error::GitSwitchMissingBranch);
+    };
+    let branch_ref = format!("refs/heads/{target}");
+    let branch_sha = refs::read_ref(&branch_ref, repo)?;
+
+    if opts.detach {
+        let sha = refs::rev_parse(target, repo)
+            .map_err(|_| err::Error::GitSwitchInvalidReference(target.to_owned()))?;
+        let sha = refs::peel(&sha, "commit", repo)?;
+        checkout_commit(&sha, repo)?;
+        let msg = format!("checkout: moving from {from} to {sha}");
+        let mut transaction = updateref::RefTransaction::new(&msg);
+        transaction.update_no_deref("HEAD", &sha, None);
+        transaction.commit(repo)?;
+        let subject = branch::commit_subject(&sha, repo)?;
+        return Ok(format!("HEAD is now at {} {subject}\n", &sha[..7]));
+    }
+
+    let Some(sha) = branch_sha else {
+        return match refs::rev_parse(target, repo) {
+            Ok(_) => Err(err::Error::GitSwitchNotABranch(target.to_owned())),
+            Err(_) => Err(err::Error::GitSwitchInvalidReference(target.to_owned())),
+        };
+    };
+    if branch::current_branch(repo)?.as_deref() == Some(target) {
+        return Ok(format!("Already on '{target}'\n"));
+    }
+    if let Some(path) = worktree::checked_out_elsewhere(&branch_ref, repo)? {
+        let path = path.display().to_string();
+        return Err(err::Error::GitWorktreeBranchCheckedOut(
+            target.to_owned(),
+            path,
+        ));
+    }
+    checkout_commit(&sha, repo)?;
+    refs::write_symref("HEAD", &branch_ref, repo)?;
+    log_head_move(old.as_deref(), &sha, &from, target, repo)?;
+    Ok(format!("Switched to branch '{target}'\n"))
+}
+
+#[cfg(test)]
+mod switch_tests {
+    use super::*;
+    use crate::test_utils;
+    use std::fs::{read_to_string, write};
+
+    #[test]
+    fn switch_updates_worktree_index_and_head() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let base =
+            test_utils::test_commit_files(&[("a", "a\n"), ("b", "b\n")], "base\n", &repo).unwrap();
+
+        let opts = SwitchOptions {
+            create: Some("topic".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            "Switched to a new branch 'topic'\n",
+            switch(None, &opts, &repo).unwrap()
+        );
+        std::fs::remove_file(repo.worktree.join("b")).unwrap();
+        let mut index = idx::read_index_or_empty(&repo).unwrap();
+        index.remove_entry("b");
+        add::write_index(index, &repo).unwrap();
+        test_utils::test_commit_files(&[("a", "topic a\n"), ("c", "c\n")], "topic\n", &repo)
+            .unwrap();
+
+        let opts = SwitchOptions::default();
+        assert_eq!(
+            "Switched to branch 'master'\n",
+            switch(Some("master"), &opts, &repo).unwrap()
+        );
+        assert_eq!("a\n", read_to_string(repo.worktree.join("a")).unwrap());
+        assert_eq!("b\n", read_to_string(repo.worktree.join("b")).unwrap());
+        assert!(!repo.worktree.join("c").exists());
+        assert_eq!(
+            Some("master".to_owned()),
+            branch::current_branch(&repo).unwrap()
+        );
+        let names: Vec<String> = idx::read_index_or_empty(&repo)
+            .unwrap()
+            .entries
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(vec!["a", "b"], names);
+        assert_eq!(
+            "checkout: moving from topic to master",
+            reflog::read_reflog("HEAD", &repo)
+                .unwrap()
+                .last()
+                .unwrap()
+                .msg
+        );
+
+        let master = refs::read_ref("refs/heads/master", &repo).unwrap();
+        let opts = SwitchOptions {
+            detach: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format!("HEAD is now at {} base\n", &base[..7]),
+            switch(Some("topic~1"), &opts, &repo).unwrap()
+        );
+        assert_eq!(
+            obj::GitHead::Detached(base.clone()),
+            refs::read_head(&repo).unwrap()
+        );
+        // detaching replaces HEAD and leaves the branch it was on alone
+        assert_eq!(master, refs::read_ref("refs/heads/master", &repo).unwrap());
+        let entry = reflog::read_reflog("HEAD", &repo).unwrap().pop().unwrap();
+        assert_eq!(format!("checkout: moving from master to {base}"), entry.msg);
+        assert_eq!(master, Some(entry.old));
+
+        write(repo.git_path("HEAD.lock"), "").unwrap();
+        assert_eq!(
+            Err(err::Error::GitRefLockFailed(
+                "HEAD".to_owned(),
+                "the lock file already exists".to_owned()
+            )),
+            switch(Some("topic~1"), &opts, &repo)
+        );
+        std::fs::remove_file(repo.git_path("HEAD.lock")).unwrap();
+        assert_eq!(
+            Err(err::Error::GitSwitchNotABranch("HEAD".to_owned())),
+            switch(Some("HEAD"), &SwitchOptions::default(), &repo)
+        );
+    }
+
+    #[test]
+    fn switch_refuses_to_overwrite_local_changes() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        test_utils::test_commit_files(&[("a", "a\n"), ("b", "b\n")], "base\n", &repo).unwrap();
+        branch::create_branch("topic", None, &repo).unwrap();
+        test_utils::test_commit_files(&[("a", "master a\n")], "master\n", &repo).unwrap();
+
+        // b is the same on both branches so the change to it carries over
+        write(repo.worktree.join("b"), "local b\n").unwrap();
+        write(repo.worktree.join("a"), "local a\n").unwrap();
+        let opts = SwitchOptions::default();
+        assert_eq!(
+            Err(err::Error::GitSwitchLocalChanges("\ta\n".to_owned())),
+            switch(Some("topic"), &opts, &repo)
+        );
+        assert_eq!(
+            "local a\n",
+            read_to_string(repo.worktree.join("a")).unwrap()
+        );
+
+        write(repo.worktree.join("a"), "master a\n").unwrap();
+        switch(Some("topic"), &opts, &repo).unwrap();
+        assert_eq!("a\n", read_to_string(repo.worktree.join("a")).unwrap());
+        assert_eq!(
+            "local b\n",
+            read_to_string(repo.worktree.join("b")).unwrap()
+        );
+
+        // a file only on master collides with an untracked file on topic
+        switch(Some("master"), &opts, &repo).unwrap();
+        test_utils::test_commit_files(&[("new", "tracked\n")], "new\n", &repo).unwrap();
+        switch(Some("topic"), &opts, &repo).unwrap();
+        assert!(!repo.worktree.join("new").exists());
+        write(repo.worktree.join("new"), "untracked\n").unwrap();
+        assert_eq!(
+            Err(err::Error::GitSwitchUntrackedFiles("\tnew\n".to_owned())),
+            switch(Some("master"), &opts, &repo)
+        );
+    }
+
+    #[test]
+    fn switch_replaces_dirs_with_files_and_back() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        test_utils::test_commit_files(&[("x", "x\n")], "base\n", &repo).unwrap();
+        branch::create_branch("file", None, &repo).unwrap();
+        test_utils::test_commit_files(&[("a/b", "b\n")], "dir\n", &repo).unwrap();
+
+        let opts = SwitchOptions::default();
+        switch(Some("file"), &opts, &repo).unwrap();
+        assert!(!repo.worktree.join("a").exists());
+        test_utils::test_commit_files(&[("a", "file a\n")], "file\n", &repo).unwrap();
+
+        // file to dir
+        switch(Some("master"), &opts, &repo).unwrap();
+        assert_eq!("b\n", read_to_string(repo.worktree.join("a/b")).unwrap());
+        // dir to file
+        switch(Some("file"), &opts, &repo).unwrap();
+        assert_eq!("file a\n", read_to_string(repo.worktree.join("a")).unwrap());
+
+        // an untracked file inside the dir still blocks the switch
+        switch(Some("master"), &opts, &repo).unwrap();
+        write(repo.worktree.join("a/c"), "untracked\n").unwrap();
+        assert_eq!(
+            Err(err::Error::GitSwitchUntrackedFiles("\ta/c\n".to_owned())),
+            switch(Some("file"), &opts, &repo)
+        );
+        assert!(repo.worktree.join("a/b").exists());
+    }
+}
@@ -0,0 +1,192 @@
+use std::fs::{read_to_string, remove_file};
+
+use crate::cmds::{reflog, refs};
+use crate::error as err;
+use crate::objects as obj;
+
+// returns the ref a symbolic ref points at, None for a ref holding a sha
+fn symref_target(name: &str, repo: &obj::Repo) -> Result<Option<String>, err::Error> {
+    let path = repo.git_path(name);
+    if !path.is_file() {
+        return Err(err::Error::GitRefNotFound(name.to_owned()));
+    }
+    let contents = read_to_string(path)?;
+    Ok(contents.strip_prefix("ref:").map(|t| t.trim().to_owned()))
+}
+
+// true when following the target's symbolic refs leads back to the name
+fn makes_loop(name: &str, target: &str, repo: &obj::Repo) -> Result<bool, err::Error> {
+    let mut current = target.to_owned();
+    for _ in 0..refs::SYMREF_MAX_DEPTH {
+        if current == name {
+            return Ok(true);
+        }
+        let path = repo.git_path(&current);
+        if !path.is_file() {
+            break;
+        }
+        match read_to_string(path)?.strip_prefix("ref:") {
+            Some(next) => current = next.trim().to_owned(),
+            None => break,
+        }
+    }
+    Ok(false)
+}
+
+/// Shortens a full ref name the way it would be written on the command line
+pub fn shorten_ref(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+/// Returns the ref the symbolic ref points at
+pub fn read_symbolic_ref(name: &str, short: bool, repo: &obj::Repo) -> Result<String, err::Error> {
+    let target = symref_target(name, repo)?
+        .ok_or_else(|| err::Error::GitNotASymbolicRef(name.to_owned()))?;
+    if short {
+        Ok(shorten_ref(&target).to_owned())
+    } else {
+        Ok(target)
+    }
+}
+
+/// Points the symbolic ref at the target ref, with a reason the change is
+/// recorded in the reflog when the sha the ref resolves to changes
+pub fn set_symbolic_ref(
+    name: &str,
+    target: &str,
+    reason: Option<&str>,
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
+    if name == "HEAD" && !target.starts_with("refs/") {
+        return Err(err::Error::GitSymbolicRefBadTarget(target.to_owned()));
+    }
+    if makes_loop(name, target, repo)? {
+        return Err(err::Error::GitSymbolicRefLoop(
+            name.to_owned(),
+            target.to_owned(),
+        ));
+    }
+    let old = refs::read_ref(name, repo)?;
+    refs::write_symref(name, target, repo)?;
+
+    if let (Some(msg), Some(new)) = (reason, refs::read_ref(target, repo)?) {
+        if old.as_ref() != Some(&new) {
+            reflog::append_reflog(name, old.as_deref(), &new, msg, repo)?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes a symbolic ref, HEAD can't be deleted
+pub fn delete_symbolic_ref(name: &str, repo: &obj::Repo) -> Result<(), err::Error> {
+    if name == "HEAD" {
+        return Err(err::Error::GitSymbolicRefDeleteHead);
+    }
+    read_symbolic_ref(name, false, repo)?;
+    remove_file(repo.git_path(name))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod symbolicref_tests {
+    use super::*;
+    use crate::cmds::{add, committree, status, writetree};
+    use crate::index as idx;
+    use crate::test_utils;
+    use crate::utils;
+
+    #[test]
+    fn read_set_and_delete_symbolic_refs() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+
+        assert_eq!(
+            "refs/heads/master",
+            read_symbolic_ref("HEAD", false, &repo).unwrap()
+        );
+        set_symbolic_ref("HEAD", "refs/heads/main", None, &repo).unwrap();
+        assert_eq!("main", read_symbolic_ref("HEAD", true, &repo).unwrap());
+        assert_eq!(
+            Err(err::Error::GitSymbolicRefBadTarget("main".to_owned())),
+            set_symbolic_ref("HEAD", "main", None, &repo)
+        );
+
+        set_symbolic_ref(
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/main",
+            None,
+            &repo,
+        )
+        .unwrap();
+        delete_symbolic_ref("refs/remotes/origin/HEAD", &repo).unwrap();
+        assert_eq!(
+            Err(err::Error::GitRefNotFound(
+                "refs/remotes/origin/HEAD".to_owned()
+            )),
+            read_symbolic_ref("refs/remotes/origin/HEAD", false, &repo)
+        );
+        assert_eq!(
+            Err(err::Error::GitSymbolicRefDeleteHead),
+            delete_symbolic_ref("HEAD", &repo)
+        );
+    }
+
+    #[test]
+    fn symbolic_ref_loops_are_refused_and_deep_chains_fail_to_resolve() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        set_symbolic_ref("refs/heads/a", "refs/heads/b", None, &repo).unwrap();
+        assert_eq!(
+            Err(err::Error::GitSymbolicRefLoop(
+                "refs/heads/b".to_owned(),
+                "refs/heads/a".to_owned()
+            )),
+            set_symbolic_ref("refs/heads/b", "refs/heads/a", None, &repo)
+        );
+        assert!(set_symbolic_ref("refs/heads/a", "refs/heads/a", None, &repo).is_err());
+
+        // a loop made behind our back errors instead of recursing forever
+        refs::write_symref("refs/heads/b", "refs/heads/a", &repo).unwrap();
+        let too_deep = || err::Error::GitSymbolicRefTooDeep("refs/heads/a".to_owned());
+        assert_eq!(Err(too_deep()), refs::read_ref("refs/heads/a", &repo));
+        assert_eq!(
+            Err(too_deep()),
+            refs::resolve_ref(std::path::Path::new("refs/heads/a"), &repo)
+        );
+    }
+
+    #[test]
+    fn commits_on_a_detached_head_move_head_only() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let tree = writetree::write_tree(&idx::Index::empty(), None, &repo).unwrap();
+        let first = committree::commit_tree(&tree, &[], "first\n", &repo).unwrap();
+        refs::update_ref("HEAD", &first, "commit (initial): first", &repo).unwrap();
+
+        refs::write_ref("HEAD", &first, &repo).unwrap();
+        assert_eq!(
+            obj::GitHead::Detached(first.clone()),
+            refs::read_head(&repo).unwrap()
+        );
+        assert_eq!(
+            Err(err::Error::GitNotASymbolicRef("HEAD".to_owned())),
+            read_symbolic_ref("HEAD", false, &repo)
+        );
+
+        let parents = [utils::git_sha_from_head(&repo).unwrap()];
+        let second = committree::commit_tree(&tree, &parents, "second\n", &repo).unwrap();
+        refs::update_ref("HEAD", &second, "commit: second", &repo).unwrap();
+        assert_eq!(second, utils::git_sha_from_head(&repo).unwrap());
+        assert_eq!(
+            Some(first),
+            refs::read_ref("refs/heads/master", &repo).unwrap()
+        );
+
+        add::write_index(idx::Index::empty(), &repo).unwrap();
+        let status = status::status(&Default::default(), &repo).unwrap();
+        assert!(status.starts_with(&format!("HEAD detached at {}\n", &second[..7])));
+    }
+}
@@ -1,13 +1,10 @@
-use std::fs::File;
-use std::io::Write;
-
-use crate::cmds::refs;
+use crate::cmds::{refs, updateref};
 use crate::error as err;
 use crate::objects as obj;
 use crate::utils;
 
 pub fn list_all_tags(repo: &obj::Repo) -> Result<Vec<String>, err::Error> {
-    let tags_path = repo.gitdir.join("refs/tags/");
+    let tags_path = repo.git_path("refs/tags/");
     let tags = refs::gather_refs(Some(&tags_path), repo)?;
     Ok(tags)
 }
@@ -22,10 +19,12 @@ pub fn create_lightweight_tag(
     } else {
         object.to_owned()
     };
-    let tag_path = repo.gitdir.join(format!("refs/tags/{}", tag_name));
-    let mut tag = File::create(&tag_path)?;
-    writeln!(tag, "{}", tag_sha)?;
-    Ok(())
+    let tag_ref = format!("refs/tags/{}", tag_name);
+    refs::check_ref_format(&tag_ref, refs::RefFormatOptions::default())
+        .map_err(|_| err::Error::GitInvalidTagName(tag_name.to_owned()))?;
+    let mut transaction = updateref::RefTransaction::new("");
+    transaction.create(&tag_ref, &tag_sha);
+    transaction.commit(repo)
 }
 
 #[cfg(test)]
@@ -44,5 +43,13 @@ mod utils_tests {
         let tag = list_all_tags(&repo).unwrap();
         let expected = format!("{tag_sha} refs/tags/foo\n");
         assert_eq!(&expected, tag.first().unwrap());
+
+        for bad_name in ["../../config", "foo..bar", "a.lock"] {
+            assert_eq!(
+                Err(err::Error::GitInvalidTagName(bad_name.to_owned())),
+                create_lightweight_tag(&bad_name.to_owned(), &tag_sha, &repo)
+            );
+        }
+        assert!(!repo.git_path("refs/tags/a.lock").exists());
     }
 }
@@ -0,0 +1,442 @@
+use chrono::{DateTime, Utc};
+use std::fmt::Write as _;
+use std::fs::symlink_metadata;
+
+use crate::attributes as attr;
+use crate::cmds::add;
+use crate::error as err;
+use crate::index as idx;
+use crate::objects as obj;
+use crate::pathspec;
+use crate::utils;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UpdateIndexOptions {
+    /// allow paths that aren't in the index yet to be added
+    pub add: bool,
+    /// remove paths from the index when they're missing from the worktree
+    pub remove: bool,
+    /// refresh the stat info of entries whose contents haven't changed
+    pub refresh: bool,
+    /// entries given as "<mode>,<sha>,<path>" which don't need a worktree file
+    pub cacheinfo: Vec<String>,
+    /// "+x" or "-x" applied to the given paths
+    pub chmod: Option<String>,
+    pub assume_unchanged: Option<bool>,
+    pub skip_worktree: Option<bool>,
+}
+
+fn parse_mode(mode: &str) -> Option<u32> {
+    u32::from_str_radix(mode, 8).ok()
+}
+
+fn parse_sha(sha: &str) -> Option<Vec<u8>> {
+    if sha.len() != 40 {
+        return None;
+    }
+    hex::decode(sha).ok()
+}
+
+/// Parses a "<mode>,<sha>,<path>" --cacheinfo argument into an index entry
+pub fn parse_cacheinfo(info: &str, repo: &obj::Repo) -> Result<idx::IndexEntry, err::Error> {
+    let invalid = || err::Error::GitUpdateIndexInvalidCacheinfo(info.to_owned());
+    let mut parts = info.splitn(3, ',');
+    let (Some(mode), Some(sha), Some(path)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    let mode = parse_mode(mode).ok_or_else(invalid)?;
+    let sha = parse_sha(sha).ok_or_else(invalid)?;
+    let name = pathspec::normalize_path(path, repo)?;
+    if name.is_empty() {
+        return Err(invalid());
+    }
+    Ok(idx::IndexEntry::new(mode, sha, &name))
+}
+
+/// Applies lines in the format read by 'git update-index --index-info',
+/// all three of git's formats are accepted:
+///   <mode> SP <sha> TAB <path>
+///   <mode> SP <type> SP <sha> TAB <path>    (the output of ls-tree)
+///   <mode> SP <sha> SP <stage> TAB <path>
+/// A mode of 0 removes the path from the index.
+pub fn apply_index_info(
+    input: &str,
+    index: &mut idx::Index,
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
+    for line in input.lines().filter(|l| !l.is_empty()) {
+        let malformed = || err::Error::GitUpdateIndexMalformedIndexInfo(line.to_owned());
+        let (info, path) = line.split_once('\t').ok_or_else(malformed)?;
+        let fields: Vec<&str> = info.split(' ').collect();
+
+        let (mode, sha, stage) = match fields.as_slice() {
+            [mode, sha] => (*mode, *sha, 0),
+            [mode, sha, stage] if parse_sha(sha).is_some() => {
+                (*mode, *sha, stage.parse::<u16>().map_err(|_| malformed())?)
+            }
+            [mode, _obj_type, sha] => (*mode, *sha, 0),
+            _ => return Err(malformed()),
+        };
+        let mode = parse_mode(mode).ok_or_else(malformed)?;
+        let name = pathspec::normalize_path(path, repo)?;
+
+        if mode == 0 {
+            index.remove_entry(&name);
+            continue;
+        }
+        if stage > 3 {
+            return Err(malformed());
+        }
+
+        let mut entry = idx::IndexEntry::new(mode, parse_sha(sha).ok_or_else(malformed)?, &name);
+        entry.set_stage(stage);
+        // a resolved entry replaces the conflict stages and vice versa
+        index
+            .entries
+            .retain(|e| e.name != name || (e.stage() != 0 && stage != 0));
+        index.add_entry(entry);
+    }
+    Ok(())
+}
+
+fn stat_matches(entry: &idx::IndexEntry, current: &idx::IndexEntry) -> bool {
+    entry.m_time == current.m_time
+        && entry.c_time == current.c_time
+        && entry.size == current.size
+        && entry.inode == current.inode
+        && entry.dev == current.dev
+        && entry.mode == current.mode
+}
+
+/// Whether the entry can be trusted to match its file without hashing it,
+/// the stat info has to match and have been recorded before the index was
+/// written rather than in the same tick
+pub fn stat_clean(
+    entry: &idx::IndexEntry,
+    current: &idx::IndexEntry,
+    index_mtime: Option<DateTime<Utc>>,
+) -> bool {
+    let racy = index_mtime.is_some_and(|t| entry.m_time >= t);
+    stat_matches(entry, current) && !racy
+}
+
+/// Compares an entry with its worktree file, which must exist. The file
+/// is only hashed when the stat info differs or was recorded too close to
+/// when the index was written to be trusted. Returns the entry with fresh
+/// stat info when the contents match and None when they changed.
+pub fn refreshed_entry(
+    entry: &idx::IndexEntry,
+    index_mtime: Option<DateTime<Utc>>,
+    attrs: &attr::AttrRules,
+    repo: &obj::Repo,
+) -> Result<Option<idx::IndexEntry>, err::Error> {
+    let current = add::stat_index_entry(&entry.name, repo)?;
+    if current.mode != entry.mode {
+        return Ok(None);
+    }
+    if stat_clean(entry, &current, index_mtime) {
+        return Ok(Some(entry.clone()));
+    }
+    // entries added without a file or smudged as racy have no size to go by
+    if entry.size != 0 && entry.size != current.size && entry.mode != 0o160000 {
+        return Ok(None);
+    }
+
+    let index_sha = hex::encode(&entry.sha);
+    let current = add::file_to_index_entry(&entry.name, attrs, Some(&index_sha), repo, false)?;
+    if current.sha != entry.sha || current.mode != entry.mode {
+        return Ok(None);
+    }
+    Ok(Some(idx::IndexEntry {
+        flags: entry.flags,
+        extended_flags: entry.extended_flags,
+        ..current
+    }))
+}
+
+/// Updates the stat info of entries whose file contents still match the
+/// index, returns the names of entries whose contents have changed. Entries
+/// marked assume-unchanged or skip-worktree are left alone.
+pub fn refresh_index(index: &mut idx::Index, repo: &obj::Repo) -> Result<Vec<String>, err::Error> {
+    let index_mtime = utils::git_index_mtime(repo);
+    let attrs = attr::AttrRules::new(repo)?;
+    let mut needs_update = Vec::new();
+    for entry in index.entries.iter_mut() {
+        if entry.assume_valid() || entry.skip_worktree() {
+            continue;
+        }
+        if entry.stage() != 0 {
+            needs_update.push(format!("{}: needs merge", entry.name));
+            continue;
+        }
+        if symlink_metadata(repo.worktree.join(&entry.name)).is_err() {
+            needs_update.push(format!("{}: needs update", entry.name));
+            continue;
+        }
+
+        match refreshed_entry(entry, index_mtime, &attrs, repo)? {
+            Some(refreshed) => *entry = refreshed,
+            None => needs_update.push(format!("{}: needs update", entry.name)),
+        }
+    }
+    Ok(needs_update)
+}
+
+fn chmod_entry(entry: &mut idx::IndexEntry, chmod: &str) -> Result<(), err::Error> {
+    if entry.mode != 0o100644 && entry.mode != 0o100755 {
+        return Err(err::Error::GitUpdateIndexCannotChmod(
+            chmod.to_owned(),
+            entry.name.clone(),
+        ));
+    }
+    entry.mode = match chmod {
+        "+x" => 0o100755,
+        "-x" => 0o100644,
+        _ => return Err(err::Error::GitUpdateIndexInvalidChmod(chmod.to_owned())),
+    };
+    Ok(())
+}
+
+fn entry_mut<'a>(
+    index: &'a mut idx::Index,
+    name: &str,
+) -> Result<&'a mut idx::IndexEntry, err::Error> {
+    match index.find_entry(name) {
+        Ok(pos) => Ok(&mut index.entries[pos]),
+        Err(_) => Err(err::Error::GitUpdateIndexUnableToMark(name.to_owned())),
+    }
+}
+
+fn update_path(
+    name: &str,
+    opts: &UpdateIndexOptions,
+    index: &mut idx::Index,
+    attrs: &attr::AttrRules,
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
+    // flag changes only mark the entry, the worktree file isn't looked at
+    if opts.assume_unchanged.is_some() || opts.skip_worktree.is_some() {
+        let entry = entry_mut(index, name)?;
+        if let Some(on) = opts.assume_unchanged {
+            entry.set_assume_valid(on);
+        }
+        if let Some(on) = opts.skip_worktree {
+            entry.set_skip_worktree(on);
+        }
+        return Ok(());
+    }
+
+    let worktree_path = repo.worktree.join(name);
+    match symlink_metadata(&worktree_path) {
+        Ok(md) if !md.is_dir() => {
+            let indexed = index.find_entry(name).ok();
+            if indexed.is_none() && !opts.add {
+                return Err(err::Error::GitUpdateIndexCannotAdd(name.to_owned()));
+            }
+            let index_sha = indexed.map(|i| hex::encode(&index.entries[i].sha));
+            let entry = add::file_to_index_entry(name, attrs, index_sha.as_deref(), repo, true)?;
+            index.add_entry(entry);
+        }
+        _ => {
+            if !opts.remove {
+                return Err(err::Error::GitUpdateIndexNoRemove(name.to_owned()));
+            }
+            index.remove_entry(name);
+            return Ok(());
+        }
+    }
+
+    if let Some(chmod) = &opts.chmod {
+        chmod_entry(entry_mut(index, name)?, chmod)?;
+    }
+    Ok(())
+}
+
+/// Plumbing for modifying the index directly, index_info holds the lines
+/// read from stdin when --index-info is used
+pub fn update_index(
+    paths: &[String],
+    opts: &UpdateIndexOptions,
+    index_info: Option<&str>,
+    repo: &obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    let starting_index = idx::read_index_or_empty(repo)?;
+    let mut index = starting_index.clone();
+
+    if let Some(info) = index_info {
+        apply_index_info(info, &mut index, repo)?;
+    }
+
+    for info in &opts.cacheinfo {
+        let entry = parse_cacheinfo(info, repo)?;
+        if index.find_entry(&entry.name).is_err() && !opts.add {
+            return Err(err::Error::GitUpdateIndexCannotAdd(entry.name));
+        }
+        index.add_entry(entry);
+    }
+
+    let attrs = attr::AttrRules::new(repo)?;
+    for path in paths {
+        let name = pathspec::normalize_path(path, repo)?;
+        update_path(&name, opts, &mut index, &attrs, repo)?;
+    }
+
+    let mut output = String::new();
+    if opts.refresh {
+        for line in refresh_index(&mut index, repo)? {
+            writeln!(output, "{line}")?;
+        }
+    }
+
+    if index != starting_index {
+        add::write_index(index, repo)?;
+    }
+    Ok(if output.is_empty() {
+        None
+    } else {
+        Some(output)
+    })
+}
+
+#[cfg(test)]
+mod update_index_tests {
+    use super::*;
+    use crate::test_utils;
+    use crate::utils;
+    use std::fs::File;
+    use std::io::Write;
+
+    const SHA: &str = "323fae03f4606ea9991df8befbb2fca795e648fa";
+
+    fn test_repo() -> (tempfile::TempDir, obj::Repo) {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        (gitdir, repo)
+    }
+
+    #[test]
+    fn cacheinfo_adds_entries_without_a_worktree_file() {
+        let (_gitdir, repo) = test_repo();
+        let opts = UpdateIndexOptions {
+            cacheinfo: vec![format!("100755,{SHA},bin/run.sh")],
+            ..Default::default()
+        };
+        assert_eq!(
+            Err(err::Error::GitUpdateIndexCannotAdd("bin/run.sh".to_owned())),
+            update_index(&[], &opts, None, &repo)
+        );
+
+        let opts = UpdateIndexOptions { add: true, ..opts };
+        update_index(&[], &opts, None, &repo).unwrap();
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        let entry = index.entries.first().unwrap();
+        assert_eq!("bin/run.sh", entry.name);
+        assert_eq!(0o100755, entry.mode);
+        assert_eq!(SHA, utils::get_sha_from_binary(&entry.sha));
+    }
+
+    #[test]
+    fn flags_round_trip_through_the_index_file() {
+        let (_gitdir, repo) = test_repo();
+        let mut file = File::create(repo.worktree.join("foo.txt")).unwrap();
+        writeln!(file, "foo").unwrap();
+
+        let add = UpdateIndexOptions {
+            add: true,
+            chmod: Some("+x".to_owned()),
+            ..Default::default()
+        };
+        update_index(&["foo.txt".to_owned()], &add, None, &repo).unwrap();
+
+        let mark = UpdateIndexOptions {
+            assume_unchanged: Some(true),
+            skip_worktree: Some(true),
+            ..Default::default()
+        };
+        update_index(&["foo.txt".to_owned()], &mark, None, &repo).unwrap();
+
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        let entry = index.entries.first().unwrap();
+        assert_eq!(0o100755, entry.mode);
+        assert!(entry.assume_valid());
+        assert!(entry.skip_worktree());
+
+        let unmark = UpdateIndexOptions {
+            skip_worktree: Some(false),
+            ..Default::default()
+        };
+        update_index(&["foo.txt".to_owned()], &unmark, None, &repo).unwrap();
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        assert!(!index.entries.first().unwrap().skip_worktree());
+        assert!(index.entries.first().unwrap().assume_valid());
+    }
+
+    #[test]
+    fn index_info_adds_stages_and_removes_entries() {
+        let (_gitdir, repo) = test_repo();
+        let info = format!(
+            "100644 {SHA}\ta.txt\n\
+             100644 blob {SHA}\tb.txt\n\
+             100644 {SHA} 2\tc.txt\n\
+             100644 {SHA} 3\tc.txt\n"
+        );
+        update_index(&[], &UpdateIndexOptions::default(), Some(&info), &repo).unwrap();
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        let names: Vec<(String, u16)> = index
+            .entries
+            .iter()
+            .map(|e| (e.name.clone(), e.stage()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("a.txt".to_owned(), 0),
+                ("b.txt".to_owned(), 0),
+                ("c.txt".to_owned(), 2),
+                ("c.txt".to_owned(), 3)
+            ],
+            names
+        );
+
+        let info = format!("0 {SHA}\tc.txt\n");
+        update_index(&[], &UpdateIndexOptions::default(), Some(&info), &repo).unwrap();
+        let index = idx::read_index_or_empty(&repo).unwrap();
+        assert_eq!(2, index.entries.len());
+    }
+
+    #[test]
+    fn refresh_reports_changed_files_and_remove_drops_missing_ones() {
+        let (_gitdir, repo) = test_repo();
+        let path = repo.worktree.join("foo.txt");
+        writeln!(File::create(&path).unwrap(), "foo").unwrap();
+
+        let add = UpdateIndexOptions {
+            add: true,
+            ..Default::default()
+        };
+        update_index(&["foo.txt".to_owned()], &add, None, &repo).unwrap();
+
+        let refresh = UpdateIndexOptions {
+            refresh: true,
+            ..Default::default()
+        };
+        assert_eq!(Ok(None), update_index(&[], &refresh, None, &repo));
+
+        writeln!(File::create(&path).unwrap(), "changed").unwrap();
+        assert_eq!(
+            Ok(Some("foo.txt: needs update\n".to_owned())),
+            update_index(&[], &refresh, None, &repo)
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            Err(err::Error::GitUpdateIndexNoRemove("foo.txt".to_owned())),
+            update_index(&["foo.txt".to_owned()], &add, None, &repo)
+        );
+        let remove = UpdateIndexOptions {
+            remove: true,
+            ..Default::default()
+        };
+        update_index(&["foo.txt".to_owned()], &remove, None, &repo).unwrap();
+        assert!(idx::read_index_or_empty(&repo).unwrap().entries.is_empty());
+    }
+}
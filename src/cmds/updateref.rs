@@ -0,0 +1,559 @@
+use std::collections::HashSet;
+use std::fs::{create_dir_all, remove_file, rename, write, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::cmds::{reflog, refs};
+use crate::error as err;
+use crate::objects as obj;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Change {
+    Update(String),
+    Delete,
+    Verify,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RefUpdate {
+    name: String,
+    change: Change,
+    // the value the ref must hold before the transaction, the zero sha
+    // means the ref must not exist and None skips the check
+    old: Option<String>,
+    // false when a symbolic ref is itself replaced rather than followed
+    deref: bool,
+}
+
+/// A set of ref changes applied together. Every ref is locked and its old
+/// value checked before anything is written, if any check fails nothing is
+/// changed and if a write fails the refs already written are restored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefTransaction {
+    updates: Vec<RefUpdate>,
+    msg: String,
+}
+
+impl RefTransaction {
+    /// Starts an empty transaction, msg is used for the reflog entries
+    pub fn new(msg: &str) -> RefTransaction {
+        RefTransaction {
+            updates: Vec::new(),
+            msg: msg.to_owned(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+
+    fn push(&mut self, name: &str, change: Change, old: Option<&str>) -> &mut RefTransaction {
+        self.updates.push(RefUpdate {
+            name: name.to_owned(),
+            change,
+            old: old.map(|o| o.to_owned()),
+            deref: true,
+        });
+        self
+    }
+
+    /// Points the ref at new, the zero sha deletes the ref
+    pub fn update(&mut self, name: &str, new: &str, old: Option<&str>) -> &mut RefTransaction {
+        if new == reflog::ZERO_SHA {
+            self.push(name, Change::Delete, old)
+        } else {
+            self.push(name, Change::Update(new.to_owned()), old)
+        }
+    }
+
+    /// Points the ref itself at new, a symbolic ref like HEAD is replaced
+    /// instead of the ref it points at being updated
+    pub fn update_no_deref(
+        &mut self,
+        name: &str,
+        new: &str,
+        old: Option<&str>,
+    ) -> &mut RefTransaction {
+        self.update(name, new, old);
+        if let Some(update) = self.updates.last_mut() {
+            update.deref = false;
+        }
+        self
+    }
+
+    /// Creates the ref, it's an error for the ref to exist already
+    pub fn create(&mut self, name: &str, new: &str) -> &mut RefTransaction {
+        self.push(name, Change::Update(new.to_owned()), Some(reflog::ZERO_SHA))
+    }
+
+    pub fn delete(&mut self, name: &str, old: Option<&str>) -> &mut RefTransaction {
+        self.push(name, Change::Delete, old)
+    }
+
+    /// Checks the ref holds old without changing it
+    pub fn verify(&mut self, name: &str, old: &str) -> &mut RefTransaction {
+        self.push(name, Change::Verify, Some(old))
+    }
+
+    /// Applies every change in the transaction or none of them
+    pub fn commit(self, repo: &obj::Repo) -> Result<(), err::Error> {
+        // changes to HEAD go to the branch it points at
+        let head = refs::head_symref(repo)?;
+        let targets: Vec<String> = self
+            .updates
+            .iter()
+            .map(|u| match (u.name.as_str(), &head) {
+                ("HEAD", Some(branch)) if u.deref => branch.to_owned(),
+                _ => u.name.to_owned(),
+            })
+            .collect();
+        for update in &self.updates {
+            refs::check_ref_name(&update.name)?;
+        }
+        let mut seen = HashSet::new();
+        if let Some(dup) = targets.iter().find(|t| !seen.insert(*t)) {
+            return Err(err::Error::GitRefTransactionDuplicate(dup.to_owned()));
+        }
+
+        let mut locks = Vec::new();
+        let mut packed_lock = None;
+        let result = self.lock_and_write(
+            &targets,
+            head.as_deref(),
+            &mut locks,
+            &mut packed_lock,
+            repo,
+        );
+        // locks of written refs were moved into place, the rest are removed.
+        // an unused packed-refs lock is removed when it's dropped.
+        drop(packed_lock);
+        for lock in locks {
+            if lock.exists() {
+                remove_file(lock)?;
+            }
+        }
+        result
+    }
+
+    fn lock_and_write(
+        &self,
+        targets: &[String],
+        head: Option<&str>,
+        locks: &mut Vec<PathBuf>,
+        packed_lock: &mut Option<refs::PackedRefsLock>,
+        repo: &obj::Repo,
+    ) -> Result<(), err::Error> {
+        for target in targets {
+            let lock = repo.git_path(format!("{target}.lock"));
+            if let Some(parent) = lock.parent() {
+                create_dir_all(parent)?;
+            }
+            match OpenOptions::new().write(true).create_new(true).open(&lock) {
+                Ok(_) => locks.push(lock),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    return Err(lock_failed(target, "the lock file already exists"))
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // deleting a packed ref rewrites packed-refs, it's locked with the
+        // loose refs so no other writer changes it during the transaction
+        let deletes: Vec<&String> = self
+            .updates
+            .iter()
+            .zip(targets)
+            .filter(|(u, _)| u.change == Change::Delete)
+            .map(|(_, t)| t)
+            .collect();
+        if !deletes.is_empty() {
+            *packed_lock = Some(refs::PackedRefsLock::acquire(repo)?);
+        }
+
+        let mut olds = Vec::new();
+        for (update, target) in self.updates.iter().zip(targets) {
+            let current = refs::read_ref(target, repo)?;
+            check_old_value(target, update.old.as_deref(), current.as_deref())?;
+            olds.push(current);
+        }
+
+        // deleted refs leave packed-refs before their loose files are
+        // removed, so readers never see an older packed value come back
+        let mut repack = false;
+        if let Some(lock) = packed_lock.as_mut() {
+            for target in deletes {
+                repack |= lock.remove(target);
+            }
+        }
+        if repack {
+            if let Some(lock) = packed_lock.take() {
+                lock.commit(repo)?;
+            }
+        }
+
+        for (i, update) in self.updates.iter().enumerate() {
+            if let Err(e) = write_change(&update.change, &targets[i], &locks[i], &olds[i], repo) {
+                // packed-refs was already rewritten so every ref is put
+                // back, refs that weren't written yet keep their value
+                for (j, (target, old)) in targets.iter().zip(&olds).enumerate() {
+                    restore(target, &locks[j], j >= i, old, repo)?;
+                }
+                return Err(e);
+            }
+        }
+
+        for ((update, target), old) in self.updates.iter().zip(targets).zip(&olds) {
+            if let Change::Update(new) = &update.change {
+                reflog::append_reflog(target, old.as_deref(), new, &self.msg, repo)?;
+                if target != "HEAD" && head == Some(target.as_str()) {
+                    reflog::append_reflog("HEAD", old.as_deref(), new, &self.msg, repo)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn lock_failed(name: &str, reason: &str) -> err::Error {
+    err::Error::GitRefLockFailed(name.to_owned(), reason.to_owned())
+}
+
+fn check_old_value(
+    name: &str,
+    expected: Option<&str>,
+    current: Option<&str>,
+) -> Result<(), err::Error> {
+    match (expected, current) {
+        (None, _) => Ok(()),
+        (Some(reflog::ZERO_SHA), None) => Ok(()),
+        (Some(reflog::ZERO_SHA), Some(_)) => Err(lock_failed(name, "reference already exists")),
+        (Some(_), None) => Err(lock_failed(name, "unable to resolve reference")),
+        (Some(expected), Some(current)) if expected == current => Ok(()),
+        (Some(expected), Some(current)) => Err(lock_failed(
+            name,
+            &format!("is at {current} but expected {expected}"),
+        )),
+    }
+}
+
+fn write_change(
+    change: &Change,
+    target: &str,
+    lock: &Path,
+    old: &Option<String>,
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
+    match change {
+        Change::Update(new) => {
+            write(lock, format!("{new}\n"))?;
+            rename(lock, repo.git_path(target))?;
+        }
+        Change::Delete if old.is_some() => {
+            let ref_path = repo.git_path(target);
+            if ref_path.is_file() {
+                refs::remove_loose_ref(&ref_path, repo)?;
+            }
+            let log = repo.git_path(format!("logs/{target}"));
+            if log.is_file() {
+                remove_file(log)?;
+            }
+        }
+        Change::Delete | Change::Verify => {}
+    }
+    Ok(())
+}
+
+// puts a ref back the way it was before the transaction through its lock
+// file. Refs that weren't written yet still hold theirs, written refs had
+// theirs moved into place and take a fresh one, failing if another writer
+// got there first.
+fn restore(
+    target: &str,
+    lock: &Path,
+    held: bool,
+    old: &Option<String>,
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
+    let ref_path = repo.git_path(target);
+    match old {
+        Some(sha) => {
+            if !held {
+                if let Some(parent) = lock.parent() {
+                    create_dir_all(parent)?;
+                }
+                match OpenOptions::new().write(true).create_new(true).open(lock) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                        return Err(lock_failed(target, "the lock file already exists"))
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            write(lock, format!("{sha}\n"))?;
+            rename(lock, ref_path)?;
+            Ok(())
+        }
+        // a ref that didn't exist can only have been written as a loose ref
+        None if ref_path.is_file() => refs::remove_loose_ref(&ref_path, repo),
+        None => Ok(()),
+    }
+}
+
+// values on the command line can be any revision, an empty value means
+// the ref must not exist
+fn resolve_value(value: &str, repo: &obj::Repo) -> Result<String, err::Error> {
+    if value.is_empty() || value == reflog::ZERO_SHA {
+        Ok(reflog::ZERO_SHA.to_owned())
+    } else {
+        refs::rev_parse(value, repo)
+    }
+}
+
+/// Updates a single ref from 'ref new [old]' or deletes it from 'ref [old]'
+pub fn update_from_args(
+    args: &[String],
+    delete: bool,
+    msg: &str,
+    repo: &obj::Repo,
+) -> Result<(), err::Error> {
+    let usage = || {
+        err::Error::GitUpdateRefUsage(
+            "usage: rusty-git update-ref [-m reason] (-d <ref> [<old>] | <ref> <new> [<old>])"
+                .to_owned(),
+        )
+    };
+    let mut transaction = RefTransaction::new(msg);
+    match (delete, args) {
+        (true, [name]) => transaction.delete(name, None),
+        (true, [name, old]) => transaction.delete(name, Some(&resolve_value(old, repo)?)),
+        (false, [name, new]) => transaction.update(name, &resolve_value(new, repo)?, None),
+        (false, [name, new, old]) => transaction.update(
+            name,
+            &resolve_value(new, repo)?,
+            Some(&resolve_value(old, repo)?),
+        ),
+        _ => return Err(usage()),
+    };
+    transaction.commit(repo)
+}
+
+/// Runs the update-ref --stdin commands. Without 'start' every command
+/// goes in one transaction committed at the end of the input, 'start',
+/// 'commit' and 'abort' manage transactions explicitly and report on
+/// success.
+pub fn update_from_stdin(input: &str, msg: &str, repo: &obj::Repo) -> Result<String, err::Error> {
+    let mut output = String::new();
+    let mut transaction = RefTransaction::new(msg);
+    let mut explicit = false;
+
+    for line in input.lines().filter(|l| !l.trim().is_empty()) {
+        let bad_line = || err::Error::GitUpdateRefStdin(line.to_owned());
+        let (cmd, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let args: Vec<&str> = if rest.is_empty() {
+            Vec::new()
+        } else {
+            rest.split(' ').collect()
+        };
+        match (cmd, args.as_slice()) {
+            ("start", []) => {
+                transaction = RefTransaction::new(msg);
+                explicit = true;
+                output.push_str("start: ok\n");
+            }
+            ("update", [name, new]) => {
+                transaction.update(name, &resolve_value(new, repo)?, None);
+            }
+            ("update", [name, new, old]) => {
+                let old = resolve_value(old, repo)?;
+                transaction.update(name, &resolve_value(new, repo)?, Some(&old));
+            }
+            ("create", [name, new]) => {
+                transaction.create(name, &resolve_value(new, repo)?);
+            }
+            ("delete", [name]) => {
+                transaction.delete(name, None);
+            }
+            ("delete", [name, old]) => {
+                transaction.delete(name, Some(&resolve_value(old, repo)?));
+            }
+            ("verify", [name]) => {
+                transaction.verify(name, reflog::ZERO_SHA);
+            }
+            ("verify", [name, old]) => {
+                transaction.verify(name, &resolve_value(old, repo)?);
+            }
+            ("commit", []) => {
+                std::mem::replace(&mut transaction, RefTransaction::new(msg)).commit(repo)?;
+                explicit = false;
+                output.push_str("commit: ok\n");
+            }
+            ("abort", []) => {
+                transaction = RefTransaction::new(msg);
+                explicit = false;
+                output.push_str("abort: ok\n");
+            }
+            _ => return Err(bad_line()),
+        }
+    }
+
+    // an explicitly started transaction that is never committed is dropped
+    if !explicit && !transaction.is_empty() {
+        transaction.commit(repo)?;
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod updateref_tests {
+    use super::*;
+    use crate::cmds::{committree, writetree};
+    use crate::index as idx;
+    use crate::test_utils;
+
+    fn two_commits(repo: &obj::Repo) -> (String, String) {
+        let tree = writetree::write_tree(&idx::Index::empty(), None, repo).unwrap();
+        let first = committree::commit_tree(&tree, &[], "first\n", repo).unwrap();
+        let second =
+            committree::commit_tree(&tree, std::slice::from_ref(&first), "second\n", repo).unwrap();
+        (first, second)
+    }
+
+    #[test]
+    fn transaction_checks_every_old_value_before_writing() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let (first, second) = two_commits(&repo);
+        refs::update_ref("refs/heads/a", &first, "", &repo).unwrap();
+
+        let mut transaction = RefTransaction::new("move");
+        transaction
+            .update("refs/heads/a", &second, Some(&first))
+            .create("refs/heads/b", &second)
+            .verify("refs/heads/c", &first);
+        assert_eq!(
+            Err(err::Error::GitRefLockFailed(
+                "refs/heads/c".to_owned(),
+                "unable to resolve reference".to_owned()
+            )),
+            transaction.commit(&repo)
+        );
+        assert_eq!(
+            Some(first.clone()),
+            refs::read_ref("refs/heads/a", &repo).unwrap()
+        );
+        assert_eq!(None, refs::read_ref("refs/heads/b", &repo).unwrap());
+        assert!(!repo.git_path("refs/heads/a.lock").exists());
+
+        let mut transaction = RefTransaction::new("move");
+        transaction
+            .update("refs/heads/a", &second, Some(&first))
+            .create("refs/heads/b", &second);
+        transaction.commit(&repo).unwrap();
+        assert_eq!(
+            Some(second.clone()),
+            refs::read_ref("refs/heads/a", &repo).unwrap()
+        );
+        assert_eq!(Some(second), refs::read_ref("refs/heads/b", &repo).unwrap());
+
+        std::fs::write(repo.git_path("refs/heads/b.lock"), "").unwrap();
+        let mut transaction = RefTransaction::new("");
+        transaction.delete("refs/heads/b", None);
+        assert_eq!(
+            Err(err::Error::GitRefLockFailed(
+                "refs/heads/b".to_owned(),
+                "the lock file already exists".to_owned()
+            )),
+            transaction.commit(&repo)
+        );
+    }
+
+    #[test]
+    fn failed_writes_put_refs_back_through_lock_files() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let (first, second) = two_commits(&repo);
+        refs::update_ref("refs/heads/a", &first, "", &repo).unwrap();
+        // a dir where the ref should go makes the second write fail
+        refs::update_ref("refs/heads/b/c", &first, "", &repo).unwrap();
+
+        let mut transaction = RefTransaction::new("");
+        transaction
+            .update("refs/heads/a", &second, Some(&first))
+            .update("refs/heads/b", &second, None);
+        assert!(transaction.commit(&repo).is_err());
+        assert_eq!(Some(first), refs::read_ref("refs/heads/a", &repo).unwrap());
+        assert!(!repo.git_path("refs/heads/a.lock").exists());
+        assert!(!repo.git_path("refs/heads/b.lock").exists());
+    }
+
+    #[test]
+    fn deleting_packed_refs_holds_the_packed_refs_lock() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let (first, second) = two_commits(&repo);
+        refs::update_ref("refs/heads/a", &first, "", &repo).unwrap();
+        refs::update_ref("refs/tags/v1", &first, "", &repo).unwrap();
+        refs::pack_refs(true, &repo).unwrap();
+
+        // nothing changes while another writer holds packed-refs
+        std::fs::write(repo.git_path("packed-refs.lock"), "").unwrap();
+        let mut transaction = RefTransaction::new("");
+        transaction
+            .update("refs/heads/a", &second, Some(&first))
+            .delete("refs/tags/v1", Some(&first));
+        assert_eq!(
+            Err(err::Error::GitRefLockFailed(
+                "packed-refs".to_owned(),
+                "the lock file already exists".to_owned()
+            )),
+            transaction.clone().commit(&repo)
+        );
+        assert_eq!(
+            Some(first.clone()),
+            refs::read_ref("refs/heads/a", &repo).unwrap()
+        );
+        assert!(!repo.git_path("refs/heads/a.lock").exists());
+
+        std::fs::remove_file(repo.git_path("packed-refs.lock")).unwrap();
+        transaction.commit(&repo).unwrap();
+        assert_eq!(Some(second), refs::read_ref("refs/heads/a", &repo).unwrap());
+        assert_eq!(None, refs::read_ref("refs/tags/v1", &repo).unwrap());
+        assert!(!repo.git_path("packed-refs.lock").exists());
+        let packed = refs::read_packed_refs(&repo).unwrap();
+        assert_eq!(
+            vec!["refs/heads/a"],
+            packed.iter().map(|p| &p.name).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn stdin_runs_implicit_and_explicit_transactions() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let (first, second) = two_commits(&repo);
+
+        let input = format!("create refs/heads/a {first}\ncreate refs/heads/b {first}\n");
+        assert_eq!("", update_from_stdin(&input, "", &repo).unwrap());
+        assert_eq!(
+            Some(first.clone()),
+            refs::read_ref("refs/heads/b", &repo).unwrap()
+        );
+
+        let input = format!(
+            "start\nupdate refs/heads/a {second} {first}\ndelete refs/heads/b {first}\n\
+             verify refs/heads/c\ncommit\nstart\ndelete refs/heads/a\nabort\n"
+        );
+        assert_eq!(
+            "start: ok\ncommit: ok\nstart: ok\nabort: ok\n",
+            update_from_stdin(&input, "", &repo).unwrap()
+        );
+        assert_eq!(Some(second), refs::read_ref("refs/heads/a", &repo).unwrap());
+        assert_eq!(None, refs::read_ref("refs/heads/b", &repo).unwrap());
+
+        assert_eq!(
+            Err(err::Error::GitUpdateRefStdin(
+                "move refs/heads/a".to_owned()
+            )),
+            update_from_stdin("move refs/heads/a\n", "", &repo)
+        );
+    }
+}
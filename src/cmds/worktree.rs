@@ -0,0 +1,537 @@
+use std::fmt::Write as _;
+use std::fs::{create_dir_all, read, read_dir, read_to_string, remove_dir, remove_dir_all, write};
+use std::path::{absolute, Path, PathBuf};
+
+use crate::attributes as attr;
+use crate::cmds::{add, branch, checkout, readtree, refs, status};
+use crate::error as err;
+use crate::ignore;
+use crate::index as idx;
+use crate::objects as obj;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WorktreeAddOptions {
+    /// create a branch with this name at the commit and check it out
+    pub create: Option<String>,
+    /// check out the commit without being on a branch
+    pub detach: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Worktree {
+    pub path: PathBuf,
+    /// .git/worktrees/<name> for a linked worktree, .git for the main one
+    pub gitdir: PathBuf,
+    /// the name under .git/worktrees, None for the main worktree
+    pub name: Option<String>,
+    pub head: Option<obj::GitHead>,
+    pub locked: Option<String>,
+    /// the worktree's dir is gone so prune would remove its gitdir
+    pub prunable: bool,
+}
+
+fn display(path: &Path) -> String {
+    path.display().to_string()
+}
+
+// a linked worktree's gitdir holds a gitdir file with the path of the
+// worktree's .git file
+fn linked_worktree(gitdir: PathBuf, name: String) -> Result<Worktree, err::Error> {
+    let dotgit = match read_to_string(gitdir.join("gitdir")) {
+        Ok(contents) => PathBuf::from(contents.trim_end()),
+        Err(_) => PathBuf::new(),
+    };
+    let locked = read_to_string(gitdir.join("locked")).ok();
+    let head = read(gitdir.join("HEAD")).ok();
+    Ok(Worktree {
+        path: dotgit.parent().map(Path::to_path_buf).unwrap_or_default(),
+        head: head.and_then(|h| obj::parse_git_head(&h).ok()),
+        prunable: !dotgit.is_file(),
+        gitdir,
+        name: Some(name),
+        locked: locked.map(|reason| reason.trim_end().to_owned()),
+    })
+}
+
+/// Lists the main worktree followed by the linked worktrees sorted by name
+pub fn list_worktrees(repo: &obj::Repo) -> Result<Vec<Worktree>, err::Error> {
+    let commondir = repo.commondir.canonicalize()?;
+    let main_head = read(commondir.join("HEAD"))?;
+    let mut worktrees = vec![Worktree {
+        path: commondir
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default(),
+        gitdir: commondir.clone(),
+        name: None,
+        head: Some(obj::parse_git_head(&main_head)?),
+        locked: None,
+        prunable: false,
+    }];
+
+    let admin_dir = commondir.join("worktrees");
+    if !admin_dir.is_dir() {
+        return Ok(worktrees);
+    }
+    let mut linked = Vec::new();
+    for node in read_dir(admin_dir)? {
+        let node = node?;
+        let name = node.file_name();
+        let name = name.to_str().ok_or(err::Error::PathToUtf8Conversion)?;
+        linked.push(linked_worktree(node.path(), name.to_owned())?);
+    }
+    linked.sort_by(|a, b| a.name.cmp(&b.name));
+    worktrees.append(&mut linked);
+    Ok(worktrees)
+}
+
+/// Formats the worktrees one per line as '<path> <sha> [<branch>]'
+pub fn list(repo: &obj::Repo) -> Result<String, err::Error> {
+    let worktrees = list_worktrees(repo)?;
+    let width = worktrees
+        .iter()
+        .map(|wt| display(&wt.path).len())
+        .max()
+        .unwrap_or(0);
+
+    let mut output = String::new();
+    for wt in worktrees {
+        let (sha, desc) = match &wt.head {
+            Some(obj::GitHead::Symbolic(target)) => (
+                refs::read_ref(target, repo)?,
+                format!("[{}]", target.strip_prefix("refs/heads/").unwrap_or(target)),
+            ),
+            Some(obj::GitHead::Detached(sha)) => (Some(sha.clone()), "(detached HEAD)".to_owned()),
+            None => (None, "(error)".to_owned()),
+        };
+        let sha = sha.unwrap_or_else(|| "0".repeat(40));
+        write!(output, "{:<width$} {} {desc}", display(&wt.path), &sha[..7])?;
+        if wt.locked.is_some() {
+            output.push_str(" locked");
+        }
+        if wt.prunable {
+            output.push_str(" prunable");
+        }
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+fn find_worktree(path: &Path, repo: &obj::Repo) -> Result<Worktree, err::Error> {
+    let wanted = path.canonicalize().or_else(|_| absolute(path))?;
+    list_worktrees(repo)?
+        .into_iter()
+        .find(|wt| wt.path == wanted || wt.path.canonicalize().is_ok_and(|p| p == wanted))
+        .ok_or_else(|| err::Error::GitWorktreeNotFound(display(path)))
+}
+
+// the worktree other than skip which has the branch checked out
+fn checked_out_at(
+    branch_ref: &str,
+    skip: Option<&Path>,
+    repo: &obj::Repo,
+) -> Result<Option<PathBuf>, err::Error> {
+    Ok(list_worktrees(repo)?
+        .into_iter()
+        .filter(|wt| Some(wt.gitdir.as_path()) != skip)
+        .find(|wt| matches!(&wt.head, Some(obj::GitHead::Symbolic(t)) if t == branch_ref))
+        .map(|wt| wt.path))
+}
+
+/// Returns the path of another worktree which has the branch checked out,
+/// a branch can only be checked out in one worktree at a time
+pub fn checked_out_elsewhere(
+    branch_ref: &str,
+    repo: &obj::Repo,
+) -> Result<Option<PathBuf>, err::Error> {
+    let current = repo.gitdir.canonicalize()?;
+    checked_out_at(branch_ref, Some(&current), repo)
+}
+
+// picks a name under .git/worktrees from the last part of the path,
+// numbered when the name is taken
+fn worktree_name(path: &Path, admin_dir: &Path) -> Result<String, err::Error> {
+    let base = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or(err::Error::PathToUtf8Conversion)?;
+    let mut name = base.to_owned();
+    let mut n = 1;
+    while admin_dir.join(&name).exists() {
+        name = format!("{base}{n}");
+        n += 1;
+    }
+    Ok(name)
+}
+
+/// Creates a new worktree at path sharing the repo's objects and refs. The
+/// commit-ish is checked out on its branch when it names one, otherwise
+/// HEAD is detached at it. Without a commit-ish a branch named after the
+/// worktree's dir is created at HEAD.
+pub fn add_worktree(
+    path: &Path,
+    commitish: Option<&str>,
+    opts: &WorktreeAddOptions,
+    repo: &obj::Repo,
+) -> Result<String, err::Error> {
+    let path = absolute(path)?;
+    if path.is_file() || (path.is_dir() && read_dir(&path)?.next().is_some()) {
+        return Err(err::Error::GitWorktreeAlreadyExists(display(&path)));
+    }
+
+    let dir_name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or(err::Error::PathToUtf8Conversion)?;
+    let new_branch = match (&opts.create, commitish, opts.detach) {
+        (Some(name), _, _) => Some(name.as_str()),
+        (None, None, false) => Some(dir_name),
+        _ => None,
+    };
+    let start = commitish.unwrap_or("HEAD");
+    let existing_branch = match commitish {
+        Some(name) if new_branch.is_none() && !opts.detach => {
+            let name_ref = format!("refs/heads/{name}");
+            refs::read_ref(&name_ref, repo)?.map(|_| name_ref)
+        }
+        _ => None,
+    };
+
+    let sha = refs::peel(&refs::rev_parse(start, repo)?, "commit", repo)?;
+    let (head, desc) = if let Some(name) = new_branch {
+        branch::create_branch(name, Some(&sha), repo)?;
+        (
+            format!("ref: refs/heads/{name}"),
+            format!("new branch '{name}'"),
+        )
+    } else if let Some(branch_ref) = existing_branch {
+        if let Some(at) = checked_out_at(&branch_ref, None, repo)? {
+            let name = branch_ref.trim_start_matches("refs/heads/").to_owned();
+            return Err(err::Error::GitWorktreeBranchCheckedOut(name, display(&at)));
+        }
+        let desc = format!("checking out '{}'", start);
+        (format!("ref: {branch_ref}"), desc)
+    } else {
+        (sha.clone(), format!("detached HEAD {}", &sha[..7]))
+    };
+
+    // the worktree's gitdir points back at the worktree and the worktree's
+    // .git file points at its gitdir
+    let commondir = repo.commondir.canonicalize()?;
+    let admin_dir = commondir.join("worktrees");
+    let gitdir = admin_dir.join(worktree_name(&path, &admin_dir)?);
+    create_dir_all(&gitdir)?;
+    create_dir_all(&path)?;
+    write(
+        gitdir.join("gitdir"),
+        format!("{}\n", display(&path.join(".git"))),
+    )?;
+    write(gitdir.join("commondir"), "../..\n")?;
+    write(gitdir.join("HEAD"), format!("{head}\n"))?;
+    write(path.join(".git"), format!("gitdir: {}\n", display(&gitdir)))?;
+
+    let worktree = obj::Repo::new(path)?;
+    let attrs = attr::AttrRules::new(&worktree)?;
+    let mut index = idx::Index::empty();
+    for entry in readtree::tree_entries(&refs::peel(&sha, "tree", repo)?, None, repo)?.values() {
+        index.add_entry(checkout::checkout_entry(entry, &attrs, &worktree)?);
+    }
+    add::write_index(index, &worktree)?;
+
+    let subject = branch::commit_subject(&sha, repo)?;
+    Ok(format!(
+        "Preparing worktree ({desc})\nHEAD is now at {} {subject}\n",
+        &sha[..7]
+    ))
+}
+
+/// Locks a linked worktree so prune and remove leave it alone
+pub fn lock(path: &Path, reason: Option<&str>, repo: &obj::Repo) -> Result<(), err::Error> {
+    let wt = find_worktree(path, repo)?;
+    if wt.name.is_none() {
+        return Err(err::Error::GitWorktreeIsMain(display(path)));
+    }
+    if wt.locked.is_some() {
+        return Err(err::Error::GitWorktreeAlreadyLocked(display(path)));
+    }
+    write(wt.gitdir.join("locked"), reason.unwrap_or(""))?;
+    Ok(())
+}
+
+pub fn unlock(path: &Path, repo: &obj::Repo) -> Result<(), err::Error> {
+    let wt = find_worktree(path, repo)?;
+    if wt.name.is_none() {
+        return Err(err::Error::GitWorktreeIsMain(display(path)));
+    }
+    if wt.locked.is_none() {
+        return Err(err::Error::GitWorktreeNotLocked(display(path)));
+    }
+    std::fs::remove_file(wt.gitdir.join("locked"))?;
+    Ok(())
+}
+
+// whether the worktree has staged, unmerged or unstaged changes or
+// untracked files which removing it would lose, ignored files don't count
+fn has_local_changes(worktree: &obj::Repo) -> Result<bool, err::Error> {
+    let index = idx::read_index_or_empty(worktree)?;
+    if index.entries.iter().any(|e| e.stage() != 0)
+        || !status::staged_changes(worktree, &index)?.is_empty()
+    {
+        return Ok(true);
+    }
+    let attrs = attr::AttrRules::new(worktree)?;
+    for entry in index.entries.iter().filter(|e| !e.skip_worktree()) {
+        let on_disk = worktree
+            .worktree
+            .join(&entry.name)
+            .symlink_metadata()
+            .is_ok();
+        if !on_disk || !checkout::worktree_file_matches_entry(entry, &attrs, worktree)? {
+            return Ok(true);
+        }
+    }
+    let ignore_rules = ignore::IgnoreRules::new(worktree)?;
+    for file in add::gather_worktree_files(None, worktree)? {
+        let tracked = index.find_entry(&file).is_ok();
+        if !tracked && !ignore_rules.is_ignored(&file, worktree.worktree.join(&file).is_dir())?
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Deletes a linked worktree and its gitdir, a worktree with local changes
+/// is only removed with force and a locked one never is
+pub fn remove(path: &Path, force: bool, repo: &obj::Repo) -> Result<(), err::Error> {
+    let wt = find_worktree(path, repo)?;
+    if wt.name.is_none() {
+        return Err(err::Error::GitWorktreeIsMain(display(path)));
+    }
+    if wt.locked.is_some() {
+        return Err(err::Error::GitWorktreeLocked(display(path)));
+    }
+    if wt.path.is_dir() {
+        if !force && has_local_changes(&obj::Repo::new(wt.path.clone())?)? {
+            return Err(err::Error::GitWorktreeDirty(display(path)));
+        }
+        remove_dir_all(&wt.path)?;
+    }
+    remove_dir_all(&wt.gitdir)?;
+    remove_empty_admin_dir(repo)
+}
+
+// .git/worktrees goes away along with the last linked worktree
+fn remove_empty_admin_dir(repo: &obj::Repo) -> Result<(), err::Error> {
+    let admin_dir = repo.commondir.join("worktrees");
+    if admin_dir.is_dir() && read_dir(&admin_dir)?.next().is_none() {
+        remove_dir(admin_dir)?;
+    }
+    Ok(())
+}
+
+/// Removes the gitdirs of linked worktrees whose dirs no longer exist,
+/// locked worktrees are kept
+pub fn prune(repo: &obj::Repo) -> Result<String, err::Error> {
+    let mut output = String::new();
+    for wt in list_worktrees(repo)? {
+        let Some(name) = &wt.name else {
+            continue;
+        };
+        if wt.prunable && wt.locked.is_none() {
+            remove_dir_all(&wt.gitdir)?;
+            writeln!(
+                output,
+                "Removing worktrees/{name}: gitdir file points to non-existent location"
+            )?;
+        }
+    }
+    remove_empty_admin_dir(repo)?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod worktree_tests {
+    use super::*;
+    use crate::cmds::{switch, updateref};
+    use crate::test_utils;
+    use crate::utils;
+
+    #[test]
+    fn linked_worktrees_share_objects_and_refs() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let first = test_utils::test_commit_files(&[("a", "a\n")], "commit\n", &repo).unwrap();
+        let other = test_utils::test_tempdir().unwrap();
+        let path = other.path().join("review");
+
+        assert_eq!(
+            format!(
+                "Preparing worktree (new branch 'review')\nHEAD is now at {} commit\n",
+                &first[..7]
+            ),
+            add_worktree(&path, None, &WorktreeAddOptions::default(), &repo).unwrap()
+        );
+        let linked = obj::Repo::new(path.clone()).unwrap();
+        assert_eq!(repo.gitdir.canonicalize().unwrap(), linked.commondir);
+        assert_eq!("a\n", read_to_string(path.join("a")).unwrap());
+        assert_eq!(
+            Some("review".to_owned()),
+            branch::current_branch(&linked).unwrap()
+        );
+        // the linked worktree is opted in through the main worktree's marker
+        assert!(!path.join(".rusty-git-allowed").exists());
+        assert_eq!(Ok(true), utils::git_check_for_rusty_git_allowed(&linked));
+
+        // commits made in the linked worktree land in the shared refs while
+        // each worktree keeps its own HEAD and index
+        let second = test_utils::test_commit_files(&[("b", "b\n")], "commit\n", &linked).unwrap();
+        assert_eq!(
+            Some(second.clone()),
+            refs::read_ref("refs/heads/review", &repo).unwrap()
+        );
+        assert_eq!(first, utils::git_sha_from_head(&repo).unwrap());
+        assert!(idx::read_index_or_empty(&repo)
+            .unwrap()
+            .find_entry("b")
+            .is_err());
+
+        assert_eq!(
+            Err(err::Error::GitWorktreeBranchCheckedOut(
+                "review".to_owned(),
+                display(&path)
+            )),
+            add_worktree(
+                &other.path().join("again"),
+                Some("review"),
+                &WorktreeAddOptions::default(),
+                &repo
+            )
+        );
+        assert_eq!(
+            Err(err::Error::GitWorktreeBranchCheckedOut(
+                "review".to_owned(),
+                display(&path)
+            )),
+            switch::switch(Some("review"), &switch::SwitchOptions::default(), &repo)
+        );
+
+        let main_path = repo.worktree.canonicalize().unwrap();
+        let width = display(&main_path).len().max(display(&path).len());
+        assert_eq!(
+            format!(
+                "{:<width$} {} [master]\n{:<width$} {} [review]\n",
+                display(&main_path),
+                &first[..7],
+                display(&path),
+                &second[..7]
+            ),
+            list(&repo).unwrap()
+        );
+    }
+
+    #[test]
+    fn lock_remove_and_prune_worktrees() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let sha = test_utils::test_commit_files(&[("a", "a\n")], "commit\n", &repo).unwrap();
+        let other = test_utils::test_tempdir().unwrap();
+        let kept = other.path().join("kept");
+        let gone = other.path().join("gone");
+        let opts = WorktreeAddOptions {
+            detach: true,
+            ..Default::default()
+        };
+        add_worktree(&kept, Some(&sha), &opts, &repo).unwrap();
+        add_worktree(&gone, Some(&sha), &opts, &repo).unwrap();
+
+        write(kept.join("a"), "edited\n").unwrap();
+        assert_eq!(
+            Err(err::Error::GitWorktreeDirty(display(&kept))),
+            remove(&kept, false, &repo)
+        );
+        lock(&kept, Some("in review"), &repo).unwrap();
+        assert_eq!(
+            Err(err::Error::GitWorktreeLocked(display(&kept))),
+            remove(&kept, true, &repo)
+        );
+        assert_eq!(
+            Err(err::Error::GitWorktreeIsMain(display(&repo.worktree))),
+            remove(&repo.worktree, true, &repo)
+        );
+
+        remove_dir_all(&gone).unwrap();
+        assert!(list(&repo).unwrap().contains(" (detached HEAD) prunable\n"));
+        assert_eq!(
+            "Removing worktrees/gone: gitdir file points to non-existent location\n",
+            prune(&repo).unwrap()
+        );
+        unlock(&kept, &repo).unwrap();
+        remove(&kept, true, &repo).unwrap();
+        assert!(!kept.exists());
+        assert_eq!(1, list_worktrees(&repo).unwrap().len());
+        assert!(!repo.commondir.join("worktrees").exists());
+    }
+
+    #[test]
+    fn remove_refuses_worktrees_with_staged_or_unmerged_changes() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let sha = test_utils::test_commit_files(&[("a", "a\n")], "commit\n", &repo).unwrap();
+        let other = test_utils::test_tempdir().unwrap();
+        let path = other.path().join("wt");
+        let opts = WorktreeAddOptions {
+            detach: true,
+            ..Default::default()
+        };
+        add_worktree(&path, Some(&sha), &opts, &repo).unwrap();
+        let linked = obj::Repo::new(path.clone()).unwrap();
+        let dirty = || Err(err::Error::GitWorktreeDirty(display(&path)));
+
+        write(path.join("staged"), "staged\n").unwrap();
+        add::add(&["staged".to_owned()], &Default::default(), &linked).unwrap();
+        assert_eq!(dirty(), remove(&path, false, &repo));
+
+        let mut index = idx::read_index_or_empty(&linked).unwrap();
+        let mut ours = index.entries[0].clone();
+        ours.set_stage(2);
+        index.entries = vec![ours];
+        add::write_index(index, &linked).unwrap();
+        std::fs::remove_file(path.join("staged")).unwrap();
+        assert_eq!(dirty(), remove(&path, false, &repo));
+    }
+
+    #[test]
+    fn linked_worktrees_lock_and_log_their_own_pseudo_refs() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let sha = test_utils::test_commit_files(&[("a", "a\n")], "commit\n", &repo).unwrap();
+        let other = test_utils::test_tempdir().unwrap();
+        let path = other.path().join("wt");
+        add_worktree(&path, None, &WorktreeAddOptions::default(), &repo).unwrap();
+        let linked = obj::Repo::new(path).unwrap();
+        assert_eq!(
+            linked.gitdir.join("HEAD.lock"),
+            linked.git_path("HEAD.lock")
+        );
+
+        // the main worktree's HEAD being locked doesn't stop this one
+        write(repo.commondir.join("HEAD.lock"), "").unwrap();
+        let opts = switch::SwitchOptions {
+            detach: true,
+            ..Default::default()
+        };
+        switch::switch(Some(&sha), &opts, &linked).unwrap();
+        assert_eq!(Some(sha.clone()), refs::read_ref("HEAD", &linked).unwrap());
+
+        // pseudo refs aren't logged by default, the log is made by hand
+        refs::write_ref("ORIG_HEAD", &sha, &linked).unwrap();
+        create_dir_all(linked.gitdir.join("logs")).unwrap();
+        write(linked.gitdir.join("logs/ORIG_HEAD"), "").unwrap();
+        let mut transaction = updateref::RefTransaction::new("delete");
+        transaction.delete("ORIG_HEAD", None);
+        transaction.commit(&linked).unwrap();
+        assert!(!linked.gitdir.join("logs/ORIG_HEAD").exists());
+        assert!(!linked.gitdir.join("ORIG_HEAD").exists());
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+
+use crate::error as err;
+use crate::index as idx;
+use crate::objects::{self as obj, tree};
+use crate::utils;
+
+// the mode encoding is different between index entries and tree entries
+// in tree entries it is stored as the ASCII encoding of the octal encoding
+// and in index entries it's stored as a BE byte order 32 bit int.
+fn entry_to_treeleaf(path: &str, entry: &idx::IndexEntry) -> tree::TreeLeaf {
+    tree::TreeLeaf {
+        mode: format!("{:o}", entry.mode),
+        path: path.to_owned(),
+        sha: entry.sha.to_vec(),
+    }
+}
+
+/// Writes a tree object for the entries, each entry is paired with its path
+/// relative to the directory the tree represents. Sub trees are written
+/// before the tree that contains them.
+fn write_tree_level(
+    entries: &[(&str, &idx::IndexEntry)],
+    repo: &obj::Repo,
+) -> Result<sha1_smol::Digest, err::Error> {
+    let mut leaves: Vec<tree::TreeLeaf> = Vec::new();
+    let mut sub_dirs: BTreeMap<&str, Vec<(&str, &idx::IndexEntry)>> = BTreeMap::new();
+
+    for (path, entry) in entries {
+        match path.split_once('/') {
+            Some((dir, rest)) => sub_dirs.entry(dir).or_default().push((rest, entry)),
+            None => {
+                // gitlinks point at commits in another repo so they won't be
+                // in the object store
+                if entry.mode != 0o160000 {
+                    utils::git_obj_path_from_sha(&utils::get_sha_from_binary(&entry.sha), repo)
+                        .map_err(|_| err::Error::GitWriteTreeMissingObject(entry.name.clone()))?;
+                }
+                leaves.push(entry_to_treeleaf(path, entry));
+            }
+        }
+    }
+
+    for (dir, children) in sub_dirs {
+        let sha = write_tree_level(&children, repo)?;
+        leaves.push(tree::TreeLeaf {
+            mode: "40000".to_owned(),
+            path: dir.to_owned(),
+            sha: sha.bytes().to_vec(),
+        });
+    }
+
+    leaves.sort_by(|a, b| tree::tree_entry_order(&a.path, a.is_tree(), &b.path, b.is_tree()));
+    obj::write_object(
+        obj::GitObj::Tree(tree::Tree { contents: leaves }),
+        Some(repo),
+    )
+}
+
+/// Creates tree objects from the index and returns the sha of the top level
+/// tree. When a prefix is given the tree for that sub directory is written
+/// instead of the tree for the whole index.
+pub fn write_tree(
+    index: &idx::Index,
+    prefix: Option<&str>,
+    repo: &obj::Repo,
+) -> Result<String, err::Error> {
+    if let Some(unmerged) = index.entries.iter().find(|e| e.stage() != 0) {
+        return Err(err::Error::GitIndexUnmerged(unmerged.name.clone()));
+    }
+
+    let prefix = prefix.map(|p| p.trim_end_matches('/')).unwrap_or("");
+    let entries: Vec<(&str, &idx::IndexEntry)> = index
+        .entries
+        .iter()
+        .filter_map(|e| {
+            if prefix.is_empty() {
+                Some((e.name.as_str(), e))
+            } else {
+                e.name
+                    .strip_prefix(prefix)
+                    .and_then(|rest| rest.strip_prefix('/'))
+                    .map(|rest| (rest, e))
+            }
+        })
+        .collect();
+
+    if !prefix.is_empty() && entries.is_empty() {
+        return Err(err::Error::GitWriteTreeBadPrefix(prefix.to_owned()));
+    }
+    Ok(write_tree_level(&entries, repo)?.to_string())
+}
+
+#[cfg(test)]
+mod write_tree_tests {
+    use super::*;
+    use crate::cmds::add;
+    use crate::test_utils;
+    use std::fs::{create_dir_all, File};
+    use std::io::Write;
+
+    #[test]
+    fn write_tree_creates_nested_trees_in_git_order() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+
+        for name in ["a.b", "a/c.txt", "a/d/e.txt"] {
+            let path = repo.worktree.join(name);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            writeln!(File::create(path).unwrap(), "{name}").unwrap();
+        }
+        add::add(
+            &["a".to_owned(), "a.b".to_owned()],
+            &Default::default(),
+            &repo,
+        )
+        .unwrap();
+        let index = idx::read_index_or_empty(&repo).unwrap();
+
+        let root_sha = write_tree(&index, None, &repo).unwrap();
+        let root = tree::read_tree(&root_sha, &repo).unwrap();
+        let names: Vec<(&str, &str)> = root
+            .contents
+            .iter()
+            .map(|l| (l.mode.as_str(), l.path.as_str()))
+            .collect();
+        assert_eq!(vec![("100644", "a.b"), ("40000", "a")], names);
+
+        let sub_sha = write_tree(&index, Some("a/"), &repo).unwrap();
+        assert_eq!(utils::get_sha_from_binary(&root.contents[1].sha), sub_sha);
+
+        let flattened: Vec<String> = tree::flatten_tree(root, None, &repo)
+            .unwrap()
+            .into_iter()
+            .map(|l| l.path)
+            .collect();
+        assert_eq!(vec!["a.b", "a/c.txt", "a/d/e.txt"], flattened);
+
+        assert_eq!(
+            Err(err::Error::GitWriteTreeBadPrefix("b".to_owned())),
+            write_tree(&index, Some("b"), &repo)
+        );
+    }
+}
@@ -1,8 +1,15 @@
+use std::io::Read;
 use std::path::{Path, PathBuf};
 
+use crate::attributes as attr;
 use crate::cli;
-use crate::cmds::{add, checkout, commit as cmt, init, log, lstree, refs, status, tag};
+use crate::cmds::{
+    add, branch, checkattr, checkignore, checkout, commit as cmt, committree, foreachref,
+    fsmonitordaemon, init, log, lstree, readtree, reflog, refs, reset, restore, sparsecheckout,
+    status, switch, symbolicref, tag, updateindex, updateref, worktree, writetree,
+};
 use crate::error as err;
+use crate::fsmonitor;
 use crate::index as idx;
 use crate::objects::{self as obj, blob};
 use crate::utils;
@@ -18,7 +25,15 @@ fn hash_object(
     write_obj: bool,
 ) -> Result<Option<String>, err::Error> {
     let bpath: PathBuf = PathBuf::from(path);
-    let blob = blob::blob_from_path(bpath)?;
+    // files inside the worktree are converted the way add would store them
+    let worktree = std::path::absolute(&repo.worktree)?;
+    let abs_path = std::path::absolute(&bpath)?;
+    let blob = match abs_path.strip_prefix(&worktree).ok().and_then(|p| p.to_str()) {
+        Some(name) if !name.is_empty() => {
+            blob::blob_from_worktree_file(name, &attr::AttrRules::new(&repo)?, None, &repo)?
+        }
+        _ => blob::blob_from_path(bpath)?,
+    };
 
     // by passing None to write_obj it will only return the hash, no write
     let repo_arg = if write_obj { Some(&repo) } else { None };
@@ -56,7 +71,22 @@ fn lstree(sha: String, repo: obj::Repo) -> Result<Option<String>, err::Error> {
     }
 }
 
-fn checkout(sha: &str, dir: &Path, repo: obj::Repo) -> Result<Option<String>, err::Error> {
+fn checkout(
+    sha: &str,
+    dir: &Option<String>,
+    new_branch: &Option<String>,
+    repo: obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    let Some(dir) = dir else {
+        // a branch is switched to, anything else detaches HEAD
+        let is_branch = refs::read_ref(&format!("refs/heads/{sha}"), &repo)?.is_some();
+        let opts = switch::SwitchOptions {
+            create: new_branch.to_owned(),
+            detach: new_branch.is_none() && !is_branch,
+        };
+        return switch(&Some(sha.to_owned()), opts, repo);
+    };
+    let dir = Path::new(dir);
     checkout::dir_ok_for_checkout(dir)?;
     let obj = obj::read_object(sha, &repo)?;
     match obj {
@@ -72,11 +102,79 @@ fn checkout(sha: &str, dir: &Path, repo: obj::Repo) -> Result<Option<String>, er
     Ok(None)
 }
 
+fn switch(
+    target: &Option<String>,
+    opts: switch::SwitchOptions,
+    repo: obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    utils::git_check_for_rusty_git_allowed(&repo)?;
+    Ok(Some(switch::switch(target.as_deref(), &opts, &repo)?))
+}
+
+fn restore(
+    pathspecs: &[String],
+    opts: restore::RestoreOptions,
+    repo: obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    utils::git_check_for_rusty_git_allowed(&repo)?;
+    restore::restore(pathspecs, &opts, &repo)?;
+    Ok(None)
+}
+
+fn reset(
+    rev: &Option<String>,
+    paths: &[String],
+    mode: Option<reset::ResetMode>,
+    repo: obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    utils::git_check_for_rusty_git_allowed(&repo)?;
+    if !paths.is_empty() {
+        let with_paths = |mode: &str| Err(err::Error::GitResetPathsWithMode(mode.to_owned()));
+        return match mode {
+            Some(reset::ResetMode::Soft) => with_paths("soft"),
+            Some(reset::ResetMode::Hard) => with_paths("hard"),
+            _ => {
+                reset::reset_paths(rev.as_deref(), paths, &repo)?;
+                Ok(None)
+            }
+        };
+    }
+    let output = reset::reset(rev.as_deref(), mode.unwrap_or_default(), &repo)?;
+    Ok((!output.is_empty()).then_some(output))
+}
+
 fn show_ref(repo: obj::Repo) -> Result<Option<String>, err::Error> {
     let refs = refs::gather_refs(None, &repo)?.concat();
     Ok(Some(refs))
 }
 
+fn for_each_ref(
+    patterns: &[String],
+    opts: foreachref::ForEachRefOptions,
+    repo: obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    Ok(Some(foreachref::for_each_ref(patterns, &opts, &repo)?))
+}
+
+fn check_ref_format(
+    refname: &str,
+    branch: bool,
+    opts: refs::RefFormatOptions,
+) -> Result<Option<String>, err::Error> {
+    if branch {
+        branch::check_branch_name(refname)?;
+        return Ok(Some(format!("{refname}\n")));
+    }
+    let name = refs::check_ref_format(refname, opts)?;
+    Ok(opts.normalize.then(|| format!("{name}\n")))
+}
+
+fn pack_refs(all: bool, repo: obj::Repo) -> Result<Option<String>, err::Error> {
+    utils::git_check_for_rusty_git_allowed(&repo)?;
+    refs::pack_refs(all, &repo)?;
+    Ok(None)
+}
+
 fn tag(
     name: &Option<String>,
     object: &String,
@@ -106,44 +204,349 @@ pub fn ls_files(repo: obj::Repo) -> Result<Option<String>, err::Error> {
     Ok(Some(file_names.concat()))
 }
 
-pub fn status(repo: obj::Repo) -> Result<Option<String>, err::Error> {
-    let status = status::status(&repo)?;
-    Ok(Some(status))
+pub fn status(opts: status::StatusOptions, repo: obj::Repo) -> Result<Option<String>, err::Error> {
+    let status = status::status(&opts, &repo)?;
+    if opts.format == status::StatusFormat::Long {
+        return Ok(Some(status));
+    }
+    // the output is printed with a newline after it
+    let status = status.strip_suffix('\n').unwrap_or(&status).to_owned();
+    Ok((!status.is_empty()).then_some(status))
 }
 
-pub fn add(file_name: String, repo: obj::Repo) -> Result<Option<String>, err::Error> {
+pub fn add(
+    pathspecs: &[String],
+    opts: add::AddOptions,
+    repo: obj::Repo,
+) -> Result<Option<String>, err::Error> {
     // don't mess with index unless user opts in
-    utils::git_check_for_rusty_git_allowed(&repo)?;
+    if !opts.dry_run {
+        utils::git_check_for_rusty_git_allowed(&repo)?;
+    }
+    add::add(pathspecs, &opts, &repo)
+}
+
+// maps a pair of --<flag>/--no-<flag> args to whether the flag should be
+// set, cleared or left alone
+fn flag_pair(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
 
-    // 'git add' hashes the file and adds it to .git/objects
-    hash_object(file_name.clone(), repo.clone(), true)?;
+fn update_index(
+    paths: &[String],
+    opts: updateindex::UpdateIndexOptions,
+    read_index_info: bool,
+    repo: obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    // don't mess with index unless user opts in
+    utils::git_check_for_rusty_git_allowed(&repo)?;
 
-    let index_exists = utils::git_index_exists(&repo);
-    if index_exists {
-        let _file_exists = utils::build_path(repo.worktree.clone(), &file_name)?;
-        add::update_index(&repo, &file_name)?;
+    let index_info = if read_index_info {
+        let mut input = String::new();
+        std::io::stdin().read_to_string(&mut input)?;
+        Some(input)
     } else {
-        // index doesn't exist yet and must be created
-        let entry = add::file_to_index_entry(&file_name, &repo)?;
-        let index = idx::Index::new(entry)?;
-        add::write_index(index, &repo)?;
+        None
+    };
+    updateindex::update_index(paths, &opts, index_info.as_deref(), &repo)
+}
+
+fn write_tree(prefix: &Option<String>, repo: obj::Repo) -> Result<Option<String>, err::Error> {
+    utils::git_check_for_rusty_git_allowed(&repo)?;
+    let index = idx::read_index_or_empty(&repo)?;
+    Ok(Some(writetree::write_tree(&index, prefix.as_deref(), &repo)?))
+}
+
+fn read_tree(
+    trees: &[String],
+    opts: readtree::ReadTreeOptions,
+    repo: obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    // don't mess with index unless user opts in
+    utils::git_check_for_rusty_git_allowed(&repo)?;
+
+    let mut tree_shas = Vec::new();
+    for tree in trees {
+        let sha = refs::rev_parse(tree, &repo)?;
+        tree_shas.push(refs::peel(&sha, "tree", &repo)?);
     }
-    Ok(None)
+    readtree::read_tree(&tree_shas, &opts, &repo)
+}
+
+fn commit_tree(
+    tree: &str,
+    parents: &[String],
+    msgs: &[String],
+    repo: obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    utils::git_check_for_rusty_git_allowed(&repo)?;
+
+    let tree_sha = refs::rev_parse(tree, &repo)?;
+    let mut parent_shas = Vec::new();
+    for parent in parents {
+        let sha = refs::rev_parse(parent, &repo)?;
+        parent_shas.push(refs::peel(&sha, "commit", &repo)?);
+    }
+
+    let msg = if msgs.is_empty() {
+        let mut input = String::new();
+        std::io::stdin().read_to_string(&mut input)?;
+        input
+    } else {
+        committree::format_commit_msg(msgs)
+    };
+    Ok(Some(committree::commit_tree(
+        &tree_sha,
+        &parent_shas,
+        &msg,
+        &repo,
+    )?))
 }
 
 fn commit(msg: String, repo: obj::Repo) -> Result<Option<String>, err::Error> {
     // don't allow commits unless user opts in
     utils::git_check_for_rusty_git_allowed(&repo)?;
 
-    cmt::commit(msg, repo)?;
+    cmt::commit(msg, repo)
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct BranchOptions {
+    all: bool,
+    verbose: bool,
+    delete: bool,
+    force_delete: bool,
+    rename: bool,
+    show_current: bool,
+}
+
+fn branch(
+    args: &[String],
+    opts: BranchOptions,
+    repo: obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    if opts.show_current {
+        return branch::current_branch(&repo);
+    }
+    if opts.delete || opts.force_delete {
+        if args.is_empty() {
+            return Err(err::Error::GitBranchUsage("branch name required".to_owned()));
+        }
+        utils::git_check_for_rusty_git_allowed(&repo)?;
+        let mut output = String::new();
+        for name in args {
+            output.push_str(&branch::delete_branch(name, opts.force_delete, &repo)?);
+        }
+        return Ok(Some(output));
+    }
+    if opts.rename {
+        utils::git_check_for_rusty_git_allowed(&repo)?;
+        match args {
+            [new] => branch::rename_branch(None, new, &repo)?,
+            [old, new] => branch::rename_branch(Some(old), new, &repo)?,
+            _ => {
+                return Err(err::Error::GitBranchUsage(
+                    "-m takes [<old-branch>] <new-branch>".to_owned(),
+                ))
+            }
+        }
+        return Ok(None);
+    }
+    match args {
+        [] => Ok(Some(branch::list_branches(opts.all, opts.verbose, &repo)?)),
+        [name] | [name, _] => {
+            utils::git_check_for_rusty_git_allowed(&repo)?;
+            branch::create_branch(name, args.get(1).map(|s| s.as_str()), &repo)?;
+            Ok(None)
+        }
+        _ => Err(err::Error::GitBranchUsage(
+            "expected <branch-name> [<start-point>]".to_owned(),
+        )),
+    }
+}
+
+fn reflog(action: &Option<cli::ReflogCmd>, repo: obj::Repo) -> Result<Option<String>, err::Error> {
+    match action {
+        None => Ok(Some(reflog::show("HEAD", &repo)?)),
+        Some(cli::ReflogCmd::Show { reference }) => Ok(Some(reflog::show(reference, &repo)?)),
+        Some(cli::ReflogCmd::Expire { refs, all, expire }) => {
+            utils::git_check_for_rusty_git_allowed(&repo)?;
+            reflog::expire(refs, *all, expire.as_deref(), &repo)?;
+            Ok(None)
+        }
+        Some(cli::ReflogCmd::Delete { entries }) => {
+            utils::git_check_for_rusty_git_allowed(&repo)?;
+            reflog::delete(entries, &repo)?;
+            Ok(None)
+        }
+    }
+}
+
+fn update_ref(
+    args: &[String],
+    delete: bool,
+    stdin: bool,
+    reason: &Option<String>,
+    repo: obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    utils::git_check_for_rusty_git_allowed(&repo)?;
+    let msg = reason.as_deref().unwrap_or("");
+    if stdin {
+        let mut input = String::new();
+        std::io::stdin().read_to_string(&mut input)?;
+        let output = updateref::update_from_stdin(&input, msg, &repo)?;
+        return Ok((!output.is_empty()).then_some(output));
+    }
+    updateref::update_from_args(args, delete, msg, &repo)?;
+    Ok(None)
+}
+
+fn symbolic_ref(
+    name: &str,
+    target: &Option<String>,
+    delete: bool,
+    short: bool,
+    reason: &Option<String>,
+    repo: obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    if delete || target.is_some() {
+        utils::git_check_for_rusty_git_allowed(&repo)?;
+    }
+    match (delete, target) {
+        (true, _) => symbolicref::delete_symbolic_ref(name, &repo)?,
+        (false, Some(target)) => {
+            symbolicref::set_symbolic_ref(name, target, reason.as_deref(), &repo)?
+        }
+        (false, None) => {
+            let target = symbolicref::read_symbolic_ref(name, short, &repo)?;
+            return Ok(Some(format!("{target}\n")));
+        }
+    }
     Ok(None)
 }
 
+fn sparse_checkout(
+    action: &cli::SparseCheckoutCmd,
+    mut repo: obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    use cli::SparseCheckoutCmd as Sc;
+
+    // list only reads the patterns, everything else changes the worktree
+    if *action != Sc::List {
+        utils::git_check_for_rusty_git_allowed(&repo)?;
+    }
+    match action {
+        Sc::Init { cone, no_cone } => sparsecheckout::init(flag_pair(*cone, *no_cone), &mut repo),
+        Sc::Set {
+            patterns,
+            cone,
+            no_cone,
+        } => sparsecheckout::set(patterns, flag_pair(*cone, *no_cone), &mut repo),
+        Sc::Add { patterns } => sparsecheckout::add(patterns, &repo),
+        Sc::List => sparsecheckout::list(&repo),
+        Sc::Reapply => sparsecheckout::reapply(&repo),
+        Sc::Disable => sparsecheckout::disable(&mut repo),
+    }
+}
+
+fn fsmonitor_daemon(
+    action: &cli::FsmonitorDaemonCmd,
+    repo: obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    use cli::FsmonitorDaemonCmd as Fsm;
+
+    match action {
+        Fsm::Start => fsmonitor::start_daemon(&repo)?,
+        Fsm::Run => fsmonitor::run_daemon(&repo)?,
+        Fsm::Stop => fsmonitor::stop_daemon(&repo)?,
+        Fsm::Status => return Ok(Some(fsmonitordaemon::status(&repo)?)),
+    }
+    Ok(None)
+}
+
+fn worktree(action: &cli::WorktreeCmd, repo: obj::Repo) -> Result<Option<String>, err::Error> {
+    use cli::WorktreeCmd as Wt;
+
+    if *action != Wt::List {
+        utils::git_check_for_rusty_git_allowed(&repo)?;
+    }
+    match action {
+        Wt::Add {
+            path,
+            commitish,
+            create,
+            detach,
+        } => {
+            let opts = worktree::WorktreeAddOptions {
+                create: create.to_owned(),
+                detach: *detach,
+            };
+            let commitish = commitish.as_deref();
+            let output = worktree::add_worktree(Path::new(path), commitish, &opts, &repo)?;
+            Ok(Some(output))
+        }
+        Wt::List => Ok(Some(worktree::list(&repo)?)),
+        Wt::Lock { path, reason } => {
+            worktree::lock(Path::new(path), reason.as_deref(), &repo)?;
+            Ok(None)
+        }
+        Wt::Unlock { path } => {
+            worktree::unlock(Path::new(path), &repo)?;
+            Ok(None)
+        }
+        Wt::Remove { path, force } => {
+            worktree::remove(Path::new(path), *force, &repo)?;
+            Ok(None)
+        }
+        Wt::Prune => Ok(Some(worktree::prune(&repo)?)),
+    }
+}
+
+fn check_attr(
+    all: bool,
+    args: &[String],
+    paths: &[String],
+    repo: obj::Repo,
+) -> Result<Option<String>, err::Error> {
+    let usage = |msg: &str| Err(err::Error::GitCheckAttrUsage(msg.to_owned()));
+    // without '--' the first arg is the attribute and the rest are paths
+    let (attrs, paths) = match (all, paths.is_empty()) {
+        (true, _) if !args.is_empty() && !paths.is_empty() => {
+            return usage("Attributes and --all both specified")
+        }
+        (true, true) => (checkattr::CheckAttrs::All, args),
+        (true, false) => (checkattr::CheckAttrs::All, paths),
+        (false, false) => (checkattr::CheckAttrs::Names(args.to_vec()), paths),
+        (false, true) => match args.split_first() {
+            Some((attr, paths)) => (checkattr::CheckAttrs::Names(vec![attr.to_owned()]), paths),
+            None => return usage("No attribute specified"),
+        },
+    };
+    if paths.is_empty() {
+        return usage("No file specified");
+    }
+    if let checkattr::CheckAttrs::Names(names) = &attrs {
+        if names.is_empty() {
+            return usage("No attribute specified");
+        }
+    }
+    let output = checkattr::check_attr(&attrs, paths, &repo)?;
+    Ok((!output.is_empty()).then_some(output))
+}
+
 pub fn run_cmd(cmd: &cli::Cli, write_obj: bool) -> Result<Option<String>, err::Error> {
     let command = &cmd.command;
 
     // unwrap calls to repo below safe because of this check
-    let repo: Option<obj::Repo> = if cmd.command != cli::GitCmd::Init {
+    let needs_repo = !matches!(
+        cmd.command,
+        cli::GitCmd::Init | cli::GitCmd::CheckRefFormat { .. }
+    );
+    let repo: Option<obj::Repo> = if needs_repo {
         Some(obj::Repo::new(PathBuf::from(cmd.repo_path.to_owned()))?)
     } else {
         None
@@ -155,17 +558,215 @@ pub fn run_cmd(cmd: &cli::Cli, write_obj: bool) -> Result<Option<String>, err::E
         cli::GitCmd::CatFile { sha } => cat_file(sha.to_owned(), repo.unwrap()),
         cli::GitCmd::Log { sha } => log(sha.to_owned(), repo.unwrap()),
         cli::GitCmd::LsTree { sha } => lstree(sha.to_owned(), repo.unwrap()),
-        cli::GitCmd::Checkout { sha, dir } => checkout(sha, Path::new(dir), repo.unwrap()),
+        cli::GitCmd::Checkout {
+            sha,
+            dir,
+            new_branch,
+        } => checkout(sha, dir, new_branch, repo.unwrap()),
+        cli::GitCmd::Switch {
+            target,
+            create,
+            detach,
+        } => {
+            let opts = switch::SwitchOptions {
+                create: create.to_owned(),
+                detach: *detach,
+            };
+            switch(target, opts, repo.unwrap())
+        }
+        cli::GitCmd::Restore {
+            pathspecs,
+            staged,
+            worktree,
+            source,
+        } => {
+            let opts = restore::RestoreOptions {
+                staged: *staged,
+                worktree: *worktree,
+                source: source.to_owned(),
+            };
+            restore(pathspecs, opts, repo.unwrap())
+        }
+        cli::GitCmd::Reset {
+            rev,
+            paths,
+            soft,
+            mixed,
+            hard,
+        } => {
+            let mode = match (soft, mixed, hard) {
+                (true, _, _) => Some(reset::ResetMode::Soft),
+                (_, true, _) => Some(reset::ResetMode::Mixed),
+                (_, _, true) => Some(reset::ResetMode::Hard),
+                _ => None,
+            };
+            reset(rev, paths, mode, repo.unwrap())
+        }
         cli::GitCmd::ShowRef => show_ref(repo.unwrap()),
+        cli::GitCmd::ForEachRef {
+            patterns,
+            format,
+            sort,
+            count,
+            points_at,
+            merged,
+            contains,
+        } => {
+            let opts = foreachref::ForEachRefOptions {
+                format: format.to_owned(),
+                sort: sort.to_owned(),
+                count: *count,
+                points_at: points_at.to_owned(),
+                merged: merged.to_owned(),
+                contains: contains.to_owned(),
+            };
+            for_each_ref(patterns, opts, repo.unwrap())
+        }
+        cli::GitCmd::CheckRefFormat {
+            refname,
+            branch,
+            normalize,
+            allow_onelevel,
+            refspec_pattern,
+        } => {
+            let opts = refs::RefFormatOptions {
+                allow_onelevel: *allow_onelevel,
+                refspec_pattern: *refspec_pattern,
+                normalize: *normalize,
+            };
+            check_ref_format(refname, *branch, opts)
+        }
+        cli::GitCmd::PackRefs { all } => pack_refs(*all, repo.unwrap()),
         cli::GitCmd::Tag {
             name,
             object,
             add_object,
         } => tag(name, object, add_object, repo.unwrap()),
         cli::GitCmd::LsFiles => ls_files(repo.unwrap()),
-        cli::GitCmd::Status => status(repo.unwrap()),
-        cli::GitCmd::Add { file_name } => add(file_name.to_owned(), repo.unwrap()),
+        cli::GitCmd::Status {
+            ignored,
+            short,
+            porcelain,
+            branch,
+            null_terminated,
+        } => {
+            let format = match (porcelain.as_deref(), *short) {
+                (Some("v2"), _) => status::StatusFormat::PorcelainV2,
+                (Some(_), _) => status::StatusFormat::PorcelainV1,
+                (None, true) => status::StatusFormat::Short,
+                (None, false) if *null_terminated => status::StatusFormat::PorcelainV1,
+                (None, false) => status::StatusFormat::Long,
+            };
+            let opts = status::StatusOptions {
+                ignored: *ignored,
+                format,
+                branch: *branch,
+                null_terminated: *null_terminated,
+            };
+            status(opts, repo.unwrap())
+        }
+        cli::GitCmd::Add {
+            pathspecs,
+            all,
+            update,
+            dry_run,
+            force,
+            sparse,
+        } => {
+            let opts = add::AddOptions {
+                all: *all,
+                update: *update,
+                dry_run: *dry_run,
+                force: *force,
+                sparse: *sparse,
+            };
+            add(pathspecs, opts, repo.unwrap())
+        }
+        cli::GitCmd::UpdateIndex {
+            paths,
+            add,
+            remove,
+            refresh,
+            cacheinfo,
+            chmod,
+            assume_unchanged,
+            no_assume_unchanged,
+            skip_worktree,
+            no_skip_worktree,
+            index_info,
+        } => {
+            let opts = updateindex::UpdateIndexOptions {
+                add: *add,
+                remove: *remove,
+                refresh: *refresh,
+                cacheinfo: cacheinfo.to_owned(),
+                chmod: chmod.to_owned(),
+                assume_unchanged: flag_pair(*assume_unchanged, *no_assume_unchanged),
+                skip_worktree: flag_pair(*skip_worktree, *no_skip_worktree),
+            };
+            update_index(paths, opts, *index_info, repo.unwrap())
+        }
+        cli::GitCmd::WriteTree { prefix } => write_tree(prefix, repo.unwrap()),
+        cli::GitCmd::ReadTree {
+            trees,
+            merge,
+            prefix,
+        } => {
+            let opts = readtree::ReadTreeOptions {
+                merge: *merge,
+                prefix: prefix.to_owned(),
+            };
+            read_tree(trees, opts, repo.unwrap())
+        }
+        cli::GitCmd::CommitTree {
+            tree,
+            parents,
+            msgs,
+        } => commit_tree(tree, parents, msgs, repo.unwrap()),
         cli::GitCmd::Commit { msg } => commit(msg.to_string(), repo.unwrap()),
+        cli::GitCmd::Branch {
+            args,
+            all,
+            verbose,
+            delete,
+            force_delete,
+            rename,
+            show_current,
+        } => {
+            let opts = BranchOptions {
+                all: *all,
+                verbose: *verbose,
+                delete: *delete,
+                force_delete: *force_delete,
+                rename: *rename,
+                show_current: *show_current,
+            };
+            branch(args, opts, repo.unwrap())
+        }
+        cli::GitCmd::UpdateRef {
+            args,
+            delete,
+            stdin,
+            reason,
+        } => update_ref(args, *delete, *stdin, reason, repo.unwrap()),
+        cli::GitCmd::SymbolicRef {
+            name,
+            target,
+            delete,
+            short,
+            reason,
+        } => symbolic_ref(name, target, *delete, *short, reason, repo.unwrap()),
+        cli::GitCmd::Reflog { action } => reflog(action, repo.unwrap()),
+        cli::GitCmd::SparseCheckout { action } => sparse_checkout(action, repo.unwrap()),
+        cli::GitCmd::Worktree { action } => worktree(action, repo.unwrap()),
+        cli::GitCmd::FsmonitorDaemon { action } => fsmonitor_daemon(action, repo.unwrap()),
+        cli::GitCmd::CheckIgnore { verbose, paths } => {
+            let output = checkignore::check_ignore(paths, *verbose, &repo.unwrap())?;
+            Ok((!output.is_empty()).then_some(output))
+        }
+        cli::GitCmd::CheckAttr { all, args, paths } => {
+            check_attr(*all, args, paths, repo.unwrap())
+        }
     }
 }
 
@@ -212,13 +813,33 @@ mod object_tests {
         Ok(())
     }
 
+    #[test]
+    fn hash_object_follows_symlinks() -> Result<(), err::Error> {
+        let worktree = test_utils::test_gitdir().unwrap();
+        let mut tmpfile = File::create(worktree.path().join("tempfoo"))?;
+        writeln!(tmpfile, "foobar")?;
+        std::os::unix::fs::symlink("tempfoo", worktree.path().join("link"))?;
+
+        let cmd = cli::Cli {
+            command: cli::GitCmd::HashObject {
+                path: worktree.path().join("link").to_str().unwrap().to_owned(),
+            },
+            repo_path: worktree.path().to_str().unwrap().to_owned(),
+        };
+        assert_eq!(
+            Some("323fae03f4606ea9991df8befbb2fca795e648fa".to_owned()),
+            run_cmd(&cmd, false)?
+        );
+        Ok(())
+    }
+
     #[test]
     fn can_read_sha_from_head() -> Result<(), err::Error> {
         // TODO: expand this test to cover the log command when added
         let worktree = test_utils::test_gitdir().unwrap();
         let repo = obj::Repo::new(worktree.path().to_path_buf())?;
 
-        test_utils::test_add_dummy_commit_and_update_ref_heads(&"fake-head-sha", &repo)?;
+        test_utils::test_add_dummy_commit_and_update_ref_heads("fake-head-sha", &repo)?;
 
         let head_sha = utils::git_sha_from_head(&repo)?;
         assert_eq!("fake-head-sha", head_sha);
@@ -241,7 +862,13 @@ mod object_tests {
         let new_file = File::create(repo.worktree.join(new_file_name));
         writeln!(new_file.unwrap(), "hahaha").unwrap();
 
-        let updated_index = add::add_entry_to_index(&repo, new_file_name).unwrap();
+        add::add(
+            &[new_file_name.to_owned()],
+            &add::AddOptions::default(),
+            &repo,
+        )
+        .unwrap();
+        let updated_index = idx::read_index_or_empty(&repo).unwrap();
         let mut updated_file_names: HashSet<String> = HashSet::new();
         for e in updated_index.entries {
             updated_file_names.insert(e.name);
@@ -272,7 +899,12 @@ mod object_tests {
 
         let add_cmd = cli::Cli {
             command: cli::GitCmd::Add {
-                file_name: new_file_full_path.clone().to_str().unwrap().to_owned(),
+                pathspecs: vec![new_file_full_path.clone().to_str().unwrap().to_owned()],
+                all: false,
+                update: false,
+                dry_run: false,
+                force: false,
+                sparse: false,
             },
             repo_path: repo.worktree.to_str().unwrap().to_owned(),
         };
@@ -296,8 +928,9 @@ mod object_tests {
         let index = read(gitdir.path().join(".git/index")).unwrap();
         let parsed_index = idx::parse_git_index(&index).unwrap();
         assert_eq!(1, parsed_index.entries.len());
+        // index entries are stored relative to the top of the worktree
         assert_eq!(
-            new_file_full_path.to_str().unwrap(),
+            new_file_name,
             parsed_index.entries.first().unwrap().name.as_str()
         );
 
@@ -0,0 +1,262 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::error as err;
+use crate::objects as obj;
+
+// a config key as written on the command line, e.g. core.bare or
+// remote.origin.url. Section and variable names are case insensitive,
+// subsection names are not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ConfigKey {
+    section: String,
+    subsection: Option<String>,
+    name: String,
+}
+
+impl ConfigKey {
+    fn parse(key: &str) -> Result<ConfigKey, err::Error> {
+        let invalid = || err::Error::GitConfigInvalidKey(key.to_owned());
+        let (section, rest) = key.split_once('.').ok_or_else(invalid)?;
+        let (subsection, name) = match rest.rsplit_once('.') {
+            Some((sub, name)) => (Some(sub.to_owned()), name),
+            None => (None, rest),
+        };
+        if section.is_empty() || name.is_empty() {
+            return Err(invalid());
+        }
+        Ok(ConfigKey {
+            section: section.to_lowercase(),
+            subsection,
+            name: name.to_lowercase(),
+        })
+    }
+
+    fn header(&self) -> String {
+        match &self.subsection {
+            Some(sub) => format!("[{} \"{}\"]", self.section, sub),
+            None => format!("[{}]", self.section),
+        }
+    }
+}
+
+// parses a section header line like [core] or [remote "origin"], the old
+// [branch.name] syntax is also accepted
+fn parse_section_header(line: &str) -> Option<(String, Option<String>)> {
+    let inner = line.trim().strip_prefix('[')?.split(']').next()?;
+    match inner.split_once(char::is_whitespace) {
+        Some((section, sub)) => {
+            let sub = sub.trim().trim_matches('"').replace("\\\"", "\"");
+            Some((section.to_lowercase(), Some(sub)))
+        }
+        None => match inner.split_once('.') {
+            Some((section, sub)) => Some((section.to_lowercase(), Some(sub.to_lowercase()))),
+            None => Some((inner.to_lowercase(), None)),
+        },
+    }
+}
+
+// strips quotes, escapes and trailing comments from a value
+fn parse_value(raw: &str) -> String {
+    let mut value = String::new();
+    let mut in_quotes = false;
+    let mut chars = raw.trim().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '#' | ';' if !in_quotes => break,
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(other) => value.push(other),
+                None => {}
+            },
+            _ => value.push(c),
+        }
+    }
+    if in_quotes {
+        value
+    } else {
+        value.trim_end().to_owned()
+    }
+}
+
+// a variable line is either "name = value" or just "name" which is
+// shorthand for true
+fn parse_variable(line: &str) -> Option<(String, Option<String>)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with(';') || line.starts_with('[') {
+        return None;
+    }
+    match line.split_once('=') {
+        Some((name, value)) => Some((name.trim().to_lowercase(), Some(parse_value(value)))),
+        None => Some((line.to_lowercase(), None)),
+    }
+}
+
+// returns the index of every line holding the key along with its value
+fn find_key(conf: &str, key: &ConfigKey) -> Vec<(usize, Option<String>)> {
+    let mut found = Vec::new();
+    let mut in_section = false;
+    for (i, line) in conf.lines().enumerate() {
+        if let Some((section, sub)) = parse_section_header(line) {
+            in_section = section == key.section && sub == key.subsection;
+        } else if in_section {
+            if let Some((name, value)) = parse_variable(line) {
+                if name == key.name {
+                    found.push((i, value));
+                }
+            }
+        }
+    }
+    found
+}
+
+/// Returns the last value set for the key, a key without a value is
+/// returned as "true"
+pub fn get(conf: &str, key: &str) -> Result<Option<String>, err::Error> {
+    let key = ConfigKey::parse(key)?;
+    Ok(find_key(conf, &key)
+        .pop()
+        .map(|(_, value)| value.unwrap_or_else(|| "true".to_owned())))
+}
+
+/// Converts a config value to a boolean using the same spellings git
+/// accepts, the key is only used in the error
+pub fn parse_bool(key: &str, value: &str) -> Result<bool, err::Error> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" | "" => Ok(false),
+        _ => Err(err::Error::GitConfigInvalidBool(
+            key.to_owned(),
+            value.to_owned(),
+        )),
+    }
+}
+
+/// Reads the key as a boolean
+pub fn get_bool(conf: &str, key: &str) -> Result<Option<bool>, err::Error> {
+    match get(conf, key)? {
+        None => Ok(None),
+        Some(value) => Ok(Some(parse_bool(key, &value)?)),
+    }
+}
+
+/// Reads the key as a path, a leading ~/ is the home dir and a relative
+/// path is relative to the top of the worktree
+pub fn get_path(repo: &obj::Repo, key: &str) -> Result<Option<PathBuf>, err::Error> {
+    let Some(path) = get(&repo.gitconf, key)? else {
+        return Ok(None);
+    };
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Ok(Some(PathBuf::from(home).join(rest))),
+        _ => Ok(Some(repo.worktree.join(path))),
+    }
+}
+
+fn format_value(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    if escaped != escaped.trim() || escaped.contains(['#', ';']) {
+        format!("\"{}\"", escaped)
+    } else {
+        escaped
+    }
+}
+
+/// Returns the config text with the key set to the value. An existing
+/// entry for the key is replaced in place, otherwise the entry is added to
+/// the end of the key's section, creating the section when needed.
+pub fn set_value(conf: &str, key: &str, value: &str) -> Result<String, err::Error> {
+    let key = ConfigKey::parse(key)?;
+    let mut lines: Vec<String> = conf.lines().map(|l| l.to_owned()).collect();
+    let entry = format!("\t{} = {}", key.name, format_value(value));
+
+    if let Some((i, _)) = find_key(conf, &key).pop() {
+        lines[i] = entry;
+    } else {
+        let header = Some((key.section.clone(), key.subsection.clone()));
+        let section_end = lines
+            .iter()
+            .rposition(|l| parse_section_header(l) == header)
+            .map(|start| {
+                lines[start + 1..]
+                    .iter()
+                    .position(|l| parse_section_header(l).is_some())
+                    .map(|n| start + 1 + n)
+                    .unwrap_or(lines.len())
+            });
+        match section_end {
+            Some(end) => lines.insert(end, entry),
+            None => {
+                lines.push(key.header());
+                lines.push(entry);
+            }
+        }
+    }
+
+    let mut output = lines.join("\n");
+    output.push('\n');
+    Ok(output)
+}
+
+/// Sets the key in .git/config, the repo's copy of the config is updated
+/// to match the file
+pub fn set(repo: &mut obj::Repo, key: &str, value: &str) -> Result<(), err::Error> {
+    let conf = set_value(&repo.gitconf, key, value)?;
+    let mut config_file = File::create(repo.git_path("config"))?;
+    config_file.write_all(conf.as_bytes())?;
+    repo.gitconf = conf;
+    Ok(())
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+    use crate::cmds::init;
+
+    #[test]
+    fn get_reads_sections_subsections_and_bools() {
+        let conf = "[core]\n\tbare = false\n\tFileMode = TRUE ; a comment\n\
+                    [remote \"origin\"]\n\turl = \"/tmp/a b\"\n[core]\n\tbare\n";
+        assert_eq!(Some(true), get_bool(conf, "core.bare").unwrap());
+        assert_eq!(Some(true), get_bool(conf, "core.filemode").unwrap());
+        assert_eq!(
+            Some("/tmp/a b".to_owned()),
+            get(conf, "remote.origin.url").unwrap()
+        );
+        assert_eq!(None, get(conf, "remote.Origin.url").unwrap());
+        assert_eq!(
+            Err(err::Error::GitConfigInvalidBool(
+                "remote.origin.url".to_owned(),
+                "/tmp/a b".to_owned()
+            )),
+            get_bool(conf, "remote.origin.url")
+        );
+        assert_eq!(
+            Err(err::Error::GitConfigInvalidKey("core".to_owned())),
+            get(conf, "core")
+        );
+    }
+
+    #[test]
+    fn set_value_replaces_or_appends_entries() {
+        let conf = init::default_repo_config();
+        let conf = set_value(conf, "core.bare", "true").unwrap();
+        let conf = set_value(&conf, "core.sparseCheckout", "true").unwrap();
+        let conf = set_value(&conf, "user.name", "A U Thor").unwrap();
+        assert_eq!(Some(true), get_bool(&conf, "core.bare").unwrap());
+        assert_eq!(Some(true), get_bool(&conf, "core.sparsecheckout").unwrap());
+        assert_eq!(
+            Some("A U Thor".to_owned()),
+            get(&conf, "user.name").unwrap()
+        );
+        // new core entries go in the existing core section
+        assert_eq!(1, conf.matches("[core]").count());
+        assert!(conf.ends_with("[user]\n\tname = A U Thor\n"));
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+
+use crate::attributes::{self as attr, AttrState};
+use crate::config;
+use crate::error as err;
+use crate::filter;
+use crate::objects as obj;
+
+/// How line endings of a path are converted, decided by the text and eol
+/// attributes falling back to core.autocrlf
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CrlfAction {
+    Binary,
+    // text files stored with LF, checked out with core.eol or core.autocrlf
+    Text,
+    TextInput,
+    TextCrlf,
+    // like the text actions but only for files that don't look binary
+    Auto,
+    AutoInput,
+    AutoCrlf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AutoCrlf {
+    True,
+    False,
+    Input,
+}
+
+fn auto_crlf(repo: &obj::Repo) -> Result<AutoCrlf, err::Error> {
+    match config::get(&repo.gitconf, "core.autocrlf")?.as_deref() {
+        None => Ok(AutoCrlf::False),
+        Some("input") => Ok(AutoCrlf::Input),
+        Some(value) if config::parse_bool("core.autocrlf", value)? => Ok(AutoCrlf::True),
+        Some(_) => Ok(AutoCrlf::False),
+    }
+}
+
+fn crlf_action(
+    attrs: &BTreeMap<String, AttrState>,
+    repo: &obj::Repo,
+) -> Result<CrlfAction, err::Error> {
+    let eol = match attrs.get("eol") {
+        Some(AttrState::Value(eol)) if eol == "lf" || eol == "crlf" => Some(eol.as_str()),
+        _ => None,
+    };
+    let text = match attrs.get("text") {
+        Some(AttrState::Unset) => return Ok(CrlfAction::Binary),
+        Some(AttrState::Set) => Some("set"),
+        Some(AttrState::Value(v)) if v == "auto" || v == "input" => Some(v.as_str()),
+        _ => None,
+    };
+    let action = match (text, eol) {
+        (Some("auto"), Some("crlf")) => CrlfAction::AutoCrlf,
+        (Some("auto"), Some(_)) => CrlfAction::AutoInput,
+        (Some("auto"), None) => match auto_crlf(repo)? {
+            AutoCrlf::True => CrlfAction::AutoCrlf,
+            AutoCrlf::Input => CrlfAction::AutoInput,
+            AutoCrlf::False => CrlfAction::Auto,
+        },
+        (Some("input"), _) => CrlfAction::TextInput,
+        // an eol attribute on its own marks the path as text
+        (_, Some("crlf")) => CrlfAction::TextCrlf,
+        (_, Some(_)) => CrlfAction::TextInput,
+        (Some(_), None) => match auto_crlf(repo)? {
+            AutoCrlf::True => CrlfAction::TextCrlf,
+            AutoCrlf::Input => CrlfAction::TextInput,
+            AutoCrlf::False => CrlfAction::Text,
+        },
+        (None, None) => match auto_crlf(repo)? {
+            AutoCrlf::True => CrlfAction::AutoCrlf,
+            AutoCrlf::Input => CrlfAction::AutoInput,
+            AutoCrlf::False => CrlfAction::Binary,
+        },
+    };
+    Ok(action)
+}
+
+// whether files get CRLF line endings when checked out
+fn output_is_crlf(action: CrlfAction, repo: &obj::Repo) -> Result<bool, err::Error> {
+    Ok(match action {
+        CrlfAction::Binary | CrlfAction::TextInput | CrlfAction::AutoInput => false,
+        CrlfAction::TextCrlf | CrlfAction::AutoCrlf => true,
+        CrlfAction::Text | CrlfAction::Auto => {
+            config::get(&repo.gitconf, "core.eol")?.as_deref() == Some("crlf")
+        }
+    })
+}
+
+#[derive(Debug, Default)]
+struct TextStats {
+    lone_cr: usize,
+    lone_lf: usize,
+    crlf: usize,
+    nul: usize,
+    printable: usize,
+    nonprintable: usize,
+}
+
+fn gather_stats(contents: &[u8]) -> TextStats {
+    let mut stats = TextStats::default();
+    let mut i = 0;
+    while i < contents.len() {
+        match contents[i] {
+            b'\r' if contents.get(i + 1) == Some(&b'\n') => {
+                stats.crlf += 1;
+                i += 1;
+            }
+            b'\r' => stats.lone_cr += 1,
+            b'\n' => stats.lone_lf += 1,
+            0 => stats.nul += 1,
+            // backspace, tab, escape and form feed are common in text
+            b'\x08' | b'\t' | b'\x1b' | b'\x0c' => stats.printable += 1,
+            c if c < 32 || c == 127 => stats.nonprintable += 1,
+            _ => stats.printable += 1,
+        }
+        i += 1;
+    }
+    // a trailing DOS end of file marker isn't counted against the file
+    if contents.last() == Some(&0x1a) {
+        stats.nonprintable -= 1;
+    }
+    stats
+}
+
+// git's heuristic for files that shouldn't have their line endings touched
+fn is_binary(stats: &TextStats) -> bool {
+    stats.lone_cr > 0 || stats.nul > 0 || (stats.printable >> 7) < stats.nonprintable
+}
+
+fn is_auto(action: CrlfAction) -> bool {
+    matches!(
+        action,
+        CrlfAction::Auto | CrlfAction::AutoInput | CrlfAction::AutoCrlf
+    )
+}
+
+/// Converts the contents of a worktree file to what gets stored in the
+/// repo, running the clean filter and then turning CRLF line endings of
+/// text files into LF. index_sha is the blob the index has for the path.
+pub fn to_git(
+    path: &str,
+    contents: Vec<u8>,
+    attrs: &attr::AttrRules,
+    index_sha: Option<&str>,
+    repo: &obj::Repo,
+) -> Result<Vec<u8>, err::Error> {
+    let attrs = attrs.path_attributes(path)?;
+    let contents = match filter::filter_driver(&attrs, repo)? {
+        Some(driver) => driver.clean(path, contents, repo)?,
+        None => contents,
+    };
+    crlf_to_git(crlf_action(&attrs, repo)?, contents, index_sha, repo)
+}
+
+// whether the blob holds a CR, a missing or unreadable blob has none
+fn has_cr_in_blob(sha: &str, repo: &obj::Repo) -> bool {
+    match obj::read_object(sha, repo) {
+        Ok(obj::GitObj::Blob(blob)) => blob.contents.contains(&b'\r'),
+        _ => false,
+    }
+}
+
+fn crlf_to_git(
+    action: CrlfAction,
+    contents: Vec<u8>,
+    index_sha: Option<&str>,
+    repo: &obj::Repo,
+) -> Result<Vec<u8>, err::Error> {
+    if action == CrlfAction::Binary || contents.is_empty() {
+        return Ok(contents);
+    }
+    let stats = gather_stats(&contents);
+    if stats.crlf == 0 || (is_auto(action) && is_binary(&stats)) {
+        return Ok(contents);
+    }
+    // a file committed with CRs keeps them when auto conversion is used,
+    // otherwise re-adding it would change its line endings
+    if is_auto(action) && index_sha.is_some_and(|sha| has_cr_in_blob(sha, repo)) {
+        return Ok(contents);
+    }
+
+    let mut converted = Vec::with_capacity(contents.len() - stats.crlf);
+    for (i, byte) in contents.iter().enumerate() {
+        if *byte == b'\r' && contents.get(i + 1) == Some(&b'\n') {
+            continue;
+        }
+        converted.push(*byte);
+    }
+    Ok(converted)
+}
+
+/// Converts the contents of a blob to what gets written to the worktree,
+/// text files checked out with CRLF get a CR before each lone LF and then
+/// the smudge filter is run
+pub fn to_worktree(
+    path: &str,
+    contents: Vec<u8>,
+    attrs: &attr::AttrRules,
+    repo: &obj::Repo,
+) -> Result<Vec<u8>, err::Error> {
+    let attrs = attrs.path_attributes(path)?;
+    let contents = crlf_to_worktree(crlf_action(&attrs, repo)?, contents, repo)?;
+    match filter::filter_driver(&attrs, repo)? {
+        Some(driver) => driver.smudge(path, contents, repo),
+        None => Ok(contents),
+    }
+}
+
+fn crlf_to_worktree(
+    action: CrlfAction,
+    contents: Vec<u8>,
+    repo: &obj::Repo,
+) -> Result<Vec<u8>, err::Error> {
+    if !output_is_crlf(action, repo)? {
+        return Ok(contents);
+    }
+    let stats = gather_stats(&contents);
+    if stats.lone_lf == 0 {
+        return Ok(contents);
+    }
+    // files already holding CRs were committed that way on purpose
+    if is_auto(action) && (stats.lone_cr > 0 || stats.crlf > 0 || is_binary(&stats)) {
+        return Ok(contents);
+    }
+
+    let mut converted = Vec::with_capacity(contents.len() + stats.lone_lf);
+    for (i, byte) in contents.iter().enumerate() {
+        if *byte == b'\n' && (i == 0 || contents[i - 1] != b'\r') {
+            converted.push(b'\r');
+        }
+        converted.push(*byte);
+    }
+    Ok(converted)
+}
+
+#[cfg(test)]
+mod convert_tests {
+    use super::*;
+    use crate::test_utils;
+    use std::fs::write;
+
+    #[test]
+    fn autocrlf_and_eol_settings_convert_line_endings() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let mut repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let crlf = b"a\r\nb\r\n".to_vec();
+        let lf = b"a\nb\n".to_vec();
+        let attrs = attr::AttrRules::new(&repo).unwrap();
+
+        // nothing is converted by default
+        assert_eq!(crlf, to_git("f.txt", crlf.clone(), &attrs, None, &repo).unwrap());
+        assert_eq!(lf, to_worktree("f.txt", lf.clone(), &attrs, &repo).unwrap());
+
+        config::set(&mut repo, "core.autocrlf", "true").unwrap();
+        assert_eq!(lf, to_git("f.txt", crlf.clone(), &attrs, None, &repo).unwrap());
+        assert_eq!(crlf, to_worktree("f.txt", lf.clone(), &attrs, &repo).unwrap());
+        let binary = b"a\r\n\0b\n".to_vec();
+        assert_eq!(binary, to_git("f.bin", binary.clone(), &attrs, None, &repo).unwrap());
+        assert_eq!(binary, to_worktree("f.bin", binary.clone(), &attrs, &repo).unwrap());
+
+        config::set(&mut repo, "core.autocrlf", "input").unwrap();
+        assert_eq!(lf, to_git("f.txt", crlf.clone(), &attrs, None, &repo).unwrap());
+        assert_eq!(lf, to_worktree("f.txt", lf.clone(), &attrs, &repo).unwrap());
+    }
+
+    #[test]
+    fn text_and_eol_attributes_override_autocrlf() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let mut repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        write(
+            repo.worktree.join(".gitattributes"),
+            "*.txt text\n*.bat eol=crlf\n*.dat -text\n*.md text=auto eol=lf\n",
+        )
+        .unwrap();
+        let crlf = b"a\r\nb\r\n".to_vec();
+        let lf = b"a\nb\n".to_vec();
+        let attrs = attr::AttrRules::new(&repo).unwrap();
+
+        assert_eq!(lf, to_git("f.txt", crlf.clone(), &attrs, None, &repo).unwrap());
+        assert_eq!(lf, to_worktree("f.txt", lf.clone(), &attrs, &repo).unwrap());
+        config::set(&mut repo, "core.eol", "crlf").unwrap();
+        assert_eq!(crlf, to_worktree("f.txt", lf.clone(), &attrs, &repo).unwrap());
+
+        assert_eq!(lf, to_git("run.bat", crlf.clone(), &attrs, None, &repo).unwrap());
+        assert_eq!(crlf, to_worktree("run.bat", lf.clone(), &attrs, &repo).unwrap());
+
+        config::set(&mut repo, "core.autocrlf", "true").unwrap();
+        assert_eq!(crlf, to_git("f.dat", crlf.clone(), &attrs, None, &repo).unwrap());
+        assert_eq!(lf, to_worktree("f.dat", lf.clone(), &attrs, &repo).unwrap());
+        assert_eq!(lf, to_git("f.md", crlf.clone(), &attrs, None, &repo).unwrap());
+        assert_eq!(lf, to_worktree("f.md", lf.clone(), &attrs, &repo).unwrap());
+    }
+
+    #[test]
+    fn auto_conversion_leaves_files_committed_with_crs_alone() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let mut repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        write(repo.worktree.join(".gitattributes"), "*.txt text\n").unwrap();
+        let crlf = b"a\r\nb\r\n".to_vec();
+        let lf = b"a\nb\n".to_vec();
+        let blob = |c: &[u8]| obj::GitObj::Blob(obj::blob::Blob::new(c));
+        let crlf_sha = obj::write_object(blob(&crlf), Some(&repo)).unwrap().to_string();
+        let lf_sha = obj::write_object(blob(&lf), Some(&repo)).unwrap().to_string();
+        let attrs = attr::AttrRules::new(&repo).unwrap();
+
+        config::set(&mut repo, "core.autocrlf", "true").unwrap();
+        let to_git_with = |path: &str, sha: &str| {
+            to_git(path, crlf.clone(), &attrs, Some(sha), &repo).unwrap()
+        };
+        assert_eq!(crlf, to_git_with("f.md", &crlf_sha));
+        assert_eq!(lf, to_git_with("f.md", &lf_sha));
+        // only auto conversion looks at the index, text always converts
+        assert_eq!(lf, to_git_with("f.txt", &crlf_sha));
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::error as err;
+
+/// Files read from the dirs of the worktree like .gitignore and
+/// .gitattributes, keyed by the dir relative to the top of the worktree.
+/// Each dir's file is read once, the first time the dir is checked, and
+/// the cache can be shared by threads.
+#[derive(Debug)]
+pub struct DirCache<T> {
+    dirs: Mutex<BTreeMap<String, Arc<T>>>,
+}
+
+impl<T> Default for DirCache<T> {
+    fn default() -> DirCache<T> {
+        DirCache {
+            dirs: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl<T> DirCache<T> {
+    /// Returns the dir's file, calling read to read it when it isn't cached
+    pub fn get_or_read<F>(&self, dir: &str, read: F) -> Result<Arc<T>, err::Error>
+    where
+        F: FnOnce() -> Result<T, err::Error>,
+    {
+        if let Some(file) = self.dirs.lock().unwrap().get(dir) {
+            return Ok(file.clone());
+        }
+        // the file is read without holding the lock, when two threads race
+        // the file the first one stored is kept
+        let file = Arc::new(read()?);
+        let mut dirs = self.dirs.lock().unwrap();
+        Ok(dirs.entry(dir.to_owned()).or_insert(file).clone())
+    }
+
+    /// Forgets the dir's file so it's read again the next time
+    pub fn forget(&self, dir: &str) {
+        self.dirs.lock().unwrap().remove(dir);
+    }
+}
@@ -21,12 +21,167 @@ pub enum Error {
     GitTreeInvalidObject,
     #[error("Git tag -a isn't implemented yet")]
     GitCreateTagObjectNotImplemented,
-    #[error("Unrecognized git index version: {0}, this tool only supports version 2")]
+    #[error("Unrecognized git index version: {0}, this tool only supports versions 2 and 3")]
     GitUnrecognizedIndexVersion(u32),
+    #[error("Unable to create '{0}': File exists. Another git process seems to be running in this repository")]
+    GitIndexLocked(String),
+    #[error("index uses {0} extension, which this tool does not understand")]
+    GitUnsupportedIndexExtension(String),
     #[error("Unexpected internal type found: {0}")]
     GitUnexpectedInternalType(String),
     #[error("Unrecognized git file header: {0}")]
     GitUnrecognizedObjInHeader(String),
+    #[error("Nothing specified, nothing added.")]
+    GitNothingSpecifiedToAdd,
+    #[error("pathspec '{0}' did not match any files")]
+    GitPathspecNoMatch(String),
+    #[error("{0}: is outside repository")]
+    GitPathspecOutsideRepo(String),
+    #[error("The following paths are ignored by one of your .gitignore files:\n{0}\nUse -f if you really want to add them.")]
+    GitPathsIgnored(String),
+    #[error("{0}: cannot add to the index - missing --add option?")]
+    GitUpdateIndexCannotAdd(String),
+    #[error("{0}: does not exist and --remove not passed")]
+    GitUpdateIndexNoRemove(String),
+    #[error("Unable to mark file {0}")]
+    GitUpdateIndexUnableToMark(String),
+    #[error("--cacheinfo cannot add {0}, expected <mode>,<sha>,<path>")]
+    GitUpdateIndexInvalidCacheinfo(String),
+    #[error("option 'chmod' expects \"+x\" or \"-x\", got: {0}")]
+    GitUpdateIndexInvalidChmod(String),
+    #[error("cannot chmod {0} '{1}'")]
+    GitUpdateIndexCannotChmod(String, String),
+    #[error("malformed --index-info line: {0}")]
+    GitUpdateIndexMalformedIndexInfo(String),
+    #[error("bad revision '{0}'")]
+    GitBadRevision(String),
+    #[error("short SHA1 {0} is ambiguous")]
+    GitAmbiguousRevision(String),
+    #[error("{0} is not a valid '{1}' object")]
+    GitNotAValidObject(String, String),
+    #[error("{0}: unmerged entry in the index, resolve the conflict first")]
+    GitIndexUnmerged(String),
+    #[error("invalid object for '{0}', the object doesn't exist in the object store")]
+    GitWriteTreeMissingObject(String),
+    #[error("prefix {0} not found in the index")]
+    GitWriteTreeBadPrefix(String),
+    #[error("Entry '{0}' would be overwritten by merge. Cannot merge.")]
+    GitReadTreeWouldOverwrite(String),
+    #[error("subdirectory '{0}' already exists in the index")]
+    GitReadTreePrefixExists(String),
+    #[error("read-tree can't be used with {0} trees, use one tree or -m with up to three")]
+    GitReadTreeWrongNumberOfTrees(usize),
+    #[error("'{0}' is not a valid branch name")]
+    GitInvalidBranchName(String),
+    #[error("'{0}' is not a valid tag name")]
+    GitInvalidTagName(String),
+    #[error("'{0}' is not a valid ref name")]
+    GitInvalidRefName(String),
+    #[error("a branch named '{0}' already exists")]
+    GitBranchAlreadyExists(String),
+    #[error("branch '{0}' not found")]
+    GitBranchNotFound(String),
+    #[error("Cannot delete branch '{0}' checked out")]
+    GitBranchCheckedOut(String),
+    #[error("The branch '{0}' is not fully merged.\nIf you are sure you want to delete it, run 'rusty-git branch -D {0}'")]
+    GitBranchNotFullyMerged(String),
+    #[error("cannot rename the current branch while not on any")]
+    GitBranchRenameDetached,
+    #[error("{0}")]
+    GitBranchUsage(String),
+    #[error("Your local changes to the following files would be overwritten by checkout:\n{0}Please commit your changes or stash them before you switch branches.")]
+    GitSwitchLocalChanges(String),
+    #[error("The following untracked working tree files would be overwritten by checkout:\n{0}Please move or remove them before you switch branches.")]
+    GitSwitchUntrackedFiles(String),
+    #[error("a branch is expected, got '{0}', use --detach to switch to a commit")]
+    GitSwitchNotABranch(String),
+    #[error("invalid reference: {0}")]
+    GitSwitchInvalidReference(String),
+    #[error("missing branch or commit argument")]
+    GitSwitchMissingBranch,
+    #[error("you must specify path(s) to restore")]
+    GitRestoreNoPathspec,
+    #[error("Cannot do {0} reset with paths.")]
+    GitResetPathsWithMode(String),
+    #[error("'{0}' already exists")]
+    GitWorktreeAlreadyExists(String),
+    #[error("'{0}' is already checked out at '{1}'")]
+    GitWorktreeBranchCheckedOut(String, String),
+    #[error("'{0}' is not a working tree")]
+    GitWorktreeNotFound(String),
+    #[error("'{0}' is a main working tree")]
+    GitWorktreeIsMain(String),
+    #[error("cannot remove a locked working tree '{0}'")]
+    GitWorktreeLocked(String),
+    #[error("'{0}' is already locked")]
+    GitWorktreeAlreadyLocked(String),
+    #[error("'{0}' is not locked")]
+    GitWorktreeNotLocked(String),
+    #[error("'{0}' contains modified or untracked files, use --force to delete it")]
+    GitWorktreeDirty(String),
+    #[error("{0}")]
+    GitCheckAttrUsage(String),
+    #[error("no path specified")]
+    GitCheckIgnoreNoPath,
+    #[error("{0}: {1} filter '{2}' failed")]
+    GitFilterFailed(String, String, String),
+    #[error("filter process protocol error: {0}")]
+    GitFilterProtocol(String),
+    #[error("'{0}' does not have a commit checked out")]
+    GitSubmoduleNoCommit(String),
+    #[error("unable to parse packed-refs line: {0}")]
+    GitPackedRefsMalformed(String),
+    #[error("ref '{0}' doesn't exist")]
+    GitRefNotFound(String),
+    #[error("ref {0} is not a symbolic ref")]
+    GitNotASymbolicRef(String),
+    #[error("ref '{0}' has too many levels of symbolic refs")]
+    GitSymbolicRefTooDeep(String),
+    #[error("refusing to point {0} at {1}, the symbolic refs would form a loop")]
+    GitSymbolicRefLoop(String, String),
+    #[error("refusing to point HEAD outside of refs/: {0}")]
+    GitSymbolicRefBadTarget(String),
+    #[error("deleting 'HEAD' is not allowed")]
+    GitSymbolicRefDeleteHead,
+    #[error("cannot lock ref '{0}': {1}")]
+    GitRefLockFailed(String, String),
+    #[error("multiple updates for ref '{0}' not allowed")]
+    GitRefTransactionDuplicate(String),
+    #[error("{0}")]
+    GitUpdateRefUsage(String),
+    #[error("update-ref --stdin: invalid command: {0}")]
+    GitUpdateRefStdin(String),
+    #[error("unknown field name: {0}")]
+    GitForEachRefBadAtom(String),
+    #[error("unable to parse reflog line: {0}")]
+    GitReflogMalformed(String),
+    #[error("log for '{0}' is empty or doesn't exist")]
+    GitReflogMissing(String),
+    #[error("log for '{0}' only has {1} entries")]
+    GitReflogTooShort(String, usize),
+    #[error("not a reflog entry: {0}")]
+    GitReflogBadSelector(String),
+    #[error("invalid date: {0}")]
+    GitInvalidDate(String),
+    #[error("invalid key: {0}")]
+    GitConfigInvalidKey(String),
+    #[error("bad boolean config value '{1}' for '{0}'")]
+    GitConfigInvalidBool(String, String),
+    #[error("this worktree is not sparse")]
+    GitSparseCheckoutNotEnabled,
+    #[error("'{0}' is not a directory, specify directories rather than patterns in cone mode")]
+    GitSparseCheckoutNotADirectory(String),
+    #[error("The following paths and/or pathspecs matched paths that exist outside of your sparse-checkout definition, so will not be updated in the index:\n{0}\nUse --sparse if you intend to update such entries.")]
+    GitPathsOutsideSparseCheckout(String),
+    #[error("fsmonitor--daemon is already running '{0}'")]
+    GitFsmonitorAlreadyRunning(String),
+    #[error("fsmonitor--daemon is not running '{0}'")]
+    GitFsmonitorNotRunning(String),
+    #[error("fsmonitor--daemon failed to start")]
+    GitFsmonitorStartFailed,
+    #[cfg(not(target_os = "linux"))]
+    #[error("fsmonitor--daemon is only supported on Linux")]
+    GitFsmonitorUnsupported,
 
     // program errors not related to git
     #[error("Path doesn't exist: {0}")]
@@ -0,0 +1,105 @@
+use nom::{
+    multi::count,
+    number::complete::{u32, u64},
+    number::Endianness::Big,
+    IResult,
+};
+
+// an EWAH bitmap is a series of marker words, each followed by literal
+// words. A marker holds a bit repeated for a run of whole words in bit 0,
+// the length of the run in the next 32 bits and the number of literal words
+// after it in the top 31 bits.
+const RUN_LEN_BITS: u64 = 32;
+const RUN_LEN_MASK: u64 = (1 << RUN_LEN_BITS) - 1;
+const MAX_LITERALS: u64 = (1 << 31) - 1;
+
+fn marker(running_bit: bool, run_len: u64, literals: u64) -> u64 {
+    running_bit as u64 | (run_len << 1) | (literals << (RUN_LEN_BITS + 1))
+}
+
+/// Compresses the bits into git's EWAH format, runs of words that are all
+/// zeros or all ones are stored as a single marker
+pub fn serialize(bits: &[bool]) -> Vec<u8> {
+    let mut words: Vec<u64> = vec![0; bits.len().div_ceil(64)];
+    for (i, _) in bits.iter().enumerate().filter(|(_, b)| **b) {
+        words[i / 64] |= 1 << (i % 64);
+    }
+
+    let mut buffer: Vec<u64> = Vec::new();
+    let mut last_marker = 0;
+    let mut i = 0;
+    while i < words.len() || buffer.is_empty() {
+        let running_bit = words.get(i) == Some(&u64::MAX);
+        let run_len = words[i..]
+            .iter()
+            .take(RUN_LEN_MASK as usize)
+            .take_while(|w| **w == if running_bit { u64::MAX } else { 0 })
+            .count();
+        i += run_len;
+        let literals = words[i..]
+            .iter()
+            .take(MAX_LITERALS as usize)
+            .take_while(|w| **w != 0 && **w != u64::MAX)
+            .count();
+        last_marker = buffer.len();
+        buffer.push(marker(running_bit, run_len as u64, literals as u64));
+        buffer.extend_from_slice(&words[i..i + literals]);
+        i += literals;
+    }
+
+    let mut out = Vec::with_capacity(12 + buffer.len() * 8);
+    out.extend_from_slice(&(bits.len() as u32).to_be_bytes());
+    out.extend_from_slice(&(buffer.len() as u32).to_be_bytes());
+    for word in buffer {
+        out.extend_from_slice(&word.to_be_bytes());
+    }
+    out.extend_from_slice(&(last_marker as u32).to_be_bytes());
+    out
+}
+
+/// Parses an EWAH bitmap into one bool per bit
+pub fn parse(input: &[u8]) -> IResult<&[u8], Vec<bool>> {
+    let (input, bit_size) = u32(Big)(input)?;
+    let (input, word_count) = u32(Big)(input)?;
+    let (input, words) = count(u64(Big), word_count as usize)(input)?;
+    let (input, _last_marker) = u32(Big)(input)?;
+
+    let bit_size = bit_size as usize;
+    let mut bits = Vec::with_capacity(bit_size);
+    let mut words = words.into_iter();
+    while let Some(marker) = words.next() {
+        let running_bit = marker & 1 == 1;
+        let run_len = (marker >> 1) & RUN_LEN_MASK;
+        let literals = marker >> (RUN_LEN_BITS + 1);
+        let run_bits = (run_len as usize * 64).min(bit_size.saturating_sub(bits.len()));
+        bits.extend(std::iter::repeat_n(running_bit, run_bits));
+        for word in words.by_ref().take(literals as usize) {
+            bits.extend((0..64).map(|b| word & (1 << b) != 0));
+        }
+    }
+    bits.resize(bit_size, false);
+    Ok((input, bits))
+}
+
+#[cfg(test)]
+mod ewah_tests {
+    use super::*;
+
+    #[test]
+    fn bitmaps_round_trip_and_runs_are_compressed() {
+        let mut bits = vec![false; 64 * 5 + 3];
+        bits[1] = true;
+        bits[64 * 4 + 2] = true;
+        bits[64 * 5 + 2] = true;
+        for bit in bits.iter_mut().skip(64).take(64) {
+            *bit = true;
+        }
+        let bytes = serialize(&bits);
+        // a marker and literal, a marker for the run of ones, then a marker
+        // for the run of zeros and the two literals after it
+        assert_eq!(4 + 4 + 6 * 8 + 4, bytes.len());
+        assert_eq!(bits, parse(&bytes).unwrap().1);
+
+        assert_eq!(Vec::<bool>::new(), parse(&serialize(&[])).unwrap().1);
+    }
+}
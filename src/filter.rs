@@ -0,0 +1,408 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::attributes::AttrState;
+use crate::config;
+use crate::error as err;
+use crate::objects as obj;
+
+// the largest payload a pkt-line can hold, 65520 minus the length prefix
+const MAX_PACKET_DATA: usize = 65516;
+
+/// The commands configured for a filter=<name> attribute through the
+/// filter.<name>.clean, .smudge, .process and .required config keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterDriver {
+    pub name: String,
+    pub clean: Option<String>,
+    pub smudge: Option<String>,
+    pub process: Option<String>,
+    pub required: bool,
+}
+
+/// Returns the driver for the path's filter attribute, None when the path
+/// has no filter or nothing is configured for it
+pub fn filter_driver(
+    attrs: &BTreeMap<String, AttrState>,
+    repo: &obj::Repo,
+) -> Result<Option<FilterDriver>, err::Error> {
+    let Some(AttrState::Value(name)) = attrs.get("filter") else {
+        return Ok(None);
+    };
+    let get = |key: &str| config::get(&repo.gitconf, &format!("filter.{name}.{key}"));
+    let driver = FilterDriver {
+        name: name.to_owned(),
+        clean: get("clean")?.filter(|c| !c.is_empty()),
+        smudge: get("smudge")?.filter(|c| !c.is_empty()),
+        process: get("process")?.filter(|c| !c.is_empty()),
+        required: config::get_bool(&repo.gitconf, &format!("filter.{name}.required"))?
+            .unwrap_or(false),
+    };
+    if driver.clean.is_none() && driver.smudge.is_none() && driver.process.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(driver))
+}
+
+impl FilterDriver {
+    /// Runs the contents of a worktree file through the clean filter
+    pub fn clean(&self, path: &str, contents: Vec<u8>, repo: &obj::Repo) -> FilterResult {
+        self.apply("clean", self.clean.as_deref(), path, contents, repo)
+    }
+
+    /// Runs the contents of a blob through the smudge filter
+    pub fn smudge(&self, path: &str, contents: Vec<u8>, repo: &obj::Repo) -> FilterResult {
+        self.apply("smudge", self.smudge.as_deref(), path, contents, repo)
+    }
+
+    // a long running process takes priority over the single file command.
+    // When the filter fails the contents are used unchanged unless the
+    // filter is required.
+    fn apply(
+        &self,
+        kind: &str,
+        command: Option<&str>,
+        path: &str,
+        contents: Vec<u8>,
+        repo: &obj::Repo,
+    ) -> FilterResult {
+        let filtered = match (&self.process, command) {
+            (Some(process), _) => run_process_filter(process, kind, path, &contents, repo),
+            (None, Some(command)) => run_filter_command(command, path, &contents, repo),
+            (None, None) => None,
+        };
+        match filtered {
+            Some(filtered) => Ok(filtered),
+            None if self.required => Err(err::Error::GitFilterFailed(
+                path.to_owned(),
+                kind.to_owned(),
+                self.name.clone(),
+            )),
+            None => Ok(contents),
+        }
+    }
+}
+
+type FilterResult = Result<Vec<u8>, err::Error>;
+
+// quotes the path for the shell so it can be substituted for %f
+fn shell_quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', "'\\''"))
+}
+
+// runs a clean or smudge command with the contents on stdin, None when
+// the command can't be run or exits with an error
+fn run_filter_command(
+    command: &str,
+    path: &str,
+    contents: &[u8],
+    repo: &obj::Repo,
+) -> Option<Vec<u8>> {
+    let command = command.replace("%f", &shell_quote(path));
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .current_dir(&repo.worktree)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .ok()?;
+
+    // stdin is written from another thread so a filter that starts writing
+    // output before reading all of its input can't deadlock
+    let mut stdin = child.stdin.take()?;
+    let output = thread::scope(|s| {
+        s.spawn(move || stdin.write_all(contents));
+        child.wait_with_output()
+    })
+    .ok()?;
+    output.status.success().then_some(output.stdout)
+}
+
+/// A running filter.<name>.process and the capabilities it announced
+struct FilterProcess {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    capabilities: Vec<String>,
+}
+
+// long running filters are started once and reused for every file
+static PROCESSES: Mutex<BTreeMap<String, FilterProcess>> = Mutex::new(BTreeMap::new());
+
+fn start_process(command: &str, repo: &obj::Repo) -> Result<FilterProcess, err::Error> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(&repo.worktree)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let (Some(mut stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        return Err(err::Error::GitFilterProtocol(command.to_owned()));
+    };
+    let mut stdout = BufReader::new(stdout);
+    let capabilities = handshake(&mut stdout, &mut stdin)?;
+    Ok(FilterProcess {
+        child,
+        stdin,
+        stdout,
+        capabilities,
+    })
+}
+
+fn run_process_filter(
+    command: &str,
+    kind: &str,
+    path: &str,
+    contents: &[u8],
+    repo: &obj::Repo,
+) -> Option<Vec<u8>> {
+    let mut processes = PROCESSES.lock().unwrap_or_else(|e| e.into_inner());
+    if !processes.contains_key(command) {
+        processes.insert(command.to_owned(), start_process(command, repo).ok()?);
+    }
+    let process = processes.get_mut(command)?;
+    if !process.capabilities.iter().any(|c| c == kind) {
+        return None;
+    }
+
+    match filter_request(
+        &mut process.stdout,
+        &mut process.stdin,
+        kind,
+        path,
+        contents,
+    ) {
+        Ok(FilterStatus::Success(filtered)) => Some(filtered),
+        Ok(FilterStatus::Error) => None,
+        // the process doesn't want any more files of this kind
+        Ok(FilterStatus::Abort) => {
+            process.capabilities.retain(|c| c != kind);
+            None
+        }
+        // a process that broke the protocol is stopped and started again
+        // for the next file
+        Err(_) => {
+            let mut process = processes.remove(command)?;
+            let _ = process.child.kill();
+            let _ = process.child.wait();
+            None
+        }
+    }
+}
+
+fn write_packet(writer: &mut impl Write, data: &[u8]) -> Result<(), err::Error> {
+    for chunk in data.chunks(MAX_PACKET_DATA) {
+        write!(writer, "{:04x}", chunk.len() + 4)?;
+        writer.write_all(chunk)?;
+    }
+    Ok(())
+}
+
+fn write_flush(writer: &mut impl Write) -> Result<(), err::Error> {
+    writer.write_all(b"0000")?;
+    Ok(writer.flush()?)
+}
+
+// reads one pkt-line, None for a flush packet
+fn read_packet(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>, err::Error> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = usize::from_str_radix(std::str::from_utf8(&len)?, 16)?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if len < 4 {
+        return Err(err::Error::GitFilterProtocol(format!(
+            "bad packet length {len}"
+        )));
+    }
+    let mut data = vec![0; len - 4];
+    reader.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+// reads text packets up to the next flush packet
+fn read_text_packets(reader: &mut impl BufRead) -> Result<Vec<String>, err::Error> {
+    let mut lines = Vec::new();
+    while let Some(packet) = read_packet(reader)? {
+        let line = String::from_utf8_lossy(&packet);
+        lines.push(line.trim_end_matches('\n').to_owned());
+    }
+    Ok(lines)
+}
+
+// negotiates version 2 of the protocol and returns the commands the
+// process supports out of clean and smudge
+fn handshake(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+) -> Result<Vec<String>, err::Error> {
+    write_packet(writer, b"git-filter-client\n")?;
+    write_packet(writer, b"version=2\n")?;
+    write_flush(writer)?;
+    let welcome = read_text_packets(reader)?;
+    if welcome.first().map(|s| s.as_str()) != Some("git-filter-server")
+        || !welcome.iter().any(|s| s == "version=2")
+    {
+        return Err(err::Error::GitFilterProtocol(welcome.join(" ")));
+    }
+
+    write_packet(writer, b"capability=clean\n")?;
+    write_packet(writer, b"capability=smudge\n")?;
+    write_flush(writer)?;
+    let capabilities = read_text_packets(reader)?
+        .iter()
+        .filter_map(|c| c.strip_prefix("capability="))
+        .map(|c| c.to_owned())
+        .collect();
+    Ok(capabilities)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum FilterStatus {
+    Success(Vec<u8>),
+    Error,
+    Abort,
+}
+
+// the last status=<status> line of a list, the status stays the same when
+// the list is empty
+fn last_status(lines: &[String]) -> Option<&str> {
+    lines.iter().rev().find_map(|l| l.strip_prefix("status="))
+}
+
+fn filter_request(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    kind: &str,
+    path: &str,
+    contents: &[u8],
+) -> Result<FilterStatus, err::Error> {
+    write_packet(writer, format!("command={kind}\n").as_bytes())?;
+    write_packet(writer, format!("pathname={path}\n").as_bytes())?;
+    write_flush(writer)?;
+    write_packet(writer, contents)?;
+    write_flush(writer)?;
+
+    let mut status = last_status(&read_text_packets(reader)?).map(|s| s.to_owned());
+    let mut filtered = Vec::new();
+    if status.as_deref() == Some("success") {
+        while let Some(packet) = read_packet(reader)? {
+            filtered.extend(packet);
+        }
+        // the process can still fail the file after sending its contents
+        if let Some(s) = last_status(&read_text_packets(reader)?) {
+            status = Some(s.to_owned());
+        }
+    }
+    Ok(match status.as_deref() {
+        Some("success") => FilterStatus::Success(filtered),
+        Some("abort") => FilterStatus::Abort,
+        _ => FilterStatus::Error,
+    })
+}
+
+#[cfg(test)]
+mod filter_tests {
+    use super::*;
+    use crate::test_utils;
+    use std::io::Cursor;
+
+    fn packets(lines: &[&str]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for line in lines {
+            match *line {
+                "" => write_flush(&mut out).unwrap(),
+                line => write_packet(&mut out, line.as_bytes()).unwrap(),
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn filter_commands_and_required_filters() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let mut repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let attrs = BTreeMap::from([("filter".to_owned(), AttrState::Value("rot13".to_owned()))]);
+        assert_eq!(None, filter_driver(&attrs, &repo).unwrap());
+
+        config::set(&mut repo, "filter.rot13.clean", "tr a-z n-za-m").unwrap();
+        config::set(&mut repo, "filter.rot13.smudge", "tr a-z n-za-m && echo %f").unwrap();
+        let driver = filter_driver(&attrs, &repo).unwrap().unwrap();
+        assert_eq!(
+            b"nop".to_vec(),
+            driver.clean("f", b"abc".to_vec(), &repo).unwrap()
+        );
+        assert_eq!(
+            b"abc\nit's\n".to_vec(),
+            driver.smudge("it's", b"nop\n".to_vec(), &repo).unwrap()
+        );
+
+        // a failing filter leaves the contents alone unless it's required
+        config::set(&mut repo, "filter.rot13.clean", "false").unwrap();
+        let driver = filter_driver(&attrs, &repo).unwrap().unwrap();
+        assert_eq!(
+            b"abc".to_vec(),
+            driver.clean("f", b"abc".to_vec(), &repo).unwrap()
+        );
+        config::set(&mut repo, "filter.rot13.required", "true").unwrap();
+        let driver = filter_driver(&attrs, &repo).unwrap().unwrap();
+        assert_eq!(
+            Err(err::Error::GitFilterFailed(
+                "f".to_owned(),
+                "clean".to_owned(),
+                "rot13".to_owned()
+            )),
+            driver.clean("f", b"abc".to_vec(), &repo)
+        );
+    }
+
+    #[test]
+    fn process_protocol_handshake_and_requests() {
+        let server = packets(&[
+            "git-filter-server\n",
+            "version=2\n",
+            "",
+            "capability=clean\n",
+            "",
+        ]);
+        let mut sent = Vec::new();
+        let capabilities = handshake(&mut Cursor::new(server), &mut sent).unwrap();
+        assert_eq!(vec!["clean"], capabilities);
+        let expected = packets(&[
+            "git-filter-client\n",
+            "version=2\n",
+            "",
+            "capability=clean\n",
+            "capability=smudge\n",
+            "",
+        ]);
+        assert_eq!(expected, sent);
+
+        let server = packets(&["status=success\n", "", "ABC", "DEF", "", ""]);
+        let mut sent = Vec::new();
+        let status = filter_request(
+            &mut Cursor::new(server),
+            &mut sent,
+            "clean",
+            "a.txt",
+            b"abcdef",
+        );
+        assert_eq!(FilterStatus::Success(b"ABCDEF".to_vec()), status.unwrap());
+        let expected = packets(&["command=clean\n", "pathname=a.txt\n", "", "abcdef", ""]);
+        assert_eq!(expected, sent);
+
+        // an error after the contents were sent fails the file
+        let server = packets(&["status=success\n", "", "ABC", "", "status=error\n", ""]);
+        let status = filter_request(&mut Cursor::new(server), &mut Vec::new(), "clean", "a", b"");
+        assert_eq!(FilterStatus::Error, status.unwrap());
+        let server = packets(&["status=abort\n", ""]);
+        let status = filter_request(&mut Cursor::new(server), &mut Vec::new(), "clean", "a", b"");
+        assert_eq!(FilterStatus::Abort, status.unwrap());
+    }
+}
@@ -0,0 +1,779 @@
+use nom::{
+    bytes::complete::{take, take_until},
+    number::complete::{u32, u64},
+    number::Endianness::Big,
+    IResult,
+};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{create_dir_all, read_dir, remove_dir_all, remove_file, symlink_metadata, File};
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::error as err;
+use crate::ewah;
+use crate::objects as obj;
+use crate::utils;
+
+// changes kept before old tokens are forgotten and get a trivial response
+const MAX_CHANGES: usize = 100_000;
+// how long a query waits for the daemon to see its cookie file
+const COOKIE_TIMEOUT: Duration = Duration::from_secs(1);
+// how long the daemon gives a client to send its request and take the
+// response, a stuck client would hold up every other one
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+// how long a client waits on the daemon, long enough for a stuck client
+// and a cookie to both time out first
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(3);
+// every token the daemon hands out starts with this
+const TOKEN_PREFIX: &str = "rusty-git:";
+
+/// The index's FSMN extension, the token the index was last checked at and
+/// which entries weren't known to be unchanged then
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsmonitorData {
+    pub token: String,
+    /// one bit per index entry, set when it needs checking
+    pub dirty: Vec<bool>,
+}
+
+fn parse_fsmonitor(input: &[u8]) -> IResult<&[u8], FsmonitorData> {
+    let (input, version) = u32(Big)(input)?;
+    // version 1 tokens are a timestamp in nanoseconds
+    let (input, token) = if version == 1 {
+        let (input, time) = u64(Big)(input)?;
+        (input, time.to_string())
+    } else {
+        let (input, token) = take_until(&b"\0"[..])(input)?;
+        let (input, _) = take(1usize)(input)?;
+        (input, String::from_utf8_lossy(token).into_owned())
+    };
+    let (input, ewah_size) = u32(Big)(input)?;
+    let (input, bitmap) = take(ewah_size as usize)(input)?;
+    let (_, dirty) = ewah::parse(bitmap)?;
+    Ok((input, FsmonitorData { token, dirty }))
+}
+
+impl FsmonitorData {
+    pub fn parse(data: &[u8]) -> Result<FsmonitorData, err::Error> {
+        Ok(parse_fsmonitor(data)?.1)
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let bitmap = ewah::serialize(&self.dirty);
+        [
+            2u32.to_be_bytes().as_slice(),
+            self.token.as_bytes(),
+            b"\0",
+            &(bitmap.len() as u32).to_be_bytes(),
+            &bitmap,
+        ]
+        .concat()
+    }
+}
+
+/// The paths changed since a token along with the token to ask with next
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsmonitorChanges {
+    pub token: String,
+    /// None when the daemon can't tell what changed and every path has to
+    /// be checked, like after it restarted
+    pub paths: Option<BTreeSet<String>>,
+}
+
+impl FsmonitorChanges {
+    /// True when the path or a dir holding it was reported changed
+    pub fn covers(&self, path: &str) -> bool {
+        let Some(paths) = &self.paths else {
+            return true;
+        };
+        paths.contains(path)
+            || path
+                .match_indices('/')
+                .any(|(i, _)| paths.contains(&path[..i]))
+    }
+}
+
+/// Whether status should ask the daemon for changes, core.fsmonitor
+pub fn enabled(repo: &obj::Repo) -> Result<bool, err::Error> {
+    Ok(config::get_bool(&repo.gitconf, "core.fsmonitor")?.unwrap_or(false))
+}
+
+// kept apart from git's own daemon, whose tokens and responses differ
+fn socket_path(repo: &obj::Repo) -> PathBuf {
+    repo.gitdir.join("rusty-git-fsmonitor.ipc")
+}
+
+fn cookie_dir(repo: &obj::Repo) -> PathBuf {
+    repo.gitdir.join("rusty-git-fsmonitor/cookies")
+}
+
+// sends the request and reads the response until the daemon hangs up,
+// None when no daemon is listening. A daemon that doesn't answer in time
+// is an error.
+fn send_request(request: &str, repo: &obj::Repo) -> Result<Option<Vec<u8>>, err::Error> {
+    let Ok(mut stream) = UnixStream::connect(socket_path(repo)) else {
+        return Ok(None);
+    };
+    stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+    stream.set_write_timeout(Some(RESPONSE_TIMEOUT))?;
+    stream.write_all(format!("{request}\n").as_bytes())?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(Some(response))
+}
+
+/// Asks the daemon watching the worktree what changed since the token, an
+/// unknown or empty token gets a response saying everything changed. None
+/// when the daemon isn't running or doesn't answer in time with one of its
+/// tokens, every path has to be checked then.
+pub fn query(token: &str, repo: &obj::Repo) -> Result<Option<FsmonitorChanges>, err::Error> {
+    let Ok(Some(response)) = send_request(&format!("query {token}"), repo) else {
+        return Ok(None);
+    };
+    // the token is always followed by a nul, even with no paths after it
+    let Some(nul) = response.iter().position(|b| *b == 0) else {
+        return Ok(None);
+    };
+    let token = String::from_utf8_lossy(&response[..nul]).into_owned();
+    if !token.starts_with(TOKEN_PREFIX) {
+        return Ok(None);
+    }
+    let paths: BTreeSet<String> = response[nul + 1..]
+        .split(|b| *b == 0)
+        .filter(|f| !f.is_empty())
+        .map(|f| String::from_utf8_lossy(f).into_owned())
+        .collect();
+    // like git's fsmonitor hooks a single "/" means every path
+    let paths = (!paths.contains("/")).then_some(paths);
+    Ok(Some(FsmonitorChanges { token, paths }))
+}
+
+pub fn is_running(repo: &obj::Repo) -> Result<bool, err::Error> {
+    Ok(send_request("ping", repo)?.is_some())
+}
+
+/// Starts the daemon for the worktree in the background, returning once it
+/// answers requests
+pub fn start_daemon(repo: &obj::Repo) -> Result<(), err::Error> {
+    if is_running(repo)? {
+        let worktree = utils::absolute_worktree(repo).display().to_string();
+        return Err(err::Error::GitFsmonitorAlreadyRunning(worktree));
+    }
+    use std::os::unix::process::CommandExt;
+    Command::new(std::env::current_exe()?)
+        .args(["fsmonitor--daemon", "run"])
+        .current_dir(&repo.worktree)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        // keep the daemon running when the terminal's process group exits
+        .process_group(0)
+        .spawn()?;
+
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(5) {
+        if is_running(repo)? {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    Err(err::Error::GitFsmonitorStartFailed)
+}
+
+pub fn stop_daemon(repo: &obj::Repo) -> Result<(), err::Error> {
+    match send_request("stop", repo)? {
+        Some(_) => Ok(()),
+        None => Err(err::Error::GitFsmonitorNotRunning(
+            utils::absolute_worktree(repo).display().to_string(),
+        )),
+    }
+}
+
+// what the daemon has seen, shared by the thread reading events and the
+// one answering queries
+#[derive(Debug, Default)]
+struct DaemonState {
+    // tokens from an earlier daemon have a different id
+    id: String,
+    seq: u64,
+    changes: Vec<(u64, String)>,
+    // tokens from before this were issued before changes were dropped
+    oldest_token: u64,
+    cookies: HashSet<String>,
+    // set once events stop being read, changes can't be seen from then on
+    failed: bool,
+}
+
+impl DaemonState {
+    fn record(&mut self, path: String) {
+        self.seq += 1;
+        self.changes.push((self.seq, path));
+        if self.changes.len() > MAX_CHANGES {
+            self.forget_changes();
+        }
+    }
+
+    // tokens issued so far get a trivial response, used when changes may
+    // have been missed
+    fn forget_changes(&mut self) {
+        self.changes.clear();
+        self.seq += 1;
+        self.oldest_token = self.seq;
+    }
+
+    fn token(&self) -> String {
+        format!("{TOKEN_PREFIX}{}:{}", self.id, self.seq)
+    }
+
+    // the paths changed since the token, None when they aren't all known
+    fn changes_since(&self, token: &str) -> Option<BTreeSet<String>> {
+        let (id, seq) = token.strip_prefix(TOKEN_PREFIX)?.rsplit_once(':')?;
+        let seq: u64 = seq.parse().ok()?;
+        if id != self.id || seq < self.oldest_token || seq > self.seq {
+            return None;
+        }
+        Some(
+            self.changes
+                .iter()
+                .filter(|(change_seq, _)| *change_seq > seq)
+                .map(|(_, path)| path.clone())
+                .collect(),
+        )
+    }
+}
+
+type Shared = Arc<(Mutex<DaemonState>, Condvar)>;
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    pub const WATCH_MASK: u32 = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MODIFY
+        | libc::IN_ATTRIB
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_DELETE_SELF
+        | libc::IN_MOVE_SELF
+        | libc::IN_ONLYDIR
+        | libc::IN_DONT_FOLLOW
+        | libc::IN_EXCL_UNLINK;
+
+    pub struct Event {
+        pub wd: i32,
+        pub mask: u32,
+        /// the name of the file in the watched dir, empty for the dir itself
+        pub name: String,
+    }
+
+    pub struct Inotify {
+        fd: File,
+    }
+
+    impl Inotify {
+        pub fn new() -> io::Result<Inotify> {
+            // SAFETY: a new fd is returned and owned by the File from here on
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Inotify {
+                fd: unsafe { File::from_raw_fd(fd) },
+            })
+        }
+
+        pub fn add_watch(&self, path: &Path) -> io::Result<i32> {
+            let path = CString::new(path.as_os_str().as_bytes())?;
+            // SAFETY: the path is a valid nul terminated string
+            let wd =
+                unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), WATCH_MASK) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(wd)
+        }
+
+        pub fn rm_watch(&self, wd: i32) {
+            // SAFETY: removing a watch that's already gone only returns an error
+            unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), wd) };
+        }
+
+        /// Blocks until there are events
+        pub fn read_events(&mut self) -> io::Result<Vec<Event>> {
+            let mut buf = vec![0u8; 64 * 1024];
+            let len = self.fd.read(&mut buf)?;
+            let mut events = Vec::new();
+            let mut offset = 0;
+            // each event is a wd, mask, cookie and name length followed by
+            // the nul padded name
+            while offset + 16 <= len {
+                let field = |i: usize| {
+                    let start = offset + i * 4;
+                    [buf[start], buf[start + 1], buf[start + 2], buf[start + 3]]
+                };
+                let name_len = u32::from_ne_bytes(field(3)) as usize;
+                let name = &buf[offset + 16..offset + 16 + name_len];
+                let name = name.split(|b| *b == 0).next().unwrap_or_default();
+                events.push(Event {
+                    wd: i32::from_ne_bytes(field(0)),
+                    mask: u32::from_ne_bytes(field(1)),
+                    name: String::from_utf8_lossy(name).into_owned(),
+                });
+                offset += 16 + name_len;
+            }
+            Ok(events)
+        }
+    }
+}
+
+// the watched dirs keyed by watch descriptor, paths are relative to the
+// top of the worktree
+#[cfg(target_os = "linux")]
+struct Watches {
+    inotify: inotify::Inotify,
+    dirs: HashMap<i32, String>,
+    cookie_wd: i32,
+    worktree: PathBuf,
+}
+
+#[cfg(target_os = "linux")]
+impl Watches {
+    // watches the dir and every dir inside it, returning the paths found
+    // inside so changes made before the watches existed aren't missed.
+    // Nested repos aren't watched since status doesn't look inside them.
+    // Anything removed while it's being watched is skipped, any other error
+    // means changes could be missed.
+    fn watch_tree(&mut self, path: &str) -> Result<Vec<String>, err::Error> {
+        let missing = |e: &io::Error| e.kind() == io::ErrorKind::NotFound;
+        let full_path = self.worktree.join(path);
+        let wd = match self.inotify.add_watch(&full_path) {
+            Err(e) if missing(&e) => return Ok(Vec::new()),
+            wd => wd?,
+        };
+        self.dirs.insert(wd, path.to_owned());
+        let nodes = match read_dir(&full_path) {
+            Err(e) if missing(&e) => return Ok(Vec::new()),
+            nodes => nodes?,
+        };
+        let mut found = Vec::new();
+        for node in nodes {
+            let node = node?;
+            let name = node.file_name().to_string_lossy().into_owned();
+            if path.is_empty() && name == ".git" {
+                continue;
+            }
+            let rel = join(path, &name);
+            let md = match symlink_metadata(node.path()) {
+                Err(e) if missing(&e) => continue,
+                md => md?,
+            };
+            if md.is_dir() && !node.path().join(".git").exists() {
+                found.append(&mut self.watch_tree(&rel)?);
+            }
+            found.push(rel);
+        }
+        Ok(found)
+    }
+
+    // stops watching the dir and the dirs inside it, used when a dir is
+    // moved since its watches would report the old path
+    fn unwatch_tree(&mut self, path: &str) {
+        let prefix = format!("{path}/");
+        let wds: Vec<i32> = self
+            .dirs
+            .iter()
+            .filter(|(_, dir)| *dir == path || dir.starts_with(&prefix))
+            .map(|(wd, _)| *wd)
+            .collect();
+        for wd in wds {
+            self.inotify.rm_watch(wd);
+            self.dirs.remove(&wd);
+        }
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_owned()
+    } else if name.is_empty() {
+        dir.to_owned()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+// records the changes the events describe until reading fails
+#[cfg(target_os = "linux")]
+fn read_events(mut watches: Watches, shared: Shared) -> Result<(), err::Error> {
+    let (state, seen) = &*shared;
+    loop {
+        let events = watches.inotify.read_events()?;
+        let mut state = state.lock().unwrap();
+        for event in events {
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                state.forget_changes();
+                continue;
+            }
+            if event.wd == watches.cookie_wd {
+                if event.mask & libc::IN_CREATE != 0 {
+                    state.cookies.insert(event.name);
+                }
+                continue;
+            }
+            if event.mask & libc::IN_IGNORED != 0 {
+                watches.dirs.remove(&event.wd);
+                continue;
+            }
+            let Some(dir) = watches.dirs.get(&event.wd) else {
+                continue;
+            };
+            let path = join(dir, &event.name);
+            if event.mask & libc::IN_ISDIR != 0 {
+                if event.mask & libc::IN_MOVED_FROM != 0 {
+                    watches.unwatch_tree(&path);
+                } else if event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                    for inside in watches.watch_tree(&path)? {
+                        state.record(inside);
+                    }
+                }
+            }
+            state.record(path);
+        }
+        seen.notify_all();
+    }
+}
+
+// creates a file in the cookie dir and waits for its event, once it's seen
+// every change made before the query has been recorded too
+fn sync_cookie(name: &str, shared: &Shared, repo: &obj::Repo) -> Result<bool, err::Error> {
+    let path = cookie_dir(repo).join(name);
+    File::create(&path)?;
+    let (state, seen) = &**shared;
+    let (mut state, timeout) = seen
+        .wait_timeout_while(state.lock().unwrap(), COOKIE_TIMEOUT, |s| {
+            !s.cookies.contains(name) && !s.failed
+        })
+        .unwrap();
+    state.cookies.remove(name);
+    drop(state);
+    remove_file(&path)?;
+    Ok(!timeout.timed_out())
+}
+
+// answers one request, returns false once the daemon should stop. After
+// events stop being read queries get a trivial response and the daemon
+// stops.
+fn handle_request(
+    mut stream: UnixStream,
+    cookie: u64,
+    shared: &Shared,
+    repo: &obj::Repo,
+) -> Result<bool, err::Error> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let started = Instant::now();
+    let mut request = Vec::new();
+    let mut byte = [0u8];
+    while stream.read(&mut byte)? == 1 && byte[0] != b'\n' {
+        if started.elapsed() > REQUEST_TIMEOUT {
+            return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+        }
+        request.push(byte[0]);
+    }
+    let request = String::from_utf8_lossy(&request).into_owned();
+    let failed = shared.0.lock().unwrap().failed;
+    let Some(token) = request.strip_prefix("query ") else {
+        stream.write_all(b"ok\0")?;
+        return Ok(request != "stop" && !failed);
+    };
+
+    let cookie = format!("{}-{cookie}", std::process::id());
+    let synced = !failed && sync_cookie(&cookie, shared, repo)?;
+    let state = shared.0.lock().unwrap();
+    let failed = state.failed;
+    let paths = match synced && !failed {
+        true => state.changes_since(token),
+        false => None,
+    };
+    let mut response = state.token().into_bytes();
+    response.push(0);
+    for path in paths.unwrap_or_else(|| BTreeSet::from(["/".to_owned()])) {
+        response.extend_from_slice(path.as_bytes());
+        response.push(0);
+    }
+    drop(state);
+    stream.write_all(&response)?;
+    Ok(!failed)
+}
+
+/// Watches the worktree and answers queries until asked to stop. The
+/// changes are only kept in memory, a restarted daemon answers every token
+/// from before with a response saying everything changed.
+#[cfg(target_os = "linux")]
+pub fn run_daemon(repo: &obj::Repo) -> Result<(), err::Error> {
+    if is_running(repo)? {
+        let worktree = utils::absolute_worktree(repo).display().to_string();
+        return Err(err::Error::GitFsmonitorAlreadyRunning(worktree));
+    }
+    // a socket left by a daemon that didn't exit cleanly
+    let socket = socket_path(repo);
+    if socket.exists() {
+        remove_file(&socket)?;
+    }
+    create_dir_all(cookie_dir(repo))?;
+
+    let inotify = inotify::Inotify::new()?;
+    let cookie_wd = inotify.add_watch(&cookie_dir(repo))?;
+    let mut watches = Watches {
+        inotify,
+        dirs: HashMap::new(),
+        cookie_wd,
+        worktree: repo.worktree.clone(),
+    };
+    watches.watch_tree("")?;
+
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let state = DaemonState {
+        id: format!("{}.{}", std::process::id(), started.as_nanos()),
+        ..Default::default()
+    };
+    let shared: Shared = Arc::new((Mutex::new(state), Condvar::new()));
+    let reader_shared = shared.clone();
+    let reader = thread::spawn(move || {
+        let result = read_events(watches, reader_shared.clone());
+        let (state, seen) = &*reader_shared;
+        state.lock().unwrap().failed = true;
+        seen.notify_all();
+        result
+    });
+
+    // a client hanging up or a request that can't be answered only ends
+    // that connection, the daemon keeps answering the others
+    let listener = UnixListener::bind(&socket)?;
+    for (cookie, stream) in listener.incoming().enumerate() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        match handle_request(stream, cookie as u64, &shared, repo) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(_) if shared.0.lock().unwrap().failed => break,
+            Err(_) => {}
+        }
+    }
+    remove_file(&socket)?;
+    remove_dir_all(cookie_dir(repo).parent().unwrap_or(Path::new("")))?;
+    // the reader only returns when reading events failed
+    if reader.is_finished() {
+        if let Ok(result) = reader.join() {
+            result?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn run_daemon(_repo: &obj::Repo) -> Result<(), err::Error> {
+    Err(err::Error::GitFsmonitorUnsupported)
+}
+
+#[cfg(test)]
+mod fsmonitor_tests {
+    use super::*;
+    use crate::test_utils;
+    use std::fs::write;
+
+    // a query answered before the daemon saw its cookie says everything
+    // changed, it's asked again so a slow machine doesn't fail the test
+    fn query_changes(token: &str, repo: &obj::Repo) -> FsmonitorChanges {
+        let started = Instant::now();
+        loop {
+            let changes = query(token, repo).unwrap().unwrap();
+            if changes.paths.is_some() || started.elapsed() > Duration::from_secs(30) {
+                return changes;
+            }
+        }
+    }
+
+    #[test]
+    fn daemon_reports_paths_changed_since_a_token() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        write(repo.worktree.join("a"), "a\n").unwrap();
+
+        let daemon_repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let daemon = thread::spawn(move || run_daemon(&daemon_repo));
+        let started = Instant::now();
+        while !is_running(&repo).unwrap() && started.elapsed() < Duration::from_secs(30) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // an unknown token means everything has to be checked
+        let first = query("", &repo).unwrap().unwrap();
+        assert_eq!(None, first.paths);
+        assert!(first.covers("a"));
+
+        write(repo.worktree.join("a"), "changed\n").unwrap();
+        create_dir_all(repo.worktree.join("dir")).unwrap();
+        write(repo.worktree.join("dir/new"), "n\n").unwrap();
+        let second = query_changes(&first.token, &repo);
+        let paths = second.paths.clone().unwrap();
+        assert!(paths.contains("a") && paths.contains("dir/new"));
+        assert!(second.covers("dir/new"));
+
+        let third = query_changes(&second.token, &repo);
+        assert_eq!(Some(BTreeSet::new()), third.paths);
+        assert!(!third.covers("a"));
+
+        stop_daemon(&repo).unwrap();
+        daemon.join().unwrap().unwrap();
+        assert!(!is_running(&repo).unwrap());
+    }
+
+    // a stand in for the daemon that reads one request and answers it with
+    // the response, or hangs up without answering when there isn't one
+    fn answer_once(response: Option<&'static [u8]>, repo: &obj::Repo) -> thread::JoinHandle<()> {
+        let listener = UnixListener::bind(socket_path(repo)).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut byte = [0u8];
+            while stream.read(&mut byte).unwrap() == 1 && byte[0] != b'\n' {}
+            if let Some(response) = response {
+                stream.write_all(response).unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn query_only_trusts_responses_with_rusty_git_tokens() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let responses: [Option<&'static [u8]>; 4] = [
+            None,
+            // git's own daemon uses tokens like this
+            Some(b"1:1700000000:2\0a\0"),
+            Some(b"rusty-git:1.2:3"),
+            Some(b"rusty-git:1.2:3\0a\0"),
+        ];
+        let mut answers = Vec::new();
+        for response in responses {
+            let answering = answer_once(response, &repo);
+            answers.push(query("rusty-git:1.2:0", &repo).unwrap());
+            answering.join().unwrap();
+            remove_file(socket_path(&repo)).unwrap();
+        }
+        let expected = FsmonitorChanges {
+            token: "rusty-git:1.2:3".to_owned(),
+            paths: Some(BTreeSet::from(["a".to_owned()])),
+        };
+        assert_eq!(vec![None, None, None, Some(expected)], answers);
+    }
+
+    #[test]
+    fn query_falls_back_to_a_full_scan_when_the_daemon_doesnt_answer() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let listener = UnixListener::bind(socket_path(&repo)).unwrap();
+        let (done, finished) = std::sync::mpsc::channel::<()>();
+        let stuck = thread::spawn(move || {
+            let (_stream, _) = listener.accept().unwrap();
+            let _ = finished.recv();
+        });
+
+        let started = Instant::now();
+        assert_eq!(None, query("rusty-git:1.2:0", &repo).unwrap());
+        assert!(started.elapsed() >= RESPONSE_TIMEOUT);
+        done.send(()).unwrap();
+        stuck.join().unwrap();
+    }
+
+    #[test]
+    fn daemon_keeps_answering_after_a_client_hangs_up_or_stalls() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let daemon_repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let daemon = thread::spawn(move || run_daemon(&daemon_repo));
+        let started = Instant::now();
+        while !is_running(&repo).unwrap() && started.elapsed() < Duration::from_secs(30) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // answering clients that are already gone fails to write
+        for _ in 0..3 {
+            drop(UnixStream::connect(socket_path(&repo)).unwrap());
+        }
+        let mut client = UnixStream::connect(socket_path(&repo)).unwrap();
+        client.write_all(b"query ").unwrap();
+        drop(client);
+        // a client that never finishes its request is given up on
+        let mut idle = UnixStream::connect(socket_path(&repo)).unwrap();
+        idle.write_all(b"query ").unwrap();
+
+        assert!(query("", &repo).unwrap().is_some());
+        drop(idle);
+        stop_daemon(&repo).unwrap();
+        daemon.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn daemon_stops_once_events_stop_being_read() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        let state = DaemonState {
+            id: "1.2".to_owned(),
+            failed: true,
+            ..Default::default()
+        };
+        let shared: Shared = Arc::new((Mutex::new(state), Condvar::new()));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(b"query rusty-git:1.2:0\n").unwrap();
+        assert!(!handle_request(server, 0, &shared, &repo).unwrap());
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        assert_eq!(b"rusty-git:1.2:0\0/\0".to_vec(), response);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn watching_skips_only_missing_dirs() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        create_dir_all(repo.worktree.join("dir")).unwrap();
+        write(repo.worktree.join("file"), "f\n").unwrap();
+        let mut watches = Watches {
+            inotify: inotify::Inotify::new().unwrap(),
+            dirs: HashMap::new(),
+            cookie_wd: -1,
+            worktree: repo.worktree.clone(),
+        };
+        assert_eq!(Vec::<String>::new(), watches.watch_tree("gone").unwrap());
+        assert!(watches.watch_tree("dir").is_ok());
+        // a file where a dir was expected can't be watched
+        assert!(watches.watch_tree("file").is_err());
+    }
+
+    #[test]
+    fn fsmonitor_extension_round_trips() {
+        let data = FsmonitorData {
+            token: "rusty-git:1.2:3".to_owned(),
+            dirty: vec![false, true, false],
+        };
+        assert_eq!(data, FsmonitorData::parse(&data.as_bytes()).unwrap());
+    }
+}
@@ -0,0 +1,193 @@
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::config;
+use crate::dircache::DirCache;
+use crate::error as err;
+use crate::objects as obj;
+use crate::pattern::Pattern;
+
+/// A pattern from one of the ignore files along with where it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IgnoreRule {
+    pub pattern: Pattern,
+    /// the line as written in the file, used by check-ignore -v
+    pub text: String,
+    pub source: String,
+    pub line: usize,
+}
+
+impl IgnoreRule {
+    /// A matching rule ignores the path unless it's negated
+    pub fn ignores(&self) -> bool {
+        !self.pattern.negated
+    }
+}
+
+/// The ignore rules of a worktree. The .gitignore files are read once as
+/// the dirs holding them are first checked, the rules can be shared by
+/// threads walking the worktree.
+#[derive(Debug)]
+pub struct IgnoreRules {
+    worktree: PathBuf,
+    // info/exclude then core.excludesFile, the order they are checked in
+    excludes: Vec<Vec<IgnoreRule>>,
+    // the rules of the .gitignore in each dir
+    dirs: DirCache<Vec<IgnoreRule>>,
+}
+
+fn parse_rules(contents: &str, source: &str) -> Vec<IgnoreRule> {
+    contents
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let pattern = Pattern::parse(line)?;
+            Some(IgnoreRule {
+                pattern,
+                text: line.to_owned(),
+                source: source.to_owned(),
+                line: i + 1,
+            })
+        })
+        .collect()
+}
+
+fn read_rules(path: &Path, source: &str) -> Result<Vec<IgnoreRule>, err::Error> {
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    Ok(parse_rules(&read_to_string(path)?, source))
+}
+
+// the last rule matching the path, a later line overrides an earlier one
+fn last_match<'a>(rules: &'a [IgnoreRule], path: &str, is_dir: bool) -> Option<&'a IgnoreRule> {
+    rules.iter().rev().find(|r| r.pattern.matches(path, is_dir))
+}
+
+impl IgnoreRules {
+    pub fn new(repo: &obj::Repo) -> Result<IgnoreRules, err::Error> {
+        let info = repo.git_path("info/exclude");
+        let info_source = match info.strip_prefix(&repo.worktree) {
+            Ok(relative) => relative.to_string_lossy().into_owned(),
+            Err(_) => info.to_string_lossy().into_owned(),
+        };
+        let mut excludes = vec![read_rules(&info, &info_source)?];
+        if let Some(file) = config::get_path(repo, "core.excludesFile")? {
+            excludes.push(read_rules(&file, &file.to_string_lossy())?);
+        }
+        Ok(IgnoreRules {
+            worktree: repo.worktree.clone(),
+            excludes,
+            dirs: DirCache::default(),
+        })
+    }
+
+    fn dir_rules(&self, dir: &str) -> Result<Arc<Vec<IgnoreRule>>, err::Error> {
+        self.dirs.get_or_read(dir, || {
+            let source = match dir {
+                "" => ".gitignore".to_owned(),
+                dir => format!("{dir}/.gitignore"),
+            };
+            read_rules(&self.worktree.join(&source), &source)
+        })
+    }
+
+    // the rule deciding whether the path itself is ignored without looking
+    // at its parent dirs. The .gitignore of the deepest dir takes priority
+    // then the ones above it, then info/exclude and core.excludesFile.
+    fn direct_match(&self, path: &str, is_dir: bool) -> Result<Option<IgnoreRule>, err::Error> {
+        let mut dirs: Vec<&str> = path.match_indices('/').map(|(i, _)| &path[..i]).collect();
+        dirs.push("");
+        // deepest first
+        dirs.sort_by_key(|d| std::cmp::Reverse(d.len()));
+        for dir in dirs {
+            let relative = path[dir.len()..].trim_start_matches('/');
+            if let Some(rule) = last_match(&self.dir_rules(dir)?, relative, is_dir) {
+                return Ok(Some(rule.clone()));
+            }
+        }
+        for rules in &self.excludes {
+            if let Some(rule) = last_match(rules, path, is_dir) {
+                return Ok(Some(rule.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the rule deciding whether the path, relative to the top of
+    /// the worktree, is ignored. A path inside an ignored dir is always
+    /// ignored since git never looks inside the dir to find a rule that
+    /// would include it again.
+    pub fn matching_rule(
+        &self,
+        path: &str,
+        is_dir: bool,
+    ) -> Result<Option<IgnoreRule>, err::Error> {
+        for (i, _) in path.match_indices('/') {
+            if let Some(rule) = self.direct_match(&path[..i], true)? {
+                if rule.ignores() {
+                    return Ok(Some(rule));
+                }
+            }
+        }
+        self.direct_match(path, is_dir)
+    }
+
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> Result<bool, err::Error> {
+        Ok(self
+            .matching_rule(path, is_dir)?
+            .is_some_and(|r| r.ignores()))
+    }
+
+    /// Like is_ignored for walks that never enter ignored dirs, the parent
+    /// dirs of the path are known not to be ignored so aren't checked again
+    pub fn is_ignored_in_walk(&self, path: &str, is_dir: bool) -> Result<bool, err::Error> {
+        Ok(self.direct_match(path, is_dir)?.is_some_and(|r| r.ignores()))
+    }
+}
+
+#[cfg(test)]
+mod ignore_tests {
+    use super::*;
+    use crate::test_utils;
+    use std::fs::{create_dir_all, write};
+
+    #[test]
+    fn deeper_files_override_and_ignored_dirs_cant_be_reincluded() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let mut repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        create_dir_all(repo.worktree.join("logs")).unwrap();
+        create_dir_all(repo.git_path("info")).unwrap();
+        write(
+            repo.worktree.join(".gitignore"),
+            "*.log\nbuild/\n**/target\n/root.txt\n",
+        )
+        .unwrap();
+        write(repo.worktree.join("logs/.gitignore"), "!keep.log\n").unwrap();
+        write(repo.git_path("info/exclude"), "*.tmp\n!*.log\n").unwrap();
+        write(repo.worktree.join("global"), "*.swp\n").unwrap();
+        config::set(&mut repo, "core.excludesFile", "global").unwrap();
+
+        let rules = IgnoreRules::new(&repo).unwrap();
+        assert!(rules.is_ignored("debug.log", false).unwrap());
+        assert!(!rules.is_ignored("logs/keep.log", false).unwrap());
+        assert!(rules.is_ignored("logs/other.log", false).unwrap());
+        assert!(rules.is_ignored("build", true).unwrap());
+        assert!(!rules.is_ignored("build", false).unwrap());
+        assert!(rules.is_ignored("build/keep.log", false).unwrap());
+        assert!(rules.is_ignored("a/b/target", true).unwrap());
+        assert!(rules.is_ignored("root.txt", false).unwrap());
+        assert!(!rules.is_ignored("src/root.txt", false).unwrap());
+        assert!(rules.is_ignored("x.tmp", false).unwrap());
+        assert!(rules.is_ignored("src/x.swp", false).unwrap());
+
+        let rule = rules
+            .matching_rule("logs/keep.log", false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(("logs/.gitignore", 1), (rule.source.as_str(), rule.line));
+        let rule = rules.matching_rule("x.tmp", false).unwrap().unwrap();
+        assert_eq!(".git/info/exclude", rule.source);
+    }
+}
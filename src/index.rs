@@ -2,7 +2,7 @@ use chrono::{DateTime, TimeZone, Utc};
 use nom::{
     bytes::complete::{is_a, take},
     error::{Error, ErrorKind},
-    multi::many0,
+    multi::count,
     number::{
         complete::{u16, u32},
         Endianness::Big,
@@ -11,10 +11,11 @@ use nom::{
 };
 use sha1_smol as sha1;
 use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::str::from_utf8;
 
 use crate::objects as obj;
-use crate::{error as err, utils};
+use crate::{error as err, fsmonitor, untracked, utils};
 
 fn nom_many0_err(input: &[u8]) -> Err<Error<&[u8]>> {
     // this error type allows the parser to continue with the input
@@ -27,6 +28,18 @@ fn nom_many0_err(input: &[u8]) -> Err<Error<&[u8]>> {
     })
 }
 
+// bits stored in the 16 bit flags field of an entry, the low 12 bits
+// hold the length of the entry's name
+pub const FLAG_ASSUME_VALID: u16 = 0x8000;
+pub const FLAG_EXTENDED: u16 = 0x4000;
+pub const FLAG_STAGE_MASK: u16 = 0x3000;
+const FLAG_NAME_MASK: u16 = 0x0fff;
+
+// bits stored in the extra 16 bit field that index version 3 adds to
+// entries with FLAG_EXTENDED set, 0x2000 is intent-to-add which isn't
+// supported yet
+pub const EXT_FLAG_SKIP_WORKTREE: u16 = 0x4000;
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct IndexEntry {
     pub c_time: DateTime<Utc>,
@@ -39,11 +52,73 @@ pub struct IndexEntry {
     pub size: u32,
     pub sha: Vec<u8>,
     pub name: String,
+    pub flags: u16,
+    pub extended_flags: u16,
+}
+
+impl IndexEntry {
+    /// Creates an entry that isn't backed by a file in the worktree, the
+    /// stat info is zeroed so the entry will look modified until refreshed
+    pub fn new(mode: u32, sha: Vec<u8>, name: &str) -> IndexEntry {
+        let epoch = Utc.timestamp_opt(0, 0).unwrap();
+        IndexEntry {
+            c_time: epoch,
+            m_time: epoch,
+            dev: 0,
+            inode: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            sha,
+            name: name.to_owned(),
+            flags: 0,
+            extended_flags: 0,
+        }
+    }
+
+    pub fn stage(&self) -> u16 {
+        (self.flags & FLAG_STAGE_MASK) >> 12
+    }
+
+    pub fn set_stage(&mut self, stage: u16) {
+        self.flags = (self.flags & !FLAG_STAGE_MASK) | ((stage << 12) & FLAG_STAGE_MASK);
+    }
+
+    pub fn assume_valid(&self) -> bool {
+        self.flags & FLAG_ASSUME_VALID != 0
+    }
+
+    pub fn set_assume_valid(&mut self, on: bool) {
+        if on {
+            self.flags |= FLAG_ASSUME_VALID;
+        } else {
+            self.flags &= !FLAG_ASSUME_VALID;
+        }
+    }
+
+    pub fn skip_worktree(&self) -> bool {
+        self.extended_flags & EXT_FLAG_SKIP_WORKTREE != 0
+    }
+
+    pub fn set_skip_worktree(&mut self, on: bool) {
+        if on {
+            self.extended_flags |= EXT_FLAG_SKIP_WORKTREE;
+        } else {
+            self.extended_flags &= !EXT_FLAG_SKIP_WORKTREE;
+        }
+    }
+
+    fn is_extended(&self) -> bool {
+        self.extended_flags != 0
+    }
 }
 
 impl Ord for IndexEntry {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.name.cmp(&other.name)
+        self.name
+            .cmp(&other.name)
+            .then(self.stage().cmp(&other.stage()))
     }
 }
 
@@ -53,17 +128,6 @@ impl PartialOrd for IndexEntry {
     }
 }
 
-impl obj::NameSha for IndexEntry {
-    fn get_name_and_sha(&self, name_prefix: Option<String>) -> (String, String) {
-        let sha = utils::get_sha_from_binary(&self.sha);
-        if let Some(prefix) = name_prefix {
-            (format!("{prefix}/{}", self.name), sha)
-        } else {
-            (self.name.clone(), sha)
-        }
-    }
-}
-
 impl obj::AsBytes for IndexEntry {
     fn as_bytes(&self) -> Vec<u8> {
         let c_seconds = self.c_time.timestamp() as u32;
@@ -79,14 +143,24 @@ impl obj::AsBytes for IndexEntry {
         .flat_map(|i| i.to_be_bytes())
         .collect();
 
-        let name_size = self.name.len() as u16;
-        let entry_length = 62 + name_size;
+        // names too long for the flags field store the max length and
+        // rely on the trailing null byte instead
+        let name_size = self.name.len().min(FLAG_NAME_MASK as usize) as u16;
+        let mut flags = (self.flags & !(FLAG_NAME_MASK | FLAG_EXTENDED)) | name_size;
+        let mut extended_flags: Vec<u8> = Vec::new();
+        if self.is_extended() {
+            flags |= FLAG_EXTENDED;
+            extended_flags = self.extended_flags.to_be_bytes().to_vec();
+        }
+
+        let entry_length = 62 + extended_flags.len() + self.name.len();
         let padding_bytes: Vec<u8> = (0..(8 - entry_length % 8)).map(|_| b'\0').collect();
 
         [
             index_meta_info,
             self.sha.clone(),
-            name_size.to_be_bytes().to_vec(),
+            flags.to_be_bytes().to_vec(),
+            extended_flags,
             self.name.as_bytes().to_vec(),
             padding_bytes,
         ]
@@ -121,8 +195,21 @@ pub fn parse_git_index_entry(input: &[u8]) -> IResult<&[u8], IndexEntry> {
     let (input, gid) = u32(Big)(input)?;
     let (input, size) = u32(Big)(input)?;
     let (input, bsha) = take(20usize)(input)?;
-    let (input, name_size) = u16(Big)(input)?;
+    let (input, flags) = u16(Big)(input)?;
+    let (input, extended_flags) = if flags & FLAG_EXTENDED != 0 {
+        u16(Big)(input)?
+    } else {
+        (input, 0)
+    };
 
+    let name_size = if flags & FLAG_NAME_MASK == FLAG_NAME_MASK {
+        match input.iter().position(|b| *b == b'\0') {
+            Some(len) => len,
+            None => return Err(nom_many0_err(input)),
+        }
+    } else {
+        (flags & FLAG_NAME_MASK) as usize
+    };
     let (input, name) = take(name_size)(input)?;
     let parsed_name;
     if let Ok(pn) = from_utf8(name) {
@@ -131,8 +218,8 @@ pub fn parse_git_index_entry(input: &[u8]) -> IResult<&[u8], IndexEntry> {
         return Err(nom_many0_err(input));
     }
 
-    // 62 bytes per entry not counting length of name
-    let entry_length = 62 + name_size;
+    // 62 bytes per entry not counting length of name or extended flags
+    let entry_length = 62 + name_size + if flags & FLAG_EXTENDED != 0 { 2 } else { 0 };
     let padding_bytes = 8 - entry_length % 8;
     // the parser need to eat the padding bytes after each entry
     let (input, _null_bytes) = take(padding_bytes)(input)?;
@@ -150,6 +237,8 @@ pub fn parse_git_index_entry(input: &[u8]) -> IResult<&[u8], IndexEntry> {
             size,
             sha: bsha.to_vec(),
             name: parsed_name.to_owned(),
+            flags: flags & !(FLAG_NAME_MASK | FLAG_EXTENDED),
+            extended_flags,
         },
     ))
 }
@@ -157,21 +246,81 @@ pub fn parse_git_index_entry(input: &[u8]) -> IResult<&[u8], IndexEntry> {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Index {
     pub entries: Vec<IndexEntry>,
+    /// the UNTR extension
+    pub untracked: Option<untracked::UntrackedCache>,
+    /// the FSMN extension
+    pub fsmonitor: Option<fsmonitor::FsmonitorData>,
 }
 
 impl Index {
-    pub fn new(entry: IndexEntry) -> Result<Index, err::Error> {
-        Ok(Index {
-            entries: [entry].to_vec(),
-        })
+    pub fn empty() -> Index {
+        Index {
+            entries: Vec::new(),
+            untracked: None,
+            fsmonitor: None,
+        }
+    }
+
+    /// Keeps the extensions in step with entry changes made since the index
+    /// was previous. The dirs of paths added or removed are read again by
+    /// the untracked cache, and the fsmonitor bits only describe the
+    /// entries they were recorded with so they're dropped when the entries
+    /// change without a new token.
+    pub fn invalidate_extensions(&mut self, previous: &Index) {
+        if let Some(cache) = self.untracked.as_mut() {
+            let old: HashSet<&str> = previous.entries.iter().map(|e| e.name.as_str()).collect();
+            let new: HashSet<&str> = self.entries.iter().map(|e| e.name.as_str()).collect();
+            for path in old.symmetric_difference(&new) {
+                cache.invalidate_path(path);
+            }
+        }
+        if self.fsmonitor.is_some()
+            && self.fsmonitor == previous.fsmonitor
+            && self.entries != previous.entries
+        {
+            self.fsmonitor = None;
+        }
+    }
+
+    /// Finds the stage 0 entry for the name, entries in other stages only
+    /// exist while a merge conflict is being resolved
+    pub fn find_entry(&self, name: &str) -> Result<usize, usize> {
+        self.entries
+            .binary_search_by(|e| e.name.as_str().cmp(name).then(e.stage().cmp(&0)))
+    }
+
+    /// Removes the entries for the name in every stage, returns true if
+    /// anything was removed
+    pub fn remove_entry(&mut self, name: &str) -> bool {
+        let starting_len = self.entries.len();
+        self.entries.retain(|e| e.name != name);
+        starting_len != self.entries.len()
+    }
+
+    /// Inserts the entry keeping the entries sorted by name, an existing
+    /// entry with the same name is replaced and returned
+    pub fn add_entry(&mut self, entry: IndexEntry) -> Option<IndexEntry> {
+        match self.entries.binary_search(&entry) {
+            Ok(pos) => Some(std::mem::replace(&mut self.entries[pos], entry)),
+            Err(pos) => {
+                self.entries.insert(pos, entry);
+                None
+            }
+        }
     }
 }
 
 impl obj::AsBytes for Index {
     fn as_bytes(&self) -> Vec<u8> {
+        // version 3 is only needed when an entry uses the extended flags
+        let version: u32 = if self.entries.iter().any(|e| e.is_extended()) {
+            3
+        } else {
+            2
+        };
         let header = [
             "DIRC".as_bytes(),
-            [0x00, 0x00, 0x00, 0x02].as_ref(),
+            &version.to_be_bytes(),
             &(self.entries.len() as u32).to_be_bytes(),
         ]
         .concat();
@@ -183,7 +332,17 @@ impl obj::AsBytes for Index {
             .collect::<Vec<Vec<u8>>>()
             .concat();
 
-        let index_contents = [header, entries].concat();
+        // extensions are a 4 byte signature and a 4 byte size before the data
+        let mut extensions = Vec::new();
+        let untracked = self.untracked.as_ref().map(|u| (b"UNTR", u.as_bytes()));
+        let fsmonitor = self.fsmonitor.as_ref().map(|f| (b"FSMN", f.as_bytes()));
+        for (signature, data) in untracked.into_iter().chain(fsmonitor) {
+            extensions.extend_from_slice(signature);
+            extensions.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            extensions.extend(data);
+        }
+
+        let index_contents = [header, entries, extensions].concat();
 
         let mut hasher = sha1::Sha1::new();
         hasher.update(&index_contents);
@@ -196,13 +355,46 @@ impl obj::AsBytes for Index {
 pub fn parse_git_index(input: &[u8]) -> Result<Index, err::Error> {
     let (input, _dirc) = is_a("DIRC")(input)?;
     let (input, version) = u32(Big)(input)?;
-    if version != 2 {
+    if version != 2 && version != 3 {
         return Err(err::Error::GitUnrecognizedIndexVersion(version));
     }
-    let (input, _num_entries) = u32(Big)(input)?;
-    let (_, entries) = many0(parse_git_index_entry)(input)?;
+    let (input, num_entries) = u32(Big)(input)?;
+    let (mut input, entries) = count(parse_git_index_entry, num_entries as usize)(input)?;
+
+    // optional extensions, which have an uppercase signature, are dropped
+    // when they aren't supported. Ones with a lowercase signature change
+    // how the index is read so an index using them can't be read at all.
+    // The index ends with a 20 byte sha.
+    let mut index = Index {
+        entries,
+        ..Index::empty()
+    };
+    while input.len() > 20 {
+        let (rest, signature) = take(4usize)(input)?;
+        let (rest, size) = u32(Big)(rest)?;
+        let (rest, data) = take(size as usize)(rest)?;
+        match signature {
+            b"UNTR" => index.untracked = Some(untracked::UntrackedCache::parse(data)?),
+            b"FSMN" => index.fsmonitor = Some(fsmonitor::FsmonitorData::parse(data)?),
+            [b'a'..=b'z', ..] => {
+                let signature = String::from_utf8_lossy(signature).into_owned();
+                return Err(err::Error::GitUnsupportedIndexExtension(signature));
+            }
+            _ => {}
+        }
+        input = rest;
+    }
+    Ok(index)
+}
 
-    Ok(Index { entries })
+/// Reads and parses .git/index, when the index doesn't exist yet an empty
+/// Index is returned so callers can add entries to it
+pub fn read_index_or_empty(repo: &obj::Repo) -> Result<Index, err::Error> {
+    if utils::git_index_exists(repo) {
+        parse_git_index(&utils::git_read_index(repo)?)
+    } else {
+        Ok(Index::empty())
+    }
 }
 
 #[cfg(test)]
@@ -241,6 +433,8 @@ mod object_parsing_tests {
             ]
             .to_vec(),
             name: "Cargo.toml".to_owned(),
+            flags: 0,
+            extended_flags: 0,
         };
         let (input, result) = parse_git_index_entry(&entry).unwrap();
         assert_eq!(expected, result);
@@ -276,9 +470,30 @@ mod object_parsing_tests {
         // the next parser after the index_entry_parser
         let index = test_utils::fake_index_no_entry();
         let parsed_index = parse_git_index(&index).unwrap();
-        let expected = Index {
-            entries: [].to_vec(),
-        };
+        let expected = Index::empty();
         assert_eq!(expected, parsed_index);
     }
+
+    #[test]
+    fn optional_extensions_are_dropped_and_required_ones_fail() {
+        // the index minus its trailing sha with an extension appended
+        let with_extension = |signature: &[u8]| {
+            let index = test_utils::fake_index_no_entry();
+            let mut bytes = index[..index.len() - 20].to_vec();
+            bytes.extend_from_slice(signature);
+            bytes.extend_from_slice(&4u32.to_be_bytes());
+            bytes.extend_from_slice(b"data");
+            bytes.extend_from_slice(&[0; 20]);
+            bytes
+        };
+        assert_eq!(Index::empty(), parse_git_index(&with_extension(b"TREE")).unwrap());
+        assert_eq!(
+            Err(err::Error::GitUnsupportedIndexExtension("link".to_owned())),
+            parse_git_index(&with_extension(b"link"))
+        );
+        assert_eq!(
+            Err(err::Error::GitUnsupportedIndexExtension("sdir".to_owned())),
+            parse_git_index(&with_extension(b"sdir"))
+        );
+    }
 }
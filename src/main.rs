@@ -1,14 +1,27 @@
 use clap::Parser;
 use std::process;
 
+mod attributes;
 mod cli;
+mod cmds;
 mod commands;
+mod config;
+mod convert;
+mod dircache;
 mod error;
+mod ewah;
+mod filter;
+mod fsmonitor;
+mod ignore;
 mod index;
+mod objects;
+mod pathspec;
+mod pattern;
 mod test_utils;
+mod untracked;
 mod utils;
-mod cmds;
-mod objects;
+mod walk;
+mod wildmatch;
 
 use crate::commands as cmd;
 use crate::error as err;
@@ -1,9 +1,11 @@
 use std::fmt;
-use std::fs::read;
+use std::fs::{read, read_link, symlink_metadata};
 use std::path::PathBuf;
 use std::str::from_utf8;
 
-use super::{AsBytes, GitObj};
+use super::{AsBytes, GitObj, Repo};
+use crate::attributes as attr;
+use crate::convert;
 use crate::error as err;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -21,6 +23,38 @@ impl Blob {
     }
 }
 
+/// Reads the worktree file at the path relative to the top of the worktree
+/// into a blob the way the index stores it, for a symlink the blob holds the
+/// path the link points at rather than the contents of the file it points to
+pub fn blob_from_worktree(
+    name: &str,
+    attrs: &attr::AttrRules,
+    index_sha: Option<&str>,
+    repo: &Repo,
+) -> Result<GitObj, err::Error> {
+    let path = repo.worktree.join(name);
+    if symlink_metadata(&path)?.is_symlink() {
+        let target = read_link(path)?;
+        let target = target.to_str().ok_or(err::Error::PathToUtf8Conversion)?;
+        return Ok(GitObj::Blob(Blob::new(target.as_bytes())));
+    }
+    blob_from_worktree_file(name, attrs, index_sha, repo)
+}
+
+/// Reads the worktree file at the path relative to the top of the worktree
+/// into a blob, converting its contents the way they are stored in the repo.
+/// Symlinks are followed like hash-object does.
+pub fn blob_from_worktree_file(
+    name: &str,
+    attrs: &attr::AttrRules,
+    index_sha: Option<&str>,
+    repo: &Repo,
+) -> Result<GitObj, err::Error> {
+    let contents = read(repo.worktree.join(name))?;
+    let contents = convert::to_git(name, contents, attrs, index_sha, repo)?;
+    Ok(GitObj::Blob(Blob::new(&contents)))
+}
+
 pub fn blob_from_path(path: PathBuf) -> Result<GitObj, err::Error> {
     let blob_contents = read(path)?;
     Ok(GitObj::Blob(Blob::new(&blob_contents)))
@@ -1,8 +1,8 @@
-use chrono::offset;
+use chrono::{offset, FixedOffset, TimeZone};
 use nom::{
-    bytes::complete::{tag, take_till1, take_while1},
+    bytes::complete::{tag, take_till1, take_until, take_while1},
     character::{complete::space0, is_newline},
-    combinator::opt,
+    multi::many0,
     sequence::terminated,
     IResult,
 };
@@ -11,6 +11,7 @@ use std::fmt;
 use std::str::from_utf8;
 
 use super::{generic_nom_failure, AsBytes};
+use crate::config;
 use crate::error as err;
 
 fn parse_seperator_line(input: &[u8]) -> IResult<&[u8], &[u8]> {
@@ -19,15 +20,33 @@ fn parse_seperator_line(input: &[u8]) -> IResult<&[u8], &[u8]> {
     Ok((input, nl))
 }
 
-pub fn create_dummy_user() -> User {
+// the current time in the '<unix timestamp> <tz offset>' form git uses
+fn timestamp_now() -> String {
     let local = offset::Local::now();
     let local_tz = local.offset().to_string().replace(':', "");
     let local_ts = local.timestamp().to_string();
+    format!("{} {}", local_ts, local_tz)
+}
+
+pub fn create_dummy_user() -> User {
     User {
         name: "foo_name".to_string(),
         email: "<foo@email.com>".to_string(),
-        timestamp: format!("{} {}", local_ts, local_tz),
+        timestamp: timestamp_now(),
+    }
+}
+
+/// The user from user.name and user.email in the config stamped with the
+/// current time, the dummy user's values are used for anything not set
+pub fn current_user(conf: &str) -> Result<User, err::Error> {
+    let mut user = create_dummy_user();
+    if let Some(name) = config::get(conf, "user.name")? {
+        user.name = name;
+    }
+    if let Some(email) = config::get(conf, "user.email")? {
+        user.email = format!("<{}>", email);
     }
+    Ok(user)
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -37,6 +56,32 @@ pub struct User {
     pub timestamp: String,
 }
 
+impl User {
+    /// The unix timestamp the user's action was recorded at
+    pub fn time(&self) -> i64 {
+        self.timestamp
+            .split_whitespace()
+            .next()
+            .and_then(|ts| ts.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Formats the timestamp in the user's timezone the way git shows
+    /// dates by default, e.g. Tue Nov 14 22:13:20 2023 +0000
+    pub fn date(&self) -> String {
+        // the offset is written as [+-]hhmm
+        let tz = self.timestamp.split_whitespace().nth(1).unwrap_or("+0000");
+        let sign = if tz.starts_with('-') { -1 } else { 1 };
+        let hhmm: i32 = tz.get(1..).and_then(|n| n.parse().ok()).unwrap_or(0);
+        let offset = FixedOffset::east_opt(sign * (hhmm / 100 * 3600 + hhmm % 100 * 60))
+            .unwrap_or_else(|| FixedOffset::east_opt(0).unwrap());
+        match offset.timestamp_opt(self.time(), 0).single() {
+            Some(date) => date.format("%a %b %-d %H:%M:%S %Y %z").to_string(),
+            None => self.timestamp.clone(),
+        }
+    }
+}
+
 impl fmt::Display for User {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "{} {} {}", self.name, self.email, self.timestamp)
@@ -64,8 +109,13 @@ fn take_till_sep_convert_val_to_string(
     }
 }
 
-fn parse_user_bytes(input: &[u8]) -> IResult<&[u8], User> {
-    let (input, name) = take_till_sep_convert_val_to_string(" ")(input)?;
+pub fn parse_user_bytes(input: &[u8]) -> IResult<&[u8], User> {
+    // names can contain spaces so everything up to the email is the name
+    let (input, name) = terminated(take_until(" <"), tag(" "))(input)?;
+    let name = match from_utf8(name) {
+        Ok(n) => n.trim().to_owned(),
+        _ => return Err(generic_nom_failure(input)),
+    };
     let (input, email) = take_till_sep_convert_val_to_string(" ")(input)?;
     let (input, timestamp) = take_till_sep_convert_val_to_string("\n")(input)?;
     Ok((
@@ -81,7 +131,7 @@ fn parse_user_bytes(input: &[u8]) -> IResult<&[u8], User> {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Commit {
     pub tree: String,
-    pub parent: Option<String>,
+    pub parents: Vec<String>,
     pub author: User,
     pub committer: User,
     pub msg: String,
@@ -103,26 +153,15 @@ impl Commit {
 
 impl fmt::Display for Commit {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(p) = &self.parent {
-            write!(
-                f,
-                "tree {}\nparent {}\nauthor {}committer {}\n{}",
-                self.tree,
-                p,
-                self.author,
-                self.committer,
-                self.msg
-            )
-        } else {
-            write!(
-                f,
-                "tree {}\nauthor {}committer {}\n{}",
-                self.tree,
-                self.author,
-                self.committer,
-                self.msg
-            )
+        writeln!(f, "tree {}", self.tree)?;
+        for parent in &self.parents {
+            writeln!(f, "parent {}", parent)?;
         }
+        write!(
+            f,
+            "author {}committer {}\n{}",
+            self.author, self.committer, self.msg
+        )
     }
 }
 
@@ -148,7 +187,7 @@ impl AsBytes for Commit {
 /// consume the key and then capture the following value converting it to a
 /// String stripping any surrounding whitespace or newlines
 /// e.g. fn("tree") called with "tree sha123\n" returns ([], "sha123".to_string())
-fn parse_kv_pair_v_to_string(key: &'static str) -> impl Fn(&[u8]) -> IResult<&[u8], String> {
+pub fn parse_kv_pair_v_to_string(key: &'static str) -> impl Fn(&[u8]) -> IResult<&[u8], String> {
     move |input| {
         let (input, _) = tag(key)(input)?;
         let (input, val) = terminated(take_till1(is_newline), tag("\n"))(input)?;
@@ -162,7 +201,7 @@ fn parse_kv_pair_v_to_string(key: &'static str) -> impl Fn(&[u8]) -> IResult<&[u
 
 pub fn parse_commit(input: &[u8], sha: &str) -> Result<Commit, err::Error> {
     let (input, tree) = parse_kv_pair_v_to_string("tree")(input)?;
-    let (input, parent) = opt(parse_kv_pair_v_to_string("parent"))(input)?;
+    let (input, parents) = many0(parse_kv_pair_v_to_string("parent"))(input)?;
     let (input, _author_tag) = tag("author ")(input)?;
     let (input, author) = parse_user_bytes(input)?;
     let (input, _committer_tag) = tag("committer ")(input)?;
@@ -172,7 +211,7 @@ pub fn parse_commit(input: &[u8], sha: &str) -> Result<Commit, err::Error> {
 
     Ok(Commit {
         tree,
-        parent,
+        parents,
         author,
         committer,
         msg: msg.to_owned(),
@@ -183,7 +222,7 @@ pub fn parse_commit(input: &[u8], sha: &str) -> Result<Commit, err::Error> {
 #[cfg(test)]
 mod commit_tests {
     use super::*;
-    use chrono::{Local, TimeZone};
+    use chrono::{FixedOffset, NaiveDateTime};
 
     // commit parsing test covered in object/mod.rs tests
 
@@ -194,8 +233,11 @@ mod commit_tests {
             103, 109, 97, 105, 108, 46, 99, 111, 109, 62, 32, 49, 54, 55, 52, 57, 51, 57, 56, 57,
             55, 32, 45, 48, 55, 48, 48, 10,
         ];
-        let local = Local
-            .datetime_from_str("2023-01-28T14:04:57", "%Y-%m-%dT%H:%M:%S")
+        // the user bytes were recorded at UTC-7, pin the offset so the
+        // test doesn't depend on the timezone of the machine running it
+        let local = NaiveDateTime::parse_from_str("2023-01-28T14:04:57", "%Y-%m-%dT%H:%M:%S")
+            .unwrap()
+            .and_local_timezone(FixedOffset::west_opt(7 * 3600).unwrap())
             .unwrap();
         let local_tz = local.offset().to_string().replace(":", "");
         let local_ts = local.timestamp().to_string();
@@ -211,5 +253,8 @@ mod commit_tests {
 
         // checking round trip of bytes
         assert_eq!(user_bytes, format!("{}", user).as_bytes());
+
+        assert_eq!(1674939897, user.time());
+        assert_eq!("Sat Jan 28 14:04:57 2023 -0700", user.date());
     }
 }
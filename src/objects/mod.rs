@@ -3,15 +3,18 @@ use deflate::Compression;
 use inflate::inflate_bytes_zlib;
 use nom::{
     branch::alt,
-    bytes::complete::{is_not, tag, take_till1},
-    character::{complete::space1, is_newline},
+    bytes::complete::{tag, take_till1, take_while_m_n},
+    character::{
+        complete::{space0, space1},
+        is_hex_digit, is_newline,
+    },
     error::{Error, ErrorKind},
     Err, IResult,
 };
 use sha1_smol as sha1;
-use std::fs::{self as fs, create_dir, read, File};
+use std::fs::{self as fs, create_dir_all, read};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
 
 use crate::error as err;
@@ -19,12 +22,16 @@ use crate::utils;
 
 pub mod blob;
 pub mod commit;
+pub mod tag;
 pub mod tree;
 
 #[derive(Debug, Clone)]
 pub struct Repo {
     pub worktree: PathBuf,
+    /// the worktree's own gitdir, for a linked worktree .git/worktrees/<name>
     pub gitdir: PathBuf,
+    /// the gitdir holding the objects, refs and config every worktree shares
+    pub commondir: PathBuf,
     pub gitconf: String,
 }
 
@@ -32,20 +39,34 @@ impl Repo {
     // new expects an existing git repo
     pub fn new(path: PathBuf) -> Result<Repo, err::Error> {
         let base_path = utils::git_repo_or_err(&path)?;
-        let gitdir = utils::build_path(base_path.clone(), ".git")?;
-        let gitconf_path = utils::build_path(gitdir.clone(), "config")?;
+        let gitdir = utils::git_resolve_gitdir(&base_path)?;
+        let commondir = utils::git_common_dir(&gitdir)?;
+        let gitconf_path = utils::build_path(commondir.clone(), "config")?;
         let gitconf = fs::read_to_string(gitconf_path)?;
 
         Ok(Repo {
             worktree: base_path,
             gitdir,
+            commondir,
             gitconf,
         })
     }
-}
 
-pub trait NameSha {
-    fn get_name_and_sha(&self, name_prefix: Option<String>) -> (String, String);
+    /// Path of a file under the gitdir. HEAD and other pseudo refs, their
+    /// logs and lock files, the index and the sparse-checkout file belong
+    /// to the worktree, everything else lives in the common dir.
+    pub fn git_path<P: AsRef<Path>>(&self, name: P) -> PathBuf {
+        let name = name.as_ref();
+        let path = name.to_string_lossy();
+        let path = path.strip_prefix("logs/").unwrap_or(&path);
+        let path = path.strip_suffix(".lock").unwrap_or(path);
+        let pseudo_ref = path.chars().all(|c| c.is_ascii_uppercase() || c == '_');
+        if pseudo_ref || path == "index" || path == "info/sparse-checkout" {
+            self.gitdir.join(name)
+        } else {
+            self.commondir.join(name)
+        }
+    }
 }
 
 pub trait AsBytes {
@@ -59,11 +80,22 @@ fn generic_nom_failure(input: &[u8]) -> Err<Error<&[u8]>> {
     })
 }
 
-pub fn parse_git_head(input: &[u8]) -> Result<String, err::Error> {
-    let (input, _key) = is_not(" ")(input)?;
-    let (input, _) = space1(input)?;
-    let (_, head_ref) = take_till1(is_newline)(input)?;
-    Ok(from_utf8(head_ref)?.to_owned())
+/// HEAD either points at the branch that is checked out or, when
+/// detached, holds the sha of a commit directly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GitHead {
+    Symbolic(String),
+    Detached(String),
+}
+
+pub fn parse_git_head(input: &[u8]) -> Result<GitHead, err::Error> {
+    if let Ok((input, _)) = tag::<_, _, Error<&[u8]>>("ref:")(input) {
+        let (input, _) = space0(input)?;
+        let (_, head_ref) = take_till1(is_newline)(input)?;
+        return Ok(GitHead::Symbolic(from_utf8(head_ref)?.trim_end().to_owned()));
+    }
+    let (_, sha) = take_while_m_n(40, 40, is_hex_digit)(input)?;
+    Ok(GitHead::Detached(from_utf8(sha)?.to_owned()))
 }
 
 fn parse_obj_len(input: &[u8]) -> IResult<&[u8], usize> {
@@ -88,10 +120,11 @@ pub enum GitObj {
     Blob(blob::Blob),
     Tree(tree::Tree),
     Commit(Box<commit::Commit>),
+    Tag(Box<tag::Tag>),
 }
 
 pub fn parse_git_obj<'a>(input: &'a [u8], sha: &'a str) -> Result<GitObj, err::Error> {
-    let (input, obj) = alt((tag("blob"), tag("commit"), tag("tree")))(input)?;
+    let (input, obj) = alt((tag("blob"), tag("commit"), tag("tree"), tag("tag")))(input)?;
     let (contents, len) = parse_obj_len(input)?;
     if len != contents.len() {
         return Err(err::Error::GitMalformedObject);
@@ -100,6 +133,7 @@ pub fn parse_git_obj<'a>(input: &'a [u8], sha: &'a str) -> Result<GitObj, err::E
         b"blob" => Ok(GitObj::Blob(blob::Blob::new(contents))),
         b"tree" => Ok(GitObj::Tree(tree::parse_git_tree(contents)?)),
         b"commit" => Ok(GitObj::Commit(Box::new(commit::parse_commit(contents, sha)?))),
+        b"tag" => Ok(GitObj::Tag(Box::new(tag::parse_tag(contents)?))),
         _ => Err(err::Error::GitUnrecognizedObjInHeader(
             from_utf8(obj)?.to_string(),
         )),
@@ -122,6 +156,7 @@ pub fn read_object_as_string(sha: &str, repo: &Repo) -> Result<String, err::Erro
         GitObj::Blob(blob) => Ok(format!("{}", blob)),
         GitObj::Tree(tree) => Ok(format!("{}", tree)),
         GitObj::Commit(commit) => Ok(format!("{}", commit)),
+        GitObj::Tag(tag) => Ok(format!("{}", tag)),
     }
 }
 
@@ -130,6 +165,7 @@ pub fn write_object(obj: GitObj, repo: Option<&Repo>) -> Result<sha1::Digest, er
         GitObj::Blob(blob) => blob.as_bytes(),
         GitObj::Tree(tree) => tree.as_bytes(),
         GitObj::Commit(commit) => commit.as_bytes(),
+        GitObj::Tag(tag) => tag.as_bytes(),
     };
 
     let mut hasher = sha1::Sha1::new();
@@ -141,19 +177,21 @@ pub fn write_object(obj: GitObj, repo: Option<&Repo>) -> Result<sha1::Digest, er
     if let Some(repo) = repo {
         utils::git_check_for_rusty_git_allowed(repo)?;
         let hash = digest.to_string();
-        let git_obj_dir = repo.worktree.join(format!(".git/objects/{}", &hash[..2]));
+        let git_obj_dir = repo.git_path(format!("objects/{}", &hash[..2]));
         let git_obj_path = git_obj_dir.join(&hash[2..]);
 
-        if !git_obj_dir.exists() {
-            create_dir(&git_obj_dir)?;
-        }
+        // another thread may create the dir or write the same object at
+        // the same time, the object is written to a temp file and renamed
+        // into place so it's never seen half written
+        create_dir_all(&git_obj_dir)?;
 
         // if path already exists a file with the same contents is already in the obj store
         if !git_obj_path.exists() {
-            let obj_file = File::create(&git_obj_path)?;
-            let mut encoder = ZlibEncoder::new(obj_file, Compression::Default);
+            let obj_file = tempfile::NamedTempFile::new_in(&git_obj_dir)?;
+            let mut encoder = ZlibEncoder::new(obj_file.as_file(), Compression::Default);
             encoder.write_all(&obj_bytes)?;
             encoder.finish()?;
+            obj_file.persist(&git_obj_path).map_err(|e| e.error)?;
         }
     }
     Ok(digest)
@@ -168,7 +206,16 @@ mod object_mod_tests {
     #[test]
     fn can_parse_git_head() {
         let head_file = "ref: refs/heads/main".as_bytes();
-        assert_eq!("refs/heads/main", parse_git_head(head_file).unwrap());
+        assert_eq!(
+            GitHead::Symbolic("refs/heads/main".to_owned()),
+            parse_git_head(head_file).unwrap()
+        );
+        let sha = "3b18e512dba79e4c8300dd08aeb37f8e728b8dad";
+        assert_eq!(
+            GitHead::Detached(sha.to_owned()),
+            parse_git_head(format!("{sha}\n").as_bytes()).unwrap()
+        );
+        assert!(parse_git_head(b"not a head\n").is_err());
     }
 
     #[test]
@@ -177,7 +224,7 @@ mod object_mod_tests {
             .map(|s| s.as_bytes())
             .concat();
         let sha = "abc123";
-        if let GitObj::Blob(blob) = parse_git_obj(&test_inflated_git_obj, &sha).unwrap() {
+        if let GitObj::Blob(blob) = parse_git_obj(&test_inflated_git_obj, sha).unwrap() {
             assert_eq!("git file contents", from_utf8(&blob.contents).unwrap());
             assert_eq!(17, blob.len);
         } else {
@@ -215,7 +262,7 @@ mod object_mod_tests {
         let repo = Repo::new(worktree.path().to_path_buf())?;
 
         let fp = worktree.path().join("tempfoo");
-        let mut tmpfile = File::create(&fp)?;
+        let mut tmpfile = fs::File::create(&fp)?;
         writeln!(tmpfile, "foobar")?;
 
         let blob = blob::blob_from_path(fp)?;
@@ -246,7 +293,7 @@ mod object_mod_tests {
             }
         }
 
-        return Ok(Repo::new(path)?);
+        Repo::new(path)
     }
 
     #[test]
@@ -273,7 +320,7 @@ mod object_mod_tests {
         let repo = Repo::new(tmpdir.path().to_path_buf());
         assert!(repo.is_err());
         match repo {
-            Err(err::Error::GitNotARepo) => assert!(true),
+            Err(err::Error::GitNotARepo) => (),
             _ => panic!("Repo creation should error!"),
         };
         Ok(())
@@ -300,7 +347,7 @@ mod object_mod_tests {
 
         let repo = find_gitdir_and_create_repo(tmpdir.path().to_str().unwrap().to_owned());
         match repo {
-            Err(err::Error::GitNotARepo) => assert!(true),
+            Err(err::Error::GitNotARepo) => (),
             _ => panic!("Repo creation should error!"),
         };
         Ok(())
@@ -0,0 +1,65 @@
+use nom::{bytes::complete::tag, combinator::opt};
+use std::fmt;
+use std::str::from_utf8;
+
+use super::commit::{parse_kv_pair_v_to_string, parse_user_bytes, User};
+use super::AsBytes;
+use crate::error as err;
+
+/// An annotated tag, it names another object, usually a commit, and adds a
+/// tagger and a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub object: String,
+    pub obj_type: String,
+    pub tag: String,
+    // very old tags were written without a tagger
+    pub tagger: Option<User>,
+    pub msg: String,
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "object {}", self.object)?;
+        writeln!(f, "type {}", self.obj_type)?;
+        writeln!(f, "tag {}", self.tag)?;
+        if let Some(tagger) = &self.tagger {
+            write!(f, "tagger {}", tagger)?;
+        }
+        write!(f, "\n{}", self.msg)
+    }
+}
+
+impl AsBytes for Tag {
+    fn as_bytes(&self) -> Vec<u8> {
+        let body = format!("{}", self);
+        [
+            format!("tag {}\x00", body.len()).as_bytes(),
+            body.as_bytes(),
+        ]
+        .concat()
+    }
+}
+
+pub fn parse_tag(input: &[u8]) -> Result<Tag, err::Error> {
+    let (input, object) = parse_kv_pair_v_to_string("object")(input)?;
+    let (input, obj_type) = parse_kv_pair_v_to_string("type")(input)?;
+    let (input, name) = parse_kv_pair_v_to_string("tag")(input)?;
+    let (input, tagger_tag) = opt(tag("tagger "))(input)?;
+    let (input, tagger) = match tagger_tag {
+        Some(_) => {
+            let (input, tagger) = parse_user_bytes(input)?;
+            (input, Some(tagger))
+        }
+        None => (input, None),
+    };
+    let input = input.strip_prefix(b"\n").unwrap_or(input);
+
+    Ok(Tag {
+        object,
+        obj_type,
+        tag: name,
+        tagger,
+        msg: from_utf8(input)?.to_owned(),
+    })
+}
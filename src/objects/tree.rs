@@ -1,19 +1,20 @@
 use nom::{
     bytes::complete::{is_not, tag, take, take_till1},
     character::complete::space1,
-    multi::many1,
+    multi::many0,
     IResult,
 };
+use std::cmp::Ordering;
 use std::fmt;
 use std::str::from_utf8;
 
-use super::{AsBytes, NameSha};
-use crate::{cmds::lstree, error as err, index as idx, utils};
+use super::{AsBytes, GitObj, Repo};
+use crate::{cmds::lstree, error as err, utils};
 
 // a single entry in a Git tree obj file
 type ParsedLeaf<'a> = (&'a [u8], &'a [u8], &'a [u8]);
 
-pub fn parse_git_tree_leaf(input: &[u8]) -> IResult<&[u8], ParsedLeaf> {
+pub fn parse_git_tree_leaf(input: &[u8]) -> IResult<&[u8], ParsedLeaf<'_>> {
     let (input, mode) = is_not(" ")(input)?;
     let (input, _) = space1(input)?;
     let (input, path) = take_till1(|c| c == b'\x00')(input)?;
@@ -29,17 +30,24 @@ pub struct TreeLeaf {
     pub sha: Vec<u8>,
 }
 
-impl NameSha for TreeLeaf {
-    fn get_name_and_sha(&self, name_prefix: Option<String>) -> (String, String) {
-        let sha = utils::get_sha_from_binary(&self.sha);
-        if let Some(prefix) = name_prefix {
-            (format!("{prefix}/{}", self.path), sha)
-        } else {
-            (self.path.clone(), sha)
-        }
+impl TreeLeaf {
+    pub fn is_tree(&self) -> bool {
+        self.mode == "40000" || self.mode == "040000"
+    }
+
+    pub fn mode_as_u32(&self) -> Result<u32, err::Error> {
+        Ok(u32::from_str_radix(&self.mode, 8)?)
     }
 }
 
+/// git sorts the entries of a tree by name, comparing the names of
+/// sub trees as though they end in a '/'
+pub fn tree_entry_order(a: &str, a_is_tree: bool, b: &str, b_is_tree: bool) -> Ordering {
+    let a_key = a.bytes().chain(a_is_tree.then_some(b'/'));
+    let b_key = b.bytes().chain(b_is_tree.then_some(b'/'));
+    a_key.cmp(b_key)
+}
+
 impl AsBytes for TreeLeaf {
     fn as_bytes(&self) -> Vec<u8> {
         let file_info = [&self.mode, " ", &self.path, "\x00"]
@@ -88,7 +96,7 @@ impl fmt::Display for Tree {
 }
 
 pub fn parse_git_tree(input: &[u8]) -> Result<Tree, err::Error> {
-    let (_, leaves) = many1(parse_git_tree_leaf)(input)?;
+    let (_, leaves) = many0(parse_git_tree_leaf)(input)?;
     let mut contents: Vec<TreeLeaf> = Vec::new();
 
     for (mode, path, sha) in leaves {
@@ -102,23 +110,35 @@ pub fn parse_git_tree(input: &[u8]) -> Result<Tree, err::Error> {
     Ok(Tree { contents })
 }
 
-fn entry_to_treeleaf(entry: &idx::IndexEntry) -> TreeLeaf {
-    let idx::IndexEntry {
-        sha, name, mode, ..
-    } = entry;
-    // the mode encoding is different between index entries and tree entries
-    // in tree entries it is stored as the ASCII encoding of the octal encoding
-    // and in index entries it's stored as a BE byte order 32 bit int.
-    TreeLeaf {
-        mode: format!("{:o}", mode), // format the 32bit int to octal String
-        path: name.to_string(),
-        sha: sha.to_vec(),
+pub fn read_tree(sha: &str, repo: &Repo) -> Result<Tree, err::Error> {
+    match super::read_object(sha, repo)? {
+        GitObj::Tree(tree) => Ok(tree),
+        obj => Err(err::Error::GitLsTreeWrongObjType(format!("{:?}", obj))),
     }
 }
 
-pub fn index_to_tree(index: &idx::Index) -> Tree {
-    let leaves = index.entries.iter().map(entry_to_treeleaf).collect();
-    Tree { contents: leaves }
+/// Walks the tree and its sub trees returning every blob and gitlink
+/// with a path relative to the top level tree. The paths of the returned
+/// leaves are joined onto the prefix when one is given.
+pub fn flatten_tree(
+    tree: Tree,
+    prefix: Option<&str>,
+    repo: &Repo,
+) -> Result<Vec<TreeLeaf>, err::Error> {
+    let mut leaves = Vec::new();
+    for leaf in tree.contents {
+        let path = match prefix {
+            Some(p) if !p.is_empty() => format!("{p}/{}", leaf.path),
+            _ => leaf.path.clone(),
+        };
+        if leaf.is_tree() {
+            let sub_tree = read_tree(&utils::get_sha_from_binary(&leaf.sha), repo)?;
+            leaves.append(&mut flatten_tree(sub_tree, Some(&path), repo)?);
+        } else {
+            leaves.push(TreeLeaf { path, ..leaf });
+        }
+    }
+    Ok(leaves)
 }
 
 #[cfg(test)]
@@ -130,7 +150,7 @@ mod tree_tests {
         let mut hasher = sha1::Sha1::new();
         hasher.update(file_name.as_bytes());
         let sha = hasher.digest().to_string();
-        return hex::decode(sha).unwrap();
+        hex::decode(sha).unwrap()
     }
 
     fn make_git_tree_leaf(file_name: &str, perms: &str) -> Vec<u8> {
@@ -142,7 +162,7 @@ mod tree_tests {
         let mut leaf: Vec<u8> = Vec::new();
         leaf.extend_from_slice(&file_info);
         leaf.extend_from_slice(&bsha);
-        return leaf;
+        leaf
     }
 
     #[test]
@@ -150,7 +170,7 @@ mod tree_tests {
         let file_path = "src/foo.txt";
         let leaf = make_git_tree_leaf(file_path, "100644");
         let bsha = get_sha_bytes(file_path);
-        let expected_val = ParsedLeaf::from((b"100644", file_path.as_bytes(), &bsha));
+        let expected_val: ParsedLeaf = (b"100644", file_path.as_bytes(), &bsha);
         let (leftover, leafvals) = parse_git_tree_leaf(&leaf).unwrap();
         assert_eq!(expected_val, leafvals);
         assert_eq!(0, leftover.len());
@@ -0,0 +1,187 @@
+use std::path::{Component, Path, PathBuf};
+
+use crate::error as err;
+use crate::objects as obj;
+use crate::wildmatch;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PathspecItem {
+    // the spec as the user typed it, used in error messages
+    original: String,
+    // the spec relative to the worktree, "" matches the whole worktree
+    path: String,
+    glob: bool,
+}
+
+impl PathspecItem {
+    fn matches(&self, path: &str) -> bool {
+        if self.path.is_empty() || self.matches_exactly(path) {
+            return true;
+        }
+        if let Some(rest) = path.strip_prefix(&self.path) {
+            if rest.starts_with('/') {
+                return true;
+            }
+        }
+        self.glob && wildmatch::wildmatch(&self.path, path, false)
+    }
+
+    fn matches_exactly(&self, path: &str) -> bool {
+        self.path == path
+    }
+}
+
+/// A list of pathspecs given on the command line. Each spec is either a
+/// path (matching the path itself and everything below it) or a glob
+/// where '*' is allowed to match across directories like in git.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pathspec {
+    items: Vec<PathspecItem>,
+}
+
+/// Converts a user supplied path into a path relative to the worktree with
+/// any '.' and '..' components removed. Relative paths are taken to be
+/// relative to the top of the worktree.
+pub fn normalize_path(spec: &str, repo: &obj::Repo) -> Result<String, err::Error> {
+    let spec_path = Path::new(spec);
+    let relative: PathBuf = if spec_path.is_absolute() {
+        if let Ok(p) = spec_path.strip_prefix(&repo.worktree) {
+            p.to_path_buf()
+        } else {
+            // the worktree path may have been given in a non canonical
+            // form e.g. through a symlinked tmp dir
+            let worktree = repo.worktree.canonicalize()?;
+            let parent = spec_path.parent().unwrap_or(spec_path);
+            let canonical = match (parent.canonicalize(), spec_path.file_name()) {
+                (Ok(p), Some(name)) => p.join(name),
+                _ => spec_path.to_path_buf(),
+            };
+            canonical
+                .strip_prefix(&worktree)
+                .map_err(|_| err::Error::GitPathspecOutsideRepo(spec.to_owned()))?
+                .to_path_buf()
+        }
+    } else {
+        spec_path.to_path_buf()
+    };
+
+    let mut components: Vec<&str> = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if components.pop().is_none() {
+                    return Err(err::Error::GitPathspecOutsideRepo(spec.to_owned()));
+                }
+            }
+            Component::Normal(c) => {
+                components.push(c.to_str().ok_or(err::Error::PathToUtf8Conversion)?)
+            }
+            _ => return Err(err::Error::GitPathspecOutsideRepo(spec.to_owned())),
+        }
+    }
+    Ok(components.join("/"))
+}
+
+impl Pathspec {
+    pub fn new(specs: &[String], repo: &obj::Repo) -> Result<Pathspec, err::Error> {
+        let mut items = Vec::new();
+        for spec in specs {
+            let path = normalize_path(spec, repo)?;
+            items.push(PathspecItem {
+                original: spec.to_owned(),
+                glob: wildmatch::has_glob_chars(&path),
+                path,
+            });
+        }
+        Ok(Pathspec { items })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// An empty pathspec matches every path
+    pub fn matches(&self, path: &str) -> bool {
+        self.is_empty() || self.items.iter().any(|i| i.matches(path))
+    }
+
+    /// True when one of the specs names the path itself rather than
+    /// matching it through a parent directory or a glob
+    pub fn names_exactly(&self, path: &str) -> bool {
+        self.items.iter().any(|i| i.matches_exactly(path))
+    }
+
+    /// Returns the specs, as given by the user, that don't match any of the paths
+    pub fn unmatched<'a, I>(&self, paths: I) -> Vec<String>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut matched = vec![false; self.items.len()];
+        for path in paths {
+            for (pos, item) in self.items.iter().enumerate() {
+                if !matched[pos] && item.matches(path) {
+                    matched[pos] = true;
+                }
+            }
+        }
+        self.items
+            .iter()
+            .zip(matched)
+            .filter(|(_, m)| !m)
+            .map(|(i, _)| i.original.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod pathspec_tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn pathspecs_match_dirs_files_and_globs() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+
+        let specs = [
+            "src/".to_owned(),
+            "*.md".to_owned(),
+            "./Cargo.toml".to_owned(),
+        ];
+        let pathspec = Pathspec::new(&specs, &repo).unwrap();
+
+        assert!(pathspec.matches("src/main.rs"));
+        assert!(pathspec.matches("src/cmds/add.rs"));
+        assert!(!pathspec.matches("srcfoo/main.rs"));
+        assert!(pathspec.matches("README.md"));
+        assert!(pathspec.matches("docs/intro.md"));
+        assert!(pathspec.matches("Cargo.toml"));
+        assert!(!pathspec.matches("Cargo.lock"));
+        assert!(pathspec.names_exactly("Cargo.toml"));
+        assert!(!pathspec.names_exactly("src/main.rs"));
+
+        assert_eq!(
+            vec!["*.md".to_owned()],
+            pathspec.unmatched(["src/main.rs", "Cargo.toml"])
+        );
+    }
+
+    #[test]
+    fn pathspecs_are_normalized_relative_to_the_worktree() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+
+        let absolute = gitdir.path().join("foo/bar.txt");
+        assert_eq!(
+            "foo/bar.txt",
+            normalize_path(absolute.to_str().unwrap(), &repo).unwrap()
+        );
+        assert_eq!("", normalize_path(".", &repo).unwrap());
+        assert_eq!("bar", normalize_path("foo/../bar/", &repo).unwrap());
+        assert_eq!(
+            Err(err::Error::GitPathspecOutsideRepo("../foo".to_owned())),
+            normalize_path("../foo", &repo)
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use crate::wildmatch;
+
+/// A single pattern line using the .gitignore syntax, shared by the files
+/// that select paths with patterns like info/sparse-checkout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    // the pattern without any leading '!', leading '/' or trailing '/'
+    pub pattern: String,
+    pub negated: bool,
+    pub dir_only: bool,
+    // patterns with a '/' before the end are matched against the full
+    // path, all others are matched against the last component only
+    pub anchored: bool,
+}
+
+impl Pattern {
+    /// Parses one line of a pattern file, blank lines and comments give None
+    pub fn parse(line: &str) -> Option<Pattern> {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+
+        // trailing spaces are dropped unless escaped with a backslash
+        let trimmed = line.trim_end_matches(' ');
+        let line = if trimmed.ends_with('\\') && trimmed.len() < line.len() {
+            format!("{trimmed} ")
+        } else {
+            trimmed.to_owned()
+        };
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, rest) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            // a leading '#' or '!' can be escaped to match it literally
+            None if line.starts_with("\\#") || line.starts_with("\\!") => (false, &line[1..]),
+            None => (false, line.as_str()),
+        };
+        let (dir_only, rest) = match rest.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let (anchored, rest) = match rest.strip_prefix('/') {
+            Some(rest) => (true, rest),
+            None => (rest.contains('/'), rest),
+        };
+        if rest.is_empty() {
+            return None;
+        }
+
+        Some(Pattern {
+            pattern: rest.to_owned(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    /// Checks the path, relative to the dir the pattern came from, against
+    /// the pattern ignoring whether the pattern is negated
+    pub fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            wildmatch::wildmatch(&self.pattern, path, true)
+        } else {
+            let basename = path.rsplit('/').next().unwrap_or(path);
+            wildmatch::wildmatch(&self.pattern, basename, true)
+        }
+    }
+}
+
+/// Parses every pattern in the contents of a pattern file
+pub fn parse_patterns(contents: &str) -> Vec<Pattern> {
+    contents.lines().filter_map(Pattern::parse).collect()
+}
+
+/// Finds the last pattern matching the path, later patterns override
+/// earlier ones. Returns true for a positive match, false for a negated one
+/// and None when no pattern matches.
+pub fn last_match(patterns: &[Pattern], path: &str, is_dir: bool) -> Option<bool> {
+    patterns
+        .iter()
+        .rev()
+        .find(|p| p.matches(path, is_dir))
+        .map(|p| !p.negated)
+}
+
+#[cfg(test)]
+mod pattern_tests {
+    use super::*;
+
+    #[test]
+    fn parse_handles_negation_anchoring_and_escapes() {
+        let p = Pattern::parse("!/build/").unwrap();
+        assert!(p.negated && p.dir_only && p.anchored);
+        assert_eq!("build", p.pattern);
+
+        let p = Pattern::parse("docs/*.md").unwrap();
+        assert!(p.anchored && !p.negated && !p.dir_only);
+
+        let p = Pattern::parse("\\!important").unwrap();
+        assert!(!p.negated);
+        assert_eq!("!important", p.pattern);
+
+        assert_eq!(None, Pattern::parse("# comment"));
+        assert_eq!(None, Pattern::parse("   "));
+        assert!(Pattern::parse("a\\  ").unwrap().matches("a ", false));
+    }
+
+    #[test]
+    fn last_match_wins() {
+        let patterns = parse_patterns("*.log\n!keep.log\n/target/\n");
+        assert_eq!(Some(true), last_match(&patterns, "logs/debug.log", false));
+        assert_eq!(Some(false), last_match(&patterns, "logs/keep.log", false));
+        assert_eq!(Some(true), last_match(&patterns, "target", true));
+        assert_eq!(None, last_match(&patterns, "target", false));
+        assert_eq!(None, last_match(&patterns, "src/target", true));
+    }
+}
@@ -1,10 +1,12 @@
-use std::fs::{metadata, read, File};
+use std::fs::{create_dir_all, metadata, read, write, File};
 use std::io::{Error, Write};
 use std::path::Path;
 use tempfile::{tempdir, TempDir};
 
-use crate::cmds::init;
+use crate::attributes as attr;
+use crate::cmds::{add, committree, init, refs, writetree};
 use crate::error as err;
+use crate::index as idx;
 use crate::objects as obj;
 
 #[allow(dead_code)]
@@ -39,14 +41,46 @@ pub fn test_add_dummy_commit_and_update_ref_heads(
     repo: &obj::Repo,
 ) -> Result<(), err::Error> {
     //TODO: expand this to add an actual commit in .git/objects later
-    let head_path = repo.gitdir.join("HEAD");
+    let head_path = repo.git_path("HEAD");
     let head = read(head_path)?;
-    let head_ref = obj::parse_git_head(&head)?;
-    let mut ref_file = File::create(repo.gitdir.join(head_ref))?;
+    let head_ref = match obj::parse_git_head(&head)? {
+        obj::GitHead::Symbolic(head_ref) => head_ref,
+        obj::GitHead::Detached(_) => "HEAD".to_owned(),
+    };
+    let mut ref_file = File::create(repo.git_path(head_ref))?;
     writeln!(ref_file, "{}", sha)?;
     Ok(())
 }
 
+/// Writes the files to the worktree, stages them on top of the index and
+/// commits the index on HEAD, returning the new commit's sha
+#[allow(dead_code)]
+pub fn test_commit_files(
+    files: &[(&str, &str)],
+    msg: &str,
+    repo: &obj::Repo,
+) -> Result<String, err::Error> {
+    let mut index = idx::read_index_or_empty(repo)?;
+    for (name, contents) in files {
+        let path = repo.worktree.join(name);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        write(path, contents)?;
+    }
+    let attrs = attr::AttrRules::new(repo)?;
+    for (name, _) in files {
+        index.add_entry(add::file_to_index_entry(name, &attrs, None, repo, true)?);
+    }
+    let tree = writetree::write_tree(&index, None, repo)?;
+    add::write_index(index, repo)?;
+    let parents: Vec<String> = refs::rev_parse("HEAD", repo).into_iter().collect();
+    let sha = committree::commit_tree(&tree, &parents, msg, repo)?;
+    let subject = msg.lines().next().unwrap_or_default();
+    refs::update_ref("HEAD", &sha, &format!("commit: {subject}"), repo)?;
+    Ok(sha)
+}
+
 #[allow(dead_code)]
 pub fn content_length(path: &Path) -> Result<u64, err::Error> {
     Ok(metadata(path)?.len())
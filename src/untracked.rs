@@ -0,0 +1,593 @@
+use nom::{
+    bytes::complete::{take, take_until},
+    multi::count,
+    number::complete::u32,
+    number::Endianness::Big,
+    IResult,
+};
+use std::collections::HashSet;
+use std::fs::{read, read_dir, symlink_metadata, Metadata};
+use std::os::unix::prelude::MetadataExt;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+
+use crate::config;
+use crate::error as err;
+use crate::ewah;
+use crate::ignore;
+use crate::objects::{self as obj, blob::Blob, GitObj};
+use crate::utils;
+
+const NULL_OID: [u8; 20] = [0; 20];
+
+/// Writes a number in git's variable width encoding, 7 bits per byte with
+/// the high bit set on every byte but the last
+pub fn encode_varint(mut value: u64, out: &mut Vec<u8>) {
+    let mut bytes = vec![(value & 127) as u8];
+    value >>= 7;
+    while value != 0 {
+        value -= 1;
+        bytes.push(128 | (value & 127) as u8);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+pub fn decode_varint(input: &[u8]) -> IResult<&[u8], u64> {
+    let mut value = 0;
+    for (i, byte) in input.iter().enumerate() {
+        if i > 0 {
+            value += 1;
+        }
+        value = (value << 7) + (byte & 127) as u64;
+        if byte & 128 == 0 {
+            return Ok((&input[i + 1..], value));
+        }
+    }
+    Err(nom::Err::Error(nom::error::Error::new(
+        input,
+        nom::error::ErrorKind::Eof,
+    )))
+}
+
+fn nul_terminated(input: &[u8]) -> IResult<&[u8], String> {
+    let (input, bytes) = take_until(&b"\0"[..])(input)?;
+    let (input, _) = take(1usize)(input)?;
+    Ok((input, String::from_utf8_lossy(bytes).into_owned()))
+}
+
+/// The stat info the cache compares to notice a file or dir changed, the
+/// same fields as an index entry less the mode
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StatData {
+    pub ctime: (u32, u32),
+    pub mtime: (u32, u32),
+    pub dev: u32,
+    pub ino: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+}
+
+impl StatData {
+    pub fn from_metadata(md: &Metadata) -> StatData {
+        StatData {
+            ctime: (md.ctime() as u32, md.ctime_nsec() as u32),
+            mtime: (md.mtime() as u32, md.mtime_nsec() as u32),
+            dev: md.dev() as u32,
+            ino: md.ino() as u32,
+            uid: md.uid(),
+            gid: md.gid(),
+            size: md.size() as u32,
+        }
+    }
+
+    /// Zeroed when the path doesn't exist
+    pub fn of_path(path: &Path) -> StatData {
+        symlink_metadata(path).map_or(StatData::default(), |md| StatData::from_metadata(&md))
+    }
+
+    fn mtime(&self) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp(self.mtime.0 as i64, self.mtime.1)
+    }
+
+    fn as_bytes(&self) -> Vec<u8> {
+        [
+            self.ctime.0,
+            self.ctime.1,
+            self.mtime.0,
+            self.mtime.1,
+            self.dev,
+            self.ino,
+            self.uid,
+            self.gid,
+            self.size,
+        ]
+        .iter()
+        .flat_map(|n| n.to_be_bytes())
+        .collect()
+    }
+
+    fn parse(input: &[u8]) -> IResult<&[u8], StatData> {
+        let (input, n) = count(u32(Big), 9)(input)?;
+        Ok((
+            input,
+            StatData {
+                ctime: (n[0], n[1]),
+                mtime: (n[2], n[3]),
+                dev: n[4],
+                ino: n[5],
+                uid: n[6],
+                gid: n[7],
+                size: n[8],
+            },
+        ))
+    }
+}
+
+/// An ignore file's stat info and blob sha, the sha is all zeros when the
+/// file doesn't exist
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct OidStat {
+    pub stat: StatData,
+    pub oid: Vec<u8>,
+}
+
+// the sha of the file as a blob. Like git a newline is added to the end
+// before hashing, so the shas match the ones in a cache git wrote.
+fn file_oid(path: &Path) -> Result<Vec<u8>, err::Error> {
+    if !path.is_file() {
+        return Ok(NULL_OID.to_vec());
+    }
+    let mut contents = read(path)?;
+    contents.push(b'\n');
+    let blob = GitObj::Blob(Blob::new(&contents));
+    Ok(obj::write_object(blob, None)?.bytes().to_vec())
+}
+
+impl OidStat {
+    /// The sha is only recomputed when the stat info changed since old
+    fn of_path(path: Option<&Path>, old: &OidStat) -> Result<OidStat, err::Error> {
+        let Some(path) = path else {
+            return Ok(OidStat {
+                stat: StatData::default(),
+                oid: NULL_OID.to_vec(),
+            });
+        };
+        let stat = StatData::of_path(path);
+        if stat == old.stat && stat != StatData::default() {
+            return Ok(old.clone());
+        }
+        Ok(OidStat {
+            stat,
+            oid: file_oid(path)?,
+        })
+    }
+}
+
+/// The cached listing of a dir, only valid while the dir's stat info and
+/// .gitignore are unchanged
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UntrackedDir {
+    /// the dir's name in its parent, empty for the top of the worktree
+    pub name: String,
+    /// untracked files directly in the dir that aren't ignored
+    pub untracked: Vec<String>,
+    /// the dirs inside it that aren't ignored
+    pub dirs: Vec<UntrackedDir>,
+    pub valid: bool,
+    pub check_only: bool,
+    pub stat: StatData,
+    /// the sha of the dir's .gitignore, all zeros when it has none
+    pub exclude_oid: Vec<u8>,
+}
+
+impl UntrackedDir {
+    fn new(name: &str) -> UntrackedDir {
+        UntrackedDir {
+            name: name.to_owned(),
+            exclude_oid: NULL_OID.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    // the dirs in pre-order, the order the blocks are written in
+    fn flatten<'a>(&'a self, dirs: &mut Vec<&'a UntrackedDir>) {
+        dirs.push(self);
+        for dir in &self.dirs {
+            dir.flatten(dirs);
+        }
+    }
+
+    // fills in the fields stored after the dir blocks, taking the stat
+    // info and shas of the dirs whose bits are set in pre-order
+    fn assign_fields<'a>(
+        &mut self,
+        i: &mut usize,
+        bits: &DirBits,
+        stats: &mut impl Iterator<Item = StatData>,
+        oids: &mut impl Iterator<Item = &'a [u8]>,
+    ) {
+        let bit = |bits: &[bool]| bits.get(*i).copied().unwrap_or(false);
+        self.check_only = bit(&bits.check_only);
+        if bit(&bits.valid) {
+            if let Some(stat) = stats.next() {
+                self.valid = true;
+                self.stat = stat;
+            }
+        }
+        if bit(&bits.oid_valid) {
+            if let Some(oid) = oids.next() {
+                self.exclude_oid = oid.to_vec();
+            }
+        }
+        *i += 1;
+        for dir in self.dirs.iter_mut() {
+            dir.assign_fields(i, bits, stats, oids);
+        }
+    }
+
+    fn collect_untracked(&self, prefix: &str, paths: &mut Vec<String>) {
+        for name in &self.untracked {
+            paths.push(format!("{prefix}{name}"));
+        }
+        for dir in &self.dirs {
+            dir.collect_untracked(&format!("{prefix}{}/", dir.name), paths);
+        }
+    }
+}
+
+/// The index's UNTR extension, a listing of the untracked files in each
+/// dir so status only needs to read the dirs that changed
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UntrackedCache {
+    /// where the cache was made, a cache moved to another location is unused
+    pub ident: String,
+    pub info_exclude: OidStat,
+    pub excludes_file: OidStat,
+    /// git's flags for how untracked dirs are listed, every untracked file
+    /// is listed on its own when it's 0
+    pub dir_flags: u32,
+    pub exclude_per_dir: String,
+    pub root: Option<UntrackedDir>,
+}
+
+// git's ident for the worktree, a cache made by git for the same worktree
+// can be used and the other way around
+fn ident(repo: &obj::Repo) -> String {
+    format!(
+        "Location {}, system {}",
+        utils::absolute_worktree(repo).display(),
+        utils::system_name()
+    )
+}
+
+// the bitmaps stored after the dir blocks, one bit per dir in pre-order
+struct DirBits {
+    valid: Vec<bool>,
+    check_only: Vec<bool>,
+    oid_valid: Vec<bool>,
+}
+
+/// core.untrackedCache, true adds the cache to the index and false drops
+/// it. None when it's unset or "keep", leaving a cache already in the index.
+pub fn configured(repo: &obj::Repo) -> Result<Option<bool>, err::Error> {
+    match config::get(&repo.gitconf, "core.untrackedCache")? {
+        None => Ok(None),
+        Some(value) if value == "keep" => Ok(None),
+        Some(value) => Ok(Some(config::parse_bool("core.untrackedCache", &value)?)),
+    }
+}
+
+// the listing of one dir, the rest of its fields are read later
+fn parse_dir_block(input: &[u8]) -> IResult<&[u8], UntrackedDir> {
+    let (input, untracked_count) = decode_varint(input)?;
+    let (input, dirs_count) = decode_varint(input)?;
+    let (input, name) = nul_terminated(input)?;
+    let (input, untracked) = count(nul_terminated, untracked_count as usize)(input)?;
+    let (input, dirs) = count(parse_dir_block, dirs_count as usize)(input)?;
+    Ok((
+        input,
+        UntrackedDir {
+            untracked,
+            dirs,
+            ..UntrackedDir::new(&name)
+        },
+    ))
+}
+
+fn parse_untracked(input: &[u8]) -> IResult<&[u8], UntrackedCache> {
+    let (input, ident_len) = decode_varint(input)?;
+    let (input, ident) = take(ident_len as usize)(input)?;
+    let ident = ident.split(|b| *b == 0).next().unwrap_or_default();
+    let (input, info_stat) = StatData::parse(input)?;
+    let (input, excludes_stat) = StatData::parse(input)?;
+    let (input, dir_flags) = u32(Big)(input)?;
+    let (input, info_oid) = take(20usize)(input)?;
+    let (input, excludes_oid) = take(20usize)(input)?;
+    let (input, exclude_per_dir) = nul_terminated(input)?;
+    let mut cache = UntrackedCache {
+        ident: String::from_utf8_lossy(ident).into_owned(),
+        info_exclude: OidStat {
+            stat: info_stat,
+            oid: info_oid.to_vec(),
+        },
+        excludes_file: OidStat {
+            stat: excludes_stat,
+            oid: excludes_oid.to_vec(),
+        },
+        dir_flags,
+        exclude_per_dir,
+        root: None,
+    };
+
+    let (input, dir_count) = decode_varint(input)?;
+    if dir_count == 0 {
+        return Ok((input, cache));
+    }
+    let (input, mut root) = parse_dir_block(input)?;
+    let (input, valid) = ewah::parse(input)?;
+    let (input, check_only) = ewah::parse(input)?;
+    let (input, oid_valid) = ewah::parse(input)?;
+    let set_bits = |bits: &[bool]| bits.iter().filter(|b| **b).count();
+    let (input, stats) = count(StatData::parse, set_bits(&valid))(input)?;
+    let (input, oids) = count(take(20usize), set_bits(&oid_valid))(input)?;
+    let bits = DirBits {
+        valid,
+        check_only,
+        oid_valid,
+    };
+    root.assign_fields(&mut 0, &bits, &mut stats.into_iter(), &mut oids.into_iter());
+    let (input, _nul) = take(1usize)(input)?;
+    cache.root = Some(root);
+    Ok((input, cache))
+}
+
+// what the cached walk needs to check each dir
+struct WalkContext<'a> {
+    rules: &'a ignore::IgnoreRules,
+    tracked: &'a HashSet<&'a str>,
+    index_mtime: Option<DateTime<Utc>>,
+    // the fsmonitor reported every change so valid dirs aren't checked
+    trust_valid: bool,
+    repo: &'a obj::Repo,
+}
+
+impl UntrackedCache {
+    /// An empty cache for the worktree, every dir is read on first use
+    pub fn new(repo: &obj::Repo) -> UntrackedCache {
+        UntrackedCache {
+            ident: ident(repo),
+            dir_flags: 0,
+            exclude_per_dir: ".gitignore".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<UntrackedCache, err::Error> {
+        Ok(parse_untracked(data)?.1)
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode_varint(self.ident.len() as u64 + 1, &mut out);
+        out.extend_from_slice(self.ident.as_bytes());
+        out.push(0);
+        out.extend(self.info_exclude.stat.as_bytes());
+        out.extend(self.excludes_file.stat.as_bytes());
+        out.extend_from_slice(&self.dir_flags.to_be_bytes());
+        out.extend_from_slice(&self.info_exclude.oid);
+        out.extend_from_slice(&self.excludes_file.oid);
+        out.extend_from_slice(self.exclude_per_dir.as_bytes());
+        out.push(0);
+
+        let Some(root) = &self.root else {
+            encode_varint(0, &mut out);
+            return out;
+        };
+        let mut dirs = Vec::new();
+        root.flatten(&mut dirs);
+        encode_varint(dirs.len() as u64, &mut out);
+        for dir in &dirs {
+            encode_varint(dir.untracked.len() as u64, &mut out);
+            encode_varint(dir.dirs.len() as u64, &mut out);
+            for name in std::iter::once(&dir.name).chain(&dir.untracked) {
+                out.extend_from_slice(name.as_bytes());
+                out.push(0);
+            }
+        }
+        let valid: Vec<bool> = dirs.iter().map(|d| d.valid).collect();
+        let check_only: Vec<bool> = dirs.iter().map(|d| d.check_only).collect();
+        let oid_valid: Vec<bool> = dirs.iter().map(|d| d.exclude_oid != NULL_OID).collect();
+        out.extend(ewah::serialize(&valid));
+        out.extend(ewah::serialize(&check_only));
+        out.extend(ewah::serialize(&oid_valid));
+        for dir in dirs.iter().filter(|d| d.valid) {
+            out.extend(dir.stat.as_bytes());
+        }
+        for dir in dirs.iter().filter(|d| d.exclude_oid != NULL_OID) {
+            out.extend_from_slice(&dir.exclude_oid);
+        }
+        out.push(0);
+        out
+    }
+
+    /// Marks the dir holding the path to be read again, used when a path is
+    /// added to or removed from the index or reported changed by fsmonitor
+    pub fn invalidate_path(&mut self, path: &str) {
+        let Some(mut dir) = self.root.as_mut() else {
+            return;
+        };
+        // a parent missing from the cache is new, so the deepest dir found
+        // is the one whose listing changed
+        let parents: Vec<&str> = path.split('/').collect();
+        for name in &parents[..parents.len() - 1] {
+            let Some(i) = dir.dirs.iter().position(|d| d.name == *name) else {
+                break;
+            };
+            dir = &mut dir.dirs[i];
+        }
+        dir.valid = false;
+    }
+
+    /// Returns the untracked files that aren't ignored, only reading the
+    /// dirs whose listing changed and updating the cache as it goes. With
+    /// trust_valid the dirs still marked valid aren't checked at all since
+    /// fsmonitor has already invalidated every dir that changed.
+    pub fn untracked_files(
+        &mut self,
+        rules: &ignore::IgnoreRules,
+        tracked: &HashSet<&str>,
+        index_mtime: Option<DateTime<Utc>>,
+        trust_valid: bool,
+        repo: &obj::Repo,
+    ) -> Result<Vec<String>, err::Error> {
+        if self.ident != ident(repo) || self.dir_flags != 0 || self.exclude_per_dir != ".gitignore"
+        {
+            *self = UntrackedCache::new(repo);
+        }
+        // a change to either file can change what's ignored anywhere
+        let info_exclude =
+            OidStat::of_path(Some(&repo.git_path("info/exclude")), &self.info_exclude)?;
+        let excludes_path = config::get_path(repo, "core.excludesFile")?;
+        let excludes_file = OidStat::of_path(excludes_path.as_deref(), &self.excludes_file)?;
+        if info_exclude.oid != self.info_exclude.oid || excludes_file.oid != self.excludes_file.oid
+        {
+            self.root = None;
+        }
+        self.info_exclude = info_exclude;
+        self.excludes_file = excludes_file;
+
+        let ctx = WalkContext {
+            rules,
+            tracked,
+            index_mtime,
+            trust_valid,
+            repo,
+        };
+        let root = self.root.get_or_insert_with(|| UntrackedDir::new(""));
+        refresh_dir(root, "", false, &ctx)?;
+        let mut paths = Vec::new();
+        root.collect_untracked("", &mut paths);
+        Ok(paths)
+    }
+}
+
+// brings the dir's listing and those of the dirs inside it up to date.
+// When a parent's .gitignore changed the dir is always read again.
+fn refresh_dir(
+    dir: &mut UntrackedDir,
+    path: &str,
+    parent_rules_changed: bool,
+    ctx: &WalkContext,
+) -> Result<(), err::Error> {
+    let full_path = ctx.repo.worktree.join(path);
+    let gitignore = full_path.join(".gitignore");
+    let mut valid = dir.valid && !parent_rules_changed;
+    let (stat, exclude_oid) = if valid && ctx.trust_valid {
+        (dir.stat, dir.exclude_oid.clone())
+    } else {
+        let stat = StatData::of_path(&full_path);
+        // a dir changed in the same instant the index was written may
+        // change again without its mtime moving
+        let racy = match (stat.mtime(), ctx.index_mtime) {
+            (Some(mtime), Some(index_mtime)) => mtime >= index_mtime,
+            _ => false,
+        };
+        valid = valid && stat == dir.stat && !racy;
+        (stat, file_oid(&gitignore)?)
+    };
+    let rules_changed = parent_rules_changed || exclude_oid != dir.exclude_oid;
+
+    if !valid || rules_changed {
+        let mut untracked = Vec::new();
+        let mut dirs = Vec::new();
+        for node in read_dir(&full_path)? {
+            let node = node?;
+            let Some(name) = node.file_name().to_str().map(|n| n.to_owned()) else {
+                return Err(err::Error::PathToUtf8Conversion);
+            };
+            if path.is_empty() && name == ".git" {
+                continue;
+            }
+            let rel = if path.is_empty() {
+                name.clone()
+            } else {
+                format!("{path}/{name}")
+            };
+            let md = symlink_metadata(node.path())?;
+            if ctx.rules.is_ignored_in_walk(&rel, md.is_dir())? {
+                continue;
+            }
+            // links aren't followed and nested repos are a single entry
+            if md.is_dir() && !node.path().join(".git").exists() {
+                let old = dir.dirs.iter().position(|d| d.name == name);
+                dirs.push(old.map_or_else(|| UntrackedDir::new(&name), |i| dir.dirs.remove(i)));
+            } else if !ctx.tracked.contains(rel.as_str()) {
+                untracked.push(name);
+            }
+        }
+        untracked.sort();
+        dirs.sort_by(|a, b| a.name.cmp(&b.name));
+        dir.untracked = untracked;
+        dir.dirs = dirs;
+        dir.stat = stat;
+        dir.exclude_oid = exclude_oid;
+        dir.valid = true;
+        dir.check_only = false;
+    }
+
+    for child in dir.dirs.iter_mut() {
+        let child_path = if path.is_empty() {
+            child.name.clone()
+        } else {
+            format!("{path}/{}", child.name)
+        };
+        refresh_dir(child, &child_path, rules_changed, ctx)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod untracked_tests {
+    use super::*;
+    use crate::test_utils;
+    use std::fs::{create_dir_all, write};
+
+    #[test]
+    fn cache_round_trips_and_only_rereads_changed_dirs() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        create_dir_all(repo.worktree.join("a/b")).unwrap();
+        write(repo.worktree.join("a/b/new"), "n\n").unwrap();
+        write(repo.worktree.join("a/tracked"), "t\n").unwrap();
+        write(repo.worktree.join("a/.gitignore"), "*.log\n").unwrap();
+        write(repo.worktree.join("a/x.log"), "x\n").unwrap();
+
+        let rules = ignore::IgnoreRules::new(&repo).unwrap();
+        let tracked: HashSet<&str> = ["a/tracked"].into();
+        let mut cache = UntrackedCache::new(&repo);
+        let files = cache
+            .untracked_files(&rules, &tracked, None, false, &repo)
+            .unwrap();
+        assert_eq!(vec![".rusty-git-allowed", "a/.gitignore", "a/b/new"], files);
+        let parsed = UntrackedCache::parse(&cache.as_bytes()).unwrap();
+        assert_eq!(cache, parsed);
+
+        // a valid dir isn't read again so a name planted in its listing
+        // stays until the dir is invalidated
+        let mut cache = parsed;
+        let root = cache.root.as_mut().unwrap();
+        root.dirs[0].dirs[0].untracked.push("planted".to_owned());
+        let files = cache
+            .untracked_files(&rules, &tracked, None, true, &repo)
+            .unwrap();
+        assert!(files.contains(&"a/b/planted".to_owned()));
+        cache.invalidate_path("a/b/planted");
+        let files = cache
+            .untracked_files(&rules, &tracked, None, true, &repo)
+            .unwrap();
+        assert!(!files.contains(&"a/b/planted".to_owned()));
+    }
+}
@@ -1,15 +1,43 @@
-use std::fs::{read, read_dir, read_to_string};
+use chrono::{DateTime, Utc};
+use std::fs::{metadata, read, read_dir, read_to_string};
 use std::path::{Path, PathBuf};
 
+use crate::cmds::refs;
 use crate::error as err;
 use crate::objects::{self as obj, tree, commit};
 
 
 // ----------- git utils ---------------
 pub fn is_git_repo(path: &Path) -> bool {
-    let gitdir = path.join(".git");
-    let conf = path.join(".git/config");
-    gitdir.exists() && conf.exists()
+    let gitdir = git_resolve_gitdir(path);
+    let commondir = gitdir.and_then(|gitdir| git_common_dir(&gitdir));
+    commondir.is_ok_and(|commondir| commondir.join("config").exists())
+}
+
+/// Returns the gitdir of the worktree at path. The .git of a linked
+/// worktree is a file with a "gitdir: <path>" line pointing at its gitdir.
+pub fn git_resolve_gitdir(path: &Path) -> Result<PathBuf, err::Error> {
+    let dotgit = path.join(".git");
+    if !dotgit.is_file() {
+        return Ok(dotgit);
+    }
+    let contents = read_to_string(&dotgit)?;
+    let target = contents
+        .trim_end()
+        .strip_prefix("gitdir:")
+        .ok_or(err::Error::GitNotARepo)?;
+    Ok(path.join(target.trim()))
+}
+
+/// Returns the gitdir holding what every worktree shares, the commondir
+/// file of a linked worktree's gitdir holds its path
+pub fn git_common_dir(gitdir: &Path) -> Result<PathBuf, err::Error> {
+    let commondir = gitdir.join("commondir");
+    if !commondir.is_file() {
+        return Ok(gitdir.to_path_buf());
+    }
+    let target = read_to_string(commondir)?;
+    Ok(gitdir.join(target.trim_end()).canonicalize()?)
 }
 
 pub fn git_repo_or_err(path: &Path) -> Result<PathBuf, err::Error> {
@@ -22,9 +50,7 @@ pub fn git_repo_or_err(path: &Path) -> Result<PathBuf, err::Error> {
 }
 
 pub fn git_obj_path_from_sha(sha: &str, repo: &obj::Repo) -> Result<PathBuf, err::Error> {
-    let obj_path = repo
-        .gitdir
-        .join(format!("objects/{}/{}", &sha[..2], &sha[2..]));
+    let obj_path = repo.git_path(format!("objects/{}/{}", &sha[..2], &sha[2..]));
 
     if obj_path.exists() {
         Ok(obj_path)
@@ -35,20 +61,41 @@ pub fn git_obj_path_from_sha(sha: &str, repo: &obj::Repo) -> Result<PathBuf, err
     }
 }
 
-pub fn git_head_ref_path(repo: &obj::Repo) -> Result<PathBuf, err::Error> {
-    let head_path = repo.gitdir.join("HEAD");
-    let head = read(head_path)?;
-    let head_ref = obj::parse_git_head(&head)?;
-    Ok(repo.gitdir.join(head_ref))
+/// Expands an abbreviated sha to the full sha of the object it names, the
+/// abbreviation must be at least 4 hex chars and match a single object
+pub fn git_expand_abbrev_sha(abbrev: &str, repo: &obj::Repo) -> Result<String, err::Error> {
+    let is_hex = abbrev.chars().all(|c| c.is_ascii_hexdigit());
+    if !is_hex || abbrev.len() < 4 || abbrev.len() > 40 {
+        return Err(err::Error::GitBadRevision(abbrev.to_owned()));
+    }
+    let abbrev = abbrev.to_ascii_lowercase();
+    let obj_dir = repo.git_path(format!("objects/{}", &abbrev[..2]));
+    if !obj_dir.exists() {
+        return Err(err::Error::GitBadRevision(abbrev));
+    }
+
+    let mut matches = Vec::new();
+    for obj_file in read_dir(obj_dir)? {
+        let name = obj_file?.file_name();
+        let name = name.to_str().ok_or(err::Error::PathToUtf8Conversion)?;
+        if name.starts_with(&abbrev[2..]) {
+            matches.push(format!("{}{}", &abbrev[..2], name));
+        }
+    }
+    match matches.len() {
+        1 => Ok(matches.remove(0)),
+        0 => Err(err::Error::GitBadRevision(abbrev)),
+        _ => Err(err::Error::GitAmbiguousRevision(abbrev)),
+    }
 }
 
 pub fn git_sha_from_head(repo: &obj::Repo) -> Result<String, err::Error> {
-    let sha_path = git_head_ref_path(repo)?;
-    if sha_path.exists() {
-        let sha = read_to_string(&sha_path)?.trim().to_owned();
-        Ok(sha)
-    } else {
-        Err(err::Error::GitNoCommitsExistYet)
+    let head = read(repo.gitdir.join("HEAD"))?;
+    match obj::parse_git_head(&head)? {
+        obj::GitHead::Symbolic(head_ref) => {
+            refs::read_ref(&head_ref, repo)?.ok_or(err::Error::GitNoCommitsExistYet)
+        }
+        obj::GitHead::Detached(sha) => Ok(sha),
     }
 }
 
@@ -72,18 +119,28 @@ pub fn git_index_exists(repo: &obj::Repo) -> bool {
     repo.gitdir.clone().join("index").exists()
 }
 
+/// The last time the index was written, entries whose files were modified
+/// at or after it can't be trusted to be unchanged by their stat info alone
+pub fn git_index_mtime(repo: &obj::Repo) -> Option<DateTime<Utc>> {
+    let mtime = metadata(repo.gitdir.join("index")).ok()?.modified().ok()?;
+    Some(mtime.into())
+}
+
+/// The worktree as an absolute path, for messages and anything shared with git
+pub fn absolute_worktree(repo: &obj::Repo) -> PathBuf {
+    repo.worktree.canonicalize().unwrap_or(repo.worktree.clone())
+}
+
+/// Checks the user opted in to rusty-git changing the repo. A linked
+/// worktree shares the opt-in of the main worktree, the marker can also be
+/// put in the common gitdir.
 pub fn git_check_for_rusty_git_allowed(repo: &obj::Repo) -> Result<bool, err::Error> {
-    let work_path = repo.worktree.clone();
-    let worktree_dir = read_dir(work_path)?;
-    let mut rusty_git_allowed = false;
-
-    for node in worktree_dir {
-        let node_val = node?;
-        let node_name = node_val.file_name();
-        if node_name == ".rusty-git-allowed" {
-            rusty_git_allowed = true;
-        }
-    }
+    let mut dirs = vec![repo.worktree.as_path(), repo.commondir.as_path()];
+    // the common dir of a non-bare repo is the main worktree's .git
+    dirs.extend(repo.commondir.parent());
+    let rusty_git_allowed = dirs
+        .iter()
+        .any(|dir| dir.join(".rusty-git-allowed").is_file());
 
     if rusty_git_allowed {
         Ok(rusty_git_allowed)
@@ -92,6 +149,21 @@ pub fn git_check_for_rusty_git_allowed(repo: &obj::Repo) -> Result<bool, err::Er
     }
 }
 
+/// The kernel name from uname, e.g. Linux
+pub fn system_name() -> String {
+    // SAFETY: uname only writes into the zeroed struct it's given
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return std::env::consts::OS.to_owned();
+    }
+    let sysname: Vec<u8> = uts
+        .sysname
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as u8)
+        .collect();
+    String::from_utf8_lossy(&sysname).into_owned()
+}
 
 pub fn get_sha_from_binary(input: &[u8]) -> String {
     let mut hexpairs = Vec::new();
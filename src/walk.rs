@@ -0,0 +1,208 @@
+use std::collections::BTreeSet;
+use std::fs::{read_dir, symlink_metadata};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+use crate::config;
+use crate::error as err;
+use crate::ignore;
+use crate::objects as obj;
+
+/// The number of threads to scan the worktree and hash files with. Like
+/// git's core.preloadIndex it's on by default, turning it off keeps all the
+/// work on a single thread.
+pub fn worker_threads(repo: &obj::Repo) -> Result<usize, err::Error> {
+    if !config::get_bool(&repo.gitconf, "core.preloadIndex")?.unwrap_or(true) {
+        return Ok(1);
+    }
+    Ok(thread::available_parallelism().map_or(1, |n| n.get()))
+}
+
+/// Calls f on every item spread across the threads, the results are in the
+/// same order as the items. The first error found is returned.
+pub fn parallel_map<T, R, F>(items: &[T], threads: usize, f: F) -> Result<Vec<R>, err::Error>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R, err::Error> + Sync,
+{
+    if threads <= 1 || items.len() <= 1 {
+        return items.iter().map(&f).collect();
+    }
+    let chunk_size = items.len().div_ceil(threads);
+    thread::scope(|s| {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| s.spawn(|| chunk.iter().map(&f).collect::<Result<Vec<R>, _>>()))
+            .collect();
+        let mut results = Vec::with_capacity(items.len());
+        for handle in handles {
+            results.extend(handle.join().expect("worker thread panicked")?);
+        }
+        Ok(results)
+    })
+}
+
+/// The paths found walking the worktree relative to its top
+#[derive(Debug, Default)]
+pub struct WorktreeNames {
+    /// files that aren't ignored, a nested repo is a single path
+    pub files: BTreeSet<String>,
+    /// ignored files, ignored dirs end in '/' and aren't looked inside
+    pub ignored: BTreeSet<String>,
+}
+
+// the dirs waiting to be scanned shared by the walking threads
+struct WalkQueue {
+    dirs: Vec<PathBuf>,
+    // threads currently scanning a dir, which may add more dirs
+    busy: usize,
+    failed: Option<err::Error>,
+}
+
+// adds the names in the dir to names and returns the dirs inside it that
+// need scanning
+fn scan_dir(
+    dir: &Path,
+    rules: Option<&ignore::IgnoreRules>,
+    names: &mut WorktreeNames,
+    repo: &obj::Repo,
+) -> Result<Vec<PathBuf>, err::Error> {
+    let mut subdirs = Vec::new();
+    for node in read_dir(dir)? {
+        let node_val = node?;
+        if node_val.file_name() == ".git" {
+            continue;
+        }
+        let node_path = node_val.path();
+        let Some(name) = node_path.strip_prefix(&repo.worktree)?.to_str() else {
+            return Err(err::Error::PathToUtf8Conversion);
+        };
+
+        // links aren't followed and nested repos are a single gitlink entry
+        let node_md = symlink_metadata(&node_path)?;
+        let is_dir = node_md.is_dir() && !node_path.join(".git").exists();
+        // the walk never enters ignored dirs so only the path itself is checked
+        if rules.map_or(Ok(false), |r| r.is_ignored_in_walk(name, node_md.is_dir()))? {
+            let name = if is_dir {
+                format!("{name}/")
+            } else {
+                name.to_owned()
+            };
+            names.ignored.insert(name);
+        } else if is_dir {
+            subdirs.push(node_path);
+        } else {
+            names.files.insert(name.to_owned());
+        }
+    }
+    Ok(subdirs)
+}
+
+// takes the next dir off the queue, waiting while other threads are busy
+// scanning dirs that may add more. None once the walk is over.
+fn next_dir(queue: &Mutex<WalkQueue>, ready: &Condvar) -> Option<PathBuf> {
+    let mut q = queue.lock().unwrap();
+    loop {
+        if q.failed.is_some() {
+            return None;
+        }
+        if let Some(dir) = q.dirs.pop() {
+            q.busy += 1;
+            return Some(dir);
+        }
+        if q.busy == 0 {
+            return None;
+        }
+        q = ready.wait(q).unwrap();
+    }
+}
+
+/// Walks the worktree below dir with the dirs spread across the threads.
+/// Without rules nothing is ignored.
+pub fn walk_worktree(
+    dir: &Path,
+    rules: Option<&ignore::IgnoreRules>,
+    threads: usize,
+    repo: &obj::Repo,
+) -> Result<WorktreeNames, err::Error> {
+    let queue = Mutex::new(WalkQueue {
+        dirs: vec![dir.to_path_buf()],
+        busy: 0,
+        failed: None,
+    });
+    let ready = Condvar::new();
+
+    let found: Vec<WorktreeNames> = thread::scope(|s| {
+        let handles: Vec<_> = (0..threads.max(1))
+            .map(|_| {
+                s.spawn(|| {
+                    let mut names = WorktreeNames::default();
+                    while let Some(dir) = next_dir(&queue, &ready) {
+                        let scanned = scan_dir(&dir, rules, &mut names, repo);
+                        let mut q = queue.lock().unwrap();
+                        q.busy -= 1;
+                        match scanned {
+                            Ok(subdirs) => q.dirs.extend(subdirs),
+                            Err(e) => {
+                                q.failed.get_or_insert(e);
+                            }
+                        }
+                        ready.notify_all();
+                    }
+                    names
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("worker thread panicked"))
+            .collect()
+    });
+
+    if let Some(e) = queue.into_inner().unwrap().failed {
+        return Err(e);
+    }
+    let mut names = WorktreeNames::default();
+    for mut found in found {
+        names.files.append(&mut found.files);
+        names.ignored.append(&mut found.ignored);
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod walk_tests {
+    use super::*;
+    use crate::test_utils;
+    use std::fs::{create_dir_all, write};
+
+    #[test]
+    fn parallel_walk_matches_a_single_thread() {
+        let gitdir = test_utils::test_gitdir().unwrap();
+        let repo = obj::Repo::new(gitdir.path().to_path_buf()).unwrap();
+        write(repo.worktree.join(".gitignore"), "*.log\nbuild/\n").unwrap();
+        for dir in 0..8 {
+            let dir = repo.worktree.join(format!("d{dir}/sub"));
+            create_dir_all(&dir).unwrap();
+            write(dir.join("a.txt"), "a\n").unwrap();
+            write(dir.join("b.log"), "b\n").unwrap();
+        }
+        create_dir_all(repo.worktree.join("build/out")).unwrap();
+        write(repo.worktree.join("build/out/x"), "x\n").unwrap();
+
+        let rules = ignore::IgnoreRules::new(&repo).unwrap();
+        let single = walk_worktree(&repo.worktree, Some(&rules), 1, &repo).unwrap();
+        let parallel = walk_worktree(&repo.worktree, Some(&rules), 4, &repo).unwrap();
+        assert_eq!(single.files, parallel.files);
+        assert_eq!(single.ignored, parallel.ignored);
+        assert!(parallel.files.contains("d3/sub/a.txt"));
+        assert!(parallel.ignored.contains("d3/sub/b.log"));
+        assert!(parallel.ignored.contains("build/"));
+        assert_eq!(19, parallel.files.len() + parallel.ignored.len());
+
+        let squares = parallel_map(&[1, 2, 3, 4, 5], 3, |n| Ok(n * n)).unwrap();
+        assert_eq!(vec![1, 4, 9, 16, 25], squares);
+    }
+}
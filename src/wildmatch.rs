@@ -0,0 +1,264 @@
+// A port of git's wildmatch.c, this is the glob matcher git uses for
+// pathspecs, .gitignore and .gitattributes patterns. Matching is done
+// on bytes so non utf8 paths behave the same way they do in git.
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum MatchResult {
+    Match,
+    NoMatch,
+    // no later '*' in the pattern can make this text match, stop trying
+    AbortAll,
+    // a single '*' hit a '/' only a '**' further up the pattern can fix this
+    AbortToStarstar,
+}
+
+fn class_matches(class: &[u8], c: u8) -> Option<bool> {
+    let matched = match class {
+        b"alnum" => c.is_ascii_alphanumeric(),
+        b"alpha" => c.is_ascii_alphabetic(),
+        b"blank" => c == b' ' || c == b'\t',
+        b"cntrl" => c.is_ascii_control(),
+        b"digit" => c.is_ascii_digit(),
+        b"graph" => c.is_ascii_graphic(),
+        b"lower" => c.is_ascii_lowercase(),
+        b"print" => c.is_ascii_graphic() || c == b' ',
+        b"punct" => c.is_ascii_punctuation(),
+        b"space" => c.is_ascii_whitespace(),
+        b"upper" => c.is_ascii_uppercase(),
+        b"xdigit" => c.is_ascii_hexdigit(),
+        _ => return None,
+    };
+    Some(matched)
+}
+
+/// Matches a '[...]' bracket expression starting at p[0] == '[' against the
+/// char c. Returns the index of the closing ']' and whether c matched, or
+/// None when the bracket expression is malformed.
+fn match_bracket(p: &[u8], c: u8) -> Option<(usize, bool)> {
+    let mut pi = 1;
+    let negated = matches!(p.get(pi), Some(b'!') | Some(b'^'));
+    if negated {
+        pi += 1;
+    }
+
+    let mut matched = false;
+    let mut prev: Option<u8> = None;
+    let mut first = true;
+    loop {
+        let pc = *p.get(pi)?;
+        if pc == b']' && !first {
+            break;
+        }
+        first = false;
+
+        if pc == b'\\' {
+            pi += 1;
+            let escaped = *p.get(pi)?;
+            matched |= escaped == c;
+            prev = Some(escaped);
+        } else if pc == b'-' && prev.is_some() && p.get(pi + 1).is_some_and(|n| *n != b']') {
+            pi += 1;
+            let mut hi = p[pi];
+            if hi == b'\\' {
+                pi += 1;
+                hi = *p.get(pi)?;
+            }
+            let lo = prev.unwrap();
+            matched |= lo <= c && c <= hi;
+            prev = None;
+        } else if pc == b'[' && p.get(pi + 1) == Some(&b':') {
+            let start = pi + 2;
+            let len = p[start..].windows(2).position(|w| w == b":]")?;
+            matched |= class_matches(&p[start..start + len], c)?;
+            pi = start + len + 1;
+            prev = None;
+        } else {
+            matched |= pc == c;
+            prev = Some(pc);
+        }
+        pi += 1;
+    }
+    Some((pi, matched != negated))
+}
+
+fn dowild(p: &[u8], t: &[u8], pathname: bool) -> MatchResult {
+    let mut pi = 0;
+    let mut ti = 0;
+
+    while pi < p.len() {
+        let pc = p[pi];
+        if ti >= t.len() && pc != b'*' {
+            return MatchResult::AbortAll;
+        }
+
+        match pc {
+            b'\\' => {
+                // a trailing backslash can't match anything
+                pi += 1;
+                if pi >= p.len() || t[ti] != p[pi] {
+                    return MatchResult::NoMatch;
+                }
+            }
+            b'?' => {
+                if pathname && t[ti] == b'/' {
+                    return MatchResult::NoMatch;
+                }
+            }
+            b'*' => {
+                let star_start = pi;
+                pi += 1;
+                let match_slash;
+                if p.get(pi) == Some(&b'*') {
+                    while p.get(pi) == Some(&b'*') {
+                        pi += 1;
+                    }
+                    if !pathname {
+                        match_slash = true;
+                    } else if (star_start == 0 || p[star_start - 1] == b'/')
+                        && (pi == p.len() || p[pi] == b'/')
+                    {
+                        // "**/" can also match zero directories
+                        if p.get(pi) == Some(&b'/')
+                            && dowild(&p[pi + 1..], &t[ti..], pathname) == MatchResult::Match
+                        {
+                            return MatchResult::Match;
+                        }
+                        match_slash = true;
+                    } else {
+                        // '**' that isn't a whole path component acts like '*'
+                        match_slash = false;
+                    }
+                } else {
+                    match_slash = !pathname;
+                }
+
+                if pi == p.len() {
+                    // a trailing star matches the rest of the text unless it
+                    // would need to cross a directory boundary
+                    if !match_slash && t[ti..].contains(&b'/') {
+                        return MatchResult::AbortToStarstar;
+                    }
+                    return MatchResult::Match;
+                }
+
+                if !match_slash && p[pi] == b'/' {
+                    // the star can only consume up to the next slash
+                    match t[ti..].iter().position(|c| *c == b'/') {
+                        Some(offset) => {
+                            ti += offset;
+                            // the slash in both pattern and text is consumed below
+                            pi += 1;
+                            ti += 1;
+                            continue;
+                        }
+                        None => return MatchResult::AbortAll,
+                    }
+                }
+
+                while ti < t.len() {
+                    let result = dowild(&p[pi..], &t[ti..], pathname);
+                    if result != MatchResult::NoMatch {
+                        if !match_slash || result != MatchResult::AbortToStarstar {
+                            return result;
+                        }
+                    } else if !match_slash && t[ti] == b'/' {
+                        return MatchResult::AbortToStarstar;
+                    }
+                    ti += 1;
+                }
+                return MatchResult::AbortAll;
+            }
+            b'[' => match match_bracket(&p[pi..], t[ti]) {
+                Some((end, matched)) => {
+                    if !matched || (pathname && t[ti] == b'/') {
+                        return MatchResult::NoMatch;
+                    }
+                    pi += end;
+                }
+                None => return MatchResult::AbortAll,
+            },
+            _ => {
+                if t[ti] != pc {
+                    return MatchResult::NoMatch;
+                }
+            }
+        }
+        pi += 1;
+        ti += 1;
+    }
+
+    if ti < t.len() {
+        MatchResult::NoMatch
+    } else {
+        MatchResult::Match
+    }
+}
+
+/// Match text against a glob pattern using git's rules. When pathname is
+/// true '*', '?' and '[...]' won't match a '/' and only '**' can cross
+/// directory boundaries, this is how .gitignore patterns behave. When it's
+/// false a '*' matches across directories which is how pathspecs behave.
+pub fn wildmatch(pattern: &str, text: &str, pathname: bool) -> bool {
+    dowild(pattern.as_bytes(), text.as_bytes(), pathname) == MatchResult::Match
+}
+
+/// Returns true if the pattern contains any glob special chars
+pub fn has_glob_chars(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '[', '\\'])
+}
+
+#[cfg(test)]
+mod wildmatch_tests {
+    use super::*;
+
+    #[test]
+    fn matches_literals_and_single_char_wildcards() {
+        assert!(wildmatch("foo", "foo", true));
+        assert!(!wildmatch("foo", "bar", true));
+        assert!(!wildmatch("foo", "foobar", true));
+        assert!(wildmatch("f?o", "foo", true));
+        assert!(!wildmatch("??", "a/", true));
+        assert!(wildmatch("??", "a/", false));
+        assert!(wildmatch("\\*", "*", true));
+        assert!(!wildmatch("\\*", "a", true));
+    }
+
+    #[test]
+    fn stars_respect_the_pathname_flag() {
+        assert!(wildmatch("*.log", "debug.log", true));
+        assert!(!wildmatch("*.log", "logs/debug.log", true));
+        assert!(wildmatch("*.log", "logs/debug.log", false));
+        assert!(wildmatch("src/*.rs", "src/main.rs", true));
+        assert!(!wildmatch("src/*.rs", "src/cmds/add.rs", true));
+        assert!(wildmatch("src/*", "src/", true));
+        assert!(!wildmatch("foo*bar", "foo", true));
+    }
+
+    #[test]
+    fn double_stars_cross_directories() {
+        assert!(wildmatch("**/target", "target", true));
+        assert!(wildmatch("**/target", "a/b/target", true));
+        assert!(wildmatch("a/**/b", "a/b", true));
+        assert!(wildmatch("a/**/b", "a/x/y/b", true));
+        assert!(wildmatch("logs/**", "logs/a/b.txt", true));
+        assert!(!wildmatch("logs/**", "other/a.txt", true));
+        // '**' in the middle of a component acts like '*'
+        assert!(!wildmatch("a**b", "a/b", true));
+        assert!(wildmatch("a**b", "axxb", true));
+    }
+
+    #[test]
+    fn bracket_expressions() {
+        assert!(wildmatch("[abc].txt", "b.txt", true));
+        assert!(!wildmatch("[abc].txt", "d.txt", true));
+        assert!(wildmatch("[!abc].txt", "d.txt", true));
+        assert!(wildmatch("[a-c]", "b", true));
+        assert!(!wildmatch("[a-c]", "d", true));
+        assert!(wildmatch("[]]", "]", true));
+        assert!(wildmatch("[[:digit:]]x", "7x", true));
+        assert!(!wildmatch("[[:digit:]]x", "ax", true));
+        assert!(!wildmatch("a[/]b", "a/b", true));
+        // unterminated brackets never match
+        assert!(!wildmatch("[ab", "a", true));
+    }
+}